@@ -0,0 +1,209 @@
+use crate::{
+    bitmap::BlockBitmap,
+    buddy::BuddyAllocator,
+    device::BlockDevice,
+    error::{FsError, FsResult},
+};
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+
+/// Block allocation strategy, recorded in the superblock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AllocatorKind {
+    /// First-fit bitmap allocator (see `bitmap.rs`)
+    #[default]
+    Bitmap,
+    /// Binary buddy allocator (see `buddy.rs`)
+    Buddy,
+}
+
+impl AllocatorKind {
+    pub fn from_u64(value: u64) -> FsResult<Self> {
+        match value {
+            0 => Ok(AllocatorKind::Bitmap),
+            1 => Ok(AllocatorKind::Buddy),
+            _ => Err(FsError::InvalidMetadata(format!(
+                "Invalid allocator kind: {}",
+                value
+            ))),
+        }
+    }
+
+    pub fn to_u64(self) -> u64 {
+        match self {
+            AllocatorKind::Bitmap => 0,
+            AllocatorKind::Buddy => 1,
+        }
+    }
+
+    /// Number of blocks after the superblock used for allocator state
+    pub fn metadata_blocks(self, total_blocks: u64, block_size: u64) -> u64 {
+        match self {
+            AllocatorKind::Bitmap => BlockBitmap::calculate_bitmap_blocks(total_blocks, block_size),
+            AllocatorKind::Buddy => BuddyAllocator::calculate_metadata_blocks(total_blocks, block_size),
+        }
+    }
+
+    /// Create an allocator for a freshly formatted disk
+    pub fn create(self, total_blocks: u64, block_size: u64) -> Box<dyn Allocator> {
+        match self {
+            AllocatorKind::Bitmap => Box::new(BlockBitmap::new(total_blocks, block_size)),
+            AllocatorKind::Buddy => Box::new(BuddyAllocator::new(total_blocks, block_size)),
+        }
+    }
+
+    /// Load allocator state from disk
+    pub fn load(self, device: &dyn BlockDevice, total_blocks: u64, block_size: u64) -> FsResult<Box<dyn Allocator>> {
+        Ok(match self {
+            AllocatorKind::Bitmap => Box::new(BlockBitmap::load(device, total_blocks, block_size)?),
+            AllocatorKind::Buddy => Box::new(BuddyAllocator::load(device, total_blocks, block_size)?),
+        })
+    }
+}
+
+/// Common interface of the block allocators
+///
+/// `VirtualDisk` allocates and frees every block through this trait, so
+/// strategies can be swapped without touching the file system code. The
+/// allocator's persistent state lives in the blocks right after the
+/// superblock; both the superblock and those blocks are reserved on creation.
+/// Allocators are `Send` so that a disk can be shared between threads.
+pub trait Allocator: Debug + Send {
+    /// Which strategy this is
+    fn kind(&self) -> AllocatorKind;
+
+    /// Allocate a single free block
+    fn allocate_block(&mut self) -> FsResult<u64>;
+
+    /// Allocate a single free block as close after `goal` as possible
+    fn allocate_block_near(&mut self, goal: u64) -> FsResult<u64>;
+
+    /// Allocate `count` contiguous blocks, returning the first one
+    fn allocate_contiguous(&mut self, count: u64) -> FsResult<u64> {
+        self.allocate_contiguous_near(0, count)
+    }
+
+    /// Allocate `count` contiguous blocks, preferring runs after `goal`
+    fn allocate_contiguous_near(&mut self, goal: u64, count: u64) -> FsResult<u64>;
+
+    /// Allocate one contiguous run of at most `max_count` blocks near
+    /// `goal`, returning its start and length
+    fn allocate_extent_near(&mut self, goal: u64, max_count: u64) -> FsResult<(u64, u64)>;
+
+    /// Mark a range of blocks as used without allocating them
+    fn reserve(&mut self, start: u64, count: u64);
+
+    /// Free a block, making it available for allocation
+    fn free_block(&mut self, block: u64);
+
+    /// Free multiple contiguous blocks
+    fn free_blocks(&mut self, start: u64, count: u64) {
+        for block in start..(start + count) {
+            self.free_block(block);
+        }
+    }
+
+    /// Check if a block is currently in use
+    fn is_block_used(&self, block: u64) -> bool;
+
+    /// Get the total number of blocks
+    fn total_blocks(&self) -> u64;
+
+    /// Get the number of blocks used by the allocator's own state
+    fn bitmap_blocks(&self) -> u64;
+
+    /// Count free blocks
+    fn count_free_blocks(&self) -> u64;
+
+    /// Count free blocks in `start..end`
+    fn count_free_in_range(&self, start: u64, end: u64) -> u64;
+
+    /// Count used blocks
+    fn count_used_blocks(&self) -> u64 {
+        self.total_blocks() - self.count_free_blocks()
+    }
+
+    /// Get utilization percentage (0.0 to 100.0)
+    fn utilization(&self) -> f64 {
+        let used = self.count_used_blocks() as f64;
+        let total = self.total_blocks() as f64;
+        (used / total) * 100.0
+    }
+
+    /// Blocks marked used only because an allocation was rounded up
+    fn padding_blocks(&self) -> u64 {
+        0
+    }
+
+    /// Check if a used block is rounding waste of an earlier allocation
+    fn is_padding(&self, _block: u64) -> bool {
+        false
+    }
+
+    /// Save allocator state to disk
+    fn save(&mut self, device: &dyn BlockDevice, block_size: u64) -> FsResult<()>;
+
+    /// Blocks of allocator state that the next `save` will write to
+    fn dirty_blocks(&self, block_size: u64) -> BTreeSet<u64>;
+
+    /// Internal and external fragmentation of the block space
+    fn stats(&self) -> AllocationStats {
+        let mut stats = AllocationStats {
+            kind: self.kind(),
+            used_blocks: self.count_used_blocks(),
+            padding_blocks: self.padding_blocks(),
+            free_blocks: self.count_free_blocks(),
+            ..Default::default()
+        };
+
+        let mut run = 0;
+        for block in 0..=self.total_blocks() {
+            if block < self.total_blocks() && !self.is_block_used(block) {
+                run += 1;
+            } else if run > 0 {
+                stats.free_runs += 1;
+                stats.largest_free_run = stats.largest_free_run.max(run);
+                run = 0;
+            }
+        }
+
+        stats
+    }
+}
+
+/// Fragmentation statistics reported by an allocator
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AllocationStats {
+    pub kind: AllocatorKind,
+    /// Blocks in use, including padding
+    pub used_blocks: u64,
+    /// Used blocks that were never requested (rounding waste)
+    pub padding_blocks: u64,
+    /// Blocks available for allocation
+    pub free_blocks: u64,
+    /// Number of separate runs of free blocks
+    pub free_runs: u64,
+    /// Length of the longest run of free blocks
+    pub largest_free_run: u64,
+}
+
+impl AllocationStats {
+    /// Percentage of used blocks lost to rounding (0.0 to 100.0)
+    pub fn internal_fragmentation(&self) -> f64 {
+        if self.used_blocks == 0 {
+            return 0.0;
+        }
+        self.padding_blocks as f64 / self.used_blocks as f64 * 100.0
+    }
+
+    /// Percentage of free space outside the largest free run (0.0 to 100.0)
+    ///
+    /// 0% means all free space is one contiguous run; values near 100%
+    /// mean free space is scattered in many small holes.
+    pub fn external_fragmentation(&self) -> f64 {
+        if self.free_blocks == 0 {
+            return 0.0;
+        }
+        (1.0 - self.largest_free_run as f64 / self.free_blocks as f64) * 100.0
+    }
+}
@@ -0,0 +1,388 @@
+use crate::{
+    error::{FsError, FsResult},
+    serialization::{DirectoryEntry, Inode, Permissions},
+    shared::SharedDisk,
+};
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+
+/// Largest read or write a file handle passes to the disk at once
+const MAX_CHUNK: usize = 1024 * 1024;
+
+/// Runs disk operations for the async API
+///
+/// The file system itself is synchronous, so `AsyncDisk` hands each
+/// operation to the device as a closure over a `SharedDisk` and awaits
+/// the result. An operation that has started must run to completion even
+/// if the returned future is dropped, so a cancelled write is either not
+/// applied at all or applied in full, never cut off halfway.
+pub trait AsyncBlockDevice: Send + Sync + 'static {
+    /// Run `op` without blocking the calling task
+    fn run<T, F>(&self, op: F) -> impl Future<Output = FsResult<T>> + Send
+    where
+        F: FnOnce(&SharedDisk) -> FsResult<T> + Send + 'static,
+        T: Send + 'static;
+}
+
+/// Runs disk operations on tokio's blocking thread pool
+#[derive(Debug, Clone)]
+pub struct BlockingDevice {
+    disk: Arc<SharedDisk>,
+}
+
+impl BlockingDevice {
+    pub fn new(disk: SharedDisk) -> Self {
+        BlockingDevice { disk: Arc::new(disk) }
+    }
+
+    /// Give back the disk, or `None` while operations are still running
+    pub fn into_disk(self) -> Option<SharedDisk> {
+        Arc::into_inner(self.disk)
+    }
+}
+
+impl AsyncBlockDevice for BlockingDevice {
+    fn run<T, F>(&self, op: F) -> impl Future<Output = FsResult<T>> + Send
+    where
+        F: FnOnce(&SharedDisk) -> FsResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let disk = Arc::clone(&self.disk);
+        // A blocking task keeps running when its handle is dropped
+        let task = tokio::task::spawn_blocking(move || op(&disk));
+        async move { task.await.map_err(|e| FsError::Io(io::Error::other(e)))? }
+    }
+}
+
+/// Async front end to a shared disk
+///
+/// Mirrors the path operations of `SharedDisk`, plus file handles that
+/// implement tokio's `AsyncRead`, `AsyncWrite` and `AsyncSeek`. Buffers
+/// are copied, since an operation may outlive the call that started it.
+#[derive(Debug)]
+pub struct AsyncDisk<D = BlockingDevice> {
+    device: Arc<D>,
+}
+
+impl<D> Clone for AsyncDisk<D> {
+    fn clone(&self) -> Self {
+        AsyncDisk { device: Arc::clone(&self.device) }
+    }
+}
+
+impl AsyncDisk<BlockingDevice> {
+    /// Run `disk` on tokio's blocking thread pool
+    ///
+    /// Must be used from within a tokio runtime.
+    pub fn new(disk: SharedDisk) -> Self {
+        Self::with_device(BlockingDevice::new(disk))
+    }
+}
+
+impl<D: AsyncBlockDevice> AsyncDisk<D> {
+    pub fn with_device(device: D) -> Self {
+        AsyncDisk { device: Arc::new(device) }
+    }
+
+    /// The device operations run on
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Run `op` with an owned copy of `path`
+    async fn run<T, F>(&self, path: &str, op: F) -> FsResult<T>
+    where
+        F: FnOnce(&SharedDisk, &str) -> FsResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let path = path.to_string();
+        self.device.run(move |disk| op(disk, &path)).await
+    }
+
+    // ==================== PATH OPERATIONS ====================
+
+    /// Get the inode block at `path`, following symlinks
+    pub async fn lookup(&self, path: &str) -> FsResult<u64> {
+        self.run(path, |disk, path| disk.lookup(path)).await
+    }
+
+    /// Get the inode at `path`, without following a final symlink
+    pub async fn stat(&self, path: &str) -> FsResult<Inode> {
+        self.run(path, |disk, path| disk.stat(path)).await
+    }
+
+    /// Get the inode at `path`, following symlinks
+    pub async fn metadata(&self, path: &str) -> FsResult<Inode> {
+        self.run(path, |disk, path| disk.metadata(path)).await
+    }
+
+    /// List the directory at `path`
+    pub async fn list_directory(&self, path: &str) -> FsResult<Vec<DirectoryEntry>> {
+        self.run(path, |disk, path| disk.list_directory(path)).await
+    }
+
+    /// Read the whole file at `path`
+    pub async fn read_file(&self, path: &str) -> FsResult<Vec<u8>> {
+        self.run(path, |disk, path| disk.read_file(path)).await
+    }
+
+    /// Read up to `len` bytes from the file at `path` at byte `offset`
+    ///
+    /// Fewer bytes are returned only at the end of the file.
+    pub async fn read_at(&self, path: &str, offset: u64, len: usize) -> FsResult<Vec<u8>> {
+        self.run(path, move |disk, path| {
+            let mut data = vec![0u8; len];
+            let read = disk.read_at(path, offset, &mut data)?;
+            data.truncate(read);
+            Ok(data)
+        })
+        .await
+    }
+
+    /// Replace the contents of the file at `path`, creating it if needed
+    pub async fn write_file(&self, path: &str, data: &[u8]) -> FsResult<u64> {
+        let data = data.to_vec();
+        self.run(path, move |disk, path| disk.write_file(path, &data)).await
+    }
+
+    /// Write `data` to the file at `path` at byte `offset`
+    ///
+    /// If the future is dropped after its first poll, the write still
+    /// completes in the background.
+    pub async fn write_at(&self, path: &str, offset: u64, data: &[u8]) -> FsResult<()> {
+        let data = data.to_vec();
+        self.run(path, move |disk, path| disk.write_at(path, offset, &data)).await
+    }
+
+    /// Create an empty file at `path`
+    pub async fn create_file(&self, path: &str, permissions: Permissions) -> FsResult<u64> {
+        self.run(path, move |disk, path| disk.create_file(path, permissions)).await
+    }
+
+    /// Create a directory at `path`
+    pub async fn create_directory(&self, path: &str, permissions: Permissions) -> FsResult<u64> {
+        self.run(path, move |disk, path| disk.create_directory(path, permissions)).await
+    }
+
+    /// Remove the file, symlink or empty directory at `path`
+    pub async fn remove(&self, path: &str) -> FsResult<()> {
+        self.run(path, |disk, path| disk.remove(path)).await
+    }
+
+    /// Rename `from` to `to`, see `SharedDisk::rename`
+    pub async fn rename(&self, from: &str, to: &str) -> FsResult<()> {
+        let to = to.to_string();
+        self.run(from, move |disk, from| disk.rename(from, &to)).await
+    }
+
+    /// Save allocator state and flush the image to stable storage
+    pub async fn sync(&self) -> FsResult<()> {
+        self.device.run(|disk| disk.sync()).await
+    }
+
+    // ==================== FILE HANDLES ====================
+
+    /// Open the existing file at `path` for reading and writing
+    pub async fn open(&self, path: &str) -> FsResult<AsyncFile<D>> {
+        // An empty read checks that the file exists and is a file
+        self.run(path, |disk, path| disk.read_at(path, 0, &mut [])).await?;
+        Ok(AsyncFile::new(Arc::clone(&self.device), path))
+    }
+
+    /// Create the file at `path`, or truncate it if it exists, and open it
+    pub async fn create(&self, path: &str) -> FsResult<AsyncFile<D>> {
+        self.run(path, |disk, path| disk.write_file(path, &[])).await?;
+        Ok(AsyncFile::new(Arc::clone(&self.device), path))
+    }
+}
+
+type Task<T> = Pin<Box<dyn Future<Output = FsResult<T>> + Send>>;
+
+/// Operation a file handle is waiting for
+enum State {
+    Idle,
+    Reading(Task<Vec<u8>>),
+    Writing(Task<()>),
+    /// File size, for a seek from the end
+    Sizing(Task<u64>),
+}
+
+/// Open file on an `AsyncDisk`
+///
+/// The handle refers to the file by path, so I/O fails with `NotFound`
+/// once the file is renamed or removed.
+///
+/// Writes go on in the background: `poll_write` starts the write and
+/// returns at once, and the next operation on the handle (or a flush)
+/// waits for it and reports its error. A write that has started always
+/// completes, even if the handle is dropped.
+pub struct AsyncFile<D = BlockingDevice> {
+    device: Arc<D>,
+    path: Arc<str>,
+    position: u64,
+    state: State,
+    /// Target of a seek started with `start_seek`
+    seek: Option<SeekFrom>,
+}
+
+impl<D: AsyncBlockDevice> AsyncFile<D> {
+    fn new(device: Arc<D>, path: &str) -> Self {
+        AsyncFile {
+            device,
+            path: Arc::from(path),
+            position: 0,
+            state: State::Idle,
+            seek: None,
+        }
+    }
+
+    /// Path the handle was opened with
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Current byte position
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn task<T, F>(&self, op: F) -> Task<T>
+    where
+        F: FnOnce(&SharedDisk, &str) -> FsResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let device = Arc::clone(&self.device);
+        let path = Arc::clone(&self.path);
+        Box::pin(async move { device.run(move |disk| op(disk, &path)).await })
+    }
+
+    /// Wait for a background write to finish; an unfinished read or size
+    /// lookup is abandoned
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let State::Writing(task) = &mut self.state {
+            let result = ready!(task.as_mut().poll(cx));
+            self.state = State::Idle;
+            result?;
+        }
+        self.state = State::Idle;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<D: AsyncBlockDevice> AsyncFile<D> {
+    /// Position a seek is relative to, and the offset from it
+    fn poll_seek_base(&mut self, cx: &mut Context<'_>, target: SeekFrom) -> Poll<io::Result<(u64, i64)>> {
+        let sizing = matches!(self.state, State::Sizing(_));
+        if !(sizing && matches!(target, SeekFrom::End(_))) {
+            // Every seek waits for a background write and drops a read
+            // started for the old position
+            ready!(self.poll_idle(cx))?;
+        }
+        match target {
+            SeekFrom::Start(offset) => Poll::Ready(Ok((offset, 0))),
+            SeekFrom::Current(delta) => Poll::Ready(Ok((self.position, delta))),
+            SeekFrom::End(delta) => {
+                if !matches!(self.state, State::Sizing(_)) {
+                    self.state = State::Sizing(self.task(|disk, path| Ok(disk.metadata(path)?.size)));
+                }
+                let State::Sizing(task) = &mut self.state else {
+                    unreachable!()
+                };
+                let result = ready!(task.as_mut().poll(cx));
+                self.state = State::Idle;
+                Poll::Ready(Ok((result?, delta)))
+            }
+        }
+    }
+}
+
+impl<D: AsyncBlockDevice> AsyncRead for AsyncFile<D> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if let State::Reading(task) = &mut this.state {
+                let result = ready!(task.as_mut().poll(cx));
+                this.state = State::Idle;
+                let data = result?;
+                // The read may have been started for a larger buffer
+                let len = data.len().min(buf.remaining());
+                buf.put_slice(&data[..len]);
+                this.position += len as u64;
+                return Poll::Ready(Ok(()));
+            }
+
+            ready!(this.poll_idle(cx))?;
+            if buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            let (position, len) = (this.position, buf.remaining().min(MAX_CHUNK));
+            this.state = State::Reading(this.task(move |disk, path| {
+                let mut data = vec![0u8; len];
+                let read = disk.read_at(path, position, &mut data)?;
+                data.truncate(read);
+                Ok(data)
+            }));
+        }
+    }
+}
+
+impl<D: AsyncBlockDevice> AsyncWrite for AsyncFile<D> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_idle(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let data = buf[..buf.len().min(MAX_CHUNK)].to_vec();
+        let (position, len) = (this.position, data.len());
+        let mut task = this.task(move |disk, path| disk.write_at(path, position, &data));
+        // Start the write now, so that it runs even if the handle is not
+        // polled again
+        match task.as_mut().poll(cx) {
+            Poll::Ready(result) => result?,
+            Poll::Pending => this.state = State::Writing(task),
+        }
+        this.position += len as u64;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_idle(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_idle(cx)
+    }
+}
+
+impl<D: AsyncBlockDevice> AsyncSeek for AsyncFile<D> {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        if this.seek.is_some() {
+            return Err(io::Error::other("another seek is in progress"));
+        }
+        this.seek = Some(position);
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        let Some(target) = this.seek else {
+            return Poll::Ready(Ok(this.position));
+        };
+        let result = ready!(this.poll_seek_base(cx, target));
+        this.seek = None;
+
+        let (base, delta) = result?;
+        let position = base.checked_add_signed(delta).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative or overflowing position")
+        })?;
+        this.position = position;
+        Poll::Ready(Ok(position))
+    }
+}
@@ -0,0 +1,1500 @@
+use file_system_simulator::{
+    allocator::AllocatorKind,
+    encryption::EncryptionOptions,
+    error::{FsError, FsResult},
+    history::HistoryPolicy,
+    nbd::{NbdExport, NbdServer, DEFAULT_EXPORT_NAME},
+    quota::{GracePeriods, QuotaKind, QuotaLimits, QuotaUsage},
+    serialization::{FileType, Inode, Superblock},
+    shell::{format_time, mode_string, type_name},
+    transfer::TransferReport,
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+use serde_json::{json, Value};
+use std::io::{IsTerminal, Write};
+use std::net::TcpListener;
+use std::path::Path;
+
+const USAGE: &str = "\
+Usage: fssim [--json] <command> [args]
+
+Commands:
+  mkfs [--size SIZE] [--block-size BYTES] [--extents] [--allocator bitmap|buddy]
+       [--checksums | --data-checksums] [--encrypt [--encrypt-names]] [--dedup]
+       [--inline-data] IMAGE
+  info IMAGE
+  ls [-R] [-l] IMAGE[:PATH]
+  cp [-r] SOURCE DEST          SOURCE and DEST are host paths or IMAGE:PATH
+  tar-export IMAGE[:PATH] TARFILE    write a tar archive; '-' for stdout
+  tar-import TARFILE IMAGE[:PATH]    unpack a tar archive; '-' for stdin
+  nbd-serve [--read-only] (--tcp ADDR | --unix SOCKET) IMAGE[:PATH]
+                               serve the image, or one file in it, over NBD
+  http-serve [--writable] [--addr ADDR] IMAGE
+                               browse the image over HTTP/WebDAV (needs the
+                               'http' feature; ADDR defaults to 127.0.0.1:8080)
+  layout [--json | --svg] IMAGE
+                               show how every block is used: a block map,
+                               fragments per file and free extents; with
+                               --json or --svg, export the map instead
+  fsck IMAGE
+  scrub IMAGE                  read every block in use and verify its checksum
+  dump-inode IMAGE INODE|PATH
+  snapshot create|delete|rollback IMAGE NAME
+  snapshot list IMAGE
+  history [--versions N] [--max-age AGE] [--max-size SIZE] IMAGE
+                               show or set how many old versions and deleted
+                               files are kept; 0 turns history off or lifts
+                               a limit
+  versions IMAGE:PATH          list the kept versions of a file
+  restore IMAGE:PATH NUMBER    bring back a version of a file
+  trash IMAGE                  list deleted files
+  undelete IMAGE:PATH          move a deleted file back
+  compress [-d] IMAGE:PATH     compress a file, or with -d store it plainly;
+                               for a directory, what is created in it (needs
+                               the 'compression' feature)
+  dedup [on|off|scan] IMAGE    show how much data is shared, turn dedup of
+                               new writes on or off, or deduplicate the
+                               data already on the image
+  quota IMAGE                  show block and inode usage and limits per
+                               user, group and project
+  quota set IMAGE user|group|project ID BLOCK_SOFT BLOCK_HARD INODE_SOFT INODE_HARD
+                               set limits, in blocks and inodes; 0 lifts a
+                               limit, and the first limit turns quotas on
+  quota grace IMAGE user|group|project BLOCK_AGE INODE_AGE
+                               set how long soft limits may be exceeded
+  quota recount IMAGE          count usage from the inodes again
+  chown IMAGE:PATH UID[:GID]   change the owner of a file or directory
+  project [-R] IMAGE:PATH [ID] show or set the project of a file or
+                               directory, with -R of everything below it;
+                               new files take the project of their directory
+
+IMAGE@SNAPSHOT in place of IMAGE opens a snapshot read-only. Encrypted
+images are created and unlocked with the passphrase in $FSSIM_PASSPHRASE
+(needs the 'encryption' feature); without it, file data and encrypted
+names cannot be read or written.
+
+SIZE accepts K, M and G suffixes, AGE s, m, h and d. With --json, results and errors are
+printed as JSON.
+
+Exit codes:
+  0 success, 1 I/O error, 2 usage error, 3 not found, 4 already exists,
+  5 invalid path or wrong file type, 6 out of space, inodes or quota,
+  7 corrupted file system, 8 permission denied or wrong passphrase,
+  9 directory not empty, 10 not supported, 11 fsck found warnings only,
+  12 fsck found errors or scrub found bad blocks";
+
+/// Environment variable holding the passphrase of encrypted images
+const PASSPHRASE_VAR: &str = "FSSIM_PASSPHRASE";
+
+/// Cells per line and lines of the `layout` block map
+const LAYOUT_WIDTH: usize = 64;
+const LAYOUT_ROWS: usize = 32;
+
+/// Blocks per row of the `layout --svg` image
+const LAYOUT_SVG_COLUMNS: u64 = 128;
+
+const EXIT_USAGE: i32 = 2;
+const EXIT_FSCK_WARNINGS: i32 = 11;
+const EXIT_FSCK_ERRORS: i32 = 12;
+
+enum CliError {
+    Usage(String),
+    Fs(FsError),
+}
+
+impl From<FsError> for CliError {
+    fn from(err: FsError) -> Self {
+        CliError::Fs(err)
+    }
+}
+
+impl From<std::io::Error> for CliError {
+    fn from(err: std::io::Error) -> Self {
+        CliError::Fs(FsError::Io(err))
+    }
+}
+
+type CliResult<T> = Result<T, CliError>;
+
+fn usage<T>(message: &str) -> CliResult<T> {
+    Err(CliError::Usage(message.to_string()))
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let json = args.iter().any(|a| a == "--json");
+    args.retain(|a| a != "--json");
+
+    let code = match run(&args, json) {
+        Ok(code) => code,
+        Err(CliError::Usage(message)) => {
+            if json {
+                eprintln!("{}", json!({ "error": message, "exit_code": EXIT_USAGE }));
+            } else {
+                eprintln!("fssim: {}\n\n{}", message, USAGE);
+            }
+            EXIT_USAGE
+        }
+        // The reader went away, as `head` does once it has enough
+        Err(CliError::Fs(FsError::Io(e))) if e.kind() == std::io::ErrorKind::BrokenPipe => 0,
+        Err(CliError::Fs(e)) => {
+            if json {
+                eprintln!("{}", json!({ "error": e.to_string(), "exit_code": e.exit_code() }));
+            } else {
+                eprintln!("fssim: {}", e);
+            }
+            e.exit_code()
+        }
+    };
+    std::process::exit(code);
+}
+
+fn run(args: &[String], json: bool) -> CliResult<i32> {
+    let Some((command, rest)) = args.split_first() else {
+        return usage("missing command");
+    };
+    match command.as_str() {
+        "mkfs" => mkfs(rest, json),
+        "info" => info(rest, json),
+        "ls" => ls(rest, json),
+        "cp" => cp(rest, json),
+        "tar-export" => tar_export(rest, json),
+        "tar-import" => tar_import(rest, json),
+        "nbd-serve" => nbd_serve(rest, json),
+        "http-serve" => http_serve(rest, json),
+        "layout" => layout(rest, json),
+        "fsck" => fsck(rest, json),
+        "scrub" => scrub(rest, json),
+        "dump-inode" => dump_inode(rest, json),
+        "snapshot" => snapshot(rest, json),
+        "history" => history(rest, json),
+        "versions" => versions(rest, json),
+        "restore" => restore(rest, json),
+        "trash" => trash(rest, json),
+        "undelete" => undelete(rest, json),
+        "compress" => compress(rest, json),
+        "dedup" => dedup(rest, json),
+        "quota" => quota(rest, json),
+        "chown" => chown(rest, json),
+        "project" => project(rest, json),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(0)
+        }
+        _ => usage(&format!("unknown command '{}'", command)),
+    }
+}
+
+/// Split `IMAGE:PATH` into its parts; plain arguments are host paths
+fn image_spec(arg: &str) -> Option<(&str, &str)> {
+    match arg.split_once(':') {
+        Some((image, path)) if !image.is_empty() => Some((image, if path.is_empty() { "/" } else { path })),
+        _ => None,
+    }
+}
+
+/// Open an existing image, or a snapshot of one given as `IMAGE@SNAPSHOT`
+///
+/// An encrypted image is unlocked if the passphrase is in the environment.
+fn open(image: &str) -> FsResult<VirtualDisk> {
+    let mut disk = if Path::new(image).exists() {
+        VirtualDisk::new(image)?
+    } else {
+        match image.rsplit_once('@') {
+            Some((path, name)) if Path::new(path).exists() => VirtualDisk::open_snapshot(path, name)?,
+            _ => return Err(FsError::FileNotFound(image.to_string())),
+        }
+    };
+    if disk.is_encrypted() {
+        if let Ok(passphrase) = std::env::var(PASSPHRASE_VAR) {
+            disk.unlock(&passphrase)?;
+        }
+    }
+    Ok(disk)
+}
+
+/// Parse a size such as `4096`, `64K`, `256M` or `1G`
+fn parse_size(value: &str) -> CliResult<u64> {
+    let (digits, multiplier) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 1 << 10),
+        Some('M') => (&value[..value.len() - 1], 1 << 20),
+        Some('G') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    match digits.parse::<u64>() {
+        Ok(n) => Ok(n * multiplier),
+        Err(_) => usage(&format!("invalid size '{}'", value)),
+    }
+}
+
+/// Parse an age in seconds such as `90`, `30m`, `12h` or `7d`
+fn parse_age(value: &str) -> CliResult<u64> {
+    let (digits, multiplier) = match value.chars().last() {
+        Some('s') => (&value[..value.len() - 1], 1),
+        Some('m') => (&value[..value.len() - 1], 60),
+        Some('h') => (&value[..value.len() - 1], 3600),
+        Some('d') => (&value[..value.len() - 1], 86_400),
+        _ => (value, 1),
+    };
+    match digits.parse::<u64>() {
+        Ok(n) => Ok(n * multiplier),
+        Err(_) => usage(&format!("invalid age '{}'", value)),
+    }
+}
+
+/// Separate `-x` style flags from positional arguments
+fn split_flags(args: &[String]) -> (Vec<char>, Vec<&str>) {
+    let mut flags = Vec::new();
+    let mut positional = Vec::new();
+    for arg in args {
+        match arg.strip_prefix('-') {
+            Some(letters) if !letters.is_empty() && !letters.starts_with('-') => flags.extend(letters.chars()),
+            _ => positional.push(arg.as_str()),
+        }
+    }
+    (flags, positional)
+}
+
+fn mkfs(args: &[String], json: bool) -> CliResult<i32> {
+    let mut options = FormatOptions::default();
+    let mut image = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--size" => options.size = parse_size(args.next().map_or("", String::as_str))?,
+            "--block-size" => options.block_size = parse_size(args.next().map_or("", String::as_str))?,
+            "--extents" => options.extents = true,
+            "--checksums" => options.checksums = true,
+            "--data-checksums" => options.data_checksums = true,
+            "--dedup" => options.dedup = true,
+            "--inline-data" => options.inline_data = true,
+            "--encrypt" | "--encrypt-names" => {
+                let Ok(passphrase) = std::env::var(PASSPHRASE_VAR) else {
+                    return usage(&format!("{} needs the passphrase in ${}", arg, PASSPHRASE_VAR));
+                };
+                let encryption = options.encryption.get_or_insert_with(|| EncryptionOptions::new(&passphrase));
+                encryption.encrypt_names |= arg == "--encrypt-names";
+            }
+            "--allocator" => {
+                options.allocator = match args.next().map(String::as_str) {
+                    Some("bitmap") => AllocatorKind::Bitmap,
+                    Some("buddy") => AllocatorKind::Buddy,
+                    _ => return usage("--allocator expects 'bitmap' or 'buddy'"),
+                }
+            }
+            other if other.starts_with("--") => return usage(&format!("unknown option '{}'", other)),
+            other if image.is_none() => image = Some(other.to_string()),
+            _ => return usage("mkfs takes one image"),
+        }
+    }
+    let Some(image) = image else {
+        return usage("mkfs needs an image path");
+    };
+
+    let mut disk = VirtualDisk::format(&image, options)?;
+    disk.initialize_root_dir()?;
+    print_info(&mut disk, json)?;
+    Ok(0)
+}
+
+fn info(args: &[String], json: bool) -> CliResult<i32> {
+    let [image] = args else {
+        return usage("info takes one image");
+    };
+    let mut disk = open(image)?;
+    print_info(&mut disk, json)?;
+    Ok(0)
+}
+
+fn print_info(disk: &mut VirtualDisk, json: bool) -> FsResult<()> {
+    let superblock = disk.block_groups().superblock().clone();
+    let group_count = disk.block_groups().group_count() as u64;
+    let total_inodes = group_count * superblock.inodes_per_group;
+    let used_inodes = disk.block_groups().used_inodes().len() as u64;
+    let stats = disk.allocation_stats();
+    let mut features = Vec::new();
+    if superblock.has_feature(Superblock::FEATURE_EXTENTS) {
+        features.push("extents");
+    }
+    if superblock.has_feature(Superblock::FEATURE_CHECKSUMS) {
+        features.push("checksums");
+    }
+    if superblock.has_feature(Superblock::FEATURE_DATA_CHECKSUMS) {
+        features.push("data-checksums");
+    }
+    if superblock.has_feature(Superblock::FEATURE_ENCRYPTION) {
+        features.push("encryption");
+    }
+    if superblock.has_feature(Superblock::FEATURE_ENCRYPTED_NAMES) {
+        features.push("encrypted-names");
+    }
+    if superblock.has_feature(Superblock::FEATURE_DEDUP) {
+        features.push("dedup");
+    }
+    if superblock.has_feature(Superblock::FEATURE_INLINE_DATA) {
+        features.push("inline-data");
+    }
+    if superblock.has_feature(Superblock::FEATURE_QUOTA) {
+        features.push("quota");
+    }
+    let allocator = format!("{:?}", disk.allocator_kind()).to_lowercase();
+    let snapshots = disk.snapshot_list().len();
+    let sharing = disk.sharing_stats();
+    let locked = disk.is_encrypted().then(|| !disk.is_unlocked());
+    let history_blocks = match disk.history_policy().is_enabled() && locked != Some(true) {
+        true => Some(disk.history_blocks()?),
+        false => None,
+    };
+    let dedup = match superblock.has_feature(Superblock::FEATURE_DEDUP) {
+        true => Some(disk.dedup_stats()?),
+        false => None,
+    };
+
+    if json {
+        let info = json!({
+            "block_size": superblock.block_size,
+            "total_blocks": superblock.total_blocks,
+            "used_blocks": disk.used_blocks_count(),
+            "free_blocks": disk.free_blocks_count(),
+            "utilization": disk.utilization(),
+            "block_groups": group_count,
+            "blocks_per_group": superblock.blocks_per_group,
+            "inodes_per_group": superblock.inodes_per_group,
+            "total_inodes": total_inodes,
+            "used_inodes": used_inodes,
+            "root_inode": disk.root_directory(),
+            "features": features,
+            "allocator": allocator,
+            "free_runs": stats.free_runs,
+            "largest_free_run": stats.largest_free_run,
+            "external_fragmentation": stats.external_fragmentation(),
+            "snapshots": snapshots,
+            "shared_blocks": sharing.shared_blocks,
+            "saved_blocks": sharing.saved_blocks,
+            "history_blocks": history_blocks,
+            "dedup_ratio": dedup.map(|stats| stats.ratio()),
+            "locked": locked,
+        });
+        println!("{}", info);
+        return Ok(());
+    }
+
+    println!("Block size:        {}", superblock.block_size);
+    println!("Total blocks:      {}", superblock.total_blocks);
+    println!("Used blocks:       {} ({:.2}%)", disk.used_blocks_count(), disk.utilization());
+    println!("Free blocks:       {}", disk.free_blocks_count());
+    println!("Block groups:      {} x {} blocks", group_count, superblock.blocks_per_group);
+    println!("Inodes:            {} used of {}", used_inodes, total_inodes);
+    match disk.root_directory() {
+        Some(root) => println!("Root inode:        {}", root),
+        None => println!("Root inode:        none"),
+    }
+    println!("Features:          {}", if features.is_empty() { "none".to_string() } else { features.join(", ") });
+    println!("Allocator:         {}", allocator);
+    if let Some(locked) = locked {
+        println!("Encryption:        {}", if locked { "locked" } else { "unlocked" });
+    }
+    println!(
+        "Free space:        {} runs, largest {} blocks ({:.1}% fragmented)",
+        stats.free_runs,
+        stats.largest_free_run,
+        stats.external_fragmentation()
+    );
+    println!("Snapshots:         {}", snapshots);
+    if let Some(blocks) = history_blocks {
+        println!("History:           {} blocks of old versions and deleted files", blocks);
+    }
+    println!(
+        "Shared blocks:     {} by cloned files, saving {} blocks",
+        sharing.shared_blocks, sharing.saved_blocks
+    );
+    if let Some(stats) = dedup {
+        println!(
+            "Dedup:             ratio {:.2}, {} blocks saved, {} hashed",
+            stats.ratio(),
+            stats.saved_blocks(),
+            stats.hashed_blocks
+        );
+    }
+    Ok(())
+}
+
+fn ls(args: &[String], json: bool) -> CliResult<i32> {
+    let (flags, positional) = split_flags(args);
+    let [spec] = positional[..] else {
+        return usage("ls takes one IMAGE[:PATH]");
+    };
+    let (image, path) = image_spec(spec).unwrap_or((spec, "/"));
+    let recursive = flags.contains(&'R');
+    let long = flags.contains(&'l');
+
+    let mut disk = open(image)?;
+    let mut listing = Vec::new();
+    list(&mut disk, path, recursive, &mut listing)?;
+
+    if json {
+        let entries: Vec<Value> = listing
+            .iter()
+            .flat_map(|(_, entries)| entries)
+            .map(|e| {
+                json!({
+                    "path": e.path,
+                    "name": e.name,
+                    "type": type_name(e.inode.file_type),
+                    "inode": e.inode_block,
+                    "size": e.inode.size,
+                    "blocks": e.inode.block_count,
+                    "mode": mode_string(&e.inode),
+                    "modified": e.inode.modified,
+                    "target": e.target,
+                })
+            })
+            .collect();
+        println!("{}", Value::Array(entries));
+        return Ok(0);
+    }
+
+    // Written through a locked handle so that a closed pipe, as when the
+    // listing is piped into `head`, ends the command instead of panicking
+    let mut out = std::io::stdout().lock();
+    for (i, (dir, entries)) in listing.iter().enumerate() {
+        if recursive {
+            if i > 0 {
+                writeln!(out)?;
+            }
+            writeln!(out, "{}:", dir)?;
+        }
+        for e in entries {
+            if long {
+                write!(
+                    out,
+                    "{} {:>2} {:>10} {} {}",
+                    mode_string(&e.inode),
+                    e.inode.link_count,
+                    e.inode.size,
+                    format_time(e.inode.modified),
+                    e.name
+                )?;
+                match &e.target {
+                    Some(target) => writeln!(out, " -> {}", target)?,
+                    None => writeln!(out)?,
+                }
+            } else {
+                writeln!(out, "{}", e.name)?;
+            }
+        }
+    }
+    Ok(0)
+}
+
+struct Listed {
+    path: String,
+    name: String,
+    inode_block: u64,
+    inode: Inode,
+    target: Option<String>,
+}
+
+/// Collect the entries of `path` (and below, if `recursive`) per directory
+fn list(
+    disk: &mut VirtualDisk,
+    path: &str,
+    recursive: bool,
+    out: &mut Vec<(String, Vec<Listed>)>,
+) -> FsResult<()> {
+    let inode_block = disk.lookup_path(path)?;
+    if disk.read_inode(inode_block)?.file_type != FileType::Directory {
+        let inode_block = disk.lookup_path_nofollow(path)?;
+        let entry = listed(disk, path.to_string(), path.to_string(), inode_block)?;
+        out.push((path.to_string(), vec![entry]));
+        return Ok(());
+    }
+
+    let mut entries = disk.list_directory(inode_block)?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut listed_entries = Vec::new();
+    for entry in entries {
+        let child = format!("{}/{}", path.trim_end_matches('/'), entry.name);
+        listed_entries.push(listed(disk, child, entry.name, entry.inode_number)?);
+    }
+
+    let subdirectories: Vec<String> = listed_entries
+        .iter()
+        .filter(|e| e.inode.file_type == FileType::Directory)
+        .map(|e| e.path.clone())
+        .collect();
+    out.push((path.to_string(), listed_entries));
+
+    if recursive {
+        for dir in subdirectories {
+            list(disk, &dir, true, out)?;
+        }
+    }
+    Ok(())
+}
+
+fn listed(disk: &mut VirtualDisk, path: String, name: String, inode_block: u64) -> FsResult<Listed> {
+    let inode = disk.read_inode(inode_block)?;
+    let target = match inode.file_type {
+        FileType::Symlink => Some(disk.read_link(&path)?),
+        _ => None,
+    };
+    Ok(Listed { path, name, inode_block, inode, target })
+}
+
+fn cp(args: &[String], json: bool) -> CliResult<i32> {
+    let (flags, positional) = split_flags(args);
+    let [source, dest] = positional[..] else {
+        return usage("cp takes SOURCE and DEST");
+    };
+    let recursive = flags.contains(&'r') || flags.contains(&'R');
+
+    let bytes = match (image_spec(source), image_spec(dest)) {
+        (None, None) => return usage("cp needs at least one IMAGE:PATH"),
+        (None, Some((image, path))) => {
+            let mut disk = open(image)?;
+            let path = image_destination(&mut disk, path, base_name(source))?;
+            if Path::new(source).is_dir() {
+                if !recursive {
+                    return Err(FsError::NotAFile(format!("{} is a directory (use cp -r)", source)).into());
+                }
+                let report = disk.import_tree(Path::new(source), &path, &mut |_| {})?;
+                return Ok(print_transfer(&report, source, dest, json));
+            }
+            let data = std::fs::read(source)?;
+            disk.write_file_at(&path, &data)?;
+            data.len()
+        }
+        (Some((image, path)), None) => {
+            let mut disk = open(image)?;
+            let mut host = Path::new(dest).to_path_buf();
+            if dest.ends_with('/') || host.is_dir() {
+                host.push(base_name(path));
+            }
+            if recursive && disk.stat_path(path)?.file_type == FileType::Directory {
+                let report = disk.export_tree(path, &host, &mut |_| {})?;
+                return Ok(print_transfer(&report, source, dest, json));
+            }
+            let data = read_image_file(&mut disk, path)?;
+            std::fs::write(host, &data)?;
+            data.len()
+        }
+        (Some((src_image, src_path)), Some((dst_image, dst_path))) => {
+            if same_file(src_image, dst_image) {
+                let disk = open(src_image)?;
+                if !recursive && disk.stat_path(src_path)?.file_type == FileType::Directory {
+                    return Err(FsError::NotAFile(format!("{} is a directory (use cp -r)", src_path)).into());
+                }
+                disk.copy_path(src_path, dst_path)?;
+                0
+            } else {
+                let data = read_image_file(&mut open(src_image)?, src_path)?;
+                let mut disk = open(dst_image)?;
+                let path = image_destination(&mut disk, dst_path, base_name(src_path))?;
+                disk.write_file_at(&path, &data)?;
+                data.len()
+            }
+        }
+    };
+
+    if json {
+        println!("{}", json!({ "source": source, "dest": dest, "bytes": bytes }));
+    }
+    Ok(0)
+}
+
+/// Print the outcome of a recursive copy between the host and an image
+///
+/// A copy that ran out of space still lists what was copied, then exits
+/// with the out-of-space code.
+fn print_transfer(report: &TransferReport, source: &str, dest: &str, json: bool) -> i32 {
+    if json {
+        let entries: Vec<Value> = report
+            .entries
+            .iter()
+            .map(|e| {
+                json!({
+                    "host_path": e.host_path.display().to_string(),
+                    "image_path": e.image_path,
+                    "type": type_name(e.file_type),
+                    "bytes": e.bytes,
+                })
+            })
+            .collect();
+        let skipped: Vec<Value> = report
+            .skipped
+            .iter()
+            .map(|(path, reason)| json!({ "host_path": path.display().to_string(), "reason": reason }))
+            .collect();
+        println!(
+            "{}",
+            json!({
+                "source": source,
+                "dest": dest,
+                "bytes": report.bytes(),
+                "files": report.files(),
+                "directories": report.directories(),
+                "symlinks": report.symlinks(),
+                "entries": entries,
+                "skipped": skipped,
+                "stopped": report.stopped.as_ref().map(|e| e.to_string()),
+            })
+        );
+    } else {
+        for (path, reason) in &report.skipped {
+            eprintln!("fssim: skipped {}: {}", path.display(), reason);
+        }
+        if let Some(e) = &report.stopped {
+            for entry in &report.entries {
+                println!("{}", entry.image_path);
+            }
+            eprintln!(
+                "fssim: {}; copied {} directories, {} files and {} symlinks before stopping",
+                e,
+                report.directories(),
+                report.files(),
+                report.symlinks()
+            );
+        }
+    }
+    report.stopped.as_ref().map_or(0, FsError::exit_code)
+}
+
+/// Read a regular file from an image, refusing directories
+fn read_image_file(disk: &mut VirtualDisk, path: &str) -> FsResult<Vec<u8>> {
+    let inode_block = disk.lookup_path(path)?;
+    if disk.read_inode(inode_block)?.file_type == FileType::Directory {
+        return Err(FsError::NotAFile(format!("{} is a directory (use cp -r)", path)));
+    }
+    disk.read_file(inode_block)
+}
+
+/// Where a copied file goes: inside `path` if it is a directory or ends in `/`
+fn image_destination(disk: &mut VirtualDisk, path: &str, name: &str) -> FsResult<String> {
+    let is_dir = match disk.lookup_path(path) {
+        Ok(block) => disk.read_inode(block)?.file_type == FileType::Directory,
+        Err(FsError::FileNotFound(_)) if path.ends_with('/') => {
+            return Err(FsError::DirectoryNotFound(path.to_string()))
+        }
+        Err(FsError::FileNotFound(_)) => false,
+        Err(e) => return Err(e),
+    };
+    Ok(if is_dir {
+        format!("{}/{}", path.trim_end_matches('/'), name)
+    } else {
+        path.to_string()
+    })
+}
+
+fn base_name(path: &str) -> &str {
+    let path = path.trim_end_matches('/');
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+fn same_file(a: &str, b: &str) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn tar_export(args: &[String], json: bool) -> CliResult<i32> {
+    let [spec, archive] = args else {
+        return usage("tar-export takes IMAGE[:PATH] and TARFILE");
+    };
+    let (image, path) = image_spec(spec).unwrap_or((spec, "/"));
+    let mut disk = open(image)?;
+    let members = if archive == "-" {
+        disk.export_tar(path, &mut std::io::stdout().lock())?
+    } else {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(archive)?);
+        disk.export_tar(path, &mut file)?
+    };
+    if json && archive != "-" {
+        println!("{}", json!({ "source": spec, "archive": archive, "members": members }));
+    }
+    Ok(0)
+}
+
+fn tar_import(args: &[String], json: bool) -> CliResult<i32> {
+    let [archive, spec] = args else {
+        return usage("tar-import takes TARFILE and IMAGE[:PATH]");
+    };
+    let (image, path) = image_spec(spec).unwrap_or((spec, "/"));
+    let mut disk = open(image)?;
+    let members = if archive == "-" {
+        disk.import_tar(&mut std::io::stdin().lock(), path)?
+    } else {
+        let mut file = std::io::BufReader::new(std::fs::File::open(archive)?);
+        disk.import_tar(&mut file, path)?
+    };
+    if json {
+        println!("{}", json!({ "archive": archive, "dest": spec, "members": members }));
+    }
+    Ok(0)
+}
+
+fn nbd_serve(args: &[String], json: bool) -> CliResult<i32> {
+    let mut read_only = false;
+    let mut tcp = None;
+    let mut unix = None;
+    let mut spec = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--read-only" => read_only = true,
+            "--tcp" => tcp = args.next().cloned(),
+            "--unix" => unix = args.next().cloned(),
+            other if other.starts_with("--") => return usage(&format!("unknown option '{}'", other)),
+            other if spec.is_none() => spec = Some(other.to_string()),
+            _ => return usage("nbd-serve takes one IMAGE[:PATH]"),
+        }
+    }
+    let Some(spec) = spec else {
+        return usage("nbd-serve needs an image");
+    };
+    let (image, export) = match image_spec(&spec) {
+        Some((image, path)) => (image, NbdExport::File(path.to_string())),
+        None => (spec.as_str(), NbdExport::Image),
+    };
+    let mut server = NbdServer::new(open(image)?, export, read_only);
+
+    let address = match (tcp, unix) {
+        (Some(addr), None) => {
+            let listener = TcpListener::bind(&addr)?;
+            let address = listener.local_addr()?.to_string();
+            announce(&address, json);
+            server.serve_tcp(&listener)?;
+            address
+        }
+        #[cfg(unix)]
+        (None, Some(path)) => {
+            let listener = std::os::unix::net::UnixListener::bind(&path)?;
+            announce(&path, json);
+            let served = server.serve_unix(&listener);
+            let _ = std::fs::remove_file(&path);
+            served?;
+            path
+        }
+        _ => return usage("nbd-serve needs exactly one of --tcp ADDR and --unix SOCKET"),
+    };
+    server.into_disk().sync()?;
+    if !json {
+        println!("Stopped serving on {}", address);
+    }
+    Ok(0)
+}
+
+#[cfg(feature = "http")]
+fn http_serve(args: &[String], json: bool) -> CliResult<i32> {
+    let mut writable = false;
+    let mut addr = "127.0.0.1:8080".to_string();
+    let mut image = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--writable" => writable = true,
+            "--addr" => match args.next() {
+                Some(value) => addr = value.clone(),
+                None => return usage("--addr expects an address"),
+            },
+            other if other.starts_with("--") => return usage(&format!("unknown option '{}'", other)),
+            other if image.is_none() => image = Some(other.to_string()),
+            _ => return usage("http-serve takes one image"),
+        }
+    }
+    let Some(image) = image else {
+        return usage("http-serve needs an image");
+    };
+
+    let mut server = file_system_simulator::http::HttpServer::new(open(&image)?, writable);
+    let listener = TcpListener::bind(&addr)?;
+    let address = listener.local_addr()?.to_string();
+    if json {
+        println!("{}", json!({ "listening": format!("http://{}/", address), "writable": writable }));
+    } else {
+        println!("Serving {} on http://{}/{}", image, address, if writable { "" } else { " (read-only)" });
+    }
+    server.serve(&listener)?;
+    server.into_disk().sync()?;
+    Ok(0)
+}
+
+#[cfg(not(feature = "http"))]
+fn http_serve(_args: &[String], _json: bool) -> CliResult<i32> {
+    Err(FsError::NotSupported("HTTP server; rebuild with --features http".to_string()).into())
+}
+
+/// Report where the NBD server listens, before blocking on connections
+fn announce(address: &str, json: bool) {
+    if json {
+        println!("{}", json!({ "listening": address, "export": DEFAULT_EXPORT_NAME }));
+    } else {
+        println!("Serving on {} (export name '{}')", address, DEFAULT_EXPORT_NAME);
+    }
+}
+
+fn layout(args: &[String], json: bool) -> CliResult<i32> {
+    let (svg, image) = match args {
+        [image] => (false, image),
+        [flag, image] if flag == "--svg" => (true, image),
+        _ => return usage("layout takes an optional --svg and one image"),
+    };
+    let mut disk = open(image)?;
+    let layout = disk.disk_layout()?;
+
+    if svg {
+        print!("{}", layout.to_svg(LAYOUT_SVG_COLUMNS));
+    } else if json {
+        println!("{}", layout.to_json());
+    } else {
+        let ansi = std::io::stdout().is_terminal();
+        let blocks_per_cell = layout.blocks_per_cell(LAYOUT_WIDTH, LAYOUT_ROWS);
+        println!("{}: {} blocks per cell", image, blocks_per_cell);
+        println!("{}", layout.render_ascii(LAYOUT_WIDTH, blocks_per_cell, ansi));
+        print!("{}", layout.render_report());
+    }
+    Ok(0)
+}
+
+fn fsck(args: &[String], json: bool) -> CliResult<i32> {
+    let [image] = args else {
+        return usage("fsck takes one image");
+    };
+    let mut disk = open(image)?;
+    let report = disk.fsck()?;
+
+    if json {
+        let issues: Vec<Value> = report
+            .issues
+            .iter()
+            .map(|i| {
+                json!({
+                    "kind": format!("{:?}", i.kind),
+                    "severity": if i.kind.is_error() { "error" } else { "warning" },
+                    "message": i.message,
+                })
+            })
+            .collect();
+        println!(
+            "{}",
+            json!({
+                "clean": report.is_clean(),
+                "errors": report.errors(),
+                "warnings": report.warnings(),
+                "files": report.files,
+                "directories": report.directories,
+                "symlinks": report.symlinks,
+                "data_blocks": report.data_blocks,
+                "mapping_blocks": report.mapping_blocks,
+                "issues": issues,
+            })
+        );
+    } else {
+        for issue in &report.issues {
+            let severity = if issue.kind.is_error() { "error" } else { "warning" };
+            println!("{}: {}", severity, issue.message);
+        }
+        println!(
+            "{}: {} files, {} directories, {} symlinks, {} data blocks, {} mapping blocks",
+            image, report.files, report.directories, report.symlinks, report.data_blocks, report.mapping_blocks
+        );
+        println!("{} errors, {} warnings", report.errors(), report.warnings());
+    }
+
+    Ok(if report.errors() > 0 {
+        EXIT_FSCK_ERRORS
+    } else if report.warnings() > 0 {
+        EXIT_FSCK_WARNINGS
+    } else {
+        0
+    })
+}
+
+fn scrub(args: &[String], json: bool) -> CliResult<i32> {
+    let [image] = args else {
+        return usage("scrub takes one image");
+    };
+    let disk = open(image)?;
+    let report = disk.scrub()?;
+
+    if json {
+        let bad_blocks: Vec<Value> = report
+            .bad_blocks
+            .iter()
+            .map(|b| json!({ "block": b.block, "owner": b.owner, "path": b.path, "error": b.error.to_string() }))
+            .collect();
+        println!(
+            "{}",
+            json!({
+                "clean": report.is_clean(),
+                "blocks_checked": report.blocks_checked,
+                "bad_blocks": bad_blocks,
+                "bad_files": report.bad_files(),
+            })
+        );
+    } else {
+        for bad in &report.bad_blocks {
+            match (&bad.path, bad.owner) {
+                (Some(path), _) => println!("block {} ({}): {}", bad.block, path, bad.error),
+                (None, Some(owner)) => println!("block {} (inode {}): {}", bad.block, owner, bad.error),
+                (None, None) => println!("block {}: {}", bad.block, bad.error),
+            }
+        }
+        println!("{}: {} blocks checked, {} bad", image, report.blocks_checked, report.bad_blocks.len());
+    }
+
+    Ok(if report.is_clean() { 0 } else { EXIT_FSCK_ERRORS })
+}
+
+fn snapshot(args: &[String], json: bool) -> CliResult<i32> {
+    let Some((action, rest)) = args.split_first() else {
+        return usage("snapshot takes an action: create, list, delete or rollback");
+    };
+    match (action.as_str(), rest) {
+        ("list", [image]) => {
+            let snapshots = open(image)?.snapshot_list();
+            if json {
+                let snapshots: Vec<Value> = snapshots
+                    .iter()
+                    .map(|s| json!({ "name": s.name, "created": s.created, "blocks": s.blocks, "copied": s.copied }))
+                    .collect();
+                println!("{}", json!(snapshots));
+            } else {
+                for s in &snapshots {
+                    println!(
+                        "{:<20} {}  {} blocks, {} copied",
+                        s.name,
+                        format_time(s.created),
+                        s.blocks,
+                        s.copied
+                    );
+                }
+            }
+        }
+        ("create", [image, name]) => {
+            let info = open(image)?.snapshot_create(name)?;
+            if json {
+                println!("{}", json!({ "name": info.name, "created": info.created, "blocks": info.blocks }));
+            } else {
+                println!("{}: created snapshot '{}' of {} blocks", image, info.name, info.blocks);
+            }
+        }
+        ("delete", [image, name]) => {
+            open(image)?.snapshot_delete(name)?;
+            if json {
+                println!("{}", json!({ "deleted": name }));
+            } else {
+                println!("{}: deleted snapshot '{}'", image, name);
+            }
+        }
+        ("rollback", [image, name]) => {
+            open(image)?.snapshot_rollback(name)?;
+            if json {
+                println!("{}", json!({ "rolled_back": name }));
+            } else {
+                println!("{}: rolled back to snapshot '{}'", image, name);
+            }
+        }
+        ("create" | "delete" | "rollback", _) => return usage(&format!("snapshot {} takes an image and a name", action)),
+        ("list", _) => return usage("snapshot list takes one image"),
+        _ => return usage(&format!("unknown snapshot action '{}'", action)),
+    }
+    Ok(0)
+}
+
+fn history(args: &[String], json: bool) -> CliResult<i32> {
+    let mut image = None;
+    let (mut versions, mut max_age, mut max_size) = (None, None, None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--versions" => match args.next().map(|n| n.parse::<u64>()) {
+                Some(Ok(n)) => versions = Some(n),
+                _ => return usage("--versions expects a number"),
+            },
+            "--max-age" => max_age = Some(parse_age(args.next().map_or("", String::as_str))?),
+            "--max-size" => max_size = Some(parse_size(args.next().map_or("", String::as_str))?),
+            other if other.starts_with("--") => return usage(&format!("unknown option '{}'", other)),
+            other if image.is_none() => image = Some(other.to_string()),
+            _ => return usage("history takes one image"),
+        }
+    }
+    let Some(image) = image else {
+        return usage("history needs an image path");
+    };
+
+    let mut disk = open(&image)?;
+    let mut policy = disk.history_policy();
+    if versions.is_some() || max_age.is_some() || max_size.is_some() {
+        policy = HistoryPolicy {
+            versions: versions.unwrap_or(policy.versions),
+            max_age: max_age.map_or(policy.max_age, |age| Some(age).filter(|&age| age > 0)),
+            max_blocks: max_size.map_or(policy.max_blocks, |size| {
+                Some(size.div_ceil(disk.block_size())).filter(|&blocks| blocks > 0)
+            }),
+        };
+        disk.set_history_policy(policy)?;
+    }
+    let blocks = disk.history_blocks()?;
+
+    if json {
+        let policy = json!({
+            "versions": policy.versions,
+            "max_age": policy.max_age,
+            "max_blocks": policy.max_blocks,
+            "history_blocks": blocks,
+        });
+        println!("{}", policy);
+    } else if !policy.is_enabled() {
+        println!("{}: no history kept", image);
+    } else {
+        println!("Versions per file: {}", policy.versions);
+        match policy.max_age {
+            Some(age) => println!("Maximum age:       {} seconds", age),
+            None => println!("Maximum age:       none"),
+        }
+        match policy.max_blocks {
+            Some(max) => println!("Maximum size:      {} blocks", max),
+            None => println!("Maximum size:      none"),
+        }
+        println!("In use:            {} blocks", blocks);
+    }
+    Ok(0)
+}
+
+fn dedup(args: &[String], json: bool) -> CliResult<i32> {
+    let (action, image) = match args {
+        [image] => (None, image),
+        [action, image] => (Some(action.as_str()), image),
+        _ => return usage("dedup takes an optional action and one image"),
+    };
+    let mut disk = open(image)?;
+    let mut report = None;
+    match action {
+        None => {}
+        Some("on") => disk.set_dedup(true)?,
+        Some("off") => disk.set_dedup(false)?,
+        Some("scan") => report = Some(disk.dedup_scan()?),
+        Some(other) => return usage(&format!("unknown dedup action '{}' (on, off or scan)", other)),
+    }
+    let enabled = disk.is_dedup_enabled();
+    let stats = disk.dedup_stats()?;
+
+    if json {
+        let mut result = json!({
+            "enabled": enabled,
+            "hashed_blocks": stats.hashed_blocks,
+            "logical_blocks": stats.logical_blocks,
+            "physical_blocks": stats.physical_blocks,
+            "saved_blocks": stats.saved_blocks(),
+            "ratio": stats.ratio(),
+        });
+        if let Some(report) = &report {
+            result["files_scanned"] = json!(report.files_scanned);
+            result["blocks_scanned"] = json!(report.blocks_scanned);
+            result["blocks_shared"] = json!(report.blocks_shared);
+            result["blocks_freed"] = json!(report.blocks_freed);
+        }
+        println!("{}", result);
+        return Ok(0);
+    }
+
+    if let Some(report) = &report {
+        println!(
+            "{}: scanned {} blocks of {} files, shared {}, freed {}",
+            image, report.blocks_scanned, report.files_scanned, report.blocks_shared, report.blocks_freed
+        );
+    }
+    println!("Dedup:             {}", if enabled { "on" } else { "off" });
+    println!("Hashed blocks:     {}", stats.hashed_blocks);
+    println!("Data blocks:       {} logical, {} stored", stats.logical_blocks, stats.physical_blocks);
+    println!("Ratio:             {:.2} ({} blocks saved)", stats.ratio(), stats.saved_blocks());
+    Ok(0)
+}
+
+/// Parse the kind of a quota: `user`, `group` or `project`
+fn quota_kind(name: &str) -> CliResult<QuotaKind> {
+    match QuotaKind::ALL.into_iter().find(|kind| kind.name() == name) {
+        Some(kind) => Ok(kind),
+        None => usage(&format!("unknown quota kind '{}' (user, group or project)", name)),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str, what: &str) -> CliResult<T> {
+    value.parse().or_else(|_| usage(&format!("invalid {} '{}'", what, value)))
+}
+
+fn quota(args: &[String], json: bool) -> CliResult<i32> {
+    let image = match args {
+        [image] => image,
+        [action, image] if action == "recount" => {
+            let mut disk = open(image)?;
+            disk.quota_recount()?;
+            return print_quotas(&disk, json);
+        }
+        [action, image, kind, id, limits @ ..] if action == "set" && limits.len() == 4 => {
+            let (kind, id) = (quota_kind(kind)?, parse_number(id, "id")?);
+            let limits = QuotaLimits {
+                block_soft: parse_number(&limits[0], "limit")?,
+                block_hard: parse_number(&limits[1], "limit")?,
+                inode_soft: parse_number(&limits[2], "limit")?,
+                inode_hard: parse_number(&limits[3], "limit")?,
+            };
+            let mut disk = open(image)?;
+            disk.set_quota(kind, id, limits)?;
+            image
+        }
+        [action, image, kind, blocks, inodes] if action == "grace" => {
+            let kind = quota_kind(kind)?;
+            let grace = GracePeriods { blocks: parse_age(blocks)?, inodes: parse_age(inodes)? };
+            let mut disk = open(image)?;
+            disk.set_grace_periods(kind, grace)?;
+            image
+        }
+        _ => return usage("quota takes an image, optionally after set, grace or recount and their arguments"),
+    };
+    let disk = open(image)?;
+    print_quotas(&disk, json)
+}
+
+fn print_quotas(disk: &VirtualDisk, json: bool) -> CliResult<i32> {
+    let report = disk.quota_report();
+    let over = report.iter().any(QuotaUsage::is_over);
+    if json {
+        let entries: Vec<Value> = report
+            .iter()
+            .map(|usage| {
+                json!({
+                    "kind": usage.kind.name(),
+                    "id": usage.id,
+                    "blocks": usage.blocks,
+                    "inodes": usage.inodes,
+                    "block_soft": usage.limits.block_soft,
+                    "block_hard": usage.limits.block_hard,
+                    "inode_soft": usage.limits.inode_soft,
+                    "inode_hard": usage.limits.inode_hard,
+                    "block_grace_ends": usage.block_grace_ends,
+                    "inode_grace_ends": usage.inode_grace_ends,
+                })
+            })
+            .collect();
+        let grace: Vec<Value> = QuotaKind::ALL
+            .iter()
+            .map(|&kind| {
+                let grace = disk.grace_periods(kind);
+                json!({ "kind": kind.name(), "blocks": grace.blocks, "inodes": grace.inodes })
+            })
+            .collect();
+        println!("{}", json!({ "enabled": disk.is_quota_enabled(), "quotas": entries, "grace": grace }));
+        return Ok(0);
+    }
+
+    if !disk.is_quota_enabled() {
+        println!("Quotas are off");
+        return Ok(0);
+    }
+    let limit = |limit: u64| if limit == 0 { "-".to_string() } else { limit.to_string() };
+    let grace = |ends: Option<u64>| ends.map_or(String::new(), |ends| format!(" (grace until {})", format_time(ends)));
+    println!("{:<7} {:>10} {:>10} {:>10} {:>10} {:>8} {:>6} {:>6}", "", "ID", "BLOCKS", "SOFT", "HARD", "INODES", "SOFT", "HARD");
+    for usage in &report {
+        println!(
+            "{:<7} {:>10} {:>10} {:>10} {:>10} {:>8} {:>6} {:>6}{}{}{}",
+            usage.kind.name(),
+            usage.id,
+            usage.blocks,
+            limit(usage.limits.block_soft),
+            limit(usage.limits.block_hard),
+            usage.inodes,
+            limit(usage.limits.inode_soft),
+            limit(usage.limits.inode_hard),
+            if usage.is_over() { "  over" } else { "" },
+            grace(usage.block_grace_ends),
+            grace(usage.inode_grace_ends),
+        );
+    }
+    for kind in QuotaKind::ALL {
+        let periods = disk.grace_periods(kind);
+        println!("Grace for {}s: {} seconds for blocks, {} for inodes", kind.name(), periods.blocks, periods.inodes);
+    }
+    if over {
+        println!("Some ids are over a limit");
+    }
+    Ok(0)
+}
+
+fn chown(args: &[String], json: bool) -> CliResult<i32> {
+    let [spec, owner] = args else {
+        return usage("chown takes IMAGE:PATH and UID[:GID]");
+    };
+    let (image, path) = file_spec(spec, "chown")?;
+    let (uid, gid) = match owner.split_once(':') {
+        Some((uid, gid)) => (parse_number(uid, "uid")?, Some(parse_number(gid, "gid")?)),
+        None => (parse_number(owner, "uid")?, None),
+    };
+    let disk = open(image)?;
+    let inode_block = disk.lookup_path_nofollow(path)?;
+    let gid = match gid {
+        Some(gid) => gid,
+        None => disk.read_inode(inode_block)?.gid,
+    };
+    disk.set_owner(inode_block, uid, gid)?;
+    disk.sync_bitmap()?;
+    if json {
+        println!("{}", json!({ "path": path, "uid": uid, "gid": gid }));
+    } else {
+        println!("{}: {} is owned by {}:{}", image, path, uid, gid);
+    }
+    Ok(0)
+}
+
+fn project(args: &[String], json: bool) -> CliResult<i32> {
+    let (flags, positional) = split_flags(args);
+    let (spec, project) = match positional[..] {
+        [spec] => (spec, None),
+        [spec, id] => (spec, Some(parse_number::<u32>(id, "project id")?)),
+        _ => return usage("project takes IMAGE:PATH and optionally a project id"),
+    };
+    let (image, path) = file_spec(spec, "project")?;
+    let Some(project) = project else {
+        let disk = open(image)?;
+        let project = disk.stat_path(path)?.project;
+        if json {
+            println!("{}", json!({ "path": path, "project": project }));
+        } else {
+            println!("{}: {} is in project {}", image, path, project);
+        }
+        return Ok(0);
+    };
+
+    let mut disk = open(image)?;
+    let changed = match flags.contains(&'R') {
+        true => disk.set_project_tree(path, project)?,
+        false => {
+            let inode_block = disk.lookup_path_nofollow(path)?;
+            disk.set_project(inode_block, project)?;
+            1
+        }
+    };
+    disk.sync_bitmap()?;
+    if json {
+        println!("{}", json!({ "path": path, "project": project, "changed": changed }));
+    } else {
+        println!("{}: {} is in project {} ({} inodes changed)", image, path, project, changed);
+    }
+    Ok(0)
+}
+
+/// Split `IMAGE:PATH` for a command that works on one file in an image
+fn file_spec<'a>(spec: &'a str, command: &str) -> CliResult<(&'a str, &'a str)> {
+    match image_spec(spec) {
+        Some(parts) => Ok(parts),
+        None => usage(&format!("{} takes IMAGE:PATH", command)),
+    }
+}
+
+fn versions(args: &[String], json: bool) -> CliResult<i32> {
+    let [spec] = args else {
+        return usage("versions takes one IMAGE:PATH");
+    };
+    let (image, path) = file_spec(spec, "versions")?;
+    let versions = open(image)?.list_versions(path)?;
+    if json {
+        let versions: Vec<Value> = versions
+            .iter()
+            .map(|v| json!({ "number": v.number, "size": v.size, "modified": v.modified, "replaced": v.replaced }))
+            .collect();
+        println!("{}", json!(versions));
+    } else {
+        for v in &versions {
+            println!(
+                "{:>4} {:>10} {}  replaced {}",
+                v.number,
+                v.size,
+                format_time(v.modified),
+                format_time(v.replaced)
+            );
+        }
+    }
+    Ok(0)
+}
+
+fn restore(args: &[String], json: bool) -> CliResult<i32> {
+    let [spec, number] = args else {
+        return usage("restore takes IMAGE:PATH and a version number");
+    };
+    let (image, path) = file_spec(spec, "restore")?;
+    let Ok(number) = number.parse::<u64>() else {
+        return usage(&format!("invalid version number '{}'", number));
+    };
+    open(image)?.restore_version(path, number)?;
+    if json {
+        println!("{}", json!({ "restored": path, "version": number }));
+    } else {
+        println!("{}: restored version {} of {}", image, number, path);
+    }
+    Ok(0)
+}
+
+fn trash(args: &[String], json: bool) -> CliResult<i32> {
+    let [image] = args else {
+        return usage("trash takes one image");
+    };
+    let trash = open(image)?.list_trash()?;
+    if json {
+        let trash: Vec<Value> = trash
+            .iter()
+            .map(|t| {
+                json!({
+                    "path": t.path,
+                    "type": type_name(t.file_type),
+                    "size": t.size,
+                    "deleted": t.deleted,
+                })
+            })
+            .collect();
+        println!("{}", json!(trash));
+    } else {
+        for t in &trash {
+            println!("{:>10} {}  {}", t.size, format_time(t.deleted), t.path);
+        }
+    }
+    Ok(0)
+}
+
+fn undelete(args: &[String], json: bool) -> CliResult<i32> {
+    let [spec] = args else {
+        return usage("undelete takes one IMAGE:PATH");
+    };
+    let (image, path) = file_spec(spec, "undelete")?;
+    let inode_block = open(image)?.undelete(path)?;
+    if json {
+        println!("{}", json!({ "undeleted": path, "inode": inode_block }));
+    } else {
+        println!("{}: undeleted {}", image, path);
+    }
+    Ok(0)
+}
+
+fn compress(args: &[String], json: bool) -> CliResult<i32> {
+    let (flags, positional) = split_flags(args);
+    let [spec] = positional[..] else {
+        return usage("compress takes one IMAGE:PATH");
+    };
+    let (image, path) = file_spec(spec, "compress")?;
+    let compressed = !flags.contains(&'d');
+    let disk = open(image)?;
+    let inode_block = disk.lookup_path(path)?;
+    disk.set_compressed(inode_block, compressed)?;
+    let inode = disk.read_inode(inode_block)?;
+    let physical = disk.physical_size(inode_block)?;
+    if json {
+        println!(
+            "{}",
+            json!({ "path": path, "compressed": compressed, "size": inode.size, "physical_size": physical })
+        );
+    } else if inode.file_type == FileType::Directory {
+        let state = if compressed { "compressed" } else { "stored plainly" };
+        println!("{}: new files in {} are {}", image, path, state);
+    } else {
+        println!("{}: {} takes {} bytes for {} bytes of data", image, path, physical, inode.size);
+    }
+    Ok(0)
+}
+
+fn dump_inode(args: &[String], json: bool) -> CliResult<i32> {
+    let [image, which] = args else {
+        return usage("dump-inode takes IMAGE and an inode block or path");
+    };
+    let mut disk = open(image)?;
+    let inode_block = match which.parse::<u64>() {
+        Ok(block) => block,
+        Err(_) => disk.lookup_path_nofollow(which)?,
+    };
+    if !disk.block_groups().is_inode_block(inode_block) {
+        return Err(FsError::BlockNotFound(inode_block).into());
+    }
+    if !disk.block_groups().is_inode_used(inode_block) {
+        return Err(FsError::FileNotFound(format!("inode {}", inode_block)).into());
+    }
+
+    let inode = disk.read_inode(inode_block)?;
+    let mapping = disk.file_mapping(inode_block)?;
+    let layout = if inode.has_flag(Inode::FLAG_EXTENTS) { "extents" } else { "block pointers" };
+    let physical = disk.physical_size(inode_block)?;
+    let mut flags = vec![layout];
+    if inode.has_flag(Inode::FLAG_COMPRESSED) {
+        flags.push("compressed");
+    }
+    if inode.has_flag(Inode::FLAG_COMPRESS) {
+        flags.push("compress new files");
+    }
+    if inode.has_flag(Inode::FLAG_ENCRYPTED) {
+        flags.push("encrypted");
+    }
+    if inode.is_inline() {
+        flags.push("inline");
+    }
+
+    if json {
+        let extents: Vec<Value> = mapping
+            .extents
+            .iter()
+            .map(|e| json!({ "logical": e.logical, "physical": e.physical, "length": e.length }))
+            .collect();
+        println!(
+            "{}",
+            json!({
+                "inode": inode_block,
+                "inode_number": inode.inode_number,
+                "type": type_name(inode.file_type),
+                "mode": mode_string(&inode),
+                "link_count": inode.link_count,
+                "size": inode.size,
+                "physical_size": physical,
+                "block_count": inode.block_count,
+                "created": inode.created,
+                "modified": inode.modified,
+                "accessed": inode.accessed,
+                "flags": inode.flags,
+                "layout": layout,
+                "compressed": inode.has_flag(Inode::FLAG_COMPRESSED),
+                "encrypted": inode.has_flag(Inode::FLAG_ENCRYPTED),
+                "inline": inode.is_inline(),
+                "uid": inode.uid,
+                "gid": inode.gid,
+                "project": inode.project,
+                "direct_blocks": inode.direct_blocks,
+                "indirect_blocks": inode.indirect_blocks,
+                "extents": extents,
+                "mapping_blocks": mapping.metadata_blocks,
+            })
+        );
+        return Ok(0);
+    }
+
+    println!("Inode:        {} (number {})", inode_block, inode.inode_number);
+    println!("Type:         {}", type_name(inode.file_type));
+    println!("Mode:         {}", mode_string(&inode));
+    println!("Links:        {}", inode.link_count);
+    println!("Owner:        {}:{}", inode.uid, inode.gid);
+    println!("Project:      {}", inode.project);
+    println!("Size:         {} ({} on disk)", inode.size, physical);
+    println!("Blocks:       {}", inode.block_count);
+    println!("Created:      {}", format_time(inode.created));
+    println!("Modified:     {}", format_time(inode.modified));
+    println!("Accessed:     {}", format_time(inode.accessed));
+    println!("Flags:        0x{:08x} ({})", inode.flags, flags.join(", "));
+    if !inode.has_flag(Inode::FLAG_EXTENTS) {
+        println!("Direct:       {:?}", inode.direct_blocks);
+        println!("Indirect:     {:?}", inode.indirect_blocks);
+    }
+    println!("Extents:");
+    for e in &mapping.extents {
+        println!("  logical {:>8}  physical {:>8}  length {}", e.logical, e.physical, e.length);
+    }
+    if !mapping.metadata_blocks.is_empty() {
+        println!("Mapping blocks: {:?}", mapping.metadata_blocks);
+    }
+    Ok(0)
+}
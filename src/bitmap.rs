@@ -1,10 +1,24 @@
-use crate::error::{FsError, FsResult};
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
+use crate::{
+    allocator::{Allocator, AllocatorKind},
+    device::BlockDevice,
+    error::{FsError, FsResult},
+};
+use std::collections::BTreeSet;
+
+/// Number of blocks tracked by one summary word (one bit per block)
+const WORD_BITS: u64 = 64;
 
 /// Bitmap-based block allocator for tracking free and used blocks
 /// The bitmap is stored at the beginning of the virtual disk.
 /// Each bit represents one block: 0 = free, 1 = used
+///
+/// On top of the raw bitmap the allocator keeps a few in-memory
+/// accelerators that are rebuilt on load and never written to disk:
+/// - a running count of free blocks, so statistics are O(1)
+/// - a second-level bitmap with one bit per 64-block word that is set
+///   when the word is completely full, so scans skip full regions
+/// - a next-fit cursor, so allocations resume where the last one ended
+/// - the set of bitmap bytes changed since the last save
 #[derive(Debug)]
 pub struct BlockBitmap {
     /// Total number of blocks in the file system
@@ -13,6 +27,14 @@ pub struct BlockBitmap {
     bitmap_blocks: u64,
     /// In-memory bitmap representation
     bitmap: Vec<u8>,
+    /// Number of free blocks, kept in sync with the bitmap
+    free_count: u64,
+    /// One bit per 64-block word: 1 = every block in the word is used
+    full_words: Vec<u64>,
+    /// Block where the next allocation search starts (next-fit)
+    cursor: u64,
+    /// Bitmap bytes modified since the last save
+    dirty: BTreeSet<usize>,
 }
 
 impl BlockBitmap {
@@ -20,104 +42,185 @@ impl BlockBitmap {
     /// Each block can hold BLOCK_SIZE * 8 bits (one bit per block)
     pub fn calculate_bitmap_blocks(total_blocks: u64, block_size: u64) -> u64 {
         let bits_per_block = block_size * 8;
-        (total_blocks + bits_per_block - 1) / bits_per_block
+        total_blocks.div_ceil(bits_per_block)
     }
 
     /// Create a new bitmap for the given number of blocks
     pub fn new(total_blocks: u64, block_size: u64) -> Self {
         let bitmap_blocks = Self::calculate_bitmap_blocks(total_blocks, block_size);
-        let bitmap_bytes = ((total_blocks + 7) / 8) as usize;
-        
+        let bitmap_bytes = total_blocks.div_ceil(8) as usize;
+
         let mut bitmap = vec![0u8; bitmap_bytes];
-        
+
         // Mark bitmap blocks and superblock as used
         let reserved_blocks = bitmap_blocks + 1; // +1 for superblock
         for block in 0..reserved_blocks {
             Self::set_bit(&mut bitmap, block);
         }
-        
-        BlockBitmap {
-            total_blocks,
-            bitmap_blocks,
-            bitmap,
-        }
+
+        let mut result = Self::from_raw(total_blocks, bitmap_blocks, bitmap);
+        // A fresh bitmap has never been written, so every byte is dirty
+        result.dirty = (0..result.bitmap.len()).collect();
+        result
     }
 
     /// Load bitmap from disk
-    pub fn load(file: &mut File, total_blocks: u64, block_size: u64) -> FsResult<Self> {
+    pub fn load(device: &dyn BlockDevice, total_blocks: u64, block_size: u64) -> FsResult<Self> {
         let bitmap_blocks = Self::calculate_bitmap_blocks(total_blocks, block_size);
-        let bitmap_bytes = ((total_blocks + 7) / 8) as usize;
-        
+        let bitmap_bytes = total_blocks.div_ceil(8) as usize;
+
         let mut bitmap = vec![0u8; bitmap_bytes];
-        
+
         // Bitmap starts after superblock (block 0)
-        file.seek(SeekFrom::Start(block_size))?;
-        file.read_exact(&mut bitmap)?;
-        
-        Ok(BlockBitmap {
+        device.read_exact_at(&mut bitmap, block_size)?;
+
+        Ok(Self::from_raw(total_blocks, bitmap_blocks, bitmap))
+    }
+
+    /// Build the in-memory accelerators for a raw bitmap
+    fn from_raw(total_blocks: u64, bitmap_blocks: u64, bitmap: Vec<u8>) -> Self {
+        let words = total_blocks.div_ceil(WORD_BITS) as usize;
+        let mut result = BlockBitmap {
             total_blocks,
             bitmap_blocks,
             bitmap,
-        })
+            free_count: 0,
+            full_words: vec![0u64; words.div_ceil(WORD_BITS as usize)],
+            cursor: 0,
+            dirty: BTreeSet::new(),
+        };
+
+        for word in 0..words as u64 {
+            let used = result.word(word);
+            result.free_count += u64::from(used.count_zeros());
+            result.update_summary(word);
+        }
+
+        result
     }
 
     /// Save bitmap to disk
-    pub fn save(&self, file: &mut File, block_size: u64) -> FsResult<()> {
+    ///
+    /// Only bytes that changed since the previous save are written,
+    /// coalesced into contiguous runs.
+    pub fn save(&mut self, device: &dyn BlockDevice, block_size: u64) -> FsResult<()> {
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        for &byte in &self.dirty {
+            match runs.last_mut() {
+                Some((_, end)) if *end == byte => *end += 1,
+                _ => runs.push((byte, byte + 1)),
+            }
+        }
+
         // Bitmap starts after superblock (block 0)
-        file.seek(SeekFrom::Start(block_size))?;
-        file.write_all(&self.bitmap)?;
-        file.flush()?;
+        for (start, end) in runs {
+            device.write_all_at(&self.bitmap[start..end], block_size + start as u64)?;
+        }
+
+        self.dirty.clear();
         Ok(())
     }
 
     /// Allocate a single free block
     /// Returns the block number if successful, or error if disk is full
     pub fn allocate_block(&mut self) -> FsResult<u64> {
-        for block in 0..self.total_blocks {
-            if !self.is_block_used(block) {
-                self.mark_used(block);
-                return Ok(block);
-            }
+        self.allocate_block_near(self.cursor)
+    }
+
+    /// Allocate a single free block as close after `goal` as possible
+    ///
+    /// The search starts at `goal` and wraps around, so callers can keep
+    /// related blocks (an inode and its data, consecutive file blocks)
+    /// together instead of taking the next-fit position.
+    pub fn allocate_block_near(&mut self, goal: u64) -> FsResult<u64> {
+        let block = self.find_free_from(goal).ok_or(FsError::DiskFull)?;
+        self.mark_used(block);
+        self.cursor = block + 1;
+        Ok(block)
+    }
+
+    /// Mark a range of blocks as used without allocating them
+    ///
+    /// Used when formatting to reserve fixed metadata regions.
+    pub fn reserve(&mut self, start: u64, count: u64) {
+        for block in start..(start + count).min(self.total_blocks) {
+            self.mark_used(block);
         }
-        Err(FsError::DiskFull)
     }
 
     /// Allocate multiple contiguous blocks
     /// Returns the starting block number if successful
     pub fn allocate_contiguous(&mut self, count: u64) -> FsResult<u64> {
+        self.allocate_contiguous_near(0, count)
+    }
+
+    /// Allocate multiple contiguous blocks, taking the first run that
+    /// starts at or after `goal` (wrapping around to the start of the disk)
+    pub fn allocate_contiguous_near(&mut self, goal: u64, count: u64) -> FsResult<u64> {
         if count == 0 {
             return Err(FsError::InvalidOffsetOrSize { offset: 0, size: 0 });
         }
+        if count > self.free_count {
+            return Err(FsError::NotEnoughContiguousSpace(count));
+        }
 
-        let mut start = 0;
-        let mut consecutive = 0;
-
-        for block in 0..self.total_blocks {
-            if !self.is_block_used(block) {
-                if consecutive == 0 {
-                    start = block;
-                }
-                consecutive += 1;
-                
-                if consecutive == count {
-                    // Mark all blocks as used
-                    for b in start..(start + count) {
-                        self.mark_used(b);
-                    }
-                    return Ok(start);
-                }
-            } else {
-                consecutive = 0;
-            }
+        let goal = goal.min(self.total_blocks);
+        let start = self
+            .find_run(goal, self.total_blocks, count)
+            .or_else(|| self.find_run(0, (goal + count - 1).min(self.total_blocks), count))
+            .ok_or(FsError::NotEnoughContiguousSpace(count))?;
+
+        // Mark all blocks as used
+        for b in start..(start + count) {
+            self.mark_used(b);
         }
+        self.cursor = start + count;
+        Ok(start)
+    }
 
-        Err(FsError::NotEnoughContiguousSpace(count))
+    /// Allocate the run of free blocks beginning at the first free block
+    /// at or after `goal`, up to `max_count` blocks long
+    ///
+    /// Returns the start and length of the run. Unlike
+    /// `allocate_contiguous_near` this never fails while any block is free,
+    /// which makes it suitable for building extents piece by piece.
+    pub fn allocate_extent_near(&mut self, goal: u64, max_count: u64) -> FsResult<(u64, u64)> {
+        let start = self.find_free_from(goal).ok_or(FsError::DiskFull)?;
+        let mut length = 0;
+        while length < max_count.max(1) && !self.is_block_used(start + length) {
+            self.mark_used(start + length);
+            length += 1;
+        }
+        self.cursor = start + length;
+        Ok((start, length))
+    }
+
+    /// Find `count` free blocks in a row, starting in `start..end`
+    fn find_run(&self, start: u64, end: u64, count: u64) -> Option<u64> {
+        let mut candidate = start;
+        while let Some(run_start) = self.find_free_in(candidate, end) {
+            if run_start + count > self.total_blocks {
+                return None;
+            }
+
+            match (run_start..run_start + count).find(|&b| self.is_block_used(b)) {
+                Some(used) => candidate = used + 1,
+                None => return Some(run_start),
+            }
+        }
+        None
     }
 
     /// Free a block, making it available for allocation
     pub fn free_block(&mut self, block: u64) {
-        if block < self.total_blocks {
+        if block < self.total_blocks && self.is_block_used(block) {
             Self::clear_bit(&mut self.bitmap, block);
+            self.free_count += 1;
+            self.touch(block);
         }
     }
 
@@ -133,27 +236,110 @@ impl BlockBitmap {
         if block >= self.total_blocks {
             return true; // Out of bounds blocks are considered "used"
         }
-        
+
         let byte_index = (block / 8) as usize;
         let bit_index = (block % 8) as u8;
-        
+
         if byte_index >= self.bitmap.len() {
             return true;
         }
-        
+
         (self.bitmap[byte_index] & (1 << bit_index)) != 0
     }
 
+    /// Find the first free block at or after `start`, wrapping around
+    /// to the beginning of the disk if necessary
+    fn find_free_from(&self, start: u64) -> Option<u64> {
+        if self.free_count == 0 {
+            return None;
+        }
+        let start = if start >= self.total_blocks { 0 } else { start };
+        self.find_free_in(start, self.total_blocks)
+            .or_else(|| self.find_free_in(0, start))
+    }
+
+    /// Find the first free block in `start..end`, skipping full words
+    fn find_free_in(&self, start: u64, end: u64) -> Option<u64> {
+        let end = end.min(self.total_blocks);
+        let mut block = start;
+
+        while block < end {
+            let word = block / WORD_BITS;
+
+            if self.is_word_full(word) {
+                // Jump to the first word of the next summary entry that
+                // still has room, instead of testing words one by one
+                let summary_index = (word / WORD_BITS) as usize;
+                let remaining = self.full_words[summary_index] >> (word % WORD_BITS);
+                let skip = u64::from((!remaining).trailing_zeros()).min(WORD_BITS);
+                block = (word + skip.max(1)) * WORD_BITS;
+                continue;
+            }
+
+            let free_bits = !self.word(word) & (u64::MAX << (block % WORD_BITS));
+            if free_bits != 0 {
+                let found = word * WORD_BITS + u64::from(free_bits.trailing_zeros());
+                return (found < end).then_some(found);
+            }
+            block = (word + 1) * WORD_BITS;
+        }
+
+        None
+    }
+
+    /// Read a 64-block word of the bitmap; bits past the end count as used
+    fn word(&self, word: u64) -> u64 {
+        let mut value = 0u64;
+        for i in 0..8 {
+            let byte_index = (word * 8 + i) as usize;
+            let byte = self.bitmap.get(byte_index).copied().unwrap_or(0xFF);
+            value |= u64::from(byte) << (i * 8);
+        }
+
+        let first_block = word * WORD_BITS;
+        if first_block + WORD_BITS > self.total_blocks {
+            let valid = self.total_blocks.saturating_sub(first_block);
+            value |= u64::MAX << valid;
+        }
+        value
+    }
+
+    fn is_word_full(&self, word: u64) -> bool {
+        let summary_index = (word / WORD_BITS) as usize;
+        (self.full_words[summary_index] >> (word % WORD_BITS)) & 1 == 1
+    }
+
+    /// Recompute the summary bit for a word after it changed
+    fn update_summary(&mut self, word: u64) {
+        let summary_index = (word / WORD_BITS) as usize;
+        let bit = 1u64 << (word % WORD_BITS);
+        if self.word(word) == u64::MAX {
+            self.full_words[summary_index] |= bit;
+        } else {
+            self.full_words[summary_index] &= !bit;
+        }
+    }
+
+    /// Record that the bit for `block` changed
+    fn touch(&mut self, block: u64) {
+        self.dirty.insert((block / 8) as usize);
+        self.update_summary(block / WORD_BITS);
+    }
+
     /// Mark a block as used
     fn mark_used(&mut self, block: u64) {
-        Self::set_bit(&mut self.bitmap, block);
+        if !self.is_block_used(block) {
+            Self::set_bit(&mut self.bitmap, block);
+            self.free_count -= 1;
+            self.touch(block);
+        }
     }
 
     /// Set a bit in the bitmap (mark as used)
     fn set_bit(bitmap: &mut [u8], block: u64) {
         let byte_index = (block / 8) as usize;
         let bit_index = (block % 8) as u8;
-        
+
         if byte_index < bitmap.len() {
             bitmap[byte_index] |= 1 << bit_index;
         }
@@ -163,7 +349,7 @@ impl BlockBitmap {
     fn clear_bit(bitmap: &mut [u8], block: u64) {
         let byte_index = (block / 8) as usize;
         let bit_index = (block % 8) as u8;
-        
+
         if byte_index < bitmap.len() {
             bitmap[byte_index] &= !(1 << bit_index);
         }
@@ -181,18 +367,28 @@ impl BlockBitmap {
 
     /// Count free blocks
     pub fn count_free_blocks(&self) -> u64 {
+        self.free_count
+    }
+
+    /// Count free blocks in `start..end`
+    pub fn count_free_in_range(&self, start: u64, end: u64) -> u64 {
+        let end = end.min(self.total_blocks);
         let mut count = 0;
-        for block in 0..self.total_blocks {
-            if !self.is_block_used(block) {
-                count += 1;
-            }
+        let mut block = start;
+        while block < end {
+            let word = block / WORD_BITS;
+            let word_end = ((word + 1) * WORD_BITS).min(end);
+            let width = word_end - block;
+            let mask = (u64::MAX >> (WORD_BITS - width)) << (block % WORD_BITS);
+            count += u64::from((!self.word(word) & mask).count_ones());
+            block = word_end;
         }
         count
     }
 
     /// Count used blocks
     pub fn count_used_blocks(&self) -> u64 {
-        self.total_blocks - self.count_free_blocks()
+        self.total_blocks - self.free_count
     }
 
     /// Get utilization percentage (0.0 to 100.0)
@@ -201,4 +397,232 @@ impl BlockBitmap {
         let total = self.total_blocks as f64;
         (used / total) * 100.0
     }
-}
\ No newline at end of file
+}
+
+impl Allocator for BlockBitmap {
+    fn kind(&self) -> AllocatorKind {
+        AllocatorKind::Bitmap
+    }
+
+    fn allocate_block(&mut self) -> FsResult<u64> {
+        BlockBitmap::allocate_block(self)
+    }
+
+    fn allocate_block_near(&mut self, goal: u64) -> FsResult<u64> {
+        BlockBitmap::allocate_block_near(self, goal)
+    }
+
+    fn allocate_contiguous_near(&mut self, goal: u64, count: u64) -> FsResult<u64> {
+        BlockBitmap::allocate_contiguous_near(self, goal, count)
+    }
+
+    fn allocate_extent_near(&mut self, goal: u64, max_count: u64) -> FsResult<(u64, u64)> {
+        BlockBitmap::allocate_extent_near(self, goal, max_count)
+    }
+
+    fn reserve(&mut self, start: u64, count: u64) {
+        BlockBitmap::reserve(self, start, count)
+    }
+
+    fn free_block(&mut self, block: u64) {
+        BlockBitmap::free_block(self, block)
+    }
+
+    fn is_block_used(&self, block: u64) -> bool {
+        BlockBitmap::is_block_used(self, block)
+    }
+
+    fn total_blocks(&self) -> u64 {
+        BlockBitmap::total_blocks(self)
+    }
+
+    fn bitmap_blocks(&self) -> u64 {
+        BlockBitmap::bitmap_blocks(self)
+    }
+
+    fn count_free_blocks(&self) -> u64 {
+        BlockBitmap::count_free_blocks(self)
+    }
+
+    fn count_free_in_range(&self, start: u64, end: u64) -> u64 {
+        BlockBitmap::count_free_in_range(self, start, end)
+    }
+
+    fn save(&mut self, device: &dyn BlockDevice, block_size: u64) -> FsResult<()> {
+        BlockBitmap::save(self, device, block_size)
+    }
+
+    fn dirty_blocks(&self, block_size: u64) -> BTreeSet<u64> {
+        // Bitmap starts after superblock (block 0)
+        self.dirty.iter().map(|&byte| 1 + byte as u64 / block_size).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::Mutex;
+
+    const BLOCK_SIZE: u64 = 4096;
+
+    /// A device in memory that records the byte ranges written to it
+    #[derive(Debug, Default)]
+    struct Recording {
+        bytes: Mutex<Vec<u8>>,
+        writes: Mutex<Vec<(u64, usize)>>,
+    }
+
+    impl Recording {
+        fn take_writes(&self) -> Vec<(u64, usize)> {
+            std::mem::take(&mut *self.writes.lock().unwrap())
+        }
+    }
+
+    impl BlockDevice for Recording {
+        fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            let bytes = self.bytes.lock().unwrap();
+            let start = offset as usize;
+            buf.copy_from_slice(&bytes[start..start + buf.len()]);
+            Ok(())
+        }
+
+        fn write_all_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+            let mut bytes = self.bytes.lock().unwrap();
+            let start = offset as usize;
+            if bytes.len() < start + data.len() {
+                bytes.resize(start + data.len(), 0);
+            }
+            bytes[start..start + data.len()].copy_from_slice(data);
+            self.writes.lock().unwrap().push((offset, data.len()));
+            Ok(())
+        }
+
+        fn size(&self) -> io::Result<u64> {
+            Ok(self.bytes.lock().unwrap().len() as u64)
+        }
+
+        fn set_size(&self, size: u64) -> io::Result<()> {
+            self.bytes.lock().unwrap().resize(size as usize, 0);
+            Ok(())
+        }
+
+        fn sync(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Free blocks by scanning every bit, to check the running count
+    fn scanned_free(bitmap: &BlockBitmap) -> u64 {
+        (0..bitmap.total_blocks()).filter(|&b| !bitmap.is_block_used(b)).count() as u64
+    }
+
+    #[test]
+    fn free_count_follows_every_change() {
+        let mut bitmap = BlockBitmap::new(1000, BLOCK_SIZE);
+        assert_eq!(bitmap.count_free_blocks(), 1000 - 2);
+
+        let single = bitmap.allocate_block().unwrap();
+        let run = bitmap.allocate_contiguous_near(100, 70).unwrap();
+        let (extent, length) = bitmap.allocate_extent_near(500, 30).unwrap();
+        bitmap.reserve(900, 200);
+        assert_eq!(bitmap.count_free_blocks(), scanned_free(&bitmap));
+        assert_eq!(bitmap.count_free_blocks(), 1000 - 2 - 1 - 70 - length - 100);
+
+        // Freeing a free block, or one past the end, changes nothing
+        bitmap.free_block(single);
+        bitmap.free_block(single);
+        bitmap.free_block(5000);
+        bitmap.free_blocks(run, 70);
+        bitmap.free_blocks(extent, length);
+        assert_eq!(bitmap.count_free_blocks(), 1000 - 2 - 100);
+        assert_eq!(bitmap.count_free_blocks(), scanned_free(&bitmap));
+        assert_eq!(bitmap.count_free_in_range(0, 1000), bitmap.count_free_blocks());
+        assert_eq!(bitmap.count_free_in_range(890, 1000), 10);
+        assert_eq!(bitmap.count_used_blocks(), 102);
+    }
+
+    #[test]
+    fn free_count_is_rebuilt_on_load() {
+        let device = Recording::default();
+        let mut bitmap = BlockBitmap::new(5000, BLOCK_SIZE);
+        bitmap.allocate_contiguous(1234).unwrap();
+        bitmap.free_blocks(600, 17);
+        bitmap.save(&device, BLOCK_SIZE).unwrap();
+
+        let loaded = BlockBitmap::load(&device, 5000, BLOCK_SIZE).unwrap();
+        assert_eq!(loaded.count_free_blocks(), bitmap.count_free_blocks());
+        assert_eq!(loaded.count_free_blocks(), scanned_free(&loaded));
+    }
+
+    #[test]
+    fn full_words_are_skipped() {
+        let total = 64 * 64 * 20;
+        let mut bitmap = BlockBitmap::new(total, BLOCK_SIZE);
+        bitmap.reserve(0, total - 10);
+        assert!((0..(total - 10) / WORD_BITS).all(|word| bitmap.is_word_full(word)));
+
+        // Clear bits behind the summary's back: a search that read the
+        // bitmap word by word would find them, one that trusts the
+        // summary goes straight past to the free blocks at the end
+        for block in [100, 5000, 40_000] {
+            BlockBitmap::clear_bit(&mut bitmap.bitmap, block);
+        }
+        assert_eq!(bitmap.allocate_block_near(0).unwrap(), total - 10);
+
+        // Once a word has room again the summary sends the search there
+        bitmap.free_block(70_000);
+        assert_eq!(bitmap.allocate_block_near(0).unwrap(), 70_000);
+        assert_eq!(bitmap.allocate_block_near(0).unwrap(), total - 9);
+    }
+
+    #[test]
+    fn next_fit_wraps_around() {
+        let mut bitmap = BlockBitmap::new(256, BLOCK_SIZE);
+        let first = bitmap.allocate_block().unwrap();
+        assert_eq!(first, 2);
+        assert_eq!(bitmap.allocate_block().unwrap(), 3);
+
+        // Freed blocks behind the cursor wait until the search wraps
+        bitmap.free_block(first);
+        bitmap.reserve(4, 250);
+        assert_eq!(bitmap.allocate_block().unwrap(), 254);
+        assert_eq!(bitmap.allocate_block().unwrap(), 255);
+        assert_eq!(bitmap.allocate_block().unwrap(), first);
+        assert!(matches!(bitmap.allocate_block(), Err(FsError::DiskFull)));
+
+        // A goal past the end starts over from the beginning
+        bitmap.free_block(3);
+        assert_eq!(bitmap.allocate_block_near(1000).unwrap(), 3);
+
+        // Runs wrap too, starting before the goal when nothing fits after
+        bitmap.free_blocks(10, 5);
+        bitmap.free_blocks(250, 3);
+        assert_eq!(bitmap.allocate_contiguous_near(200, 4).unwrap(), 10);
+        assert!(matches!(bitmap.allocate_contiguous_near(0, 4), Err(FsError::NotEnoughContiguousSpace(4))));
+    }
+
+    #[test]
+    fn save_writes_back_only_changed_bytes() {
+        let device = Recording::default();
+        let mut bitmap = BlockBitmap::new(64 * 1024, BLOCK_SIZE);
+        bitmap.save(&device, BLOCK_SIZE).unwrap();
+        assert_eq!(device.take_writes(), [(BLOCK_SIZE, 8 * 1024)]);
+
+        // Nothing changed, nothing written
+        bitmap.save(&device, BLOCK_SIZE).unwrap();
+        assert!(device.take_writes().is_empty());
+
+        // Neighbouring bytes go out together, distant ones separately
+        bitmap.reserve(800, 16);
+        bitmap.reserve(40_000, 1);
+        assert_eq!(bitmap.dirty_blocks(BLOCK_SIZE), BTreeSet::from([1, 2]));
+        bitmap.save(&device, BLOCK_SIZE).unwrap();
+        assert_eq!(device.take_writes(), [(BLOCK_SIZE + 100, 2), (BLOCK_SIZE + 5000, 1)]);
+
+        let loaded = BlockBitmap::load(&device, 64 * 1024, BLOCK_SIZE).unwrap();
+        assert!((800..816).all(|b| loaded.is_block_used(b)));
+        assert!(loaded.is_block_used(40_000));
+        assert_eq!(loaded.count_free_blocks(), bitmap.count_free_blocks());
+    }
+}
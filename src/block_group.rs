@@ -0,0 +1,518 @@
+use crate::{
+    allocator::Allocator,
+    checksum::ChecksumTable,
+    device::BlockDevice,
+    encryption::{KEY_CHECK_SIZE, SALT_SIZE},
+    error::{FsError, FsResult},
+    quota::QuotaKind,
+    serialization::{GroupDescriptor, Superblock},
+};
+use std::collections::BTreeSet;
+
+/// Number of blocks in each block group
+pub const BLOCKS_PER_GROUP: u64 = 2048;
+
+/// Number of inode blocks in each group's inode table
+pub const INODES_PER_GROUP: u64 = 128;
+
+/// Size of every image written before block groups existed
+const LEGACY_IMAGE_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Block size of images written before block groups existed
+const LEGACY_BLOCK_SIZE: u64 = 4 * 1024;
+
+/// ext2-style block groups
+///
+/// The disk is split into fixed-size groups. Each group owns a slice of
+/// the block bitmap, a slice of the inode bitmap, an inode table (one
+/// inode per block) at its start, and a descriptor in the superblock.
+/// Inodes are allocated from inode tables, and data is placed in the
+/// same group as its inode so that related blocks stay close together.
+///
+/// Layout:
+/// - Block 0: superblock followed by the group descriptor table
+/// - Allocator state, e.g. the block bitmap (see `Allocator`)
+/// - Inode bitmap: one bit per inode table block, across all groups
+/// - Checksum table, if the image has checksums (see `checksum.rs`)
+/// - Groups, each starting with its inode table
+#[derive(Debug)]
+pub struct BlockGroups {
+    superblock: Superblock,
+    /// In-memory inode bitmap: 1 = inode table slot in use
+    inode_bitmap: Vec<u8>,
+    /// Group descriptors modified since the last save
+    dirty_groups: BTreeSet<usize>,
+    /// Superblock header (not descriptors) modified since the last save
+    superblock_dirty: bool,
+    /// Inode bitmap bytes modified since the last save
+    dirty_inode_bytes: BTreeSet<usize>,
+    /// Group the most recent directory was placed in
+    last_directory_group: usize,
+}
+
+impl BlockGroups {
+    /// Lay out block groups on a freshly created disk
+    ///
+    /// Reserves the inode bitmap and every inode table in `bitmap`, and
+    /// fills in the group descriptors from the resulting free counts.
+    pub fn format(bitmap: &mut dyn Allocator, block_size: u64, features: u64) -> FsResult<Self> {
+        let total_blocks = bitmap.total_blocks();
+        let group_count = total_blocks.div_ceil(BLOCKS_PER_GROUP);
+        if group_count > Superblock::max_groups(block_size) {
+            return Err(FsError::NotSupported(format!(
+                "{} block groups do not fit in the superblock",
+                group_count
+            )));
+        }
+
+        let block_bitmap_start = 1;
+        let block_bitmap_blocks = bitmap.bitmap_blocks();
+        let inode_bitmap_start = block_bitmap_start + block_bitmap_blocks;
+        let total_inodes = group_count * INODES_PER_GROUP;
+        let inode_bitmap_blocks = total_inodes.div_ceil(block_size * 8);
+        let checksum_table_start = inode_bitmap_start + inode_bitmap_blocks;
+        let checksum_table_blocks = if features & Superblock::FEATURE_CHECKSUMS != 0 {
+            let sealed = features & Superblock::FEATURE_ENCRYPTION != 0;
+            ChecksumTable::table_blocks(total_blocks, block_size, sealed)
+        } else {
+            0
+        };
+        let metadata_end = checksum_table_start + checksum_table_blocks;
+
+        bitmap.reserve(inode_bitmap_start, inode_bitmap_blocks);
+        bitmap.reserve(checksum_table_start, checksum_table_blocks);
+
+        let mut groups = Vec::with_capacity(group_count as usize);
+        for group in 0..group_count {
+            let start_block = group * BLOCKS_PER_GROUP;
+            let end_block = (start_block + BLOCKS_PER_GROUP).min(total_blocks);
+            let inode_table_start = start_block.max(metadata_end);
+            let inode_count = INODES_PER_GROUP.min(end_block.saturating_sub(inode_table_start));
+
+            bitmap.reserve(inode_table_start, inode_count);
+
+            groups.push(GroupDescriptor {
+                start_block,
+                block_count: end_block - start_block,
+                inode_table_start,
+                inode_count,
+                free_blocks: bitmap.count_free_in_range(start_block, end_block),
+                free_inodes: inode_count,
+                directories: 0,
+            });
+        }
+
+        let superblock = Superblock {
+            block_size,
+            total_blocks,
+            blocks_per_group: BLOCKS_PER_GROUP,
+            inodes_per_group: INODES_PER_GROUP,
+            block_bitmap_start,
+            block_bitmap_blocks,
+            inode_bitmap_start,
+            inode_bitmap_blocks,
+            root_inode: 0,
+            features,
+            allocator: bitmap.kind().to_u64(),
+            checksum_table_start: if checksum_table_blocks > 0 { checksum_table_start } else { 0 },
+            checksum_table_blocks,
+            snapshot_table: 0,
+            refcount_table: 0,
+            history_versions: 0,
+            history_max_age: 0,
+            history_max_blocks: 0,
+            kdf_iterations: 0,
+            kdf_salt: [0; SALT_SIZE],
+            key_check: [0; KEY_CHECK_SIZE],
+            dedup_table: 0,
+            user_quota_inode: 0,
+            group_quota_inode: 0,
+            project_quota_inode: 0,
+            groups,
+        };
+
+        let inode_bitmap = vec![0u8; total_inodes.div_ceil(8) as usize];
+        let dirty_inode_bytes = (0..inode_bitmap.len()).collect();
+        let dirty_groups = (0..group_count as usize).collect();
+
+        Ok(BlockGroups {
+            superblock,
+            inode_bitmap,
+            dirty_groups,
+            superblock_dirty: true,
+            dirty_inode_bytes,
+            last_directory_group: 0,
+        })
+    }
+
+    /// Load the superblock, group descriptors and inode bitmap from disk
+    ///
+    /// The block size is taken from the superblock header.
+    pub fn load(device: &dyn BlockDevice) -> FsResult<Self> {
+        let mut header = [0u8; Superblock::HEADER_SIZE];
+        device.read_exact_at(&mut header, 0)?;
+        let block_size = match Superblock::read_block_size(&header) {
+            Ok(block_size) => block_size,
+            Err(_) if Self::is_legacy_image(device)? => {
+                return Err(FsError::NotSupported("pre-block-group image; reformat".to_string()));
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut block = vec![0u8; block_size as usize];
+        device.read_exact_at(&mut block, 0)?;
+        let superblock = Superblock::from_bytes(&block)?;
+
+        let total_inodes = superblock.groups.len() as u64 * superblock.inodes_per_group;
+        let mut inode_bitmap = vec![0u8; total_inodes.div_ceil(8) as usize];
+        device.read_exact_at(&mut inode_bitmap, superblock.inode_bitmap_start * block_size)?;
+
+        Ok(BlockGroups {
+            superblock,
+            inode_bitmap,
+            dirty_groups: BTreeSet::new(),
+            superblock_dirty: false,
+            dirty_inode_bytes: BTreeSet::new(),
+            last_directory_group: 0,
+        })
+    }
+
+    /// Whether `device` holds an image from before block groups
+    ///
+    /// Those images are always 100 MiB of 4 KiB blocks, never wrote
+    /// block 0, and keep the block bitmap in block 1 with the bits of
+    /// blocks 0 and 1 set. Their inodes live wherever a free block was
+    /// found, so they cannot be moved into inode tables in place.
+    fn is_legacy_image(device: &dyn BlockDevice) -> FsResult<bool> {
+        if device.size()? != LEGACY_IMAGE_SIZE {
+            return Ok(false);
+        }
+        let mut block = vec![0u8; LEGACY_BLOCK_SIZE as usize + 1];
+        device.read_exact_at(&mut block, 0)?;
+        let (superblock, bitmap) = block.split_at(LEGACY_BLOCK_SIZE as usize);
+        Ok(superblock.iter().all(|&b| b == 0) && bitmap[0] & 0b11 == 0b11)
+    }
+
+    /// Write back the parts of the superblock and inode bitmap that changed
+    pub fn save(&mut self, device: &dyn BlockDevice) -> FsResult<()> {
+        let block_size = self.superblock.block_size;
+
+        if self.superblock_dirty {
+            device.write_all_at(&self.superblock.to_bytes(), 0)?;
+            self.superblock_dirty = false;
+            self.dirty_groups.clear();
+        }
+
+        for &group in &self.dirty_groups {
+            let offset = Superblock::descriptor_offset(group) as u64;
+            device.write_all_at(&self.superblock.groups[group].to_bytes(), offset)?;
+        }
+        self.dirty_groups.clear();
+
+        let inode_bitmap_offset = self.superblock.inode_bitmap_start * block_size;
+        for &byte in &self.dirty_inode_bytes {
+            device.write_all_at(&self.inode_bitmap[byte..byte + 1], inode_bitmap_offset + byte as u64)?;
+        }
+        self.dirty_inode_bytes.clear();
+        Ok(())
+    }
+
+    /// Blocks that the next `save` will write to
+    pub fn dirty_blocks(&self) -> BTreeSet<u64> {
+        let mut blocks = BTreeSet::new();
+        if self.superblock_dirty || !self.dirty_groups.is_empty() {
+            blocks.insert(0);
+        }
+        let block_size = self.superblock.block_size;
+        for &byte in &self.dirty_inode_bytes {
+            blocks.insert(self.superblock.inode_bitmap_start + byte as u64 / block_size);
+        }
+        blocks
+    }
+
+    /// The superblock, including the group descriptor table
+    pub fn superblock(&self) -> &Superblock {
+        &self.superblock
+    }
+
+    /// Number of block groups
+    pub fn group_count(&self) -> usize {
+        self.superblock.groups.len()
+    }
+
+    /// Descriptor of a group
+    pub fn descriptor(&self, group: usize) -> &GroupDescriptor {
+        &self.superblock.groups[group]
+    }
+
+    /// Group that contains `block`
+    pub fn group_of(&self, block: u64) -> usize {
+        ((block / self.superblock.blocks_per_group) as usize).min(self.group_count() - 1)
+    }
+
+    /// Block of the root directory inode, if one has been created
+    pub fn root_inode(&self) -> Option<u64> {
+        match self.superblock.root_inode {
+            0 => None,
+            block => Some(block),
+        }
+    }
+
+    /// Record the block of the root directory inode
+    pub fn set_root_inode(&mut self, block: u64) {
+        self.superblock.root_inode = block;
+        self.superblock_dirty = true;
+    }
+
+    /// Record the first block of the snapshot table, 0 for none
+    pub fn set_snapshot_table(&mut self, block: u64) {
+        self.superblock.snapshot_table = block;
+        self.superblock_dirty = true;
+    }
+
+    /// Record the first block of the reference count table, 0 for none
+    pub fn set_refcount_table(&mut self, block: u64) {
+        self.superblock.refcount_table = block;
+        self.superblock_dirty = true;
+    }
+
+    /// Record the first block of the dedup table, 0 for none
+    pub fn set_dedup_table(&mut self, block: u64) {
+        self.superblock.dedup_table = block;
+        self.superblock_dirty = true;
+    }
+
+    /// Record the inode block of the quota file of `kind`
+    pub fn set_quota_inode(&mut self, kind: QuotaKind, block: u64) {
+        match kind {
+            QuotaKind::User => self.superblock.user_quota_inode = block,
+            QuotaKind::Group => self.superblock.group_quota_inode = block,
+            QuotaKind::Project => self.superblock.project_quota_inode = block,
+        }
+        self.superblock_dirty = true;
+    }
+
+    /// Turn a feature flag on or off
+    pub fn set_feature(&mut self, feature: u64, enabled: bool) {
+        if enabled {
+            self.superblock.features |= feature;
+        } else {
+            self.superblock.features &= !feature;
+        }
+        self.superblock_dirty = true;
+    }
+
+    /// Record the history policy: versions kept per file and the maximum
+    /// age and blocks of history, 0 for none or no limit
+    pub fn set_history_policy(&mut self, versions: u64, max_age: u64, max_blocks: u64) {
+        self.superblock.history_versions = versions;
+        self.superblock.history_max_age = max_age;
+        self.superblock.history_max_blocks = max_blocks;
+        self.superblock_dirty = true;
+    }
+
+    /// Record how the volume key of an encrypted image is derived from its
+    /// passphrase, and the check value of the key
+    pub fn set_key_derivation(&mut self, iterations: u64, salt: [u8; SALT_SIZE], check: [u8; KEY_CHECK_SIZE]) {
+        self.superblock.kdf_iterations = iterations;
+        self.superblock.kdf_salt = salt;
+        self.superblock.key_check = check;
+        self.superblock_dirty = true;
+    }
+
+    /// Check whether `block` lies inside an inode table
+    pub fn is_inode_block(&self, block: u64) -> bool {
+        let group = self.descriptor(self.group_of(block));
+        block >= group.inode_table_start && block < group.data_start()
+    }
+
+    /// Check whether the inode table slot for `block` is in use
+    pub fn is_inode_used(&self, block: u64) -> bool {
+        match self.inode_index(block) {
+            Some(index) => self.inode_bitmap[index / 8] & (1 << (index % 8)) != 0,
+            None => false,
+        }
+    }
+
+    /// Global inode bitmap index of an inode table block
+    fn inode_index(&self, block: u64) -> Option<usize> {
+        if !self.is_inode_block(block) {
+            return None;
+        }
+        let group_index = self.group_of(block);
+        let group = self.descriptor(group_index);
+        let slot = block - group.inode_table_start;
+        Some((group_index as u64 * self.superblock.inodes_per_group + slot) as usize)
+    }
+
+    /// Allocate an inode block, preferring `preferred_group`
+    ///
+    /// Falls back to the following groups (wrapping) when the preferred
+    /// group's inode table is full.
+    pub fn allocate_inode(&mut self, preferred_group: usize, is_directory: bool) -> FsResult<u64> {
+        let group_count = self.group_count();
+        for i in 0..group_count {
+            let group_index = (preferred_group + i) % group_count;
+            let group = &self.superblock.groups[group_index];
+            if group.free_inodes == 0 {
+                continue;
+            }
+
+            let base = group_index as u64 * self.superblock.inodes_per_group;
+            for slot in 0..group.inode_count {
+                let index = (base + slot) as usize;
+                if self.inode_bitmap[index / 8] & (1 << (index % 8)) == 0 {
+                    self.inode_bitmap[index / 8] |= 1 << (index % 8);
+                    self.dirty_inode_bytes.insert(index / 8);
+
+                    let group = &mut self.superblock.groups[group_index];
+                    group.free_inodes -= 1;
+                    if is_directory {
+                        group.directories += 1;
+                        self.last_directory_group = group_index;
+                    }
+                    self.dirty_groups.insert(group_index);
+                    return Ok(group.inode_table_start + slot);
+                }
+            }
+        }
+
+        Err(FsError::NoFreeInodes)
+    }
+
+    /// Release an inode block back to its group's inode table
+    pub fn free_inode(&mut self, block: u64, is_directory: bool) -> FsResult<()> {
+        let index = self.inode_index(block).ok_or(FsError::BlockNotFound(block))?;
+        if self.inode_bitmap[index / 8] & (1 << (index % 8)) == 0 {
+            return Err(FsError::BlockAlreadyFree(block));
+        }
+
+        self.inode_bitmap[index / 8] &= !(1 << (index % 8));
+        self.dirty_inode_bytes.insert(index / 8);
+
+        let group_index = self.group_of(block);
+        let group = &mut self.superblock.groups[group_index];
+        group.free_inodes += 1;
+        if is_directory {
+            group.directories = group.directories.saturating_sub(1);
+        }
+        self.dirty_groups.insert(group_index);
+        Ok(())
+    }
+
+    /// All inode blocks currently in use, in ascending order
+    pub fn used_inodes(&self) -> Vec<u64> {
+        let mut inodes = Vec::new();
+        for (group_index, group) in self.superblock.groups.iter().enumerate() {
+            let base = group_index as u64 * self.superblock.inodes_per_group;
+            for slot in 0..group.inode_count {
+                let index = (base + slot) as usize;
+                if self.inode_bitmap[index / 8] & (1 << (index % 8)) != 0 {
+                    inodes.push(group.inode_table_start + slot);
+                }
+            }
+        }
+        inodes
+    }
+
+    /// Update the free block count after `block` was allocated
+    pub fn block_allocated(&mut self, block: u64) {
+        let group_index = self.group_of(block);
+        let group = &mut self.superblock.groups[group_index];
+        group.free_blocks = group.free_blocks.saturating_sub(1);
+        self.dirty_groups.insert(group_index);
+    }
+
+    /// Update the free block count after `block` was freed
+    pub fn block_freed(&mut self, block: u64) {
+        let group_index = self.group_of(block);
+        let group = &mut self.superblock.groups[group_index];
+        group.free_blocks += 1;
+        self.dirty_groups.insert(group_index);
+    }
+
+    /// Choose a group for a new directory
+    ///
+    /// Directories are spread across the disk: among the groups with at
+    /// least an average share of free inodes and free blocks, pick the one
+    /// holding the fewest directories. Its files will follow it there.
+    pub fn directory_group(&self) -> usize {
+        let groups = &self.superblock.groups;
+        let count = groups.len() as u64;
+        let average_free_inodes = groups.iter().map(|g| g.free_inodes).sum::<u64>() / count;
+        let average_free_blocks = groups.iter().map(|g| g.free_blocks).sum::<u64>() / count;
+
+        groups
+            .iter()
+            .enumerate()
+            .filter(|(_, g)| {
+                g.free_inodes > 0
+                    && g.free_inodes >= average_free_inodes
+                    && g.free_blocks >= average_free_blocks
+            })
+            .min_by_key(|(_, g)| g.directories)
+            .or_else(|| groups.iter().enumerate().max_by_key(|(_, g)| g.free_inodes))
+            .map(|(index, _)| index)
+            .unwrap_or(0)
+    }
+
+    /// Group of the most recently created directory
+    ///
+    /// Used as the placement hint for files created without a parent.
+    pub fn last_directory_group(&self) -> usize {
+        self.last_directory_group
+    }
+
+    /// Allocation goal for data belonging to the inode at `inode_block`
+    ///
+    /// The first data block of the inode's group, so data lands right
+    /// after the inode table that holds the inode.
+    pub fn data_goal(&self, inode_block: u64) -> u64 {
+        self.descriptor(self.group_of(inode_block)).data_start()
+    }
+}
+
+/// Locality and fragmentation statistics over all allocated inodes
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FragmentationStats {
+    /// Number of files and directories inspected
+    pub inodes: u64,
+    /// Inodes that reference at least one data block
+    pub inodes_with_data: u64,
+    /// Number of data blocks referenced by those inodes
+    pub data_blocks: u64,
+    /// Number of contiguous runs of data blocks
+    pub fragments: u64,
+    /// Inodes whose data is split across more than one run
+    pub fragmented_inodes: u64,
+    /// Data blocks that live in a different group than their inode
+    pub off_group_blocks: u64,
+    /// Sum of distances (in blocks) between each inode and its first data block
+    pub total_inode_distance: u64,
+}
+
+impl FragmentationStats {
+    /// Average number of runs per inode with data (1.0 = fully contiguous)
+    pub fn fragments_per_inode(&self) -> f64 {
+        if self.inodes_with_data == 0 {
+            return 0.0;
+        }
+        self.fragments as f64 / self.inodes_with_data as f64
+    }
+
+    /// Average distance in blocks between an inode and its first data block
+    pub fn average_inode_distance(&self) -> f64 {
+        if self.inodes_with_data == 0 {
+            return 0.0;
+        }
+        self.total_inode_distance as f64 / self.inodes_with_data as f64
+    }
+
+    /// Percentage of data blocks stored outside their inode's group
+    pub fn off_group_percentage(&self) -> f64 {
+        if self.data_blocks == 0 {
+            return 0.0;
+        }
+        self.off_group_blocks as f64 / self.data_blocks as f64 * 100.0
+    }
+}
@@ -0,0 +1,448 @@
+use crate::{
+    allocator::{Allocator, AllocatorKind},
+    bitmap::BlockBitmap,
+    device::BlockDevice,
+    error::{FsError, FsResult},
+};
+use std::collections::BTreeSet;
+
+/// Binary buddy allocator for power-of-two runs of blocks
+///
+/// Free space is kept as aligned blocks of 2^k blocks in one free list per
+/// order. Allocations are rounded up to the next power of two; larger free
+/// blocks are split in halves ("buddies") until one of the right order is
+/// left, and freed blocks are merged with their buddy whenever it is free.
+///
+/// On disk the allocator stores two bitmaps after the superblock:
+/// - the used bitmap, in the same format as `BlockBitmap`
+/// - the padding bitmap, marking blocks that are used only because an
+///   allocation was rounded up to a power of two
+///
+/// The free lists are rebuilt from the used bitmap on load. Padding
+/// blocks are released together with the allocated block just before them.
+#[derive(Debug)]
+pub struct BuddyAllocator {
+    /// Used bitmap, including padding blocks
+    used: BlockBitmap,
+    /// Padding bitmap: 1 = block is rounding waste of an earlier allocation
+    padding: Vec<u8>,
+    /// Number of padding blocks
+    padding_count: u64,
+    /// Padding bitmap bytes modified since the last save
+    dirty_padding: BTreeSet<usize>,
+    /// Start blocks of free buddies, indexed by order
+    free_lists: Vec<BTreeSet<u64>>,
+}
+
+impl BuddyAllocator {
+    /// Largest buddy order: blocks of 2^MAX_ORDER (4 MiB with 4 KiB blocks)
+    pub const MAX_ORDER: u32 = 10;
+
+    /// Number of blocks needed for the used and padding bitmaps
+    pub fn calculate_metadata_blocks(total_blocks: u64, block_size: u64) -> u64 {
+        2 * BlockBitmap::calculate_bitmap_blocks(total_blocks, block_size)
+    }
+
+    /// Create a new buddy allocator for the given number of blocks
+    pub fn new(total_blocks: u64, block_size: u64) -> Self {
+        let mut used = BlockBitmap::new(total_blocks, block_size);
+
+        // Reserve the padding bitmap, which follows the used bitmap
+        let bitmap_blocks = used.bitmap_blocks();
+        used.reserve(1 + bitmap_blocks, bitmap_blocks);
+
+        let padding = vec![0u8; total_blocks.div_ceil(8) as usize];
+        let dirty_padding = (0..padding.len()).collect();
+
+        let mut allocator = BuddyAllocator {
+            used,
+            padding,
+            padding_count: 0,
+            dirty_padding,
+            free_lists: Vec::new(),
+        };
+        allocator.rebuild_free_lists();
+        allocator
+    }
+
+    /// Load allocator state from disk
+    pub fn load(device: &dyn BlockDevice, total_blocks: u64, block_size: u64) -> FsResult<Self> {
+        let used = BlockBitmap::load(device, total_blocks, block_size)?;
+
+        let mut padding = vec![0u8; total_blocks.div_ceil(8) as usize];
+        device.read_exact_at(&mut padding, Self::padding_offset(&used, block_size))?;
+        let padding_count = padding.iter().map(|b| u64::from(b.count_ones())).sum();
+
+        let mut allocator = BuddyAllocator {
+            used,
+            padding,
+            padding_count,
+            dirty_padding: BTreeSet::new(),
+            free_lists: Vec::new(),
+        };
+        allocator.rebuild_free_lists();
+        Ok(allocator)
+    }
+
+    /// Byte offset of the padding bitmap on disk
+    fn padding_offset(used: &BlockBitmap, block_size: u64) -> u64 {
+        (1 + used.bitmap_blocks()) * block_size
+    }
+
+    /// Rebuild the free lists by splitting free space into the largest
+    /// aligned power-of-two blocks
+    fn rebuild_free_lists(&mut self) {
+        self.free_lists = vec![BTreeSet::new(); Self::MAX_ORDER as usize + 1];
+
+        let total = self.used.total_blocks();
+        let mut block = 0;
+        while block < total {
+            if self.used.is_block_used(block) {
+                block += 1;
+                continue;
+            }
+
+            let mut order = block.trailing_zeros().min(Self::MAX_ORDER);
+            while order > 0 {
+                let size = 1u64 << order;
+                if block + size <= total && self.used.count_free_in_range(block, block + size) == size {
+                    break;
+                }
+                order -= 1;
+            }
+
+            self.free_lists[order as usize].insert(block);
+            block += 1 << order;
+        }
+    }
+
+    /// Take a free buddy of exactly `order`, splitting a larger one if needed
+    ///
+    /// Prefers the buddy at or after `goal`; when splitting, keeps the half
+    /// that contains `goal` so related blocks stay together.
+    fn take_order(&mut self, order: u32, goal: u64) -> Option<u64> {
+        for k in order..=Self::MAX_ORDER {
+            let list = &self.free_lists[k as usize];
+            let aligned_goal = goal & !((1u64 << k) - 1);
+            let Some(&start) = list.range(aligned_goal..).next().or_else(|| list.iter().next()) else {
+                continue;
+            };
+            self.free_lists[k as usize].remove(&start);
+
+            let mut start = start;
+            let mut k = k;
+            while k > order {
+                k -= 1;
+                let half = 1u64 << k;
+                if goal >= start + half && goal < start + 2 * half {
+                    self.free_lists[k as usize].insert(start);
+                    start += half;
+                } else {
+                    self.free_lists[k as usize].insert(start + half);
+                }
+            }
+            return Some(start);
+        }
+        None
+    }
+
+    /// Remove one specific free block from the free lists, splitting the
+    /// buddy that contains it
+    fn take_block(&mut self, block: u64) {
+        for k in 0..=Self::MAX_ORDER {
+            let mut start = block & !((1u64 << k) - 1);
+            if !self.free_lists[k as usize].remove(&start) {
+                continue;
+            }
+
+            let mut k = k;
+            while k > 0 {
+                k -= 1;
+                let half = 1u64 << k;
+                if block >= start + half {
+                    self.free_lists[k as usize].insert(start);
+                    start += half;
+                } else {
+                    self.free_lists[k as usize].insert(start + half);
+                }
+            }
+            return;
+        }
+    }
+
+    /// Return a single block to the free lists, merging with free buddies
+    fn release(&mut self, block: u64) {
+        let total = self.used.total_blocks();
+        let mut start = block;
+        let mut order = 0;
+        while order < Self::MAX_ORDER {
+            let size = 1u64 << order;
+            let buddy = start ^ size;
+            if buddy + size > total || !self.free_lists[order as usize].remove(&buddy) {
+                break;
+            }
+            start = start.min(buddy);
+            order += 1;
+        }
+        self.free_lists[order as usize].insert(start);
+    }
+
+    fn is_padding(&self, block: u64) -> bool {
+        let byte_index = (block / 8) as usize;
+        byte_index < self.padding.len() && self.padding[byte_index] & (1 << (block % 8)) != 0
+    }
+
+    fn set_padding(&mut self, block: u64, value: bool) {
+        let byte_index = (block / 8) as usize;
+        let bit = 1 << (block % 8);
+        if value {
+            self.padding[byte_index] |= bit;
+            self.padding_count += 1;
+        } else {
+            self.padding[byte_index] &= !bit;
+            self.padding_count -= 1;
+        }
+        self.dirty_padding.insert(byte_index);
+    }
+
+    /// Smallest order whose blocks hold `count` blocks
+    fn order_for(count: u64) -> u32 {
+        count.next_power_of_two().trailing_zeros()
+    }
+}
+
+impl Allocator for BuddyAllocator {
+    fn kind(&self) -> AllocatorKind {
+        AllocatorKind::Buddy
+    }
+
+    fn allocate_block(&mut self) -> FsResult<u64> {
+        self.allocate_block_near(0)
+    }
+
+    fn allocate_block_near(&mut self, goal: u64) -> FsResult<u64> {
+        let block = self.take_order(0, goal).ok_or(FsError::DiskFull)?;
+        self.used.reserve(block, 1);
+        Ok(block)
+    }
+
+    fn allocate_contiguous_near(&mut self, goal: u64, count: u64) -> FsResult<u64> {
+        if count == 0 {
+            return Err(FsError::InvalidOffsetOrSize { offset: 0, size: 0 });
+        }
+
+        let order = Self::order_for(count);
+        if order > Self::MAX_ORDER {
+            return Err(FsError::NotEnoughContiguousSpace(count));
+        }
+
+        let start = self
+            .take_order(order, goal)
+            .ok_or(FsError::NotEnoughContiguousSpace(count))?;
+
+        // The rounded-up tail stays used as padding until the run is freed
+        self.used.reserve(start, 1 << order);
+        for block in start + count..start + (1 << order) {
+            self.set_padding(block, true);
+        }
+        Ok(start)
+    }
+
+    fn allocate_extent_near(&mut self, goal: u64, max_count: u64) -> FsResult<(u64, u64)> {
+        let largest = (63 - max_count.max(1).leading_zeros()).min(Self::MAX_ORDER);
+        for order in (0..=largest).rev() {
+            if let Some(start) = self.take_order(order, goal) {
+                self.used.reserve(start, 1 << order);
+                return Ok((start, 1 << order));
+            }
+        }
+        Err(FsError::DiskFull)
+    }
+
+    fn reserve(&mut self, start: u64, count: u64) {
+        for block in start..(start + count).min(self.used.total_blocks()) {
+            if !self.used.is_block_used(block) {
+                self.take_block(block);
+                self.used.reserve(block, 1);
+            }
+        }
+    }
+
+    fn free_block(&mut self, block: u64) {
+        if !self.used.is_block_used(block) {
+            return;
+        }
+        if self.is_padding(block) {
+            self.set_padding(block, false);
+        }
+        self.used.free_block(block);
+        self.release(block);
+
+        // Padding directly after the block belongs to the same allocation
+        let mut next = block + 1;
+        while self.is_padding(next) {
+            self.set_padding(next, false);
+            self.used.free_block(next);
+            self.release(next);
+            next += 1;
+        }
+    }
+
+    fn is_block_used(&self, block: u64) -> bool {
+        self.used.is_block_used(block)
+    }
+
+    fn total_blocks(&self) -> u64 {
+        self.used.total_blocks()
+    }
+
+    fn bitmap_blocks(&self) -> u64 {
+        2 * self.used.bitmap_blocks()
+    }
+
+    fn count_free_blocks(&self) -> u64 {
+        self.used.count_free_blocks()
+    }
+
+    fn count_free_in_range(&self, start: u64, end: u64) -> u64 {
+        self.used.count_free_in_range(start, end)
+    }
+
+    fn padding_blocks(&self) -> u64 {
+        self.padding_count
+    }
+
+    fn is_padding(&self, block: u64) -> bool {
+        BuddyAllocator::is_padding(self, block)
+    }
+
+    fn save(&mut self, device: &dyn BlockDevice, block_size: u64) -> FsResult<()> {
+        self.used.save(device, block_size)?;
+
+        let offset = Self::padding_offset(&self.used, block_size);
+        for &byte in &self.dirty_padding {
+            device.write_all_at(&self.padding[byte..byte + 1], offset + byte as u64)?;
+        }
+        self.dirty_padding.clear();
+        Ok(())
+    }
+
+    fn dirty_blocks(&self, block_size: u64) -> BTreeSet<u64> {
+        let mut blocks = self.used.dirty_blocks(block_size);
+        let first = Self::padding_offset(&self.used, block_size) / block_size;
+        blocks.extend(self.dirty_padding.iter().map(|&byte| first + byte as u64 / block_size));
+        blocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK_SIZE: u64 = 4096;
+
+    /// Free buddies as (order, start), smallest order first
+    fn free_buddies(allocator: &BuddyAllocator) -> Vec<(u32, u64)> {
+        let mut buddies = Vec::new();
+        for (order, list) in allocator.free_lists.iter().enumerate() {
+            buddies.extend(list.iter().map(|&start| (order as u32, start)));
+        }
+        buddies
+    }
+
+    /// A fresh allocator over 1024 blocks: the superblock and both bitmaps
+    /// take blocks 0 to 2, the rest is split into the largest buddies
+    fn fresh() -> BuddyAllocator {
+        let allocator = BuddyAllocator::new(1024, BLOCK_SIZE);
+        let initial = [(0, 3), (2, 4), (3, 8), (4, 16), (5, 32), (6, 64), (7, 128), (8, 256), (9, 512)];
+        assert_eq!(free_buddies(&allocator), initial);
+        allocator
+    }
+
+    #[test]
+    fn buddies_split_towards_the_goal_and_merge_back() {
+        let mut allocator = fresh();
+        allocator.reserve(3, 509);
+        assert_eq!(free_buddies(&allocator), [(9, 512)]);
+
+        // Each split keeps the half holding the goal, and frees the other
+        assert_eq!(allocator.allocate_block_near(700).unwrap(), 700);
+        assert_eq!(
+            free_buddies(&allocator),
+            [(0, 701), (1, 702), (2, 696), (3, 688), (4, 672), (5, 640), (6, 704), (7, 512), (8, 768)]
+        );
+
+        // Freeing it merges every buddy back into one
+        allocator.free_block(700);
+        assert_eq!(free_buddies(&allocator), [(9, 512)]);
+        allocator.free_blocks(3, 509);
+        assert_eq!(free_buddies(&fresh()), free_buddies(&allocator));
+    }
+
+    #[test]
+    fn rounding_up_is_counted_as_padding() {
+        let mut allocator = fresh();
+        let start = allocator.allocate_contiguous(100).unwrap();
+        assert_eq!(start, 128);
+        assert!((start..start + 128).all(|b| allocator.is_block_used(b)));
+        assert!(!allocator.is_padding(start + 99));
+        assert!((start + 100..start + 128).all(|b| allocator.is_padding(b)));
+
+        let stats = allocator.stats();
+        assert_eq!((stats.used_blocks, stats.padding_blocks), (3 + 128, 28));
+        assert!((stats.internal_fragmentation() - 28.0 / 131.0 * 100.0).abs() < 1e-9);
+
+        // The padding goes with the last block of the run
+        allocator.free_blocks(start, 100);
+        assert_eq!(allocator.padding_blocks(), 0);
+        assert_eq!(allocator.stats().internal_fragmentation(), 0.0);
+        assert_eq!(free_buddies(&allocator), free_buddies(&fresh()));
+
+        // Runs larger than the largest buddy cannot be had
+        let too_big = (1 << BuddyAllocator::MAX_ORDER) + 1;
+        assert!(matches!(allocator.allocate_contiguous(too_big), Err(FsError::NotEnoughContiguousSpace(_))));
+    }
+
+    #[test]
+    fn scattered_free_space_is_external_fragmentation() {
+        let mut allocator = fresh();
+        assert_eq!(allocator.stats().free_runs, 1);
+        assert_eq!(allocator.stats().external_fragmentation(), 0.0);
+
+        allocator.reserve(3, 509);
+        allocator.allocate_block_near(700).unwrap();
+        let stats = allocator.stats();
+        assert_eq!((stats.free_blocks, stats.free_runs, stats.largest_free_run), (511, 2, 323));
+        assert!((stats.external_fragmentation() - (1.0 - 323.0 / 511.0) * 100.0).abs() < 1e-9);
+
+        allocator.reserve(0, 1024);
+        assert_eq!(allocator.stats().external_fragmentation(), 0.0);
+        assert!(matches!(allocator.allocate_block(), Err(FsError::DiskFull)));
+    }
+
+    #[test]
+    fn padding_and_free_lists_survive_a_reload() {
+        let path = std::env::temp_dir().join(format!("fssim-unit-buddy-{}.img", std::process::id()));
+        let device = std::fs::File::options().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        device.set_size(1024 * BLOCK_SIZE).unwrap();
+
+        let mut allocator = fresh();
+        let start = allocator.allocate_contiguous_near(512, 5).unwrap();
+        allocator.allocate_block_near(900).unwrap();
+        allocator.save(&device, BLOCK_SIZE).unwrap();
+        assert!(allocator.dirty_blocks(BLOCK_SIZE).is_empty());
+
+        let mut loaded = BuddyAllocator::load(&device, 1024, BLOCK_SIZE).unwrap();
+        assert_eq!(loaded.kind(), AllocatorKind::Buddy);
+        assert_eq!(free_buddies(&loaded), free_buddies(&allocator));
+        assert_eq!(loaded.stats(), allocator.stats());
+        assert_eq!(loaded.padding_blocks(), 3);
+        assert!((start + 5..start + 8).all(|b| loaded.is_padding(b)));
+
+        // Freeing after the reload still takes the padding along
+        loaded.free_blocks(start, 5);
+        assert_eq!(loaded.padding_blocks(), 0);
+        assert_eq!(loaded.dirty_blocks(BLOCK_SIZE), BTreeSet::from([1, 2]));
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::{
+    device::BlockDevice,
+    encryption::SEAL_SIZE,
+    error::{FsError, FsResult},
+};
+
+/// Size of one checksum table entry in bytes
+pub const CHECKSUM_SIZE: u64 = 4;
+
+/// Size of a checksum table entry on encrypted images, which also holds
+/// the seal of the block
+pub const SEALED_ENTRY_SIZE: u64 = CHECKSUM_SIZE + SEAL_SIZE as u64;
+
+/// CRC32C (Castagnoli) lookup table, one entry per byte value
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82F6_3B78 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC32C (Castagnoli) checksum of `data`
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc = CRC32C_TABLE[((crc ^ u32::from(byte)) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// What a block holds, as far as checksums are concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockClass {
+    /// Superblock, bitmaps, inodes, directory entries and mapping blocks
+    Metadata,
+    /// File and symlink data
+    Data,
+}
+
+/// Per-block CRC32C checksums stored in a table after the inode bitmap
+///
+/// The table has one entry per block of the image, indexed by block
+/// number, starting with a little-endian `u32` checksum. Entries are only
+/// meaningful for blocks of a class the image checksums: metadata always,
+/// file data only with `Superblock::FEATURE_DATA_CHECKSUMS`. Entries of
+/// free blocks and of the table itself are never checked. On encrypted
+/// images each entry also holds the nonce and tag of the block if it is
+/// encrypted (see `encryption.rs`).
+#[derive(Debug, Clone)]
+pub(crate) struct ChecksumTable {
+    /// Byte offset of the table in the image
+    offset: u64,
+    /// Whether file data blocks are checksummed too
+    data: bool,
+    /// Bytes per entry
+    entry_size: u64,
+}
+
+impl ChecksumTable {
+    pub(crate) fn new(start_block: u64, block_size: u64, data: bool, sealed: bool) -> Self {
+        ChecksumTable {
+            offset: start_block * block_size,
+            data,
+            entry_size: Self::entry_size(sealed),
+        }
+    }
+
+    fn entry_size(sealed: bool) -> u64 {
+        if sealed { SEALED_ENTRY_SIZE } else { CHECKSUM_SIZE }
+    }
+
+    /// Number of blocks needed for the entries of `total_blocks` blocks
+    pub(crate) fn table_blocks(total_blocks: u64, block_size: u64, sealed: bool) -> u64 {
+        (total_blocks * Self::entry_size(sealed)).div_ceil(block_size)
+    }
+
+    /// Block of the table holding the entry of `block`
+    pub(crate) fn entry_block(&self, block: u64, block_size: u64) -> u64 {
+        (self.offset + block * self.entry_size) / block_size
+    }
+
+    /// Whether blocks of `class` are checksummed
+    pub(crate) fn covers(&self, class: BlockClass) -> bool {
+        class == BlockClass::Metadata || self.data
+    }
+
+    /// Stored checksum of `block`
+    pub(crate) fn load(&self, device: &dyn BlockDevice, block: u64) -> FsResult<u32> {
+        let mut bytes = [0u8; CHECKSUM_SIZE as usize];
+        device.read_exact_at(&mut bytes, self.offset + block * self.entry_size)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// Record the checksum of `block`
+    pub(crate) fn store(&self, device: &dyn BlockDevice, block: u64, checksum: u32) -> FsResult<()> {
+        device.write_all_at(&checksum.to_le_bytes(), self.offset + block * self.entry_size)?;
+        Ok(())
+    }
+
+    /// Stored seal of encrypted `block`
+    pub(crate) fn load_seal(&self, device: &dyn BlockDevice, block: u64) -> FsResult<[u8; SEAL_SIZE]> {
+        let mut seal = [0u8; SEAL_SIZE];
+        device.read_exact_at(&mut seal, self.offset + block * self.entry_size + CHECKSUM_SIZE)?;
+        Ok(seal)
+    }
+
+    /// Record the seal of encrypted `block`
+    pub(crate) fn store_seal(&self, device: &dyn BlockDevice, block: u64, seal: &[u8; SEAL_SIZE]) -> FsResult<()> {
+        device.write_all_at(seal, self.offset + block * self.entry_size + CHECKSUM_SIZE)?;
+        Ok(())
+    }
+
+    /// Whether entries hold seals
+    pub(crate) fn has_seals(&self) -> bool {
+        self.entry_size == SEALED_ENTRY_SIZE
+    }
+
+    /// Record the checksum of `block` holding `contents`
+    pub(crate) fn update(&self, device: &dyn BlockDevice, block: u64, contents: &[u8]) -> FsResult<()> {
+        self.store(device, block, crc32c(contents))
+    }
+
+    /// Check `contents` of `block` against the stored checksum
+    pub(crate) fn verify(&self, device: &dyn BlockDevice, block: u64, contents: &[u8]) -> FsResult<()> {
+        let expected = self.load(device, block)?;
+        let actual = crc32c(contents);
+        if expected != actual {
+            return Err(FsError::ChecksumMismatch { block, expected, actual });
+        }
+        Ok(())
+    }
+}
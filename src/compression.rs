@@ -0,0 +1,442 @@
+use crate::{
+    checksum::BlockClass,
+    encryption::FileKey,
+    error::{FsError, FsResult},
+    extent::{Extent, HOLE},
+    serialization::{FileType, Inode},
+    virtual_disk::VirtualDisk,
+};
+
+/// Logical blocks per compressed cluster
+pub const CLUSTER_BLOCKS: u64 = 8;
+
+/// Bytes before the compressed data in the first block of a cluster,
+/// holding its length
+const CLUSTER_HEADER: usize = 4;
+
+/// True if this build can compress files
+pub const AVAILABLE: bool = cfg!(feature = "compression");
+
+#[cfg(feature = "compression")]
+fn compress(data: &[u8]) -> Vec<u8> {
+    lz4_flex::block::compress(data)
+}
+
+#[cfg(feature = "compression")]
+fn decompress(compressed: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut data = vec![0u8; len];
+    match lz4_flex::block::decompress_into(compressed, &mut data) {
+        Ok(n) if n == len => Some(data),
+        _ => None,
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress(_compressed: &[u8], _len: usize) -> Option<Vec<u8>> {
+    None
+}
+
+fn unsupported() -> FsError {
+    FsError::NotSupported("Compressed files (built without the compression feature)".to_string())
+}
+
+/// Encode the data of a cluster as whole blocks
+///
+/// A cluster of zeros needs no blocks at all. Otherwise, for a compressed
+/// file, it is compressed behind a length header if that saves at least one
+/// block, and stored as it is if not.
+fn encode(data: &[u8], block_size: usize, compressed: bool) -> Vec<u8> {
+    if data.iter().all(|&b| b == 0) {
+        return Vec::new();
+    }
+    let raw_blocks = data.len().div_ceil(block_size);
+
+    #[cfg(feature = "compression")]
+    if compressed {
+        let compressed = compress(data);
+        let blocks = (CLUSTER_HEADER + compressed.len()).div_ceil(block_size);
+        if blocks < raw_blocks {
+            let mut stored = Vec::with_capacity(blocks * block_size);
+            stored.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            stored.extend_from_slice(&compressed);
+            stored.resize(blocks * block_size, 0);
+            return stored;
+        }
+    }
+
+    #[cfg(not(feature = "compression"))]
+    let _ = compressed;
+
+    let mut stored = data.to_vec();
+    stored.resize(raw_blocks * block_size, 0);
+    stored
+}
+
+/// The slots of the block map belonging to `cluster`, as far as mapped
+fn cluster_slots(blocks: &[u64], cluster: u64) -> &[u64] {
+    let first = ((cluster * CLUSTER_BLOCKS) as usize).min(blocks.len());
+    let end = (first + CLUSTER_BLOCKS as usize).min(blocks.len());
+    &blocks[first..end]
+}
+
+/// Copy the part of `data`, written at `offset`, that falls into a
+/// cluster starting at byte `start` whose contents are `cluster`
+fn copy_overlap(cluster: &mut [u8], start: u64, data: &[u8], offset: u64) {
+    let from = offset.max(start);
+    let to = (offset + data.len() as u64).min(start + cluster.len() as u64);
+    if from < to {
+        cluster[(from - start) as usize..(to - start) as usize]
+            .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+    }
+}
+
+impl VirtualDisk {
+    // ==================== COMPRESSION ====================
+    //
+    // A compressed file is split into clusters of CLUSTER_BLOCKS logical
+    // blocks, each compressed on its own so that reading or writing part
+    // of the file only touches the clusters it covers. The file's block
+    // map doubles as the cluster table: cluster `c` owns logical blocks
+    // `c * CLUSTER_BLOCKS..`, and a compressed cluster is stored in the
+    // first few of them, the rest being holes, with its compressed length
+    // at the start. A cluster with every block mapped did not compress
+    // and is stored as it is; one with none mapped is all zeros. Clusters
+    // are always rewritten to new blocks, so blocks shared with clones or
+    // snapshots are never changed in place.
+    //
+    // Encrypted files are stored in clusters too, whether compressed or
+    // not: each stored block is encrypted after compression, on its way
+    // to its new block (see `encryption.rs`). Clusters of zeros stay
+    // unmapped, as in any sparse file.
+
+    /// Bytes of the file's data in `cluster` for a file of `size` bytes
+    fn cluster_len(&self, size: u64, cluster: u64) -> usize {
+        let cluster_size = CLUSTER_BLOCKS * self.block_size();
+        size.saturating_sub(cluster * cluster_size).min(cluster_size) as usize
+    }
+
+    /// Read and decode one cluster of a file mapped to `blocks`, which is
+    /// encrypted with `key` if given
+    fn read_cluster(&self, inode: &Inode, key: Option<&FileKey>, blocks: &[u64], cluster: u64) -> FsResult<Vec<u8>> {
+        let block_size = self.block_size() as usize;
+        let len = self.cluster_len(inode.size, cluster);
+        let damaged = || {
+            FsError::CorruptedFileSystem(format!(
+                "Cluster {} of inode {} is damaged",
+                cluster, inode.inode_number
+            ))
+        };
+
+        let slots = cluster_slots(blocks, cluster)
+            .get(..len.div_ceil(block_size))
+            .ok_or_else(damaged)?;
+        let mapped = slots.iter().take_while(|&&b| b != HOLE).count();
+        if slots[mapped..].iter().any(|&b| b != HOLE) {
+            return Err(damaged());
+        }
+        if mapped == 0 {
+            return Ok(vec![0u8; len]);
+        }
+
+        let mut stored = vec![0u8; mapped * block_size];
+        for extent in Extent::from_blocks(&slots[..mapped]) {
+            let start = extent.logical as usize * block_size;
+            let end = start + extent.length as usize * block_size;
+            self.read_run(extent.physical, &mut stored[start..end], BlockClass::Data)?;
+        }
+        if let Some(key) = key {
+            let first = cluster * CLUSTER_BLOCKS;
+            for (i, contents) in stored.chunks_exact_mut(block_size).enumerate() {
+                let seal = self.block_seal(slots[i])?;
+                if !key.open_block(first + i as u64, contents, &seal)? {
+                    return Err(damaged());
+                }
+            }
+        }
+        if mapped == slots.len() {
+            stored.truncate(len);
+            return Ok(stored);
+        }
+
+        if !AVAILABLE {
+            return Err(unsupported());
+        }
+        let compressed_len = u32::from_le_bytes(stored[..CLUSTER_HEADER].try_into().unwrap()) as usize;
+        stored
+            .get(CLUSTER_HEADER..CLUSTER_HEADER + compressed_len)
+            .and_then(|compressed| decompress(compressed, len))
+            .ok_or_else(damaged)
+    }
+
+    /// Fill `buf` with the data of a clustered file from byte `offset`
+    ///
+    /// The range must lie within the file.
+    pub(crate) fn read_clusters(&self, inode: &Inode, offset: u64, buf: &mut [u8]) -> FsResult<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let cluster_size = CLUSTER_BLOCKS * self.block_size();
+        let end = offset + buf.len() as u64;
+        let key = self.file_key(inode)?;
+        let blocks = self.file_blocks(inode)?;
+        for cluster in offset / cluster_size..=(end - 1) / cluster_size {
+            let data = self.read_cluster(inode, key.as_ref(), &blocks, cluster)?;
+            let start = cluster * cluster_size;
+            let from = offset.max(start);
+            let to = end.min(start + data.len() as u64);
+            buf[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&data[(from - start) as usize..(to - start) as usize]);
+        }
+        Ok(())
+    }
+
+    /// Store encoded cluster data in new blocks and map them in place of
+    /// the cluster's old blocks, which are freed
+    ///
+    /// With `key`, the blocks are encrypted first and their seals recorded.
+    fn store_cluster(
+        &self,
+        inode_block: u64,
+        key: Option<&FileKey>,
+        blocks: &mut [u64],
+        cluster: u64,
+        mut stored: Vec<u8>,
+    ) -> FsResult<()> {
+        let block_size = self.block_size() as usize;
+        let first = (cluster * CLUSTER_BLOCKS) as usize;
+        let goal = blocks[..first]
+            .iter()
+            .rev()
+            .find(|&&b| b != HOLE)
+            .map_or_else(|| self.data_goal(inode_block), |&b| b + 1);
+        let new = self.allocate_extents(goal, (stored.len() / block_size) as u64)?;
+        let mut seals = Vec::new();
+        if let Some(key) = key {
+            for (i, contents) in stored.chunks_exact_mut(block_size).enumerate() {
+                seals.push(key.seal_block((first + i) as u64, contents)?);
+            }
+        }
+        for extent in Extent::from_blocks(&new) {
+            let start = extent.logical as usize * block_size;
+            let end = start + extent.length as usize * block_size;
+            self.write_run(extent.physical, &stored[start..end], BlockClass::Data)?;
+        }
+        for (&block, seal) in new.iter().zip(&seals) {
+            self.set_block_seal(block, seal)?;
+        }
+
+        let end = (first + CLUSTER_BLOCKS as usize).min(blocks.len());
+        for (i, slot) in blocks[first..end].iter_mut().enumerate() {
+            let old = std::mem::replace(slot, new.get(i).copied().unwrap_or(HOLE));
+            if old != HOLE {
+                self.free_block(old)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Blocks whole clusters of encoded data take
+    fn stored_blocks<'a>(&self, stored: impl IntoIterator<Item = &'a Vec<u8>>) -> u64 {
+        stored.into_iter().map(|s| s.len() as u64 / self.block_size()).sum()
+    }
+
+    /// Replace the whole data of a clustered file
+    pub(crate) fn write_compressed(&self, inode_block: u64, mut inode: Inode, data: &[u8]) -> FsResult<()> {
+        let block_size = self.block_size() as usize;
+        let key = self.file_key(&inode)?;
+        let compressed = inode.has_flag(Inode::FLAG_COMPRESSED);
+        let stored: Vec<Vec<u8>> = data
+            .chunks(CLUSTER_BLOCKS as usize * block_size)
+            .map(|cluster| encode(cluster, block_size, compressed))
+            .collect();
+        let count = (data.len() as u64).div_ceil(self.block_size());
+
+        // Fail before touching the old data if the new data cannot fit;
+        // shared blocks stay in use by the other files
+        let (old_data, old_metadata) = self.walk_mapping(&inode)?;
+        let old_mapped = old_data.iter().filter(|&&b| b != HOLE && !self.is_block_shared(b)).count();
+        let available = self.free_blocks_count() + (old_mapped + old_metadata.len()) as u64;
+        let needed = self.stored_blocks(&stored) + self.mapping_overhead(&inode, count);
+        if needed > available {
+            return Err(FsError::DiskFull);
+        }
+        let held = (old_data.iter().filter(|&&b| b != HOLE).count() + old_metadata.len()) as u64;
+        self.check_quota(needed.saturating_sub(held))?;
+
+        self.release_file_blocks(&mut inode)?;
+        let mut blocks = vec![HOLE; count as usize];
+        for (cluster, stored) in stored.into_iter().enumerate() {
+            self.store_cluster(inode_block, key.as_ref(), &mut blocks, cluster as u64, stored)?;
+        }
+        self.finish_clusters(inode_block, inode, &blocks, data.len() as u64)
+    }
+
+    /// Replace some clusters of a clustered file and set its size
+    ///
+    /// `clusters` holds the new contents of each cluster replaced; it must
+    /// include every cluster whose length changes with the size, which may
+    /// only grow.
+    fn replace_clusters(&self, inode_block: u64, inode: Inode, size: u64, clusters: Vec<(u64, Vec<u8>)>) -> FsResult<()> {
+        let block_size = self.block_size() as usize;
+        let key = self.file_key(&inode)?;
+        let compressed = inode.has_flag(Inode::FLAG_COMPRESSED);
+        let (mut blocks, metadata) = self.walk_mapping(&inode)?;
+        let stored: Vec<(u64, Vec<u8>)> = clusters
+            .into_iter()
+            .map(|(cluster, data)| (cluster, encode(&data, block_size, compressed)))
+            .collect();
+        let count = size.div_ceil(self.block_size());
+
+        // Fail before changing anything if the new clusters cannot fit
+        let replaced = || {
+            stored
+                .iter()
+                .flat_map(|(cluster, _)| cluster_slots(&blocks, *cluster))
+                .filter(|&&b| b != HOLE)
+        };
+        let freed = replaced().filter(|&&b| !self.is_block_shared(b)).count();
+        let available = self.free_blocks_count() + (freed + metadata.len()) as u64;
+        let needed = self.stored_blocks(stored.iter().map(|(_, s)| s)) + self.mapping_overhead(&inode, count);
+        if needed > available {
+            return Err(FsError::DiskFull);
+        }
+        self.check_quota(needed.saturating_sub((replaced().count() + metadata.len()) as u64))?;
+
+        blocks.resize(count as usize, HOLE);
+        for (cluster, stored) in stored {
+            self.store_cluster(inode_block, key.as_ref(), &mut blocks, cluster, stored)?;
+        }
+        for block in metadata {
+            self.free_block(block)?;
+        }
+        self.finish_clusters(inode_block, inode, &blocks, size)
+    }
+
+    /// Map a clustered file's new blocks and write back its inode
+    fn finish_clusters(&self, inode_block: u64, mut inode: Inode, blocks: &[u64], size: u64) -> FsResult<()> {
+        let goal = blocks
+            .iter()
+            .rev()
+            .find(|&&b| b != HOLE)
+            .map_or_else(|| self.data_goal(inode_block), |&b| b + 1);
+        self.map_file_blocks(&mut inode, blocks, goal)?;
+        inode.size = size;
+        inode.modified = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.write_inode(inode_block, &inode)
+    }
+
+    /// Write `data` at byte `offset` of a clustered file, rewriting the
+    /// clusters it covers
+    pub(crate) fn write_clusters(&self, inode_block: u64, inode: Inode, offset: u64, data: &[u8]) -> FsResult<()> {
+        let cluster_size = CLUSTER_BLOCKS * self.block_size();
+        let end = offset + data.len() as u64;
+        let size = inode.size.max(end);
+        let key = self.file_key(&inode)?;
+        let blocks = self.file_blocks(&inode)?;
+
+        // The old last cluster grows too if the file does, and is stored
+        // anew even when the write does not reach it
+        let mut touched: Vec<u64> = (offset / cluster_size..=(end - 1) / cluster_size).collect();
+        if size > inode.size && inode.size > 0 {
+            let last = (inode.size - 1) / cluster_size;
+            if last < touched[0] {
+                touched.insert(0, last);
+            }
+        }
+
+        let mut clusters = Vec::with_capacity(touched.len());
+        for cluster in touched {
+            let mut contents = if cluster * cluster_size < inode.size {
+                self.read_cluster(&inode, key.as_ref(), &blocks, cluster)?
+            } else {
+                Vec::new()
+            };
+            contents.resize(self.cluster_len(size, cluster), 0);
+            copy_overlap(&mut contents, cluster * cluster_size, data, offset);
+            clusters.push((cluster, contents));
+        }
+        self.replace_clusters(inode_block, inode, size, clusters)
+    }
+
+    /// Zero the byte range `offset..end` of a clustered file, which must
+    /// lie within it
+    ///
+    /// Clusters inside the range lose their blocks; those at either edge
+    /// are rewritten.
+    pub(crate) fn punch_clusters(&self, inode_block: u64, inode: Inode, offset: u64, end: u64) -> FsResult<()> {
+        let cluster_size = CLUSTER_BLOCKS * self.block_size();
+        let key = self.file_key(&inode)?;
+        let blocks = self.file_blocks(&inode)?;
+        let mut clusters = Vec::new();
+        for cluster in offset / cluster_size..=(end - 1) / cluster_size {
+            let start = cluster * cluster_size;
+            let len = self.cluster_len(inode.size, cluster);
+            let whole = start >= offset && start + len as u64 <= end;
+            let mut contents = if whole {
+                vec![0u8; len]
+            } else {
+                self.read_cluster(&inode, key.as_ref(), &blocks, cluster)?
+            };
+            let from = offset.max(start) - start;
+            let to = end.min(start + len as u64) - start;
+            contents[from as usize..to as usize].fill(0);
+            clusters.push((cluster, contents));
+        }
+        let size = inode.size;
+        self.replace_clusters(inode_block, inode, size, clusters)
+    }
+
+    /// Turn compression of a file on or off
+    ///
+    /// The file's data is rewritten in the new form; an encrypted file
+    /// stays encrypted either way. For a directory this sets whether files
+    /// and directories created in it from now on are compressed.
+    pub fn set_compressed(&self, inode_block: u64, compressed: bool) -> FsResult<()> {
+        if compressed && !AVAILABLE {
+            return Err(unsupported());
+        }
+        let mut inode = self.read_inode(inode_block)?;
+        match inode.file_type {
+            FileType::Directory => {
+                if compressed {
+                    inode.flags |= Inode::FLAG_COMPRESS;
+                } else {
+                    inode.flags &= !Inode::FLAG_COMPRESS;
+                }
+                self.write_inode(inode_block, &inode)
+            }
+            FileType::File => {
+                if inode.has_flag(Inode::FLAG_COMPRESSED) == compressed {
+                    return Ok(());
+                }
+                let data = self.read_inode_data(&inode)?;
+                let modified = inode.modified;
+                if compressed {
+                    inode.flags |= Inode::FLAG_COMPRESSED;
+                } else {
+                    inode.flags &= !Inode::FLAG_COMPRESSED;
+                }
+                self.write_inode_data(inode_block, inode, &data)?;
+
+                // The contents are the same, only stored differently
+                let mut inode = self.read_inode(inode_block)?;
+                inode.modified = modified;
+                self.write_inode(inode_block, &inode)
+            }
+            FileType::Symlink => Err(FsError::NotAFile(format!("Inode {} is a symlink", inode_block))),
+        }
+    }
+
+    /// Bytes of data blocks a file takes on disk
+    ///
+    /// Holes take none, and a compressed file usually takes less than its
+    /// size. Blocks shared with clones count in full for each file.
+    pub fn physical_size(&self, inode_block: u64) -> FsResult<u64> {
+        let inode = self.read_inode(inode_block)?;
+        let blocks = self.file_blocks(&inode)?;
+        Ok(blocks.iter().filter(|&&b| b != HOLE).count() as u64 * self.block_size())
+    }
+}
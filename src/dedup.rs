@@ -0,0 +1,458 @@
+use crate::{
+    checksum::BlockClass,
+    device::BlockDevice,
+    error::FsResult,
+    extent::{Extent, HOLE},
+    serialization::{FileType, Superblock},
+    table::{Reader, TableFormat},
+    virtual_disk::VirtualDisk,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Range;
+
+/// 64-bit FNV-1a hash of the contents of a block
+///
+/// Only used to find candidates: blocks are compared in full before they
+/// are shared, so a collision costs a read and nothing else.
+pub(crate) fn block_hash(contents: &[u8]) -> u64 {
+    contents
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3))
+}
+
+/// Hashes of data blocks, for finding a block identical to one about to
+/// be written
+///
+/// Each hash maps to one block in use by a file. An entry is dropped when
+/// the last reference to its block goes, but blocks written in place keep
+/// their old hash until they are hashed again, so a candidate is always
+/// compared with the new contents before it is shared. Shared blocks are
+/// counted in the reference count table like those of cloned files.
+///
+/// The table is stored in a chain of blocks (see `TableFormat`), which
+/// snapshots freeze and roll back like any other file system blocks.
+#[derive(Debug, Default)]
+pub(crate) struct DedupTable {
+    /// Block recorded for each hash
+    blocks: BTreeMap<u64, u64>,
+    /// Hash recorded for each block
+    hashes: HashMap<u64, u64>,
+    /// Blocks the table is stored in
+    store: Vec<u64>,
+    /// Changed since it was last written
+    dirty: bool,
+}
+
+impl DedupTable {
+    pub(crate) const FORMAT: TableFormat = TableFormat {
+        magic: 0x44445550, // "DDUP" in ASCII
+        version: 1,
+        name: "Dedup table",
+    };
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Number of blocks recorded
+    pub(crate) fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// The block recorded for `hash`
+    pub(crate) fn lookup(&self, hash: u64) -> Option<u64> {
+        self.blocks.get(&hash).copied()
+    }
+
+    /// Record that `block` holds contents with `hash`, replacing whatever
+    /// was recorded for either
+    pub(crate) fn insert(&mut self, hash: u64, block: u64) {
+        if self.blocks.get(&hash) == Some(&block) {
+            return;
+        }
+        self.forget(block);
+        if let Some(old) = self.blocks.insert(hash, block) {
+            self.hashes.remove(&old);
+        }
+        self.hashes.insert(block, hash);
+        self.dirty = true;
+    }
+
+    /// Drop the entry of a block that is no longer in use
+    pub(crate) fn forget(&mut self, block: u64) {
+        if let Some(hash) = self.hashes.remove(&block) {
+            self.blocks.remove(&hash);
+            self.dirty = true;
+        }
+    }
+
+    /// Drop every entry
+    pub(crate) fn clear(&mut self) {
+        if !self.is_empty() {
+            self.blocks.clear();
+            self.hashes.clear();
+            self.dirty = true;
+        }
+    }
+
+    /// Blocks the table is stored in
+    pub(crate) fn store(&self) -> &[u64] {
+        &self.store
+    }
+
+    /// Blocks the table is stored in, which the caller frees
+    pub(crate) fn take_store(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.store)
+    }
+
+    /// Record that the table was written to `blocks`
+    pub(crate) fn stored(&mut self, blocks: Vec<u64>) {
+        self.store = blocks;
+        self.dirty = false;
+    }
+
+    /// Serialize the table
+    pub(crate) fn body(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(8 + self.blocks.len() * 16);
+        body.extend_from_slice(&(self.blocks.len() as u64).to_le_bytes());
+        for (&hash, &block) in &self.blocks {
+            body.extend_from_slice(&hash.to_le_bytes());
+            body.extend_from_slice(&block.to_le_bytes());
+        }
+        body
+    }
+
+    /// Load the table stored from block `first`, 0 for none
+    pub(crate) fn load(device: &dyn BlockDevice, block_size: u64, total_blocks: u64, first: u64) -> FsResult<Self> {
+        let (store, body) = Self::FORMAT.read(device, block_size, total_blocks, first)?;
+        let mut table = DedupTable { store, ..DedupTable::default() };
+        if table.store.is_empty() {
+            return Ok(table);
+        }
+
+        let mut reader = Reader::new(&body, &Self::FORMAT);
+        for _ in 0..reader.u64()? {
+            let hash = reader.u64()?;
+            let block = reader.u64()?;
+            if block >= total_blocks || table.hashes.insert(block, hash).is_some() {
+                return Err(Self::FORMAT.corrupted(format!("records block {} wrongly", block)));
+            }
+            table.blocks.insert(hash, block);
+        }
+        Ok(table)
+    }
+}
+
+/// How much files share identical blocks, from `VirtualDisk::dedup_stats`
+///
+/// Blocks shared between cloned files count too, as they are stored the
+/// same way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DedupStats {
+    /// Data blocks recorded in the dedup table
+    pub hashed_blocks: u64,
+    /// Data blocks of all files, counting a shared block once per file
+    /// that refers to it
+    pub logical_blocks: u64,
+    /// Data blocks actually stored for all files
+    pub physical_blocks: u64,
+}
+
+impl DedupStats {
+    /// Blocks the files would take up beyond what is stored if nothing
+    /// were shared
+    pub fn saved_blocks(&self) -> u64 {
+        self.logical_blocks - self.physical_blocks
+    }
+
+    /// Logical blocks per physical block, 1.0 when nothing is shared
+    pub fn ratio(&self) -> f64 {
+        if self.physical_blocks == 0 {
+            return 1.0;
+        }
+        self.logical_blocks as f64 / self.physical_blocks as f64
+    }
+}
+
+/// Result of `VirtualDisk::dedup_scan`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DedupReport {
+    /// Files and symlinks whose blocks were hashed
+    pub files_scanned: u64,
+    /// Data blocks hashed
+    pub blocks_scanned: u64,
+    /// Blocks now sharing an identical block instead of their own copy
+    pub blocks_shared: u64,
+    /// Free blocks gained, as `free_blocks_count` counts them: the blocks
+    /// shared, less what the dedup and reference count tables grew by and
+    /// what snapshots still keep
+    pub blocks_freed: u64,
+}
+
+/// Blocks of data about to be written to a file with dedup
+pub(crate) struct DedupPlan {
+    /// For each logical block, an identical block on disk that it now
+    /// holds a reference to, or `HOLE` if it is written
+    shared: Vec<u64>,
+    /// Logical blocks to write to new blocks, with their hashes
+    fresh: Vec<(usize, u64)>,
+    /// Logical blocks that repeat an earlier fresh one in the same data
+    repeats: Vec<(usize, usize)>,
+}
+
+impl DedupPlan {
+    /// Number of new blocks needed
+    pub(crate) fn new_blocks(&self) -> u64 {
+        self.fresh.len() as u64
+    }
+
+    /// Blocks already on disk the data refers to
+    pub(crate) fn shared(&self) -> impl Iterator<Item = u64> + '_ {
+        self.shared.iter().copied().filter(|&b| b != HOLE)
+    }
+}
+
+/// The contents of logical block `logical` of `data`, padded with zeros
+fn block_of(data: &[u8], logical: usize, block_size: usize) -> Vec<u8> {
+    let start = logical * block_size;
+    let mut contents = data[start..(start + block_size).min(data.len())].to_vec();
+    contents.resize(block_size, 0);
+    contents
+}
+
+impl VirtualDisk {
+    // ==================== DEDUPLICATION ====================
+    //
+    // With `Superblock::FEATURE_DEDUP`, data replacing a file's contents
+    // is hashed block by block, and each block identical to one already
+    // on disk is mapped to that block instead of being written. Partial
+    // writes hash the blocks they touched afterwards. Sharing works like
+    // cloning: the reference count table counts the files using a block,
+    // freeing drops a reference, and a shared block is copied before a
+    // file writes to it. Compressed and encrypted files are left alone,
+    // as their clusters are rewritten on every change and encrypted
+    // blocks differ between files anyway.
+
+    /// Whether new writes are deduplicated
+    pub fn is_dedup_enabled(&self) -> bool {
+        self.has_feature(Superblock::FEATURE_DEDUP)
+    }
+
+    /// Turn deduplication of new writes on or off
+    ///
+    /// Turning it off drops the dedup table; blocks already shared stay
+    /// shared. Use `dedup_scan` to deduplicate data written before.
+    pub fn set_dedup(&mut self, enabled: bool) -> FsResult<()> {
+        self.block_groups_mut().set_feature(Superblock::FEATURE_DEDUP, enabled);
+        if !enabled {
+            self.clear_dedup_table();
+        }
+        self.sync_bitmap()
+    }
+
+    /// Hash the blocks of `data` and share those already on disk
+    ///
+    /// On failure, the references taken are dropped again.
+    pub(crate) fn plan_dedup(&self, data: &[u8]) -> FsResult<DedupPlan> {
+        let block_size = self.block_size() as usize;
+        let count = data.len().div_ceil(block_size);
+        let mut plan = DedupPlan { shared: vec![HOLE; count], fresh: Vec::new(), repeats: Vec::new() };
+        let mut first_fresh: HashMap<u64, usize> = HashMap::new();
+        for logical in 0..count {
+            let contents = block_of(data, logical, block_size);
+            let hash = block_hash(&contents);
+            match self.share_duplicate(hash, &contents, HOLE) {
+                Ok(Some(block)) => {
+                    plan.shared[logical] = block;
+                    continue;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    self.drop_shared(&plan)?;
+                    return Err(e);
+                }
+            }
+            match first_fresh.get(&hash) {
+                Some(&first) if block_of(data, first, block_size) == contents => plan.repeats.push((logical, first)),
+                _ => {
+                    first_fresh.entry(hash).or_insert(logical);
+                    plan.fresh.push((logical, hash));
+                }
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Drop the references a plan took
+    pub(crate) fn drop_shared(&self, plan: &DedupPlan) -> FsResult<()> {
+        for block in plan.shared() {
+            self.free_block(block)?;
+        }
+        Ok(())
+    }
+
+    /// Free blocks to keep for the reference count and dedup tables
+    /// growing while `count` blocks are shared or recorded
+    ///
+    /// The tables are rewritten to new blocks when saved, and the old
+    /// ones may be kept for snapshots, so this allows for a copy of each.
+    pub(crate) fn dedup_headroom(&self, count: u64) -> u64 {
+        let tables = (self.refcount_table_blocks().len() + self.dedup_table_blocks().len()) as u64;
+        2 * tables + (count * 32).div_ceil(self.block_size()) + 2
+    }
+
+    /// Write the fresh blocks of `data` to `new` and return the blocks
+    /// mapping the whole data
+    pub(crate) fn store_planned(&self, plan: DedupPlan, data: &[u8], new: &[u64]) -> FsResult<Vec<u64>> {
+        let block_size = self.block_size() as usize;
+        let mut blocks = plan.shared;
+        let mut written = vec![HOLE; blocks.len()];
+        for (&(logical, _), &block) in plan.fresh.iter().zip(new) {
+            written[logical] = block;
+            blocks[logical] = block;
+        }
+        // The last block is written whole, so it compares equal to
+        // blocks of the same data elsewhere
+        let whole = data.len() / block_size;
+        for extent in Extent::from_blocks(&written) {
+            let (logical, length) = (extent.logical as usize, extent.length as usize);
+            let full = length.min(whole.saturating_sub(logical));
+            if full > 0 {
+                let start = logical * block_size;
+                self.write_run(extent.physical, &data[start..start + full * block_size], BlockClass::Data)?;
+            }
+            if full < length {
+                self.write_block(extent.physical + full as u64, &block_of(data, whole, block_size), BlockClass::Data)?;
+            }
+        }
+        for &(logical, hash) in &plan.fresh {
+            self.record_block_hash(hash, blocks[logical]);
+        }
+        for &(logical, first) in &plan.repeats {
+            blocks[logical] = blocks[first];
+        }
+        let repeated: Vec<u64> = plan.repeats.iter().map(|&(logical, _)| blocks[logical]).collect();
+        self.share_blocks(&repeated)?;
+        Ok(blocks)
+    }
+
+    /// Hash the blocks `range` of a file or symlink, sharing those that
+    /// have an identical copy elsewhere
+    ///
+    /// Returns the number of blocks hashed and the number shared.
+    pub(crate) fn dedup_file(&self, inode_block: u64, range: Range<u64>) -> FsResult<(u64, u64)> {
+        let mut inode = self.read_inode(inode_block)?;
+        if inode.file_type == FileType::Directory || inode.is_clustered() {
+            return Ok((0, 0));
+        }
+        let (mut blocks, metadata) = self.walk_mapping(&inode)?;
+        let end = range.end.min(blocks.len() as u64);
+        // Sharing can split extents, so leave the file alone if a new
+        // mapping might not fit
+        let needed = self.mapping_overhead(&inode, blocks.len() as u64) + self.dedup_headroom(end.saturating_sub(range.start));
+        if needed > self.free_blocks_count() + metadata.len() as u64 {
+            return Ok((0, 0));
+        }
+
+        let mut hashed = 0;
+        let mut replaced = Vec::new();
+        let mut result = Ok(());
+        for logical in range.start..end {
+            let block = blocks[logical as usize];
+            if block == HOLE {
+                continue;
+            }
+            let shared = self.read_block(block, BlockClass::Data).and_then(|contents| {
+                let hash = block_hash(&contents);
+                let shared = self.share_duplicate(hash, &contents, block)?;
+                if shared.is_none() {
+                    self.record_block_hash(hash, block);
+                }
+                Ok(shared)
+            });
+            match shared {
+                Ok(Some(other)) => {
+                    blocks[logical as usize] = other;
+                    replaced.push((logical, block));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+            hashed += 1;
+        }
+        if replaced.is_empty() {
+            return result.map(|_| (hashed, 0));
+        }
+
+        // Write back the references taken before anything maps them, or
+        // drop them again if that fails
+        if let Err(e) = result.and_then(|_| self.sync_bitmap()) {
+            for &(logical, _) in &replaced {
+                self.free_block(blocks[logical as usize])?;
+            }
+            return Err(e);
+        }
+        for block in metadata {
+            self.free_block(block)?;
+        }
+        let goal = self.data_goal(inode_block);
+        self.map_file_blocks(&mut inode, &blocks, goal)?;
+        self.write_inode(inode_block, &inode)?;
+        for &(_, block) in &replaced {
+            self.free_block(block)?;
+        }
+        Ok((hashed, replaced.len() as u64))
+    }
+
+    /// Deduplicate the data of every file already on the image
+    ///
+    /// Each block of each file and symlink is hashed and either shared
+    /// with an identical block found before, freeing it, or recorded in
+    /// the dedup table. This works whether or not dedup is enabled; if it
+    /// is not, the table is dropped again at the end.
+    pub fn dedup_scan(&mut self) -> FsResult<DedupReport> {
+        let mut report = DedupReport::default();
+        let free = self.free_blocks_count();
+        for inode_block in self.block_groups().used_inodes() {
+            let inode = self.read_inode(inode_block)?;
+            if inode.file_type == FileType::Directory || inode.is_clustered() {
+                continue;
+            }
+            let (hashed, shared) = self.dedup_file(inode_block, 0..u64::MAX)?;
+            report.files_scanned += 1;
+            report.blocks_scanned += hashed;
+            report.blocks_shared += shared;
+        }
+        if !self.is_dedup_enabled() {
+            self.clear_dedup_table();
+        }
+        self.sync_bitmap()?;
+        report.blocks_freed = self.free_blocks_count().saturating_sub(free);
+        Ok(report)
+    }
+
+    /// How much the files on the image share identical blocks
+    pub fn dedup_stats(&mut self) -> FsResult<DedupStats> {
+        let mut stats = DedupStats { hashed_blocks: self.dedup_entries(), ..DedupStats::default() };
+        let mut physical = HashSet::new();
+        for inode_block in self.block_groups().used_inodes() {
+            let inode = self.read_inode(inode_block)?;
+            if inode.file_type == FileType::Directory {
+                continue;
+            }
+            for block in self.file_blocks(&inode)?.into_iter().filter(|&b| b != HOLE) {
+                stats.logical_blocks += 1;
+                physical.insert(block);
+            }
+        }
+        stats.physical_blocks = physical.len() as u64;
+        Ok(stats)
+    }
+}
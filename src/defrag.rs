@@ -0,0 +1,181 @@
+use crate::{
+    allocator::AllocationStats,
+    block_group::FragmentationStats,
+    error::{FsError, FsResult},
+    extent::{Extent, HOLE},
+    virtual_disk::VirtualDisk,
+};
+
+/// Options for `VirtualDisk::defragment`
+#[derive(Debug, Clone, Default)]
+pub struct DefragOptions {
+    /// Also move already-contiguous files to the lowest free run that
+    /// fits them, packing data toward the start of the disk and leaving
+    /// free space in one piece at the end
+    pub compact: bool,
+}
+
+/// Result of a defragmentation pass
+#[derive(Debug, Clone, Default)]
+pub struct DefragReport {
+    /// Inodes looked at
+    pub files_examined: u64,
+    /// Inodes whose data was relocated
+    pub files_moved: u64,
+    /// Inodes left alone because no contiguous run was large enough
+    pub files_skipped: u64,
+    /// Data blocks copied to a new location
+    pub blocks_moved: u64,
+    /// File layout before the pass
+    pub before: FragmentationStats,
+    /// File layout after the pass
+    pub after: FragmentationStats,
+    /// Free space layout before the pass
+    pub free_space_before: AllocationStats,
+    /// Free space layout after the pass
+    pub free_space_after: AllocationStats,
+}
+
+/// What happened to a single inode
+enum Relocation {
+    Moved(u64),
+    Unchanged,
+    NoSpace,
+}
+
+impl VirtualDisk {
+    /// Defragment every file and directory on the disk
+    ///
+    /// Each inode whose data is split into several runs is copied into a
+    /// single contiguous run, allocated as close to its inode as possible.
+    /// With `compact`, inodes are visited from the start of the disk and
+    /// moved to the lowest run that fits, which gathers free space at the
+    /// end of the disk. Files sharing blocks with clones stay where they
+    /// are.
+    pub fn defragment(&mut self, options: &DefragOptions) -> FsResult<DefragReport> {
+        let mut report = self.start_report()?;
+
+        let mut inodes = Vec::new();
+        for inode_block in self.block_groups().used_inodes() {
+            let inode = self.read_inode(inode_block)?;
+            let first_block = self
+                .file_blocks(&inode)?
+                .into_iter()
+                .find(|&b| b != HOLE)
+                .unwrap_or(u64::MAX);
+            inodes.push((first_block, inode_block));
+        }
+        if options.compact {
+            inodes.sort_unstable();
+        }
+
+        for (_, inode_block) in inodes {
+            self.relocate_into_report(inode_block, options.compact, &mut report)?;
+        }
+
+        self.finish_report(report)
+    }
+
+    /// Defragment a single file or directory
+    ///
+    /// Moves the inode's data into one contiguous run near the inode.
+    /// Nothing is moved if the data is already contiguous or if no free
+    /// run is large enough.
+    pub fn defragment_file(&mut self, inode_block: u64) -> FsResult<DefragReport> {
+        let mut report = self.start_report()?;
+        self.relocate_into_report(inode_block, false, &mut report)?;
+        self.finish_report(report)
+    }
+
+    fn start_report(&mut self) -> FsResult<DefragReport> {
+        Ok(DefragReport {
+            before: self.fragmentation_stats()?,
+            free_space_before: self.allocation_stats(),
+            ..Default::default()
+        })
+    }
+
+    fn finish_report(&mut self, mut report: DefragReport) -> FsResult<DefragReport> {
+        report.after = self.fragmentation_stats()?;
+        report.free_space_after = self.allocation_stats();
+        Ok(report)
+    }
+
+    fn relocate_into_report(
+        &mut self,
+        inode_block: u64,
+        compact: bool,
+        report: &mut DefragReport,
+    ) -> FsResult<()> {
+        report.files_examined += 1;
+        match self.relocate_inode(inode_block, compact)? {
+            Relocation::Moved(blocks) => {
+                report.files_moved += 1;
+                report.blocks_moved += blocks;
+            }
+            Relocation::Unchanged => {}
+            Relocation::NoSpace => report.files_skipped += 1,
+        }
+        Ok(())
+    }
+
+    /// Copy an inode's data into one contiguous run and repoint the inode
+    ///
+    /// The order keeps the file readable at every step: data is copied to
+    /// the new run and a new mapping (indirect blocks or extent tree) is
+    /// built while the old one is untouched; the inode is then rewritten in
+    /// a single block write; only after that are the old data and mapping
+    /// blocks freed. Holes stay holes; only mapped blocks are moved.
+    fn relocate_inode(&mut self, inode_block: u64, compact: bool) -> FsResult<Relocation> {
+        let mut inode = self.read_inode(inode_block)?;
+        let (blocks, old_metadata) = self.walk_mapping(&inode)?;
+        let mapped: Vec<u64> = blocks.iter().copied().filter(|&b| b != HOLE).collect();
+        // Moving blocks shared with clones would give this file copies
+        if mapped.is_empty() || mapped.iter().any(|&b| self.is_block_shared(b)) {
+            return Ok(Relocation::Unchanged);
+        }
+
+        let fragments = Extent::from_blocks(&mapped).len();
+        if fragments == 1 && !compact {
+            return Ok(Relocation::Unchanged);
+        }
+
+        let count = mapped.len() as u64;
+        let goal = if compact { 0 } else { self.block_groups().data_goal(inode_block) };
+        let start = match self.allocate_contiguous_blocks_near(goal, count) {
+            Ok(start) => start,
+            Err(FsError::NotEnoughContiguousSpace(_)) => return Ok(Relocation::NoSpace),
+            Err(e) => return Err(e),
+        };
+
+        // When compacting, only move contiguous files that end up lower
+        if fragments == 1 && start >= mapped[0] {
+            self.free_blocks(start, count)?;
+            return Ok(Relocation::Unchanged);
+        }
+
+        for (i, &block) in mapped.iter().enumerate() {
+            self.copy_block(block, start + i as u64)?;
+        }
+
+        let mut next = start;
+        let new_blocks: Vec<u64> = blocks
+            .iter()
+            .map(|&b| {
+                if b == HOLE {
+                    return HOLE;
+                }
+                next += 1;
+                next - 1
+            })
+            .collect();
+        self.map_file_blocks(&mut inode, &new_blocks, start + count)?;
+        self.write_inode(inode_block, &inode)?;
+
+        for block in mapped.into_iter().chain(old_metadata) {
+            self.free_block(block)?;
+        }
+
+        Ok(Relocation::Moved(count))
+    }
+}
@@ -0,0 +1,50 @@
+use crate::block_io;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io;
+
+/// Storage a `VirtualDisk` lives on
+///
+/// Devices are addressed by byte offset, with positional reads and
+/// writes that do not depend on a cursor, so a disk shared between
+/// threads can use one device from all of them at once. A disk image
+/// file is the usual device; `FaultyDevice` wraps another device to
+/// inject errors.
+pub trait BlockDevice: Debug + Send + Sync {
+    /// Read exactly `buf.len()` bytes at `offset`
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    /// Write all of `data` at `offset`
+    fn write_all_at(&self, data: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Size of the device in bytes
+    fn size(&self) -> io::Result<u64>;
+
+    /// Resize the device, zero-filling any new space
+    fn set_size(&self, size: u64) -> io::Result<()>;
+
+    /// Flush written data to stable storage
+    fn sync(&self) -> io::Result<()>;
+}
+
+impl BlockDevice for File {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        block_io::read_exact_at(self, buf, offset)
+    }
+
+    fn write_all_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        block_io::write_all_at(self, data, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        self.set_len(size)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
@@ -0,0 +1,429 @@
+use crate::{
+    error::{FsError, FsResult},
+    serialization::{DirectoryEntry, Inode, Superblock, MAX_FILENAME_LENGTH},
+    virtual_disk::VirtualDisk,
+};
+use std::fmt;
+
+/// True if this build can encrypt images
+pub const AVAILABLE: bool = cfg!(feature = "encryption");
+
+/// PBKDF2-HMAC-SHA256 iterations for the volume key of new images
+pub const DEFAULT_KDF_ITERATIONS: u64 = 100_000;
+
+/// Bytes of random salt for the key derivation
+pub const SALT_SIZE: usize = 16;
+
+/// Bytes of the value the superblock keeps to recognise the right key
+pub const KEY_CHECK_SIZE: usize = 16;
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+/// Bytes of the nonce and tag of an encrypted block, kept in its
+/// checksum table entry
+pub const SEAL_SIZE: usize = NONCE_SIZE + TAG_SIZE;
+
+/// Bytes of a file key encrypted with the volume key: nonce, key and tag
+pub const WRAPPED_KEY_SIZE: usize = NONCE_SIZE + KEY_SIZE + TAG_SIZE;
+
+/// Longest name in a directory of an image with encrypted names; the
+/// encrypted name, nonce and tag must fit a directory entry in base64
+pub const MAX_ENCRYPTED_NAME_LENGTH: usize = MAX_FILENAME_LENGTH * 3 / 4 - NONCE_SIZE - TAG_SIZE;
+
+fn unsupported() -> FsError {
+    FsError::NotSupported("Encrypted images (built without the encryption feature)".to_string())
+}
+
+#[cfg(feature = "encryption")]
+mod cipher {
+    use super::{KEY_SIZE, NONCE_SIZE, TAG_SIZE};
+    use crate::error::{FsError, FsResult};
+    use chacha20poly1305::{
+        aead::{AeadInPlace, KeyInit},
+        ChaCha20Poly1305, Key, Nonce, Tag,
+    };
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    pub(super) fn random(buf: &mut [u8]) -> FsResult<()> {
+        getrandom::getrandom(buf).map_err(|e| FsError::Io(e.into()))
+    }
+
+    pub(super) fn derive(passphrase: &[u8], salt: &[u8], iterations: u64, out: &mut [u8]) -> FsResult<()> {
+        let rounds = u32::try_from(iterations)
+            .map_err(|_| FsError::CorruptedFileSystem(format!("{} key derivation iterations", iterations)))?;
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase, salt, rounds, out)
+            .map_err(|_| FsError::CorruptedFileSystem("Key derivation failed".to_string()))
+    }
+
+    pub(super) fn mac(key: &[u8; KEY_SIZE], data: &[u8]) -> FsResult<[u8; 32]> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+        mac.update(data);
+        Ok(mac.finalize().into_bytes().into())
+    }
+
+    pub(super) fn seal(key: &[u8; KEY_SIZE], nonce: &[u8; NONCE_SIZE], aad: &[u8], buf: &mut [u8]) -> FsResult<[u8; TAG_SIZE]> {
+        let tag = ChaCha20Poly1305::new(Key::from_slice(key))
+            .encrypt_in_place_detached(Nonce::from_slice(nonce), aad, buf)
+            .map_err(|_| FsError::InvalidOffsetOrSize { offset: 0, size: buf.len() as u64 })?;
+        Ok(tag.into())
+    }
+
+    pub(super) fn open(
+        key: &[u8; KEY_SIZE],
+        nonce: &[u8; NONCE_SIZE],
+        aad: &[u8],
+        buf: &mut [u8],
+        tag: &[u8; TAG_SIZE],
+    ) -> FsResult<bool> {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+        Ok(cipher.decrypt_in_place_detached(Nonce::from_slice(nonce), aad, buf, Tag::from_slice(tag)).is_ok())
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+mod cipher {
+    use super::{unsupported, KEY_SIZE, NONCE_SIZE, TAG_SIZE};
+    use crate::error::FsResult;
+
+    pub(super) fn random(_buf: &mut [u8]) -> FsResult<()> {
+        Err(unsupported())
+    }
+
+    pub(super) fn derive(_passphrase: &[u8], _salt: &[u8], _iterations: u64, _out: &mut [u8]) -> FsResult<()> {
+        Err(unsupported())
+    }
+
+    pub(super) fn mac(_key: &[u8; KEY_SIZE], _data: &[u8]) -> FsResult<[u8; 32]> {
+        Err(unsupported())
+    }
+
+    pub(super) fn seal(_key: &[u8; KEY_SIZE], _nonce: &[u8; NONCE_SIZE], _aad: &[u8], _buf: &mut [u8]) -> FsResult<[u8; TAG_SIZE]> {
+        Err(unsupported())
+    }
+
+    pub(super) fn open(
+        _key: &[u8; KEY_SIZE],
+        _nonce: &[u8; NONCE_SIZE],
+        _aad: &[u8],
+        _buf: &mut [u8],
+        _tag: &[u8; TAG_SIZE],
+    ) -> FsResult<bool> {
+        Err(unsupported())
+    }
+}
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// URL-safe base64 without padding, which is also safe in file names
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity((bytes.len() * 4).div_ceil(3));
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &b)| bits | u32::from(b) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            out.push(BASE64_ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    for chunk in text.as_bytes().chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut bits = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let value = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+            bits |= value << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            out.push((bits >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// How to encrypt a new image, see `FormatOptions::encryption`
+#[derive(Clone)]
+pub struct EncryptionOptions {
+    /// Passphrase the volume key is derived from
+    pub passphrase: String,
+    /// Encrypt names in directory entries as well as file data
+    pub encrypt_names: bool,
+    /// PBKDF2 iterations for deriving the volume key
+    pub kdf_iterations: u64,
+}
+
+impl EncryptionOptions {
+    /// Encrypt file data with a key derived from `passphrase`
+    pub fn new(passphrase: &str) -> Self {
+        EncryptionOptions {
+            passphrase: passphrase.to_string(),
+            encrypt_names: false,
+            kdf_iterations: DEFAULT_KDF_ITERATIONS,
+        }
+    }
+}
+
+impl fmt::Debug for EncryptionOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionOptions")
+            .field("encrypt_names", &self.encrypt_names)
+            .field("kdf_iterations", &self.kdf_iterations)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Keys of an unlocked image, derived from its passphrase
+#[derive(Clone)]
+pub(crate) struct VolumeKeys {
+    /// Encrypts the keys of files
+    wrapping: [u8; KEY_SIZE],
+    /// Encrypts names in directory entries
+    names: [u8; KEY_SIZE],
+}
+
+impl VolumeKeys {
+    /// Derive the keys from `passphrase`, together with the check value
+    /// recognising them
+    fn derive(passphrase: &str, salt: &[u8; SALT_SIZE], iterations: u64) -> FsResult<(Self, [u8; KEY_CHECK_SIZE])> {
+        let mut derived = [0u8; 2 * KEY_SIZE];
+        cipher::derive(passphrase.as_bytes(), salt, iterations, &mut derived)?;
+        let keys = VolumeKeys {
+            wrapping: derived[..KEY_SIZE].try_into().unwrap(),
+            names: derived[KEY_SIZE..].try_into().unwrap(),
+        };
+        let check = cipher::mac(&keys.wrapping, b"key check")?[..KEY_CHECK_SIZE].try_into().unwrap();
+        Ok((keys, check))
+    }
+
+    /// A new random file key, wrapped
+    fn new_file_key(&self) -> FsResult<[u8; WRAPPED_KEY_SIZE]> {
+        let mut wrapped = [0u8; WRAPPED_KEY_SIZE];
+        cipher::random(&mut wrapped[..NONCE_SIZE + KEY_SIZE])?;
+        let (nonce, rest) = wrapped.split_at_mut(NONCE_SIZE);
+        let (key, tag) = rest.split_at_mut(KEY_SIZE);
+        tag.copy_from_slice(&cipher::seal(&self.wrapping, (&*nonce).try_into().unwrap(), b"file key", key)?);
+        Ok(wrapped)
+    }
+
+    /// Unwrap a file key, `None` if it was not wrapped with these keys
+    fn unwrap_file_key(&self, wrapped: &[u8; WRAPPED_KEY_SIZE]) -> FsResult<Option<FileKey>> {
+        let (nonce, rest) = wrapped.split_at(NONCE_SIZE);
+        let (key, tag) = rest.split_at(KEY_SIZE);
+        let mut key: [u8; KEY_SIZE] = key.try_into().unwrap();
+        let opened = cipher::open(&self.wrapping, nonce.try_into().unwrap(), b"file key", &mut key, tag.try_into().unwrap())?;
+        Ok(opened.then_some(FileKey(key)))
+    }
+
+    /// Encrypt a name for a directory entry
+    ///
+    /// The nonce is derived from the name, so that a name always encrypts
+    /// the same way and entries can still be looked up by name.
+    fn encrypt_name(&self, name: &str) -> FsResult<String> {
+        let nonce: [u8; NONCE_SIZE] = cipher::mac(&self.names, name.as_bytes())?[..NONCE_SIZE].try_into().unwrap();
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(name.as_bytes());
+        let tag = cipher::seal(&self.names, &nonce, b"name", &mut sealed[NONCE_SIZE..])?;
+        sealed.extend_from_slice(&tag);
+        Ok(base64_encode(&sealed))
+    }
+
+    /// Decrypt the name of a directory entry, `None` if it does not decrypt
+    fn decrypt_name(&self, stored: &str) -> FsResult<Option<String>> {
+        let Some(mut sealed) = base64_decode(stored).filter(|s| s.len() >= NONCE_SIZE + TAG_SIZE) else {
+            return Ok(None);
+        };
+        let tag: [u8; TAG_SIZE] = sealed.split_off(sealed.len() - TAG_SIZE).try_into().unwrap();
+        let (nonce, name) = sealed.split_at_mut(NONCE_SIZE);
+        if !cipher::open(&self.names, (&*nonce).try_into().unwrap(), b"name", name, &tag)? {
+            return Ok(None);
+        }
+        Ok(String::from_utf8(name.to_vec()).ok())
+    }
+}
+
+/// Key of the data of one file
+pub(crate) struct FileKey([u8; KEY_SIZE]);
+
+impl FileKey {
+    /// Encrypt the contents of logical block `logical` in place and
+    /// return the seal needed to decrypt them
+    pub(crate) fn seal_block(&self, logical: u64, contents: &mut [u8]) -> FsResult<[u8; SEAL_SIZE]> {
+        let mut seal = [0u8; SEAL_SIZE];
+        cipher::random(&mut seal[..NONCE_SIZE])?;
+        let nonce = seal[..NONCE_SIZE].try_into().unwrap();
+        let tag = cipher::seal(&self.0, &nonce, &logical.to_le_bytes(), contents)?;
+        seal[NONCE_SIZE..].copy_from_slice(&tag);
+        Ok(seal)
+    }
+
+    /// Decrypt the contents of logical block `logical` in place; false if
+    /// they do not match their seal
+    pub(crate) fn open_block(&self, logical: u64, contents: &mut [u8], seal: &[u8; SEAL_SIZE]) -> FsResult<bool> {
+        let (nonce, tag) = seal.split_at(NONCE_SIZE);
+        cipher::open(&self.0, nonce.try_into().unwrap(), &logical.to_le_bytes(), contents, tag.try_into().unwrap())
+    }
+}
+
+/// Encryption state of an open image
+pub(crate) struct Keyring {
+    /// File data is encrypted
+    encrypted: bool,
+    /// Names in directory entries are encrypted
+    names: bool,
+    /// Keys, once the image is unlocked
+    keys: Option<VolumeKeys>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("encrypted", &self.encrypted)
+            .field("names", &self.names)
+            .field("unlocked", &self.keys.is_some())
+            .finish()
+    }
+}
+
+impl Keyring {
+    pub(crate) fn new(superblock: &Superblock) -> Self {
+        Keyring {
+            encrypted: superblock.has_feature(Superblock::FEATURE_ENCRYPTION),
+            names: superblock.has_feature(Superblock::FEATURE_ENCRYPTED_NAMES),
+            keys: None,
+        }
+    }
+
+    /// Set up the keys of a new image, returning the salt and check value
+    /// for the superblock
+    pub(crate) fn create(&mut self, options: &EncryptionOptions) -> FsResult<([u8; SALT_SIZE], [u8; KEY_CHECK_SIZE])> {
+        let mut salt = [0u8; SALT_SIZE];
+        cipher::random(&mut salt)?;
+        let (keys, check) = VolumeKeys::derive(&options.passphrase, &salt, options.kdf_iterations)?;
+        self.keys = Some(keys);
+        Ok((salt, check))
+    }
+
+    fn keys(&self) -> FsResult<&VolumeKeys> {
+        self.keys.as_ref().ok_or(FsError::WrongKey)
+    }
+
+    /// Fail unless names can be looked up, being stored as they are or
+    /// the image unlocked
+    pub(crate) fn require_names(&self) -> FsResult<()> {
+        match self.names {
+            true => self.keys().map(|_| ()),
+            false => Ok(()),
+        }
+    }
+
+    /// An entry as read from disk, with its name decrypted unless the
+    /// image is locked
+    pub(crate) fn reveal(&self, mut entry: DirectoryEntry) -> FsResult<DirectoryEntry> {
+        if let (true, Some(keys)) = (self.names, &self.keys) {
+            entry.name = keys.decrypt_name(&entry.name)?.ok_or_else(|| {
+                FsError::CorruptedFileSystem(format!("Name of the entry for inode {} does not decrypt", entry.inode_number))
+            })?;
+        }
+        Ok(entry)
+    }
+
+    /// An entry as it is to be written to disk
+    pub(crate) fn conceal(&self, entry: &DirectoryEntry) -> FsResult<DirectoryEntry> {
+        let mut entry = entry.clone();
+        if self.names {
+            if entry.name.len() > MAX_ENCRYPTED_NAME_LENGTH {
+                return Err(FsError::InvalidFileName(format!(
+                    "Name too long: {} bytes (max {} with encrypted names)",
+                    entry.name.len(),
+                    MAX_ENCRYPTED_NAME_LENGTH
+                )));
+            }
+            entry.name = self.keys()?.encrypt_name(&entry.name)?;
+        }
+        Ok(entry)
+    }
+}
+
+impl VirtualDisk {
+    // ==================== ENCRYPTION ====================
+    //
+    // The volume key is derived from the passphrase with PBKDF2 and never
+    // stored; the superblock only keeps the salt, the iteration count and
+    // a check value telling a wrong passphrase from corruption. Every file
+    // and symlink gets its own random key, stored in its inode wrapped
+    // (encrypted) with the volume key, and its data is kept in clusters
+    // like compressed data (see `compression.rs`). Each block of a
+    // cluster is encrypted with ChaCha20-Poly1305 under a fresh random
+    // nonce whenever it is written, and authenticated together with its
+    // logical position in the file; the nonce and tag live in the block's
+    // checksum table entry. Since clusters are always written to new
+    // blocks, a nonce is never reused, and clones and defragmentation can
+    // move encrypted blocks around as they are. Directories and the rest
+    // of the metadata stay readable without the key, apart from names
+    // where the image encrypts those too.
+
+    /// Open an existing encrypted image and unlock it with `passphrase`
+    pub fn open_encrypted(path: &str, passphrase: &str) -> FsResult<VirtualDisk> {
+        let file = std::fs::OpenOptions::new().read(true).write(true).open(path)?;
+        let mut disk = VirtualDisk::open_device(Box::new(file))?;
+        disk.unlock(passphrase)?;
+        Ok(disk)
+    }
+
+    /// Derive the volume key from `passphrase`, so that encrypted files
+    /// can be read and written
+    ///
+    /// Fails with `FsError::WrongKey` if the passphrase is not the one the
+    /// image was formatted with.
+    pub fn unlock(&mut self, passphrase: &str) -> FsResult<()> {
+        if !self.is_encrypted() {
+            return Err(FsError::NotSupported("Image is not encrypted".to_string()));
+        }
+        if !AVAILABLE {
+            return Err(unsupported());
+        }
+        let superblock = self.superblock();
+        let (keys, check) = VolumeKeys::derive(passphrase, &superblock.kdf_salt, superblock.kdf_iterations)?;
+        if check != superblock.key_check {
+            return Err(FsError::WrongKey);
+        }
+        self.keyring_mut().keys = Some(keys);
+        Ok(())
+    }
+
+    /// True if file data on this image is encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.keyring().encrypted
+    }
+
+    /// True if encrypted files can be read and written, the image having
+    /// been unlocked or created with its passphrase
+    pub fn is_unlocked(&self) -> bool {
+        self.keyring().keys.is_some()
+    }
+
+    /// A new wrapped file key for an inode created on an encrypted image
+    pub(crate) fn new_file_key(&self) -> FsResult<[u8; WRAPPED_KEY_SIZE]> {
+        self.keyring().keys()?.new_file_key()
+    }
+
+    /// The key of an encrypted file's data, `None` if it is not encrypted
+    pub(crate) fn file_key(&self, inode: &Inode) -> FsResult<Option<FileKey>> {
+        if !inode.has_flag(Inode::FLAG_ENCRYPTED) {
+            return Ok(None);
+        }
+        match self.keyring().keys()?.unwrap_file_key(&inode.wrapped_key)? {
+            Some(key) => Ok(Some(key)),
+            None => Err(FsError::CorruptedFileSystem(format!(
+                "Key of inode {} does not unwrap",
+                inode.inode_number
+            ))),
+        }
+    }
+}
@@ -16,6 +16,14 @@ pub enum FsError {
     #[error("File not found: {0}")]
     FileNotFound(String),
 
+    /// No snapshot with the given name
+    #[error("Snapshot not found: {0}")]
+    SnapshotNotFound(String),
+
+    /// No kept version of a file with the given number
+    #[error("Version not found: {0}")]
+    VersionNotFound(String),
+
     /// Directory not found at the specified path
     #[error("Directory not found: {0}")]
     DirectoryNotFound(String),
@@ -28,6 +36,19 @@ pub enum FsError {
     #[error("Disk is full - no free blocks available")]
     DiskFull,
 
+    /// Every inode table slot is in use
+    #[error("No free inodes available")]
+    NoFreeInodes,
+
+    /// A user, group or project quota does not allow the allocation
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    /// A directory or hard-linked file cannot be moved or linked into
+    /// another project
+    #[error("Cannot cross projects: {0}")]
+    CrossesProjects(String),
+
     /// Not enough contiguous space for allocation
     #[error("Not enough contiguous space - requested {0} blocks")]
     NotEnoughContiguousSpace(u64),
@@ -76,6 +97,15 @@ pub enum FsError {
     #[error("Corrupted file system: {0}")]
     CorruptedFileSystem(String),
 
+    /// Block contents do not match their stored checksum
+    #[error("Checksum mismatch in block {block}: expected 0x{expected:08x}, found 0x{actual:08x}")]
+    ChecksumMismatch { block: u64, expected: u32, actual: u32 },
+
+    /// The passphrase of an encrypted image is wrong, or it was never
+    /// given and the operation needs the key
+    #[error("Wrong or missing encryption key")]
+    WrongKey,
+
     /// Not a directory
     #[error("Not a directory: {0}")]
     NotADirectory(String),
@@ -96,6 +126,132 @@ pub enum FsError {
 /// Result type alias for file system operations
 pub type FsResult<T> = Result<T, FsError>;
 
+impl FsError {
+    /// True for errors caused by running out of blocks or inodes, on
+    /// the disk or in a quota
+    pub fn is_out_of_space(&self) -> bool {
+        matches!(
+            self,
+            FsError::DiskFull
+                | FsError::NoFreeInodes
+                | FsError::NotEnoughContiguousSpace(_)
+                | FsError::QuotaExceeded(_)
+        )
+    }
+
+    /// Process exit code for command-line tools
+    ///
+    /// Related errors share a code so scripts can react to the kind of
+    /// failure; 0 is success and 2 is reserved for usage errors.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            FsError::Io(_) => 1,
+            FsError::FileNotFound(_)
+            | FsError::DirectoryNotFound(_)
+            | FsError::SnapshotNotFound(_)
+            | FsError::VersionNotFound(_)
+            | FsError::BlockNotFound(_) => 3,
+            FsError::AlreadyExists(_) => 4,
+            FsError::InvalidPath(_)
+            | FsError::InvalidFileName(_)
+            | FsError::NotADirectory(_)
+            | FsError::NotAFile(_)
+            | FsError::InvalidOffsetOrSize { .. } => 5,
+            FsError::DiskFull
+            | FsError::NoFreeInodes
+            | FsError::NotEnoughContiguousSpace(_)
+            | FsError::QuotaExceeded(_) => 6,
+            FsError::CorruptedFileSystem(_)
+            | FsError::ChecksumMismatch { .. }
+            | FsError::InvalidMetadata(_)
+            | FsError::InvalidBlockSize { .. }
+            | FsError::SerializationError(_)
+            | FsError::DeserializationError(_)
+            | FsError::BlockInUse(_)
+            | FsError::BlockAlreadyFree(_) => 7,
+            FsError::PermissionDenied(_) | FsError::WrongKey => 8,
+            FsError::DirectoryNotEmpty(_) => 9,
+            FsError::NotSupported(_) | FsError::CrossesProjects(_) => 10,
+        }
+    }
+
+    /// HTTP status code for servers exposing the file system
+    pub fn http_status(&self) -> u16 {
+        match self {
+            FsError::FileNotFound(_)
+            | FsError::DirectoryNotFound(_)
+            | FsError::SnapshotNotFound(_)
+            | FsError::VersionNotFound(_) => 404,
+            FsError::InvalidPath(_) | FsError::InvalidFileName(_) => 400,
+            FsError::PermissionDenied(_) | FsError::WrongKey => 403,
+            FsError::AlreadyExists(_)
+            | FsError::NotADirectory(_)
+            | FsError::NotAFile(_)
+            | FsError::DirectoryNotEmpty(_)
+            | FsError::CrossesProjects(_) => 409,
+            FsError::InvalidOffsetOrSize { .. } => 416,
+            FsError::NotSupported(_) => 501,
+            FsError::DiskFull
+            | FsError::NoFreeInodes
+            | FsError::NotEnoughContiguousSpace(_)
+            | FsError::QuotaExceeded(_) => 507,
+            FsError::Io(_)
+            | FsError::BlockNotFound(_)
+            | FsError::CorruptedFileSystem(_)
+            | FsError::ChecksumMismatch { .. }
+            | FsError::InvalidMetadata(_)
+            | FsError::InvalidBlockSize { .. }
+            | FsError::SerializationError(_)
+            | FsError::DeserializationError(_)
+            | FsError::BlockInUse(_)
+            | FsError::BlockAlreadyFree(_) => 500,
+        }
+    }
+
+    /// Closest `std::io` error kind, for `Read`/`Write`-style interfaces
+    pub fn io_kind(&self) -> io::ErrorKind {
+        match self {
+            FsError::Io(e) => e.kind(),
+            FsError::FileNotFound(_)
+            | FsError::DirectoryNotFound(_)
+            | FsError::SnapshotNotFound(_)
+            | FsError::VersionNotFound(_)
+            | FsError::BlockNotFound(_) => io::ErrorKind::NotFound,
+            FsError::AlreadyExists(_) => io::ErrorKind::AlreadyExists,
+            FsError::InvalidPath(_) | FsError::InvalidFileName(_) | FsError::InvalidOffsetOrSize { .. } => {
+                io::ErrorKind::InvalidInput
+            }
+            FsError::NotADirectory(_) => io::ErrorKind::NotADirectory,
+            FsError::NotAFile(_) => io::ErrorKind::IsADirectory,
+            FsError::DiskFull | FsError::NoFreeInodes | FsError::NotEnoughContiguousSpace(_) => {
+                io::ErrorKind::StorageFull
+            }
+            FsError::QuotaExceeded(_) => io::ErrorKind::QuotaExceeded,
+            FsError::CorruptedFileSystem(_)
+            | FsError::ChecksumMismatch { .. }
+            | FsError::InvalidMetadata(_)
+            | FsError::InvalidBlockSize { .. }
+            | FsError::SerializationError(_)
+            | FsError::DeserializationError(_)
+            | FsError::BlockInUse(_)
+            | FsError::BlockAlreadyFree(_) => io::ErrorKind::InvalidData,
+            FsError::PermissionDenied(_) | FsError::WrongKey => io::ErrorKind::PermissionDenied,
+            FsError::DirectoryNotEmpty(_) => io::ErrorKind::DirectoryNotEmpty,
+            FsError::NotSupported(_) => io::ErrorKind::Unsupported,
+            FsError::CrossesProjects(_) => io::ErrorKind::CrossesDevices,
+        }
+    }
+}
+
+impl From<FsError> for io::Error {
+    fn from(err: FsError) -> Self {
+        match err {
+            FsError::Io(e) => e,
+            err => io::Error::new(err.io_kind(), err),
+        }
+    }
+}
+
 impl From<serde_json::Error> for FsError {
     fn from(err: serde_json::Error) -> Self {
         FsError::SerializationError(err.to_string())
@@ -0,0 +1,222 @@
+use crate::error::{FsError, FsResult};
+
+/// Physical block recorded for a logical block with no data (a hole)
+///
+/// Block 0 holds the superblock, so it never stores file data. Holes read
+/// as zeros and use no space.
+pub const HOLE: u64 = 0;
+
+/// A contiguous run of file blocks
+///
+/// Maps `length` logical blocks starting at `logical` onto physical
+/// blocks starting at `physical`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub logical: u64,
+    pub physical: u64,
+    pub length: u64,
+}
+
+impl Extent {
+    /// Largest run a single extent record can describe
+    pub const MAX_LENGTH: u64 = u32::MAX as u64;
+
+    /// Build extents from a list of physical blocks in logical order
+    ///
+    /// Holes are left out, so they end up as gaps between extents.
+    pub fn from_blocks(blocks: &[u64]) -> Vec<Extent> {
+        let mut extents: Vec<Extent> = Vec::new();
+        for (logical, &physical) in blocks.iter().enumerate() {
+            if physical == HOLE {
+                continue;
+            }
+            match extents.last_mut() {
+                Some(last)
+                    if last.physical + last.length == physical
+                        && last.logical + last.length == logical as u64
+                        && last.length < Self::MAX_LENGTH =>
+                {
+                    last.length += 1;
+                }
+                _ => extents.push(Extent {
+                    logical: logical as u64,
+                    physical,
+                    length: 1,
+                }),
+            }
+        }
+        extents
+    }
+
+    /// Expand extents back into the list of physical blocks
+    ///
+    /// Gaps between extents become holes. Extents reaching past `limit`
+    /// logical blocks are rejected, so a corrupt extent cannot make the
+    /// list arbitrarily long.
+    pub fn to_blocks(extents: &[Extent], limit: u64) -> FsResult<Vec<u64>> {
+        let mut blocks = Vec::new();
+        for extent in extents {
+            let end = extent.logical.checked_add(extent.length);
+            if end.is_none_or(|end| end > limit) || extent.physical.checked_add(extent.length).is_none() {
+                return Err(FsError::CorruptedFileSystem(format!(
+                    "Extent of {} blocks at logical block {} is past the end of a {} block file",
+                    extent.length, extent.logical, limit
+                )));
+            }
+            if (blocks.len() as u64) < extent.logical {
+                blocks.resize(extent.logical as usize, HOLE);
+            }
+            blocks.extend(extent.physical..extent.physical + extent.length);
+        }
+        Ok(blocks)
+    }
+}
+
+/// How a file's data is laid out on disk
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileMapping {
+    /// Data blocks as contiguous runs, in logical order
+    pub extents: Vec<Extent>,
+    /// Blocks holding mapping metadata (indirect blocks or extent tree nodes)
+    pub metadata_blocks: Vec<u64>,
+}
+
+/// One entry of an extent tree node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtentEntry {
+    /// Leaf entry: a run of data blocks
+    Leaf(Extent),
+    /// Index entry: the child node covering logical blocks from `logical`
+    Index { logical: u64, child: u64 },
+}
+
+/// Extent tree node
+///
+/// The root node lives in the inode's block pointer area (120 bytes);
+/// deeper nodes occupy a whole block each.
+///
+/// Layout:
+/// - Header: 8 bytes
+///   - Magic number: 2 bytes
+///   - Entry count: 2 bytes
+///   - Maximum entries: 2 bytes
+///   - Depth (0 = leaf): 2 bytes
+/// - Entries: 16 bytes each
+///   - Leaf: logical start (4), length (4), physical start (8)
+///   - Index: logical start (4), reserved (4), child block (8)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtentNode {
+    pub depth: u16,
+    pub entries: Vec<ExtentEntry>,
+}
+
+impl ExtentNode {
+    const MAGIC: u16 = 0xF30A;
+    const HEADER_SIZE: usize = 8;
+    const ENTRY_SIZE: usize = 16;
+
+    /// Deepest tree a file can need: even with 1 KiB blocks, five levels
+    /// below the root cover every 32-bit logical block
+    pub const MAX_DEPTH: u16 = 5;
+
+    /// Maximum entries in a node stored in `size` bytes
+    pub fn capacity(size: usize) -> usize {
+        (size - Self::HEADER_SIZE) / Self::ENTRY_SIZE
+    }
+
+    /// Serialize node into a buffer of `size` bytes
+    pub fn to_bytes(&self, size: usize) -> FsResult<Vec<u8>> {
+        let capacity = Self::capacity(size);
+        if self.entries.len() > capacity {
+            return Err(FsError::SerializationError(format!(
+                "Extent node has {} entries, but only {} fit",
+                self.entries.len(),
+                capacity
+            )));
+        }
+
+        let mut bytes = vec![0u8; size];
+        bytes[0..2].copy_from_slice(&Self::MAGIC.to_le_bytes());
+        bytes[2..4].copy_from_slice(&(self.entries.len() as u16).to_le_bytes());
+        bytes[4..6].copy_from_slice(&(capacity as u16).to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.depth.to_le_bytes());
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            let offset = Self::HEADER_SIZE + i * Self::ENTRY_SIZE;
+            let (logical, middle, pointer) = match *entry {
+                ExtentEntry::Leaf(extent) => (extent.logical, extent.length, extent.physical),
+                ExtentEntry::Index { logical, child } => (logical, 0, child),
+            };
+            if logical > u64::from(u32::MAX) {
+                return Err(FsError::SerializationError(format!(
+                    "Logical block {} does not fit in an extent entry",
+                    logical
+                )));
+            }
+            bytes[offset..offset + 4].copy_from_slice(&(logical as u32).to_le_bytes());
+            bytes[offset + 4..offset + 8].copy_from_slice(&(middle as u32).to_le_bytes());
+            bytes[offset + 8..offset + 16].copy_from_slice(&pointer.to_le_bytes());
+        }
+
+        Ok(bytes)
+    }
+
+    /// Deserialize node from binary format
+    pub fn from_bytes(bytes: &[u8]) -> FsResult<Self> {
+        if bytes.len() < Self::HEADER_SIZE {
+            return Err(FsError::InvalidMetadata(format!(
+                "Extent node data too short: {} bytes",
+                bytes.len()
+            )));
+        }
+
+        let magic = u16::from_le_bytes([bytes[0], bytes[1]]);
+        if magic != Self::MAGIC {
+            return Err(FsError::CorruptedFileSystem(format!(
+                "Invalid extent node magic number: 0x{:04X}",
+                magic
+            )));
+        }
+
+        let count = u16::from_le_bytes([bytes[2], bytes[3]]) as usize;
+        let depth = u16::from_le_bytes([bytes[6], bytes[7]]);
+        if depth > Self::MAX_DEPTH {
+            return Err(FsError::CorruptedFileSystem(format!(
+                "Extent node claims depth {}",
+                depth
+            )));
+        }
+        if count > Self::capacity(bytes.len()) {
+            return Err(FsError::CorruptedFileSystem(format!(
+                "Extent node claims {} entries",
+                count
+            )));
+        }
+
+        let mut entries = Vec::with_capacity(count);
+        for i in 0..count {
+            let offset = Self::HEADER_SIZE + i * Self::ENTRY_SIZE;
+            let field = |start: usize, len: usize| {
+                let mut buf = [0u8; 8];
+                buf[..len].copy_from_slice(&bytes[offset + start..offset + start + len]);
+                u64::from_le_bytes(buf)
+            };
+            let logical = field(0, 4);
+            let pointer = field(8, 8);
+            entries.push(if depth == 0 {
+                ExtentEntry::Leaf(Extent {
+                    logical,
+                    physical: pointer,
+                    length: field(4, 4),
+                })
+            } else {
+                ExtentEntry::Index {
+                    logical,
+                    child: pointer,
+                }
+            });
+        }
+
+        Ok(ExtentNode { depth, entries })
+    }
+}
@@ -0,0 +1,172 @@
+use crate::device::BlockDevice;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Faults to inject, keyed by block number
+#[derive(Debug, Default)]
+struct Faults {
+    /// Bits flipped in every read of a block, numbered from its first byte
+    flipped: HashMap<u64, Vec<u64>>,
+    /// Blocks whose writes are silently thrown away
+    dropped: HashSet<u64>,
+    /// Blocks where only the first bytes of each write land
+    torn: HashMap<u64, u64>,
+    /// Blocks that fail every read and write
+    failing: HashSet<u64>,
+    /// Operations left before every operation fails
+    remaining: Option<u64>,
+    /// Reads, writes and syncs seen so far
+    operations: u64,
+}
+
+/// A block device that injects faults into another one
+///
+/// Meant for testing how the file system handles bad storage: reads can
+/// come back with flipped bits, writes can be dropped or torn part way
+/// through a block, chosen blocks can fail with I/O errors, and the whole
+/// device can fail after a number of operations. Clones share their
+/// faults, so one clone can be handed to `VirtualDisk::open_device` and
+/// the other kept to change faults while the disk is in use.
+#[derive(Debug, Clone)]
+pub struct FaultyDevice {
+    inner: Arc<dyn BlockDevice>,
+    block_size: u64,
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl FaultyDevice {
+    /// Wrap `inner`, addressing faults in blocks of `block_size` bytes
+    pub fn new(inner: impl BlockDevice + 'static, block_size: u64) -> Self {
+        FaultyDevice {
+            inner: Arc::new(inner),
+            block_size,
+            faults: Arc::default(),
+        }
+    }
+
+    fn faults(&self) -> MutexGuard<'_, Faults> {
+        self.faults.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Flip bit `bit` of `block` whenever the block is read
+    ///
+    /// The data on the inner device is left alone.
+    pub fn flip_bit(&self, block: u64, bit: u64) {
+        self.faults().flipped.entry(block).or_default().push(bit % (self.block_size * 8));
+    }
+
+    /// Report writes to `block` as done without writing anything
+    pub fn drop_writes(&self, block: u64) {
+        self.faults().dropped.insert(block);
+    }
+
+    /// Write only the first `bytes` bytes of `block`, as if power was
+    /// lost part way through each write to it
+    pub fn tear_writes(&self, block: u64, bytes: u64) {
+        self.faults().torn.insert(block, bytes);
+    }
+
+    /// Fail every read and write touching `block`
+    pub fn fail_block(&self, block: u64) {
+        self.faults().failing.insert(block);
+    }
+
+    /// Let `operations` more reads, writes and syncs through, then fail
+    /// all of them
+    pub fn fail_after(&self, operations: u64) {
+        self.faults().remaining = Some(operations);
+    }
+
+    /// Reads, writes and syncs done so far, including failed ones
+    pub fn operations(&self) -> u64 {
+        self.faults().operations
+    }
+
+    /// Remove all faults
+    pub fn clear(&self) {
+        let mut faults = self.faults();
+        let operations = faults.operations;
+        *faults = Faults { operations, ..Faults::default() };
+    }
+
+    /// Blocks covered by `len` bytes at `offset`
+    fn blocks(&self, offset: u64, len: usize) -> Range<u64> {
+        let end = offset + len as u64;
+        offset / self.block_size..end.div_ceil(self.block_size)
+    }
+
+    /// Count an operation on `blocks` and fail it if a fault says so
+    fn operation(&self, mut blocks: Range<u64>) -> io::Result<MutexGuard<'_, Faults>> {
+        let mut faults = self.faults();
+        faults.operations += 1;
+        match faults.remaining {
+            Some(0) => return Err(io::Error::other("injected device failure")),
+            Some(ref mut remaining) => *remaining -= 1,
+            None => {}
+        }
+        if let Some(block) = blocks.find(|block| faults.failing.contains(block)) {
+            return Err(io::Error::other(format!("injected I/O error in block {}", block)));
+        }
+        Ok(faults)
+    }
+}
+
+impl BlockDevice for FaultyDevice {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let faults = self.operation(self.blocks(offset, buf.len()))?;
+        self.inner.read_exact_at(buf, offset)?;
+
+        let end = offset + buf.len() as u64;
+        for (&block, bits) in &faults.flipped {
+            for &bit in bits {
+                let byte = block * self.block_size + bit / 8;
+                if (offset..end).contains(&byte) {
+                    buf[(byte - offset) as usize] ^= 1 << (bit % 8);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        let faults = self.operation(self.blocks(offset, data.len()))?;
+        let end = offset + data.len() as u64;
+        let mut blocks = self.blocks(offset, data.len());
+        if !blocks.any(|block| faults.dropped.contains(&block) || faults.torn.contains_key(&block)) {
+            return self.inner.write_all_at(data, offset);
+        }
+
+        // Write block by block, so faults only affect their own block
+        for block in self.blocks(offset, data.len()) {
+            if faults.dropped.contains(&block) {
+                continue;
+            }
+            let block_start = block * self.block_size;
+            let start = offset.max(block_start);
+            let mut stop = end.min(block_start + self.block_size);
+            if let Some(&bytes) = faults.torn.get(&block) {
+                stop = stop.min(block_start + bytes);
+            }
+            if start < stop {
+                let piece = &data[(start - offset) as usize..(stop - offset) as usize];
+                self.inner.write_all_at(piece, start)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.inner.size()
+    }
+
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        self.inner.set_size(size)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        drop(self.operation(0..0)?);
+        self.inner.sync()
+    }
+}
@@ -0,0 +1,541 @@
+use crate::{
+    checksum::BlockClass,
+    error::{FsError, FsResult},
+    extent::HOLE,
+    serialization::{FileType, Inode, INLINE_DATA_SIZE},
+    virtual_disk::VirtualDisk,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Kind of inconsistency found by `VirtualDisk::fsck`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsckIssueKind {
+    /// Inode table slot marked used but not holding a valid inode
+    BadInode,
+    /// Block mapping (indirect blocks or extent tree) cannot be read
+    BadMapping,
+    /// Inode points at a block outside the data area
+    InvalidBlock,
+    /// Block referenced by more than one inode, or twice by one, more
+    /// often than its reference count allows
+    DuplicateBlock,
+    /// Block referenced by an inode but free in the allocator
+    UnallocatedBlock,
+    /// Directory entry pointing at a missing inode or with the wrong type
+    BadEntry,
+    /// Directory reachable through more than one entry
+    DirectoryLoop,
+    /// Used inode not reachable from the root directory
+    OrphanInode,
+    /// Used block not referenced by any inode
+    LeakedBlock,
+    /// Size, link count, reference count, group counter or quota usage
+    /// disagrees with the actual state
+    CountMismatch,
+    /// Block contents do not match the stored checksum
+    ChecksumMismatch,
+}
+
+impl FsckIssueKind {
+    /// Whether data can be lost or corrupted by this issue; everything
+    /// else only wastes space or skews statistics
+    pub fn is_error(self) -> bool {
+        !matches!(
+            self,
+            FsckIssueKind::OrphanInode | FsckIssueKind::LeakedBlock | FsckIssueKind::CountMismatch
+        )
+    }
+}
+
+/// A single inconsistency
+#[derive(Debug, Clone)]
+pub struct FsckIssue {
+    pub kind: FsckIssueKind,
+    pub message: String,
+}
+
+/// Result of a consistency check
+#[derive(Debug, Clone, Default)]
+pub struct FsckReport {
+    pub files: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    /// Data blocks referenced by inodes
+    pub data_blocks: u64,
+    /// Indirect blocks and extent tree nodes
+    pub mapping_blocks: u64,
+    pub issues: Vec<FsckIssue>,
+}
+
+impl FsckReport {
+    /// Number of issues that can lose or corrupt data
+    pub fn errors(&self) -> usize {
+        self.issues.iter().filter(|i| i.kind.is_error()).count()
+    }
+
+    /// Number of issues that only waste space or skew statistics
+    pub fn warnings(&self) -> usize {
+        self.issues.len() - self.errors()
+    }
+
+    /// True if no issues were found
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    fn report(&mut self, kind: FsckIssueKind, message: String) {
+        self.issues.push(FsckIssue { kind, message });
+    }
+
+    /// Report `error`, as a checksum mismatch if it is one and as `kind`
+    /// otherwise
+    fn report_error(&mut self, kind: FsckIssueKind, context: String, error: &FsError) {
+        let kind = match error {
+            FsError::ChecksumMismatch { .. } => FsckIssueKind::ChecksumMismatch,
+            _ => kind,
+        };
+        self.report(kind, format!("{}: {}", context, error));
+    }
+}
+
+impl VirtualDisk {
+    /// Check the consistency of the file system without modifying it
+    ///
+    /// Verifies that every used inode and its block mapping can be read,
+    /// that each block is owned at most once and is marked used, that the
+    /// directory tree only refers to valid inodes, and that the allocator
+    /// and group descriptors agree with what the inodes actually use.
+    /// On images with checksums, every metadata block in use (and every
+    /// data block, if data is checksummed) is verified as well.
+    /// Directory entries are expected to store the inode block, as the
+    /// path API does.
+    pub fn fsck(&mut self) -> FsResult<FsckReport> {
+        let mut report = FsckReport::default();
+        let superblock = self.block_groups().superblock().clone();
+        let total = superblock.total_blocks;
+
+        // Blocks that belong to the file system layout itself
+        let mut reserved = vec![false; total as usize];
+        let mut reserve = |start: u64, count: u64| {
+            for block in start..(start + count).min(total) {
+                reserved[block as usize] = true;
+            }
+        };
+        reserve(0, 1);
+        reserve(superblock.block_bitmap_start, superblock.block_bitmap_blocks);
+        reserve(superblock.inode_bitmap_start, superblock.inode_bitmap_blocks);
+        reserve(superblock.checksum_table_start, superblock.checksum_table_blocks);
+        for group in &superblock.groups {
+            reserve(group.inode_table_start, group.inode_count);
+        }
+        let tables = self.refcount_table_blocks().into_iter().chain(self.dedup_table_blocks());
+        for block in self.snapshot_blocks().into_iter().chain(tables) {
+            reserve(block, 1);
+        }
+        for (block, _) in reserved.iter().enumerate().filter(|(_, &r)| r) {
+            if !self.is_block_used(block as u64) {
+                report.report(
+                    FsckIssueKind::UnallocatedBlock,
+                    format!("Metadata block {} is free in the allocator", block),
+                );
+            }
+        }
+
+        // The superblock and bitmaps before the checksum table
+        if self.has_checksums(BlockClass::Metadata) {
+            for block in 0..superblock.checksum_table_start {
+                match self.verify_block(block, BlockClass::Metadata) {
+                    Err(e @ FsError::ChecksumMismatch { .. }) => {
+                        report.report(FsckIssueKind::ChecksumMismatch, e.to_string())
+                    }
+                    result => result?,
+                }
+            }
+        }
+
+        // Check every used inode and claim its blocks
+        let mut owners: HashMap<u64, u64> = HashMap::new();
+        let mut references: HashMap<u64, u64> = HashMap::new();
+        let mut inodes: HashMap<u64, Inode> = HashMap::new();
+        for inode_block in self.block_groups().used_inodes() {
+            let inode = match self.read_inode(inode_block) {
+                Ok(inode) => inode,
+                Err(e) => {
+                    report.report_error(FsckIssueKind::BadInode, format!("Inode {}", inode_block), &e);
+                    continue;
+                }
+            };
+            match inode.file_type {
+                FileType::File => report.files += 1,
+                FileType::Directory => report.directories += 1,
+                FileType::Symlink => report.symlinks += 1,
+            }
+
+            let (data, metadata) = match self.walk_mapping(&inode) {
+                Ok(mapping) => mapping,
+                Err(e) => {
+                    report.report_error(FsckIssueKind::BadMapping, format!("Inode {}", inode_block), &e);
+                    inodes.insert(inode_block, inode);
+                    continue;
+                }
+            };
+            let mapped: Vec<u64> = data.iter().copied().filter(|&b| b != HOLE).collect();
+            report.data_blocks += mapped.len() as u64;
+            report.mapping_blocks += metadata.len() as u64;
+
+            for block in mapped.iter().chain(&metadata).copied() {
+                if block >= total || reserved[block as usize] {
+                    report.report(
+                        FsckIssueKind::InvalidBlock,
+                        format!("Inode {} points at block {} outside the data area", inode_block, block),
+                    );
+                    continue;
+                }
+                // Cloned files share blocks, as many times as recorded
+                let seen = references.entry(block).or_insert(0);
+                *seen += 1;
+                let shared = *seen <= self.block_references(block);
+                if let Some(other) = owners.insert(block, inode_block).filter(|_| !shared) {
+                    report.report(
+                        FsckIssueKind::DuplicateBlock,
+                        format!("Block {} is used by inodes {} and {}", block, other, inode_block),
+                    );
+                }
+                if !self.is_block_used(block) {
+                    report.report(
+                        FsckIssueKind::UnallocatedBlock,
+                        format!("Block {} of inode {} is free in the allocator", block, inode_block),
+                    );
+                }
+            }
+
+            let class = match inode.file_type {
+                FileType::Directory => BlockClass::Metadata,
+                _ => BlockClass::Data,
+            };
+            for &block in mapped.iter().filter(|&&b| b < total && !reserved[b as usize]) {
+                match self.verify_block(block, class) {
+                    Err(e @ FsError::ChecksumMismatch { .. }) => report.report(
+                        FsckIssueKind::ChecksumMismatch,
+                        format!("Inode {}: {}", inode_block, e),
+                    ),
+                    result => result?,
+                }
+            }
+
+            if inode.is_inline() && (!data.is_empty() || inode.size > INLINE_DATA_SIZE as u64) {
+                report.report(
+                    FsckIssueKind::CountMismatch,
+                    format!(
+                        "Inode {} is stored inline with size {} and {} logical blocks",
+                        inode_block,
+                        inode.size,
+                        data.len()
+                    ),
+                );
+            } else if inode.file_type != FileType::Directory
+                && !inode.is_inline()
+                && inode.size.div_ceil(self.block_size()) != data.len() as u64
+            {
+                report.report(
+                    FsckIssueKind::CountMismatch,
+                    format!(
+                        "Inode {} has size {} but {} logical blocks",
+                        inode_block,
+                        inode.size,
+                        data.len()
+                    ),
+                );
+            }
+            inodes.insert(inode_block, inode);
+        }
+
+        for (block, refs) in self.shared_blocks() {
+            let found = references.get(&block).copied().unwrap_or(0);
+            if found < refs {
+                report.report(
+                    FsckIssueKind::CountMismatch,
+                    format!("Block {} has {} references recorded but {} found", block, refs, found),
+                );
+            }
+        }
+
+        self.check_tree(&inodes, &mut report)?;
+
+        // Used blocks nobody owns, reported as runs
+        let mut leak_start = None;
+        for block in 0..=total {
+            let leaked = block < total
+                && !reserved[block as usize]
+                && self.is_block_used(block)
+                && !owners.contains_key(&block)
+                && !self.allocator().is_padding(block);
+            match (leaked, leak_start) {
+                (true, None) => leak_start = Some(block),
+                (false, Some(start)) => {
+                    let blocks = if start == block - 1 {
+                        format!("Block {} is", start)
+                    } else {
+                        format!("Blocks {}-{} are", start, block - 1)
+                    };
+                    report.report(
+                        FsckIssueKind::LeakedBlock,
+                        format!("{} used but not owned by any inode", blocks),
+                    );
+                    leak_start = None;
+                }
+                _ => {}
+            }
+        }
+
+        self.check_group_counters(&inodes, &mut report);
+        self.check_quota_usage(&mut report)?;
+        Ok(report)
+    }
+
+    /// Walk the directory tree from the root, checking entries and links
+    fn check_tree(&mut self, inodes: &HashMap<u64, Inode>, report: &mut FsckReport) -> FsResult<()> {
+        let Some(root) = self.root_directory() else {
+            if !inodes.is_empty() {
+                report.report(
+                    FsckIssueKind::OrphanInode,
+                    format!("No root directory; {} inodes are unreachable", inodes.len()),
+                );
+            }
+            return Ok(());
+        };
+
+        let mut links: HashMap<u64, u64> = HashMap::new();
+        let mut visited = HashSet::from([root]);
+        let mut queue = VecDeque::from([(root, "/".to_string())]);
+
+        while let Some((dir, path)) = queue.pop_front() {
+            if inodes.get(&dir).map(|i| i.file_type) != Some(FileType::Directory) {
+                report.report(FsckIssueKind::BadEntry, format!("{} is not a valid directory", path));
+                continue;
+            }
+            let entries = match self.list_directory(dir) {
+                Ok(entries) => entries,
+                // Already reported with the directory's blocks
+                Err(FsError::ChecksumMismatch { .. }) => continue,
+                Err(e) => {
+                    report.report(FsckIssueKind::BadEntry, format!("{}: {}", path, e));
+                    continue;
+                }
+            };
+
+            for entry in entries {
+                let child_path = format!("{}/{}", path.trim_end_matches('/'), entry.name);
+                let Some(child) = inodes.get(&entry.inode_number) else {
+                    report.report(
+                        FsckIssueKind::BadEntry,
+                        format!("{} points at unused inode {}", child_path, entry.inode_number),
+                    );
+                    continue;
+                };
+                if child.file_type != entry.file_type {
+                    report.report(
+                        FsckIssueKind::BadEntry,
+                        format!(
+                            "{} is listed as {:?} but inode {} is {:?}",
+                            child_path, entry.file_type, entry.inode_number, child.file_type
+                        ),
+                    );
+                    continue;
+                }
+
+                *links.entry(entry.inode_number).or_default() += 1;
+                if child.file_type == FileType::Directory {
+                    if visited.insert(entry.inode_number) {
+                        queue.push_back((entry.inode_number, child_path));
+                    } else {
+                        report.report(
+                            FsckIssueKind::DirectoryLoop,
+                            format!("Directory {} is linked more than once", child_path),
+                        );
+                    }
+                }
+            }
+        }
+
+        // Quota files have no name
+        let mut orphans: Vec<_> = inodes
+            .keys()
+            .filter(|&&i| i != root && !links.contains_key(&i) && !self.is_quota_inode(i))
+            .collect();
+        orphans.sort_unstable();
+        for inode_block in orphans {
+            report.report(
+                FsckIssueKind::OrphanInode,
+                format!("Inode {} is not reachable from the root directory", inode_block),
+            );
+        }
+
+        for (&inode_block, &count) in &links {
+            let recorded = u64::from(inodes[&inode_block].link_count);
+            if recorded != count {
+                report.report(
+                    FsckIssueKind::CountMismatch,
+                    format!("Inode {} has link count {} but {} entries", inode_block, recorded, count),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Compare the usage quotas follow with a count from the inodes
+    fn check_quota_usage(&self, report: &mut FsckReport) -> FsResult<()> {
+        if !self.is_quota_enabled() {
+            return Ok(());
+        }
+        let followed = self.with_quotas(|quotas| quotas.usage());
+        let counted = self.count_quota_usage()?;
+        let mut owners: Vec<_> = followed.keys().chain(counted.keys()).copied().collect();
+        owners.sort_unstable();
+        owners.dedup();
+        for (kind, id) in owners {
+            let [blocks, inodes] = followed.get(&(kind, id)).copied().unwrap_or_default();
+            let [actual_blocks, actual_inodes] = counted.get(&(kind, id)).copied().unwrap_or_default();
+            if (blocks, inodes) != (actual_blocks, actual_inodes) {
+                report.report(
+                    FsckIssueKind::CountMismatch,
+                    format!(
+                        "Quota of {} {} records {} blocks and {} inodes, actual {} and {}",
+                        kind.name(),
+                        id,
+                        blocks,
+                        inodes,
+                        actual_blocks,
+                        actual_inodes
+                    ),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Compare group descriptor counters with the allocator and inode bitmap
+    fn check_group_counters(&mut self, inodes: &HashMap<u64, Inode>, report: &mut FsckReport) {
+        let (groups, allocator) = self.groups_and_allocator();
+        for index in 0..groups.group_count() {
+            let group = groups.descriptor(index);
+            // Descriptors count rounding padding as free, since no inode uses it
+            let padding = (group.start_block..group.end_block())
+                .filter(|&b| allocator.is_padding(b))
+                .count() as u64;
+            let free_blocks = allocator.count_free_in_range(group.start_block, group.end_block()) + padding;
+            let table = group.inode_table_start..group.data_start();
+            let used_inodes = table.clone().filter(|&b| groups.is_inode_used(b)).count() as u64;
+            let directories = table
+                .filter(|b| inodes.get(b).map(|i| i.file_type) == Some(FileType::Directory))
+                .count() as u64;
+
+            let counters = [
+                ("free blocks", group.free_blocks, free_blocks),
+                ("free inodes", group.free_inodes, group.inode_count - used_inodes),
+                ("directories", group.directories, directories),
+            ];
+            for (name, recorded, actual) in counters {
+                if recorded != actual {
+                    report.report(
+                        FsckIssueKind::CountMismatch,
+                        format!("Group {} records {} {}, actual {}", index, recorded, name, actual),
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{checksum::crc32c, serialization::Permissions, virtual_disk::FormatOptions};
+
+    /// An image with data checksums holding `/docs/report.txt` over two
+    /// data blocks, with nothing stored inline
+    fn checksummed_disk(name: &str) -> (VirtualDisk, String) {
+        let path = std::env::temp_dir().join(format!("fssim-unit-fsck-{}-{}.img", name, std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let options = FormatOptions {
+            size: 8 * 1024 * 1024,
+            data_checksums: true,
+            inline_data: false,
+            ..FormatOptions::default()
+        };
+        let disk = VirtualDisk::format(&path, options).unwrap();
+        disk.initialize_root_dir().unwrap();
+        disk.create_directory_at("/docs", Permissions::new(true, true, true)).unwrap();
+        disk.write_file_at("/docs/report.txt", &[0x5A; 6000]).unwrap();
+        (disk, path)
+    }
+
+    /// Flip a byte of `block` behind the disk's back; returns the error
+    /// verifying it must give
+    fn corrupt(disk: &VirtualDisk, block: u64) -> FsError {
+        let mut contents = vec![0u8; disk.block_size() as usize];
+        disk.read_raw(block * disk.block_size(), &mut contents).unwrap();
+        let expected = crc32c(&contents);
+        contents[100] ^= 0x01;
+        disk.write_raw(block * disk.block_size(), &contents).unwrap();
+        FsError::ChecksumMismatch { block, expected, actual: crc32c(&contents) }
+    }
+
+    /// Check that fsck reports exactly one checksum mismatch, for `error`
+    fn assert_reported(disk: &mut VirtualDisk, error: &FsError) {
+        let report = disk.fsck().unwrap();
+        let mismatches: Vec<&FsckIssue> = report
+            .issues
+            .iter()
+            .filter(|i| i.kind == FsckIssueKind::ChecksumMismatch)
+            .collect();
+        assert_eq!(mismatches.len(), 1, "{:?}", report.issues);
+        assert!(mismatches[0].message.contains(&error.to_string()), "{}", mismatches[0].message);
+        assert!(report.errors() >= 1);
+    }
+
+    #[test]
+    fn clean_image_has_no_mismatches() {
+        let (mut disk, path) = checksummed_disk("clean");
+        assert!(disk.fsck().unwrap().is_clean());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn reports_corrupted_inode() {
+        let (mut disk, path) = checksummed_disk("inode");
+        let inode_block = disk.lookup_path("/docs/report.txt").unwrap();
+        let error = corrupt(&disk, inode_block);
+        assert_reported(&mut disk, &error);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn reports_corrupted_directory_block() {
+        let (mut disk, path) = checksummed_disk("directory");
+        let dir = disk.lookup_path("/docs").unwrap();
+        let entries = disk.file_blocks(&disk.read_inode(dir).unwrap()).unwrap()[0];
+        let error = corrupt(&disk, entries);
+        assert_reported(&mut disk, &error);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn reports_corrupted_bitmap() {
+        let (mut disk, path) = checksummed_disk("bitmap");
+        let bitmap = disk.block_groups().superblock().block_bitmap_start;
+        let error = corrupt(&disk, bitmap);
+        assert_reported(&mut disk, &error);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn reports_corrupted_data_block() {
+        let (mut disk, path) = checksummed_disk("data");
+        let file = disk.lookup_path("/docs/report.txt").unwrap();
+        let data = disk.file_blocks(&disk.read_inode(file).unwrap()).unwrap()[1];
+        let error = corrupt(&disk, data);
+        assert_reported(&mut disk, &error);
+        let _ = std::fs::remove_file(path);
+    }
+}
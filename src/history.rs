@@ -0,0 +1,529 @@
+use crate::{
+    error::{FsError, FsResult},
+    path::{components, join, split_parent},
+    serialization::{DirectoryEntry, FileType, Inode, Permissions},
+    virtual_disk::VirtualDisk,
+};
+use std::sync::{Condvar, Mutex, PoisonError};
+use std::thread::{self, ThreadId};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Directory holding old versions, one subdirectory per file
+pub const VERSIONS_DIR: &str = "/.versions";
+
+/// Directory holding deleted files, one numbered subdirectory each
+pub const TRASH_DIR: &str = "/.trash";
+
+/// Symlink in a trash entry pointing at the path the file was deleted from
+const TRASH_ORIGIN: &str = "path";
+
+/// Name of the deleted file or symlink in a trash entry
+const TRASH_FILE: &str = "file";
+
+/// How much history an image keeps of overwritten and deleted files
+///
+/// Stored in the superblock. While `versions` is 0, the default, files
+/// are overwritten and deleted outright. Otherwise replacing the whole
+/// contents of a file keeps the old contents as a version, and removing
+/// the last name of a file or symlink moves it to the trash.
+/// History beyond the limits is dropped oldest first, and so is any
+/// history in the way of an operation that would otherwise run out of
+/// space.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HistoryPolicy {
+    /// Versions kept per file; 0 keeps no history at all
+    pub versions: u64,
+    /// Seconds versions and deleted files are kept for, if limited
+    pub max_age: Option<u64>,
+    /// Blocks versions and deleted files may take together, if limited
+    pub max_blocks: Option<u64>,
+}
+
+impl HistoryPolicy {
+    /// True if overwritten and deleted files are kept
+    pub fn is_enabled(&self) -> bool {
+        self.versions > 0
+    }
+}
+
+/// An old version of a file, as listed by `VirtualDisk::list_versions`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionInfo {
+    /// Number of the version, counting up from 1 for each file
+    pub number: u64,
+    pub size: u64,
+    /// When the contents were last modified, in seconds since the Unix epoch
+    pub modified: u64,
+    /// When they were replaced, in seconds since the Unix epoch
+    pub replaced: u64,
+}
+
+/// A deleted file, as listed by `VirtualDisk::list_trash`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrashEntry {
+    /// Number of the entry's directory in `TRASH_DIR`
+    pub id: u64,
+    /// Absolute path the file was deleted from
+    pub path: String,
+    pub file_type: FileType,
+    pub size: u64,
+    /// When the file was deleted, in seconds since the Unix epoch
+    pub deleted: u64,
+}
+
+/// A version or trash entry, as far as pruning is concerned
+struct HistoryItem {
+    /// Path of the version file or of the trash entry's directory
+    path: String,
+    /// When it became history
+    since: u64,
+    /// Data and mapping blocks it holds
+    blocks: u64,
+}
+
+/// Lock held while versions or the trash change
+///
+/// Any write or removal may change them, so threads sharing a disk take
+/// turns. The thread holding the lock may take it again: dropping
+/// history removes files, which drops their versions in turn.
+#[derive(Debug, Default)]
+pub(crate) struct HistoryLock {
+    /// The thread holding the lock, and how many times it took it
+    owner: Mutex<Option<(ThreadId, usize)>>,
+    released: Condvar,
+}
+
+impl HistoryLock {
+    pub(crate) fn lock(&self) -> HistoryGuard<'_> {
+        let me = thread::current().id();
+        let mut owner = self.owner.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            match owner.as_mut() {
+                Some((thread, depth)) if *thread == me => *depth += 1,
+                Some(_) => {
+                    owner = self.released.wait(owner).unwrap_or_else(PoisonError::into_inner);
+                    continue;
+                }
+                None => *owner = Some((me, 1)),
+            }
+            return HistoryGuard { lock: self };
+        }
+    }
+}
+
+/// Guard returned by `HistoryLock::lock`
+pub(crate) struct HistoryGuard<'a> {
+    lock: &'a HistoryLock,
+}
+
+impl Drop for HistoryGuard<'_> {
+    fn drop(&mut self) {
+        let mut owner = self.lock.owner.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((_, depth)) = owner.as_mut() {
+            *depth -= 1;
+            if *depth == 0 {
+                *owner = None;
+                self.lock.released.notify_one();
+            }
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Numbered entries of a history directory, in ascending order
+fn numbered(entries: Vec<DirectoryEntry>) -> Vec<u64> {
+    let mut numbers: Vec<u64> = entries.iter().filter_map(|e| e.name.parse().ok()).collect();
+    numbers.sort_unstable();
+    numbers
+}
+
+impl VirtualDisk {
+    // ==================== HISTORY ====================
+    //
+    // Versions and deleted files are ordinary files in hidden directories,
+    // so fsck, scrub and snapshots handle them like any other. The
+    // versions of a file live in `VERSIONS_DIR/<inode block>-<created>`:
+    // the creation time tells the file apart from a later one reusing its
+    // inode block. A trash entry is a directory holding the deleted file
+    // as `file` and a symlink `path` to where it was deleted from. Both
+    // carry `Inode::FLAG_HISTORY`, so they are never kept as history
+    // themselves.
+
+    /// The history policy recorded in the superblock
+    pub fn history_policy(&self) -> HistoryPolicy {
+        let superblock = self.superblock();
+        HistoryPolicy {
+            versions: superblock.history_versions,
+            max_age: Some(superblock.history_max_age).filter(|&age| age > 0),
+            max_blocks: Some(superblock.history_max_blocks).filter(|&blocks| blocks > 0),
+        }
+    }
+
+    /// Change the history policy, dropping history beyond the new limits
+    ///
+    /// Setting `versions` to 0 drops all versions and empties the trash.
+    pub fn set_history_policy(&mut self, policy: HistoryPolicy) -> FsResult<()> {
+        self.block_groups_mut().set_history_policy(
+            policy.versions,
+            policy.max_age.unwrap_or(0),
+            policy.max_blocks.unwrap_or(0),
+        );
+        self.sync_bitmap()?;
+        self.prune_history()
+    }
+
+    /// Old versions of the file at `path`, oldest first
+    pub fn list_versions(&self, path: &str) -> FsResult<Vec<VersionInfo>> {
+        let inode_block = self.lookup_path(path)?;
+        let dir = self.versions_dir(inode_block)?;
+        let mut versions = Vec::new();
+        for number in self.version_numbers(&dir)? {
+            let inode = self.stat_path(&join(&dir, &number.to_string()))?;
+            versions.push(VersionInfo {
+                number,
+                size: inode.size,
+                modified: inode.modified,
+                replaced: inode.created,
+            });
+        }
+        Ok(versions)
+    }
+
+    /// Read version `number` of the file at `path`
+    pub fn read_version(&self, path: &str, number: u64) -> FsResult<Vec<u8>> {
+        let version = self.version_inode(path, number)?;
+        self.read_file(version)
+    }
+
+    /// Bring back version `number` of the file at `path`
+    ///
+    /// The version is cloned into the file, so they share blocks. What
+    /// the file held until now is kept as its newest version, and the
+    /// restored version is kept as well.
+    pub fn restore_version(&self, path: &str, number: u64) -> FsResult<()> {
+        let version = self.version_inode(path, number)?;
+        let inode_block = self.lookup_path(path)?;
+        let _history = self.lock_history();
+        self.reclaiming(|disk| disk.keep_version(inode_block))?;
+        self.reclaiming(|disk| disk.clone_file(version, inode_block))?;
+        self.prune_history()
+    }
+
+    /// Deleted files in the trash, oldest first
+    pub fn list_trash(&self) -> FsResult<Vec<TrashEntry>> {
+        let mut trash = Vec::new();
+        for id in self.trash_ids()? {
+            let entry = join(TRASH_DIR, &id.to_string());
+            // Entries left half made by an interrupted delete are skipped
+            let (path, inode) = match (
+                self.read_link(&join(&entry, TRASH_ORIGIN)),
+                self.stat_path(&join(&entry, TRASH_FILE)),
+            ) {
+                (Ok(path), Ok(inode)) => (path, inode),
+                (Err(FsError::FileNotFound(_)), _) | (_, Err(FsError::FileNotFound(_))) => continue,
+                (Err(e), _) | (_, Err(e)) => return Err(e),
+            };
+            trash.push(TrashEntry {
+                id,
+                path,
+                file_type: inode.file_type,
+                size: inode.size,
+                deleted: self.stat_path(&entry)?.created,
+            });
+        }
+        Ok(trash)
+    }
+
+    /// Move the most recently deleted file that was at `path` back there
+    ///
+    /// Missing parent directories are created again. Fails if something
+    /// else exists at `path` by now. Returns the inode block of the file.
+    pub fn undelete(&self, path: &str) -> FsResult<u64> {
+        let wanted = format!("/{}", components(path).collect::<Vec<_>>().join("/"));
+        let entry = self
+            .list_trash()?
+            .into_iter()
+            .rev()
+            .find(|entry| entry.path == wanted)
+            .ok_or_else(|| FsError::FileNotFound(format!("{} in the trash", path)))?;
+
+        let _history = self.lock_history();
+        let (parent, name) = split_parent(&entry.path)?;
+        let dir = self.create_directories(parent, Permissions::new(true, true, true))?;
+        if self.find_directory_entry(dir, name).is_ok() {
+            return Err(FsError::AlreadyExists(entry.path));
+        }
+        let trash_entry = join(TRASH_DIR, &entry.id.to_string());
+        let trash_dir = self.lookup_path(&trash_entry)?;
+        let file = self.find_directory_entry(trash_dir, TRASH_FILE)?;
+        let moved = DirectoryEntry::new(file.inode_number, file.file_type, name.to_string())?;
+        self.add_directory_entry(dir, moved)?;
+        self.remove_directory_entry(trash_dir, TRASH_FILE)?;
+        let mut inode = self.read_inode(file.inode_number)?;
+        inode.flags &= !Inode::FLAG_HISTORY;
+        self.write_inode(file.inode_number, &inode)?;
+        self.remove_tree(&trash_entry)?;
+        Ok(file.inode_number)
+    }
+
+    /// Delete everything in the trash for good
+    pub fn empty_trash(&self) -> FsResult<()> {
+        let _history = self.lock_history();
+        for id in self.trash_ids()? {
+            self.remove_tree(&join(TRASH_DIR, &id.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// True if `inode` is kept as history when it is overwritten or
+    /// removed
+    pub(crate) fn keeps_history(&self, inode: &Inode) -> bool {
+        self.history_policy().is_enabled() && !inode.has_flag(Inode::FLAG_HISTORY)
+    }
+
+    /// Keep the current contents of the file at `inode_block` as its
+    /// newest version
+    ///
+    /// Nothing is kept for empty files or where `keeps_history` says no.
+    /// Returns whether a version was kept; callers prune the history once
+    /// the file has been changed.
+    pub(crate) fn keep_version(&self, inode_block: u64) -> FsResult<bool> {
+        let inode = self.read_inode(inode_block)?;
+        if inode.file_type != FileType::File || inode.size == 0 || !self.keeps_history(&inode) {
+            return Ok(false);
+        }
+
+        let _history = self.lock_history();
+        let dir = self.versions_dir(inode_block)?;
+        self.create_directories(&dir, Permissions::new(true, true, true))?;
+        let number = self.version_numbers(&dir)?.last().map_or(1, |n| n + 1);
+        let version_path = join(&dir, &number.to_string());
+        let version = self.create_file_at(&version_path, inode.permissions)?;
+        // Versions count against the quotas of the file's owners and project
+        let cloned = self
+            .set_owner(version, inode.uid, inode.gid)
+            .and_then(|_| self.set_project(version, inode.project))
+            .and_then(|_| self.clone_file(inode_block, version));
+        if let Err(e) = cloned {
+            self.remove_path(&version_path)?;
+            return Err(e);
+        }
+        let mut copy = self.read_inode(version)?;
+        copy.modified = inode.modified;
+        copy.flags |= Inode::FLAG_HISTORY;
+        self.write_inode(version, &copy)?;
+        Ok(true)
+    }
+
+    /// Move `entry`, named `name` in `dir` and reached through `path`, to
+    /// the trash
+    pub(crate) fn move_to_trash(&self, dir: u64, name: &str, entry: &DirectoryEntry, path: &str) -> FsResult<()> {
+        let _history = self.lock_history();
+        let origin = join(&self.canonicalize_path(split_parent(path)?.0)?, name);
+        self.create_directories(TRASH_DIR, Permissions::new(true, true, true))?;
+        let id = self.trash_ids()?.last().map_or(1, |id| id + 1);
+        let trash_entry = join(TRASH_DIR, &id.to_string());
+        let trash_dir = self.create_directory_at(&trash_entry, Permissions::new(true, true, true))?;
+        let linked = self
+            .create_symlink(&origin, &join(&trash_entry, TRASH_ORIGIN))
+            .and_then(|link| self.mark_history(link))
+            .and_then(|_| DirectoryEntry::new(entry.inode_number, entry.file_type, TRASH_FILE.to_string()))
+            .and_then(|moved| self.add_directory_entry(trash_dir, moved));
+        if let Err(e) = linked {
+            self.remove_tree(&trash_entry)?;
+            return Err(e);
+        }
+        self.remove_directory_entry(dir, name)?;
+        self.mark_history(entry.inode_number)
+    }
+
+    /// Set `Inode::FLAG_HISTORY` on the inode at `inode_block`
+    fn mark_history(&self, inode_block: u64) -> FsResult<()> {
+        let mut inode = self.read_inode(inode_block)?;
+        inode.flags |= Inode::FLAG_HISTORY;
+        self.write_inode(inode_block, &inode)
+    }
+
+    /// Delete the versions of the file at `inode_block`, if it has any
+    pub(crate) fn drop_versions(&self, inode_block: u64) -> FsResult<()> {
+        // With history off, `prune_history` has dropped them all already
+        if !self.history_policy().is_enabled() {
+            return Ok(());
+        }
+        let _history = self.lock_history();
+        let dir = self.versions_dir(inode_block)?;
+        match self.remove_tree(&dir) {
+            Err(FsError::FileNotFound(_)) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Run `op`, dropping the oldest history and retrying whenever it
+    /// runs out of space
+    ///
+    /// `op` must leave nothing half done when it fails. Exceeding a quota
+    /// is not retried, as the history dropped might belong to others.
+    pub(crate) fn reclaiming<T>(&self, mut op: impl FnMut(&Self) -> FsResult<T>) -> FsResult<T> {
+        loop {
+            match op(self) {
+                Err(e) if e.is_out_of_space() && !matches!(e, FsError::QuotaExceeded(_)) => {
+                    let _history = self.lock_history();
+                    let Some(oldest) = self.history_items()?.into_iter().next() else {
+                        return Err(e);
+                    };
+                    self.drop_history_item(&oldest)?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Drop history the policy no longer allows
+    ///
+    /// Versions of files that no longer exist are dropped as well.
+    pub(crate) fn prune_history(&self) -> FsResult<()> {
+        let _history = self.lock_history();
+        let policy = self.history_policy();
+        if !policy.is_enabled() {
+            self.empty_trash()?;
+        }
+        for (dir, owner) in self.version_dirs()? {
+            let numbers = self.version_numbers(&dir)?;
+            let keep = if owner.is_some() { policy.versions as usize } else { 0 };
+            if numbers.len() <= keep {
+                continue;
+            }
+            if keep == 0 {
+                self.remove_tree(&dir)?;
+                continue;
+            }
+            for number in &numbers[..numbers.len() - keep] {
+                self.remove_path(&join(&dir, &number.to_string()))?;
+            }
+        }
+
+        let items = self.history_items()?;
+        let now = now();
+        let mut total: u64 = items.iter().map(|item| item.blocks).sum();
+        for item in items {
+            let expired = policy.max_age.is_some_and(|age| now.saturating_sub(item.since) > age);
+            let over = policy.max_blocks.is_some_and(|max| total > max);
+            if !expired && !over {
+                break;
+            }
+            total = total.saturating_sub(item.blocks);
+            self.drop_history_item(&item)?;
+        }
+        Ok(())
+    }
+
+    /// Blocks in use by history: versions and deleted files
+    pub fn history_blocks(&self) -> FsResult<u64> {
+        Ok(self.history_items()?.iter().map(|item| item.blocks).sum())
+    }
+
+    /// Inode block of version `number` of the file at `path`
+    fn version_inode(&self, path: &str, number: u64) -> FsResult<u64> {
+        let inode_block = self.lookup_path(path)?;
+        let dir = self.versions_dir(inode_block)?;
+        match self.lookup_path(&join(&dir, &number.to_string())) {
+            Err(FsError::FileNotFound(_)) => Err(FsError::VersionNotFound(format!("{} version {}", path, number))),
+            result => result,
+        }
+    }
+
+    /// Path of the directory holding the versions of the file at `inode_block`
+    fn versions_dir(&self, inode_block: u64) -> FsResult<String> {
+        let inode = self.read_inode(inode_block)?;
+        if inode.file_type != FileType::File {
+            return Err(FsError::NotAFile(format!("Inode {} is not a file", inode_block)));
+        }
+        Ok(join(VERSIONS_DIR, &format!("{}-{}", inode_block, inode.created)))
+    }
+
+    /// Version numbers in the versions directory `dir`, oldest first
+    fn version_numbers(&self, dir: &str) -> FsResult<Vec<u64>> {
+        match self.list_directory_at(dir) {
+            Ok(entries) => Ok(numbered(entries)),
+            Err(FsError::FileNotFound(_)) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Numbers of the entries in the trash, oldest first
+    fn trash_ids(&self) -> FsResult<Vec<u64>> {
+        self.version_numbers(TRASH_DIR)
+    }
+
+    /// Directories in `VERSIONS_DIR`, with the inode block of the file
+    /// they belong to if it still exists
+    fn version_dirs(&self) -> FsResult<Vec<(String, Option<u64>)>> {
+        let entries = match self.list_directory_at(VERSIONS_DIR) {
+            Ok(entries) => entries,
+            Err(FsError::FileNotFound(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut dirs = Vec::new();
+        for entry in entries {
+            let dir = join(VERSIONS_DIR, &entry.name);
+            let owner = entry
+                .name
+                .split_once('-')
+                .and_then(|(block, _)| block.parse::<u64>().ok())
+                .filter(|&block| self.is_inode_used(block))
+                .filter(|&block| self.versions_dir(block).is_ok_and(|d| d == dir));
+            dirs.push((dir, owner));
+        }
+        Ok(dirs)
+    }
+
+    /// Every version and trash entry, oldest first
+    fn history_items(&self) -> FsResult<Vec<HistoryItem>> {
+        let mut items = Vec::new();
+        for id in self.trash_ids()? {
+            let path = join(TRASH_DIR, &id.to_string());
+            let since = self.stat_path(&path)?.created;
+            let blocks = match self.lookup_path_nofollow(&join(&path, TRASH_FILE)) {
+                Ok(inode_block) => self.held_blocks(&self.read_inode(inode_block)?)?,
+                Err(FsError::FileNotFound(_)) => 0,
+                Err(e) => return Err(e),
+            };
+            items.push(HistoryItem { path, since, blocks });
+        }
+        for (dir, _) in self.version_dirs()? {
+            for number in self.version_numbers(&dir)? {
+                let path = join(&dir, &number.to_string());
+                let inode_block = self.lookup_path_nofollow(&path)?;
+                let inode = self.read_inode(inode_block)?;
+                let (since, blocks) = (inode.created, self.held_blocks(&inode)?);
+                items.push(HistoryItem { path, since, blocks });
+            }
+        }
+        items.sort_by_key(|item| item.since);
+        Ok(items)
+    }
+
+    /// Delete a version or trash entry, and the versions directory it
+    /// leaves empty
+    ///
+    /// Dropping a trash entry drops the versions of its file with it, so
+    /// the item may already be gone.
+    fn drop_history_item(&self, item: &HistoryItem) -> FsResult<()> {
+        match self.remove_tree(&item.path) {
+            Ok(()) | Err(FsError::FileNotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+        let (parent, _) = split_parent(&item.path)?;
+        if parent == TRASH_DIR {
+            return Ok(());
+        }
+        match self.list_directory_at(parent) {
+            Ok(entries) if entries.is_empty() => self.remove_path(parent),
+            Ok(_) | Err(FsError::FileNotFound(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
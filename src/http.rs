@@ -0,0 +1,729 @@
+use crate::{
+    error::{FsError, FsResult},
+    path::{join, split_parent},
+    serialization::{FileType, Inode, Permissions},
+    shell::civil_date,
+    virtual_disk::VirtualDisk,
+};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+
+/// Bytes read from or written to the disk per chunk of a response or upload
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Longest request line or header line accepted
+const MAX_LINE: usize = 8 * 1024;
+
+/// Largest number of request headers accepted
+const MAX_HEADERS: usize = 100;
+
+const READ_METHODS: &str = "OPTIONS, GET, HEAD, PROPFIND";
+const WRITE_METHODS: &str = "OPTIONS, GET, HEAD, PROPFIND, PUT, MKCOL, DELETE, MOVE";
+
+/// A parsed HTTP request, without its body
+#[derive(Debug)]
+struct Request {
+    method: String,
+    /// Decoded path, always starting with '/'
+    path: String,
+    /// Header names in lower case
+    headers: HashMap<String, String>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    fn content_length(&self) -> FsResult<u64> {
+        match self.header("content-length") {
+            None => Ok(0),
+            Some(value) => value
+                .trim()
+                .parse()
+                .map_err(|_| FsError::InvalidPath(format!("Bad Content-Length: {}", value))),
+        }
+    }
+}
+
+/// A response ready to be sent, unless its body is streamed from a file
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16) -> Self {
+        Response { status, headers: Vec::new(), body: Vec::new() }
+    }
+
+    fn text(status: u16, text: &str) -> Self {
+        Response::new(status)
+            .header("Content-Type", "text/plain; charset=utf-8".to_string())
+            .body(format!("{}\n", text).into_bytes())
+    }
+
+    fn header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    fn body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+}
+
+impl From<FsError> for Response {
+    fn from(err: FsError) -> Self {
+        Response::text(err.http_status(), &err.to_string())
+    }
+}
+
+/// Local HTTP server for browsing a disk image, with WebDAV support
+///
+/// GET returns file contents, with single-range `Range` requests served
+/// from offset reads, or an HTML listing for directories. PROPFIND with
+/// depth 0 or 1 answers WebDAV clients. When writable, PUT, MKCOL, DELETE
+/// and MOVE change the image too. Connections are served one at a time
+/// and closed after each response.
+#[derive(Debug)]
+pub struct HttpServer {
+    disk: VirtualDisk,
+    writable: bool,
+}
+
+impl HttpServer {
+    pub fn new(disk: VirtualDisk, writable: bool) -> Self {
+        HttpServer { disk, writable }
+    }
+
+    /// Give back the disk, e.g. to sync it when the server stops
+    pub fn into_disk(self) -> VirtualDisk {
+        self.disk
+    }
+
+    /// Accept and serve connections until accepting fails
+    ///
+    /// A connection that breaks off mid-request only ends that
+    /// connection.
+    pub fn serve(&mut self, listener: &TcpListener) -> FsResult<()> {
+        for stream in listener.incoming() {
+            let _ = self.serve_connection(&mut stream?);
+        }
+        Ok(())
+    }
+
+    /// Read one request from `stream` and answer it
+    pub fn serve_connection<S: Read + Write>(&mut self, stream: &mut S) -> FsResult<()> {
+        let mut reader = BufReader::new(stream);
+        let request = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(FsError::Io(e)) => return Err(FsError::Io(e)),
+            Err(e) => return send(reader.get_mut(), Response::from(e), false),
+        };
+
+        let head = request.method == "HEAD";
+        match self.respond(&request, &mut reader) {
+            Ok(Some(response)) => send(reader.get_mut(), response, head),
+            // The body was already streamed
+            Ok(None) => Ok(()),
+            Err(FsError::Io(e)) => Err(FsError::Io(e)),
+            Err(e) => send(reader.get_mut(), Response::from(e), head),
+        }
+    }
+
+    /// Handle a request; returns `None` if the response was streamed
+    fn respond<S: Read + Write>(
+        &mut self,
+        request: &Request,
+        reader: &mut BufReader<&mut S>,
+    ) -> FsResult<Option<Response>> {
+        let writes = matches!(request.method.as_str(), "PUT" | "MKCOL" | "DELETE" | "MOVE");
+        if request.method != "PUT" {
+            // Bodies of other requests are not used
+            discard(reader, request.content_length()?)?;
+        }
+        if writes && !self.writable {
+            return Ok(Some(
+                Response::text(405, "The image is served read-only").header("Allow", READ_METHODS.to_string()),
+            ));
+        }
+
+        let response = match request.method.as_str() {
+            "OPTIONS" => Response::new(200)
+                .header("Allow", self.allowed().to_string())
+                .header("DAV", "1".to_string()),
+            "GET" | "HEAD" => return self.get(request, reader.get_mut()),
+            "PROPFIND" => self.propfind(request)?,
+            "PUT" => self.put(request, reader)?,
+            "MKCOL" => self.mkcol(request)?,
+            "DELETE" => {
+                self.resolve(&request.path)?;
+                self.disk.remove_tree(&request.path)?;
+                Response::new(204)
+            }
+            "MOVE" => self.move_resource(request)?,
+            _ => Response::text(405, "Method not allowed").header("Allow", self.allowed().to_string()),
+        };
+        Ok(Some(response))
+    }
+
+    fn allowed(&self) -> &'static str {
+        if self.writable { WRITE_METHODS } else { READ_METHODS }
+    }
+
+    /// Follow `path`, including symlinks, to its inode
+    fn resolve(&mut self, path: &str) -> FsResult<(u64, Inode)> {
+        let inode_block = self.disk.lookup_path(path)?;
+        Ok((inode_block, self.disk.read_inode(inode_block)?))
+    }
+
+    // ==================== GET ====================
+
+    fn get<S: Write>(&mut self, request: &Request, stream: &mut S) -> FsResult<Option<Response>> {
+        let (inode_block, inode) = self.resolve(&request.path)?;
+        if inode.file_type == FileType::Directory {
+            if !request.path.ends_with('/') {
+                let location = format!("{}/", encode_path(&request.path));
+                return Ok(Some(Response::new(301).header("Location", location)));
+            }
+            return Ok(Some(self.listing(&request.path)?));
+        }
+
+        let size = inode.size;
+        let (status, start, end) = match request.header("range").map(|r| parse_range(r, size)) {
+            None | Some(RangeRequest::Ignored) => (200, 0, size),
+            Some(RangeRequest::Satisfiable(start, end)) => (206, start, end),
+            Some(RangeRequest::Unsatisfiable) => {
+                let response = Response::from(FsError::InvalidOffsetOrSize { offset: size, size: 0 })
+                    .header("Content-Range", format!("bytes */{}", size));
+                return Ok(Some(response));
+            }
+        };
+
+        let mut response = Response::new(status)
+            .header("Content-Type", content_type(&request.path).to_string())
+            .header("Accept-Ranges", "bytes".to_string())
+            .header("Last-Modified", http_date(inode.modified));
+        if status == 206 {
+            response = response.header("Content-Range", format!("bytes {}-{}/{}", start, end - 1, size));
+        }
+        response = response.header("Content-Length", (end - start).to_string());
+        write_head(stream, &response)?;
+        if request.method == "HEAD" {
+            stream.flush()?;
+            return Ok(None);
+        }
+
+        // The status line is already out, so a failure from here on can
+        // only be reported by breaking off the connection
+        match self.stream_body(inode_block, start, end, stream) {
+            Ok(()) => Ok(None),
+            Err(FsError::Io(e)) => Err(FsError::Io(e)),
+            Err(e) => Err(FsError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, e.to_string()))),
+        }
+    }
+
+    /// Send bytes `start..end` of a file in chunks
+    fn stream_body<S: Write>(&mut self, inode_block: u64, start: u64, end: u64, stream: &mut S) -> FsResult<()> {
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let mut offset = start;
+        while offset < end {
+            let want = ((end - offset) as usize).min(CHUNK_SIZE);
+            let read = self.disk.read_at(inode_block, offset, &mut buffer[..want])?;
+            if read == 0 {
+                break;
+            }
+            stream.write_all(&buffer[..read])?;
+            offset += read as u64;
+        }
+        stream.flush()?;
+        Ok(())
+    }
+
+    /// HTML page listing a directory
+    fn listing(&mut self, path: &str) -> FsResult<Response> {
+        let mut entries = self.disk.list_directory_at(path)?;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let title = html_escape(path);
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n\
+             <body><h1>Index of {0}</h1>\n<table>\n<tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n",
+            title
+        );
+        if path != "/" {
+            html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+        }
+        for entry in entries {
+            let inode = self.disk.read_inode(entry.inode_number)?;
+            let suffix = if inode.file_type == FileType::Directory { "/" } else { "" };
+            let size = if inode.file_type == FileType::Directory { String::new() } else { inode.size.to_string() };
+            html.push_str(&format!(
+                "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+                encode_segment(&entry.name),
+                suffix,
+                html_escape(&entry.name),
+                suffix,
+                size,
+                http_date(inode.modified)
+            ));
+        }
+        html.push_str("</table></body></html>\n");
+
+        Ok(Response::new(200)
+            .header("Content-Type", "text/html; charset=utf-8".to_string())
+            .body(html.into_bytes()))
+    }
+
+    // ==================== WEBDAV ====================
+
+    fn propfind(&mut self, request: &Request) -> FsResult<Response> {
+        let depth = request.header("depth").unwrap_or("infinity");
+        if depth != "0" && depth != "1" {
+            return Ok(Response::text(403, "PROPFIND with infinite depth is not supported"));
+        }
+
+        let (_, inode) = self.resolve(&request.path)?;
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n");
+        let is_dir = inode.file_type == FileType::Directory;
+        let base = if is_dir && !request.path.ends_with('/') {
+            format!("{}/", request.path)
+        } else {
+            request.path.clone()
+        };
+        xml.push_str(&prop_response(&base, &inode));
+
+        if is_dir && depth == "1" {
+            let mut entries = self.disk.list_directory_at(&request.path)?;
+            entries.sort_by(|a, b| a.name.cmp(&b.name));
+            for entry in entries {
+                let child = self.disk.read_inode(entry.inode_number)?;
+                let mut href = format!("{}{}", base, entry.name);
+                if child.file_type == FileType::Directory {
+                    href.push('/');
+                }
+                xml.push_str(&prop_response(&href, &child));
+            }
+        }
+        xml.push_str("</D:multistatus>\n");
+
+        Ok(Response::new(207)
+            .header("Content-Type", "application/xml; charset=utf-8".to_string())
+            .body(xml.into_bytes()))
+    }
+
+    fn put<S: Read + Write>(&mut self, request: &Request, reader: &mut BufReader<&mut S>) -> FsResult<Response> {
+        if request.header("transfer-encoding").is_some() {
+            return Ok(Response::text(411, "PUT needs a Content-Length"));
+        }
+        let length = request.content_length()?;
+        // The whole body comes first, so a request that breaks off leaves
+        // the file as it was
+        let mut body = Vec::new();
+        reader.take(length).read_to_end(&mut body)?;
+        if (body.len() as u64) < length {
+            return Err(FsError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "PUT body ended early")));
+        }
+
+        let (inode_block, created) = match self.disk.lookup_path(&request.path) {
+            Ok(inode_block) => {
+                if self.disk.read_inode(inode_block)?.file_type == FileType::Directory {
+                    return Err(FsError::NotAFile(request.path.clone()));
+                }
+                (inode_block, false)
+            }
+            Err(FsError::FileNotFound(_)) => {
+                let inode_block = self.disk.create_file_at(&request.path, Permissions::new(true, true, false))?;
+                (inode_block, true)
+            }
+            Err(e) => return Err(e),
+        };
+        self.disk.write_file(inode_block, &body)?;
+        Ok(Response::new(if created { 201 } else { 204 }))
+    }
+
+    fn mkcol(&mut self, request: &Request) -> FsResult<Response> {
+        if request.content_length()? > 0 {
+            return Ok(Response::text(415, "MKCOL with a body is not supported"));
+        }
+        match self.disk.create_directory_at(&request.path, Permissions::new(true, true, true)) {
+            Ok(_) => Ok(Response::new(201)),
+            // RFC 4918: MKCOL on an existing resource is not allowed
+            Err(FsError::AlreadyExists(path)) => Ok(Response::text(405, &format!("Already exists: {}", path))),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn move_resource(&mut self, request: &Request) -> FsResult<Response> {
+        let Some(destination) = request.header("destination") else {
+            return Ok(Response::text(400, "MOVE needs a Destination header"));
+        };
+        let destination = destination_path(destination)?;
+        let overwrite = request.header("overwrite").is_none_or(|v| !v.eq_ignore_ascii_case("F"));
+
+        let source = self.entry_path(&request.path)?;
+        let target = self.entry_path(&destination)?;
+        self.disk.lookup_path_nofollow(&source)?;
+        if source == target {
+            return Ok(Response::text(403, "Source and destination are the same"));
+        }
+        if target.starts_with(&format!("{}/", source)) || source.starts_with(&format!("{}/", target)) {
+            return Ok(Response::text(409, "Cannot move a resource into itself or over its parent"));
+        }
+        let exists = match self.disk.lookup_path_nofollow(&target) {
+            Ok(_) => true,
+            Err(FsError::FileNotFound(_)) => false,
+            Err(e) => return Err(e),
+        };
+        if !exists {
+            self.disk.rename_path(&source, &target)?;
+            return Ok(Response::new(201));
+        }
+        if !overwrite {
+            return Ok(Response::text(412, &format!("{} exists", destination)));
+        }
+
+        // The old destination is moved aside rather than removed, so it can
+        // be put back if the move fails
+        let aside = self.aside_path(&target)?;
+        self.disk.rename_path(&target, &aside)?;
+        if let Err(e) = self.disk.rename_path(&source, &target) {
+            self.disk.rename_path(&aside, &target)?;
+            return Err(e);
+        }
+        self.disk.remove_tree(&aside)?;
+        Ok(Response::new(204))
+    }
+
+    /// Absolute path of the entry named by `path`, with its parent
+    /// directory canonicalized but a final symlink left alone
+    fn entry_path(&mut self, path: &str) -> FsResult<String> {
+        let (parent, name) = split_parent(path)?;
+        Ok(join(&self.disk.canonicalize_path(parent)?, name))
+    }
+
+    /// An unused name next to `path`
+    fn aside_path(&mut self, path: &str) -> FsResult<String> {
+        let (parent, name) = split_parent(path)?;
+        let mut n = 0;
+        loop {
+            let aside = join(parent, &format!(".{}.moving-{}", name, n));
+            match self.disk.lookup_path_nofollow(&aside) {
+                Ok(_) => n += 1,
+                Err(FsError::FileNotFound(_)) => return Ok(aside),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+// ==================== PROTOCOL HELPERS ====================
+
+/// Read the request line and headers; `None` if the client sent nothing
+fn read_request<R: BufRead>(reader: &mut R) -> FsResult<Option<Request>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(_version)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(FsError::InvalidPath(format!("Bad request line: {}", line)));
+    };
+
+    let mut headers = HashMap::new();
+    loop {
+        let Some(line) = read_line(reader)? else {
+            return Err(FsError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+        };
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(FsError::InvalidPath("Too many headers".to_string()));
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(FsError::InvalidPath(format!("Bad header: {}", line)));
+        };
+        headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+
+    // Absolute-form targets ("http://host/path") are accepted too
+    let target = strip_origin(target);
+    let target = target.split(['?', '#']).next().unwrap_or("/");
+    Ok(Some(Request {
+        method: method.to_ascii_uppercase(),
+        path: normalize(&decode_path(target)?),
+        headers,
+    }))
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> FsResult<Option<String>> {
+    let mut line = Vec::new();
+    let read = reader.take(MAX_LINE as u64).read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(FsError::InvalidPath("Request line too long".to_string()));
+    }
+    let line = String::from_utf8(line).map_err(|_| FsError::InvalidPath("Request is not UTF-8".to_string()))?;
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+fn discard<R: Read>(reader: &mut R, length: u64) -> FsResult<()> {
+    std::io::copy(&mut reader.take(length), &mut std::io::sink())?;
+    Ok(())
+}
+
+fn status_text(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        207 => "Multi-Status",
+        301 => "Moved Permanently",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        501 => "Not Implemented",
+        507 => "Insufficient Storage",
+        _ => "Internal Server Error",
+    }
+}
+
+fn write_head<W: Write>(stream: &mut W, response: &Response) -> FsResult<()> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, status_text(response.status));
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    stream.write_all(head.as_bytes())?;
+    Ok(())
+}
+
+/// Send a buffered response, leaving out the body for HEAD requests
+fn send<W: Write>(stream: &mut W, response: Response, head: bool) -> FsResult<()> {
+    let length = response.body.len();
+    let response = response.header("Content-Length", length.to_string());
+    write_head(stream, &response)?;
+    if !head {
+        stream.write_all(&response.body)?;
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+/// Outcome of parsing a `Range` header against a file size
+#[derive(Debug, PartialEq, Eq)]
+enum RangeRequest {
+    /// Not a single byte range; the whole file is sent
+    Ignored,
+    /// Serve `start..end`
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+}
+
+fn parse_range(header: &str, size: u64) -> RangeRequest {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Ignored;
+    };
+    // Multiple ranges would need a multipart response
+    if spec.contains(',') {
+        return RangeRequest::Ignored;
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return RangeRequest::Ignored;
+    };
+    let (first, last) = (first.trim(), last.trim());
+
+    let range = if first.is_empty() {
+        // Suffix range: the last N bytes
+        match last.parse::<u64>() {
+            Ok(0) => return RangeRequest::Unsatisfiable,
+            Ok(n) => (size.saturating_sub(n), size),
+            Err(_) => return RangeRequest::Ignored,
+        }
+    } else {
+        let Ok(start) = first.parse::<u64>() else {
+            return RangeRequest::Ignored;
+        };
+        let end = match last {
+            "" => size,
+            last => match last.parse::<u64>() {
+                Ok(last) if last >= start => last.saturating_add(1).min(size),
+                _ => return RangeRequest::Ignored,
+            },
+        };
+        (start, end)
+    };
+
+    if range.0 >= size {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Satisfiable(range.0, range.1)
+    }
+}
+
+/// Remove the scheme and authority from an absolute URL
+fn strip_origin(url: &str) -> &str {
+    match url.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |i| &rest[i..]),
+        None => url,
+    }
+}
+
+/// Path of a MOVE `Destination` header
+fn destination_path(destination: &str) -> FsResult<String> {
+    Ok(normalize(&decode_path(strip_origin(destination))?))
+}
+
+fn decode_path(path: &str) -> FsResult<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3).unwrap_or(b""))
+                .ok()
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+                .ok_or_else(|| FsError::InvalidPath(format!("Bad percent-encoding in {}", path)))?;
+            decoded.push(hex);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| FsError::InvalidPath(format!("Path is not UTF-8: {}", path)))
+}
+
+/// Make a request path absolute, keeping a trailing slash
+///
+/// `..` is resolved here so a request cannot reach above the root.
+fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    let mut normalized = format!("/{}", parts.join("/"));
+    if path.ends_with('/') && normalized != "/" {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/// Percent-encode one path segment for use in a URL
+fn encode_segment(name: &str) -> String {
+    let mut encoded = String::new();
+    for byte in name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn encode_path(path: &str) -> String {
+    path.split('/').map(encode_segment).collect::<Vec<_>>().join("/")
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Format a Unix timestamp as an HTTP date (RFC 7231 IMF-fixdate)
+fn http_date(secs: u64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (year, month, day) = civil_date(secs);
+    let rest = secs % 86_400;
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        DAYS[(secs / 86_400 % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60
+    )
+}
+
+fn content_type(path: &str) -> &'static str {
+    let extension = path.rsplit_once('.').map(|(_, e)| e.to_ascii_lowercase()).unwrap_or_default();
+    match extension.as_str() {
+        "txt" | "md" | "log" | "rs" | "toml" => "text/plain; charset=utf-8",
+        "html" | "htm" => "text/html; charset=utf-8",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+/// One `<D:response>` element of a PROPFIND answer
+fn prop_response(href: &str, inode: &Inode) -> String {
+    let name = href.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+    let mut props = format!(
+        "<D:displayname>{}</D:displayname><D:getlastmodified>{}</D:getlastmodified>\
+         <D:creationdate>{}</D:creationdate>",
+        html_escape(name),
+        http_date(inode.modified),
+        iso_date(inode.created)
+    );
+    if inode.file_type == FileType::Directory {
+        props.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+    } else {
+        props.push_str(&format!(
+            "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength>\
+             <D:getcontenttype>{}</D:getcontenttype>",
+            inode.size,
+            content_type(href)
+        ));
+    }
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop>\
+         <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+        html_escape(&encode_path(href)),
+        props
+    )
+}
+
+/// Format a Unix timestamp as an RFC 3339 date, for `creationdate`
+fn iso_date(secs: u64) -> String {
+    let (year, month, day) = civil_date(secs);
+    let rest = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60
+    )
+}
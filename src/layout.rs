@@ -0,0 +1,538 @@
+use crate::{
+    error::FsResult,
+    extent::{Extent, HOLE},
+    serialization::FileType,
+    virtual_disk::VirtualDisk,
+};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// What a block is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BlockKind {
+    /// Superblock and group descriptors
+    Superblock,
+    /// Allocator state (block bitmap, buddy padding bitmap)
+    Bitmap,
+    /// Inode bitmap
+    InodeBitmap,
+    /// Checksum table
+    Checksums,
+    /// Kept for snapshots: the snapshot table, copies of overwritten
+    /// blocks, and blocks freed since a snapshot was taken
+    Snapshot,
+    /// Reference count table of shared blocks, or the dedup table
+    RefCounts,
+    /// Inode table slot holding an inode
+    Inode,
+    /// Unused inode table slot
+    FreeInode,
+    /// File data
+    Data,
+    /// Directory entries
+    Directory,
+    /// Indirect block or extent tree node
+    Mapping,
+    /// Allocated, but not referenced by any inode
+    Unowned,
+    /// Free
+    Free,
+}
+
+impl BlockKind {
+    /// Short name used in JSON exports and legends
+    pub fn name(self) -> &'static str {
+        match self {
+            BlockKind::Superblock => "superblock",
+            BlockKind::Bitmap => "bitmap",
+            BlockKind::InodeBitmap => "inode-bitmap",
+            BlockKind::Checksums => "checksums",
+            BlockKind::Snapshot => "snapshot",
+            BlockKind::RefCounts => "refcounts",
+            BlockKind::Inode => "inode",
+            BlockKind::FreeInode => "free-inode",
+            BlockKind::Data => "data",
+            BlockKind::Directory => "directory",
+            BlockKind::Mapping => "mapping",
+            BlockKind::Unowned => "unowned",
+            BlockKind::Free => "free",
+        }
+    }
+
+    /// Map symbol for blocks that do not belong to a file's data
+    fn symbol(self) -> char {
+        match self {
+            BlockKind::Superblock => 'S',
+            BlockKind::Bitmap => 'B',
+            BlockKind::InodeBitmap => 'b',
+            BlockKind::Checksums => '=',
+            BlockKind::Snapshot => '@',
+            BlockKind::RefCounts => '%',
+            BlockKind::Inode => '#',
+            BlockKind::FreeInode => '-',
+            BlockKind::Data | BlockKind::Directory => '*',
+            BlockKind::Mapping => '+',
+            BlockKind::Unowned => '?',
+            BlockKind::Free => '.',
+        }
+    }
+
+    /// ANSI color code
+    fn color(self) -> u8 {
+        match self {
+            BlockKind::Superblock => 31,
+            BlockKind::Bitmap | BlockKind::InodeBitmap | BlockKind::Checksums | BlockKind::RefCounts => 33,
+            BlockKind::Inode => 35,
+            BlockKind::Snapshot => 34,
+            BlockKind::FreeInode | BlockKind::Free => 90,
+            BlockKind::Data | BlockKind::Directory => 32,
+            BlockKind::Mapping => 36,
+            BlockKind::Unowned => 91,
+        }
+    }
+
+    /// SVG fill color
+    fn fill(self) -> &'static str {
+        match self {
+            BlockKind::Superblock => "#d62728",
+            BlockKind::Bitmap | BlockKind::InodeBitmap => "#ff7f0e",
+            BlockKind::Checksums => "#bcbd22",
+            BlockKind::Snapshot => "#8c564b",
+            BlockKind::RefCounts => "#c49c94",
+            BlockKind::Inode => "#9467bd",
+            BlockKind::FreeInode => "#dddddd",
+            BlockKind::Data => "#2ca02c",
+            BlockKind::Directory => "#1f77b4",
+            BlockKind::Mapping => "#17becf",
+            BlockKind::Unowned => "#e377c2",
+            BlockKind::Free => "#f5f5f5",
+        }
+    }
+}
+
+/// Use of a single block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockInfo {
+    pub kind: BlockKind,
+    /// Inode block of the file or directory the block belongs to
+    pub owner: Option<u64>,
+}
+
+/// Placement of one file or directory
+#[derive(Debug, Clone)]
+pub struct FileLayout {
+    pub inode_block: u64,
+    pub inode_number: u64,
+    /// Name from a directory entry, if one refers to this inode
+    pub name: Option<String>,
+    pub file_type: FileType,
+    pub data_blocks: u64,
+    pub metadata_blocks: u64,
+    /// Number of contiguous runs the data is split into
+    pub fragments: u64,
+}
+
+impl FileLayout {
+    /// Name if known, otherwise the inode block
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("inode@{}", self.inode_block),
+        }
+    }
+}
+
+/// A run of consecutive blocks with the same use and owner
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: u64,
+    pub length: u64,
+    pub info: BlockInfo,
+}
+
+/// Number of free runs whose length falls in `min..=max`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreeExtentBucket {
+    pub min: u64,
+    pub max: u64,
+    pub runs: u64,
+    pub blocks: u64,
+}
+
+/// Snapshot of how every block of the disk is used
+#[derive(Debug, Clone)]
+pub struct DiskLayout {
+    pub block_size: u64,
+    pub blocks: Vec<BlockInfo>,
+    pub files: Vec<FileLayout>,
+}
+
+impl VirtualDisk {
+    /// Classify every block of the disk and record which inode owns it
+    pub fn disk_layout(&mut self) -> FsResult<DiskLayout> {
+        let superblock = self.block_groups().superblock().clone();
+        let total = superblock.total_blocks;
+        let unowned = |kind| BlockInfo { kind, owner: None };
+        let mut blocks = vec![unowned(BlockKind::Free); total as usize];
+
+        for (block, info) in blocks.iter_mut().enumerate() {
+            if self.is_block_used(block as u64) {
+                *info = unowned(BlockKind::Unowned);
+            }
+        }
+
+        let mut mark = |start: u64, count: u64, info: BlockInfo| {
+            for block in start..(start + count).min(total) {
+                blocks[block as usize] = info;
+            }
+        };
+
+        mark(0, 1, unowned(BlockKind::Superblock));
+        mark(
+            superblock.block_bitmap_start,
+            superblock.block_bitmap_blocks,
+            unowned(BlockKind::Bitmap),
+        );
+        mark(
+            superblock.inode_bitmap_start,
+            superblock.inode_bitmap_blocks,
+            unowned(BlockKind::InodeBitmap),
+        );
+        mark(
+            superblock.checksum_table_start,
+            superblock.checksum_table_blocks,
+            unowned(BlockKind::Checksums),
+        );
+        for group in &superblock.groups {
+            mark(group.inode_table_start, group.inode_count, unowned(BlockKind::FreeInode));
+        }
+        for block in self.snapshot_blocks() {
+            mark(block, 1, unowned(BlockKind::Snapshot));
+        }
+        for block in self.refcount_table_blocks().into_iter().chain(self.dedup_table_blocks()) {
+            mark(block, 1, unowned(BlockKind::RefCounts));
+        }
+
+        let mut files = Vec::new();
+        let mut names = HashMap::new();
+        for inode_block in self.block_groups().used_inodes() {
+            let inode = self.read_inode(inode_block)?;
+            let (data, metadata) = self.walk_mapping(&inode)?;
+            let owner = Some(inode_block);
+
+            mark(inode_block, 1, BlockInfo { kind: BlockKind::Inode, owner });
+            let data_kind = match inode.file_type {
+                FileType::Directory => BlockKind::Directory,
+                _ => BlockKind::Data,
+            };
+            for &block in data.iter().filter(|&&b| b != HOLE) {
+                mark(block, 1, BlockInfo { kind: data_kind, owner });
+            }
+            for &block in &metadata {
+                mark(block, 1, BlockInfo { kind: BlockKind::Mapping, owner });
+            }
+
+            if inode.file_type == FileType::Directory {
+                for entry in self.list_directory(inode_block)? {
+                    names.insert(entry.inode_number, entry.name);
+                }
+            }
+
+            files.push(FileLayout {
+                inode_block,
+                inode_number: inode.inode_number,
+                name: None,
+                file_type: inode.file_type,
+                data_blocks: data.iter().filter(|&&b| b != HOLE).count() as u64,
+                metadata_blocks: metadata.len() as u64,
+                fragments: Extent::from_blocks(&data).len() as u64,
+            });
+        }
+
+        let root = self.root_directory();
+        for file in &mut files {
+            file.name = if Some(file.inode_block) == root {
+                Some("/".to_string())
+            } else {
+                names.get(&file.inode_number).cloned()
+            };
+        }
+
+        Ok(DiskLayout {
+            block_size: superblock.block_size,
+            blocks,
+            files,
+        })
+    }
+}
+
+impl DiskLayout {
+    /// Total number of blocks
+    pub fn total_blocks(&self) -> u64 {
+        self.blocks.len() as u64
+    }
+
+    /// Consecutive blocks with the same use and owner, in disk order
+    pub fn regions(&self) -> Vec<Region> {
+        let mut regions: Vec<Region> = Vec::new();
+        for (block, &info) in self.blocks.iter().enumerate() {
+            match regions.last_mut() {
+                Some(region) if region.info == info => region.length += 1,
+                _ => regions.push(Region {
+                    start: block as u64,
+                    length: 1,
+                    info,
+                }),
+            }
+        }
+        regions
+    }
+
+    /// Runs of free blocks as (start, length)
+    pub fn free_extents(&self) -> Vec<(u64, u64)> {
+        self.regions()
+            .into_iter()
+            .filter(|r| r.info.kind == BlockKind::Free)
+            .map(|r| (r.start, r.length))
+            .collect()
+    }
+
+    /// Length of the longest run of free blocks
+    pub fn largest_free_run(&self) -> u64 {
+        self.free_extents().iter().map(|&(_, len)| len).max().unwrap_or(0)
+    }
+
+    /// Free runs grouped by size in power-of-two buckets (1, 2-3, 4-7, ...)
+    pub fn free_extent_histogram(&self) -> Vec<FreeExtentBucket> {
+        let mut buckets: Vec<FreeExtentBucket> = Vec::new();
+        for (_, length) in self.free_extents() {
+            let index = (63 - length.leading_zeros()) as usize;
+            while buckets.len() <= index {
+                let min = 1u64 << buckets.len();
+                buckets.push(FreeExtentBucket {
+                    min,
+                    max: min * 2 - 1,
+                    runs: 0,
+                    blocks: 0,
+                });
+            }
+            buckets[index].runs += 1;
+            buckets[index].blocks += length;
+        }
+        buckets
+    }
+
+    /// Smallest number of blocks per cell that fits the whole map in
+    /// `rows` lines of `width` cells
+    pub fn blocks_per_cell(&self, width: usize, rows: usize) -> u64 {
+        let cells = (width * rows).max(1) as u64;
+        self.total_blocks().div_ceil(cells).max(1)
+    }
+
+    /// Render the block map as text, `width` cells per line
+    ///
+    /// Each cell covers `blocks_per_cell` blocks and shows the most common
+    /// non-free use among them. Data and directory blocks use a letter per
+    /// file (listed in the legend); with `ansi`, cells are colored by kind.
+    pub fn render_ascii(&self, width: usize, blocks_per_cell: u64, ansi: bool) -> String {
+        let blocks_per_cell = blocks_per_cell.max(1);
+        let symbols = self.file_symbols();
+        let mut out = String::new();
+
+        let cells = self.total_blocks().div_ceil(blocks_per_cell);
+        for row_start in (0..cells).step_by(width.max(1)) {
+            let _ = write!(out, "{:>7} ", row_start * blocks_per_cell);
+            for cell in row_start..(row_start + width as u64).min(cells) {
+                let info = self.dominant(cell * blocks_per_cell, blocks_per_cell);
+                let symbol = match info.kind {
+                    BlockKind::Data | BlockKind::Directory => info
+                        .owner
+                        .and_then(|owner| symbols.get(&owner).copied())
+                        .unwrap_or_else(|| info.kind.symbol()),
+                    kind => kind.symbol(),
+                };
+                if ansi {
+                    let _ = write!(out, "\x1b[{}m{}\x1b[0m", info.kind.color(), symbol);
+                } else {
+                    out.push(symbol);
+                }
+            }
+            out.push('\n');
+        }
+
+        out.push_str("\nLegend: S superblock, B bitmap, b inode bitmap, = checksums, % reference counts, # inode,\n");
+        out.push_str("        - free inode slot, + indirect/extent block, @ kept for snapshots, ? allocated but unowned,\n");
+        out.push_str("        . free\n");
+        for file in &self.files {
+            if let Some(symbol) = symbols.get(&file.inode_block) {
+                let _ = writeln!(
+                    out,
+                    "        {} {} ({} blocks, {} fragments)",
+                    symbol,
+                    file.label(),
+                    file.data_blocks,
+                    file.fragments
+                );
+            }
+        }
+        out
+    }
+
+    /// Render a text report: fragment counts per file, free extent
+    /// histogram and the largest free run
+    pub fn render_report(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("Files:\n");
+        for file in &self.files {
+            let _ = writeln!(
+                out,
+                "  {:<24} {:>9} data={:<6} mapping={:<4} fragments={}",
+                file.label(),
+                format!("{:?}", file.file_type),
+                file.data_blocks,
+                file.metadata_blocks,
+                file.fragments
+            );
+        }
+
+        out.push_str("Free extents:\n");
+        for bucket in self.free_extent_histogram() {
+            if bucket.runs > 0 {
+                let _ = writeln!(
+                    out,
+                    "  {:>6}-{:<6} {:>6} runs {:>8} blocks",
+                    bucket.min, bucket.max, bucket.runs, bucket.blocks
+                );
+            }
+        }
+        let _ = writeln!(out, "Largest free run: {} blocks", self.largest_free_run());
+        out
+    }
+
+    /// Export the layout as JSON
+    ///
+    /// Blocks are run-length encoded into regions to keep the output small.
+    pub fn to_json(&self) -> String {
+        let regions: Vec<_> = self
+            .regions()
+            .iter()
+            .map(|r| {
+                json!({
+                    "start": r.start,
+                    "length": r.length,
+                    "kind": r.info.kind.name(),
+                    "owner": r.info.owner,
+                })
+            })
+            .collect();
+        let files: Vec<_> = self
+            .files
+            .iter()
+            .map(|f| {
+                json!({
+                    "inode_block": f.inode_block,
+                    "inode_number": f.inode_number,
+                    "name": f.name,
+                    "type": format!("{:?}", f.file_type),
+                    "data_blocks": f.data_blocks,
+                    "metadata_blocks": f.metadata_blocks,
+                    "fragments": f.fragments,
+                })
+            })
+            .collect();
+        let histogram: Vec<_> = self
+            .free_extent_histogram()
+            .iter()
+            .map(|b| json!({ "min": b.min, "max": b.max, "runs": b.runs, "blocks": b.blocks }))
+            .collect();
+
+        json!({
+            "block_size": self.block_size,
+            "total_blocks": self.total_blocks(),
+            "largest_free_run": self.largest_free_run(),
+            "free_extent_histogram": histogram,
+            "files": files,
+            "regions": regions,
+        })
+        .to_string()
+    }
+
+    /// Export the block map as an SVG image, `columns` blocks per row
+    pub fn to_svg(&self, columns: u64) -> String {
+        const CELL: u64 = 4;
+        let columns = columns.max(1);
+        let rows = self.total_blocks().div_ceil(columns);
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" shape-rendering="crispEdges">"#,
+            columns * CELL,
+            rows * CELL
+        );
+        for region in self.regions() {
+            // Split regions at row boundaries so each piece is one rectangle
+            let mut start = region.start;
+            let end = region.start + region.length;
+            while start < end {
+                let row = start / columns;
+                let piece_end = end.min((row + 1) * columns);
+                let title = match region.info.owner {
+                    Some(owner) => format!("{} (inode@{})", region.info.kind.name(), owner),
+                    None => region.info.kind.name().to_string(),
+                };
+                let _ = writeln!(
+                    out,
+                    r#"  <rect x="{}" y="{}" width="{}" height="{}" fill="{}"><title>{} {}-{}</title></rect>"#,
+                    (start % columns) * CELL,
+                    row * CELL,
+                    (piece_end - start) * CELL,
+                    CELL,
+                    region.info.kind.fill(),
+                    title,
+                    start,
+                    piece_end - 1
+                );
+                start = piece_end;
+            }
+        }
+        out.push_str("</svg>\n");
+        out
+    }
+
+    /// Letter used for each file's data in the ASCII map
+    fn file_symbols(&self) -> HashMap<u64, char> {
+        const SYMBOLS: &[u8] = b"acdefghijklmnopqrstuvwxyzACDEFGHJKLMNOPQRTUVWXYZ0123456789";
+        self.files
+            .iter()
+            .filter(|f| f.data_blocks > 0)
+            .enumerate()
+            .map(|(i, f)| (f.inode_block, SYMBOLS[i % SYMBOLS.len()] as char))
+            .collect()
+    }
+
+    /// Most common use among `count` blocks from `start`, ignoring free blocks
+    fn dominant(&self, start: u64, count: u64) -> BlockInfo {
+        let end = (start + count).min(self.total_blocks());
+        let mut counts: HashMap<BlockInfo, u64> = HashMap::new();
+        for block in start..end {
+            let info = self.blocks[block as usize];
+            if info.kind != BlockKind::Free {
+                *counts.entry(info).or_default() += 1;
+            }
+        }
+        // Unused inode slots only show when nothing else is in the cell
+        counts
+            .into_iter()
+            .max_by_key(|&(info, n)| {
+                (info.kind != BlockKind::FreeInode, n, std::cmp::Reverse(info.owner))
+            })
+            .map(|(info, _)| info)
+            .unwrap_or(BlockInfo {
+                kind: BlockKind::Free,
+                owner: None,
+            })
+    }
+}
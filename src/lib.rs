@@ -1,7 +1,37 @@
+pub mod allocator;
+#[cfg(feature = "async")]
+pub mod async_disk;
 pub mod bitmap;
+pub mod block_group;
+mod block_io;
+pub mod device;
 pub mod block_metadata;
+pub mod buddy;
+pub mod checksum;
+pub mod compression;
+pub mod dedup;
+pub mod defrag;
+pub mod encryption;
 pub mod error;
+pub mod extent;
+pub mod faulty;
 pub mod file_operations;
+pub mod fsck;
+pub mod history;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod layout;
 pub mod metadata;
+pub mod nbd;
+pub mod path;
+pub mod quota;
+mod refcount;
+pub mod scrub;
 pub mod serialization;
+pub mod shared;
+pub mod shell;
+pub mod snapshot;
+mod table;
+pub mod tar;
+pub mod transfer;
 pub mod virtual_disk;
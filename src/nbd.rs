@@ -0,0 +1,572 @@
+use crate::{
+    error::{FsError, FsResult},
+    serialization::FileType,
+    virtual_disk::VirtualDisk,
+};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+
+// ==================== PROTOCOL CONSTANTS ====================
+//
+// Fixed newstyle handshake and simple replies, as described in the NBD
+// protocol document (https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md).
+
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943; // "NBDMAGIC"
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054; // "IHAVEOPT"
+const REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+const CLIENT_FLAG_NO_ZEROES: u32 = 1 << 1;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_ABORT: u32 = 2;
+const OPT_LIST: u32 = 3;
+const OPT_INFO: u32 = 6;
+const OPT_GO: u32 = 7;
+
+const REP_ACK: u32 = 1;
+const REP_SERVER: u32 = 2;
+const REP_INFO: u32 = 3;
+const REP_ERR_UNSUP: u32 = (1 << 31) + 1;
+const REP_ERR_INVALID: u32 = (1 << 31) + 3;
+const REP_ERR_UNKNOWN: u32 = (1 << 31) + 6;
+const INFO_EXPORT: u16 = 0;
+
+const TRANSMIT_HAS_FLAGS: u16 = 1 << 0;
+const TRANSMIT_READ_ONLY: u16 = 1 << 1;
+const TRANSMIT_SEND_FLUSH: u16 = 1 << 2;
+const TRANSMIT_SEND_FUA: u16 = 1 << 3;
+const TRANSMIT_SEND_TRIM: u16 = 1 << 5;
+const TRANSMIT_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+const CMD_TRIM: u16 = 4;
+const CMD_WRITE_ZEROES: u16 = 6;
+const CMD_FLAG_FUA: u16 = 1 << 0;
+const CMD_FLAG_NO_HOLE: u16 = 1 << 1;
+
+const EPERM: u32 = 1;
+const EIO: u32 = 5;
+const EINVAL: u32 = 22;
+const ENOSPC: u32 = 28;
+const ENOTSUP: u32 = 95;
+
+/// Largest request payload accepted, to bound memory per request
+const MAX_REQUEST_LENGTH: u32 = 32 * 1024 * 1024;
+
+/// Export name the server answers to besides the empty default name
+pub const DEFAULT_EXPORT_NAME: &str = "fssim";
+
+/// What an NBD server exposes as its block device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NbdExport {
+    /// The whole image file, byte for byte
+    ///
+    /// Writes bypass the file system, so the image should not be used
+    /// through the file system API while it is exported writable.
+    Image,
+    /// The contents of a regular file inside the image
+    ///
+    /// The device size is the file size when the client connects. Trim
+    /// punches holes in the file.
+    File(String),
+}
+
+fn io_error(message: &str) -> FsError {
+    FsError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string()))
+}
+
+/// Errno reported to the client for a failed request
+fn errno(err: &FsError) -> u32 {
+    match err {
+        e if e.is_out_of_space() => ENOSPC,
+        FsError::InvalidOffsetOrSize { .. } => EINVAL,
+        FsError::PermissionDenied(_) => EPERM,
+        FsError::NotSupported(_) => ENOTSUP,
+        _ => EIO,
+    }
+}
+
+fn read_u16(stream: &mut dyn Read) -> FsResult<u16> {
+    let mut bytes = [0u8; 2];
+    stream.read_exact(&mut bytes)?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+fn read_u32(stream: &mut dyn Read) -> FsResult<u32> {
+    let mut bytes = [0u8; 4];
+    stream.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64(stream: &mut dyn Read) -> FsResult<u64> {
+    let mut bytes = [0u8; 8];
+    stream.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn read_bytes(stream: &mut dyn Read, len: u32) -> FsResult<Vec<u8>> {
+    if len > MAX_REQUEST_LENGTH {
+        return Err(io_error("NBD request too large"));
+    }
+    let mut data = vec![0u8; len as usize];
+    stream.read_exact(&mut data)?;
+    Ok(data)
+}
+
+// ==================== SERVER ====================
+
+/// NBD server for one disk image
+///
+/// Connections are served one at a time on the calling thread. Read,
+/// write, flush, trim and write-zeroes requests are supported; trim and
+/// write-zeroes punch holes when a file is exported and are ignored or
+/// written out as zeros for the raw image.
+#[derive(Debug)]
+pub struct NbdServer {
+    disk: VirtualDisk,
+    export: NbdExport,
+    read_only: bool,
+}
+
+impl NbdServer {
+    pub fn new(disk: VirtualDisk, export: NbdExport, read_only: bool) -> Self {
+        NbdServer { disk, export, read_only }
+    }
+
+    /// Give back the disk, e.g. to check it after the clients are done
+    pub fn into_disk(self) -> VirtualDisk {
+        self.disk
+    }
+
+    /// Accept and serve TCP connections until accepting fails
+    ///
+    /// A connection that fails, e.g. because the client resets it or
+    /// sends a malformed request, only ends that connection.
+    pub fn serve_tcp(&mut self, listener: &TcpListener) -> FsResult<()> {
+        for stream in listener.incoming() {
+            let mut stream = stream?;
+            if stream.set_nodelay(true).is_ok() {
+                let _ = self.serve_connection(&mut stream);
+            }
+        }
+        Ok(())
+    }
+
+    /// Accept and serve Unix socket connections until accepting fails
+    ///
+    /// A connection that fails only ends that connection.
+    #[cfg(unix)]
+    pub fn serve_unix(&mut self, listener: &UnixListener) -> FsResult<()> {
+        for stream in listener.incoming() {
+            let _ = self.serve_connection(&mut stream?);
+        }
+        Ok(())
+    }
+
+    /// Run the handshake and transmission phases on one connection
+    ///
+    /// Returns when the client disconnects. A client that drops the
+    /// connection without a disconnect request is not an error.
+    pub fn serve_connection<S: Read + Write>(&mut self, stream: &mut S) -> FsResult<()> {
+        let result = match self.handshake(stream) {
+            Ok(Some(size)) => self.transmission(stream, size),
+            other => other.map(|_| ()),
+        };
+        match result {
+            Err(FsError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Size of the exported device, failing if the export is not usable
+    fn export_size(&mut self) -> FsResult<u64> {
+        match &self.export {
+            NbdExport::Image => Ok(self.disk.image_size()),
+            NbdExport::File(path) => {
+                let inode_block = self.disk.lookup_path(path)?;
+                let inode = self.disk.read_inode(inode_block)?;
+                if inode.file_type != FileType::File {
+                    return Err(FsError::NotAFile(path.clone()));
+                }
+                Ok(inode.size)
+            }
+        }
+    }
+
+    fn transmission_flags(&self) -> u16 {
+        let mut flags = TRANSMIT_HAS_FLAGS | TRANSMIT_SEND_FLUSH | TRANSMIT_SEND_FUA;
+        if self.read_only {
+            flags |= TRANSMIT_READ_ONLY;
+        } else {
+            flags |= TRANSMIT_SEND_TRIM | TRANSMIT_SEND_WRITE_ZEROES;
+        }
+        flags
+    }
+
+    /// Negotiate options; returns the device size once the client picks
+    /// the export, or `None` if it aborts
+    fn handshake<S: Read + Write>(&mut self, stream: &mut S) -> FsResult<Option<u64>> {
+        stream.write_all(&NBD_MAGIC.to_be_bytes())?;
+        stream.write_all(&IHAVEOPT.to_be_bytes())?;
+        stream.write_all(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes())?;
+        stream.flush()?;
+        let no_zeroes = read_u32(stream)? & CLIENT_FLAG_NO_ZEROES != 0;
+
+        loop {
+            if read_u64(stream)? != IHAVEOPT {
+                return Err(io_error("Bad NBD option magic"));
+            }
+            let option = read_u32(stream)?;
+            let length = read_u32(stream)?;
+            let data = read_bytes(stream, length)?;
+
+            match option {
+                OPT_EXPORT_NAME => {
+                    let name = String::from_utf8_lossy(&data);
+                    if !is_export_name(&name) {
+                        // The old-style option has no way to report errors
+                        return Ok(None);
+                    }
+                    let size = self.export_size()?;
+                    stream.write_all(&size.to_be_bytes())?;
+                    stream.write_all(&self.transmission_flags().to_be_bytes())?;
+                    if !no_zeroes {
+                        stream.write_all(&[0u8; 124])?;
+                    }
+                    stream.flush()?;
+                    return Ok(Some(size));
+                }
+                OPT_ABORT => {
+                    send_option_reply(stream, option, REP_ACK, &[])?;
+                    return Ok(None);
+                }
+                OPT_LIST => {
+                    let name = DEFAULT_EXPORT_NAME.as_bytes();
+                    let mut reply = (name.len() as u32).to_be_bytes().to_vec();
+                    reply.extend_from_slice(name);
+                    send_option_reply(stream, option, REP_SERVER, &reply)?;
+                    send_option_reply(stream, option, REP_ACK, &[])?;
+                }
+                OPT_INFO | OPT_GO => {
+                    let Some(name) = parse_info_request(&data) else {
+                        send_option_reply(stream, option, REP_ERR_INVALID, &[])?;
+                        continue;
+                    };
+                    if !is_export_name(&name) {
+                        send_option_reply(stream, option, REP_ERR_UNKNOWN, &[])?;
+                        continue;
+                    }
+                    let size = match self.export_size() {
+                        Ok(size) => size,
+                        Err(e) => {
+                            let message = e.to_string();
+                            send_option_reply(stream, option, REP_ERR_UNKNOWN, message.as_bytes())?;
+                            continue;
+                        }
+                    };
+                    let mut info = INFO_EXPORT.to_be_bytes().to_vec();
+                    info.extend_from_slice(&size.to_be_bytes());
+                    info.extend_from_slice(&self.transmission_flags().to_be_bytes());
+                    send_option_reply(stream, option, REP_INFO, &info)?;
+                    send_option_reply(stream, option, REP_ACK, &[])?;
+                    if option == OPT_GO {
+                        return Ok(Some(size));
+                    }
+                }
+                _ => send_option_reply(stream, option, REP_ERR_UNSUP, &[])?,
+            }
+        }
+    }
+
+    fn transmission<S: Read + Write>(&mut self, stream: &mut S, size: u64) -> FsResult<()> {
+        let inode_block = match &self.export {
+            NbdExport::Image => None,
+            NbdExport::File(path) => Some(self.disk.lookup_path(path)?),
+        };
+
+        loop {
+            if read_u32(stream)? != REQUEST_MAGIC {
+                return Err(io_error("Bad NBD request magic"));
+            }
+            let flags = read_u16(stream)?;
+            let command = read_u16(stream)?;
+            let handle = read_u64(stream)?;
+            let offset = read_u64(stream)?;
+            let length = read_u32(stream)?;
+            let payload = if command == CMD_WRITE { read_bytes(stream, length)? } else { Vec::new() };
+
+            let in_range = offset.checked_add(u64::from(length)).is_some_and(|end| end <= size);
+            let writes = matches!(command, CMD_WRITE | CMD_TRIM | CMD_WRITE_ZEROES);
+            let result = if command == CMD_DISC {
+                self.disk.sync()?;
+                return Ok(());
+            } else if writes && self.read_only {
+                Err(EPERM)
+            } else if matches!(command, CMD_READ | CMD_WRITE | CMD_TRIM | CMD_WRITE_ZEROES) && !in_range {
+                Err(if command == CMD_READ { EINVAL } else { ENOSPC })
+            } else if command == CMD_READ && length > MAX_REQUEST_LENGTH {
+                Err(EINVAL)
+            } else {
+                self.execute(inode_block, command, flags, offset, length, &payload)
+                    .map_err(|e| errno(&e))
+            };
+
+            stream.write_all(&SIMPLE_REPLY_MAGIC.to_be_bytes())?;
+            match result {
+                Ok(data) => {
+                    stream.write_all(&0u32.to_be_bytes())?;
+                    stream.write_all(&handle.to_be_bytes())?;
+                    stream.write_all(&data)?;
+                }
+                Err(code) => {
+                    stream.write_all(&code.to_be_bytes())?;
+                    stream.write_all(&handle.to_be_bytes())?;
+                }
+            }
+            stream.flush()?;
+        }
+    }
+
+    /// Carry out one request; returns the data to send back
+    fn execute(
+        &mut self,
+        inode_block: Option<u64>,
+        command: u16,
+        flags: u16,
+        offset: u64,
+        length: u32,
+        payload: &[u8],
+    ) -> FsResult<Vec<u8>> {
+        match command {
+            CMD_READ => {
+                let mut data = vec![0u8; length as usize];
+                match inode_block {
+                    None => self.disk.read_raw(offset, &mut data)?,
+                    // Reads past the current end of a shrunk file stay zero
+                    Some(inode_block) => {
+                        self.disk.read_at(inode_block, offset, &mut data)?;
+                    }
+                }
+                return Ok(data);
+            }
+            CMD_WRITE => match inode_block {
+                None => self.disk.write_raw(offset, payload)?,
+                Some(inode_block) => self.disk.write_at(inode_block, offset, payload)?,
+            },
+            CMD_FLUSH => self.disk.sync()?,
+            CMD_TRIM => {
+                // Trim is advisory; there is nothing to deallocate in the raw image
+                if let Some(inode_block) = inode_block {
+                    self.disk.punch_hole(inode_block, offset, u64::from(length))?;
+                }
+            }
+            CMD_WRITE_ZEROES => match inode_block {
+                Some(inode_block) if flags & CMD_FLAG_NO_HOLE == 0 => {
+                    self.disk.punch_hole(inode_block, offset, u64::from(length))?
+                }
+                Some(inode_block) => {
+                    self.disk.write_at(inode_block, offset, &vec![0u8; length as usize])?
+                }
+                None => self.disk.write_raw(offset, &vec![0u8; length as usize])?,
+            },
+            _ => return Err(FsError::NotSupported(format!("NBD command {}", command))),
+        }
+        if flags & CMD_FLAG_FUA != 0 {
+            self.disk.sync()?;
+        }
+        Ok(Vec::new())
+    }
+}
+
+fn is_export_name(name: &str) -> bool {
+    name.is_empty() || name == DEFAULT_EXPORT_NAME
+}
+
+/// Export name from an NBD_OPT_INFO or NBD_OPT_GO payload
+fn parse_info_request(data: &[u8]) -> Option<String> {
+    let len = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let name = data.get(4..4 + len)?;
+    let requests = u16::from_be_bytes(data.get(4 + len..6 + len)?.try_into().ok()?) as usize;
+    if data.len() != 6 + len + 2 * requests {
+        return None;
+    }
+    Some(String::from_utf8_lossy(name).into_owned())
+}
+
+fn send_option_reply(stream: &mut dyn Write, option: u32, reply: u32, data: &[u8]) -> FsResult<()> {
+    stream.write_all(&REPLY_MAGIC.to_be_bytes())?;
+    stream.write_all(&option.to_be_bytes())?;
+    stream.write_all(&reply.to_be_bytes())?;
+    stream.write_all(&(data.len() as u32).to_be_bytes())?;
+    stream.write_all(data)?;
+    stream.flush()?;
+    Ok(())
+}
+
+// ==================== CLIENT ====================
+
+/// Minimal NBD client, for driving an `NbdServer` from tests and tools
+///
+/// Requests are sent one at a time and wait for their reply. A request
+/// the server rejects fails with an I/O error carrying the errno.
+#[derive(Debug)]
+pub struct NbdClient<S: Read + Write> {
+    stream: S,
+    size: u64,
+    flags: u16,
+    next_handle: u64,
+}
+
+impl NbdClient<TcpStream> {
+    pub fn connect_tcp(addr: impl ToSocketAddrs) -> FsResult<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Self::new(stream)
+    }
+}
+
+#[cfg(unix)]
+impl NbdClient<UnixStream> {
+    pub fn connect_unix(path: impl AsRef<Path>) -> FsResult<Self> {
+        Self::new(UnixStream::connect(path)?)
+    }
+}
+
+impl<S: Read + Write> NbdClient<S> {
+    /// Run the handshake over `stream` and select the default export
+    pub fn new(mut stream: S) -> FsResult<Self> {
+        if read_u64(&mut stream)? != NBD_MAGIC || read_u64(&mut stream)? != IHAVEOPT {
+            return Err(io_error("Not a newstyle NBD server"));
+        }
+        let server_flags = read_u16(&mut stream)?;
+        if server_flags & FLAG_FIXED_NEWSTYLE == 0 {
+            return Err(io_error("NBD server does not support fixed newstyle"));
+        }
+        stream.write_all(&1u32.to_be_bytes())?;
+
+        // NBD_OPT_GO with the default name and no extra information requests
+        let mut data = 0u32.to_be_bytes().to_vec();
+        data.extend_from_slice(&0u16.to_be_bytes());
+        stream.write_all(&IHAVEOPT.to_be_bytes())?;
+        stream.write_all(&OPT_GO.to_be_bytes())?;
+        stream.write_all(&(data.len() as u32).to_be_bytes())?;
+        stream.write_all(&data)?;
+        stream.flush()?;
+
+        let mut export = None;
+        loop {
+            if read_u64(&mut stream)? != REPLY_MAGIC || read_u32(&mut stream)? != OPT_GO {
+                return Err(io_error("Bad NBD option reply"));
+            }
+            let reply = read_u32(&mut stream)?;
+            let length = read_u32(&mut stream)?;
+            let data = read_bytes(&mut stream, length)?;
+            match reply {
+                REP_INFO if data.len() >= 12 && data[..2] == INFO_EXPORT.to_be_bytes() => {
+                    let size = u64::from_be_bytes(data[2..10].try_into().unwrap());
+                    let flags = u16::from_be_bytes(data[10..12].try_into().unwrap());
+                    export = Some((size, flags));
+                }
+                REP_INFO => {}
+                REP_ACK => break,
+                _ => {
+                    return Err(io_error(&format!(
+                        "NBD server refused the export: {}",
+                        String::from_utf8_lossy(&data)
+                    )))
+                }
+            }
+        }
+
+        let (size, flags) = export.ok_or_else(|| io_error("NBD server sent no export size"))?;
+        Ok(NbdClient { stream, size, flags, next_handle: 1 })
+    }
+
+    /// Size of the device in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// True if the server refuses writes
+    pub fn is_read_only(&self) -> bool {
+        self.flags & TRANSMIT_READ_ONLY != 0
+    }
+
+    pub fn read(&mut self, offset: u64, len: u32) -> FsResult<Vec<u8>> {
+        self.request(CMD_READ, 0, offset, len, &[])?;
+        let mut data = vec![0u8; len as usize];
+        self.stream.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> FsResult<()> {
+        self.request(CMD_WRITE, 0, offset, data.len() as u32, data)
+    }
+
+    /// Write and wait until the data is on stable storage
+    pub fn write_fua(&mut self, offset: u64, data: &[u8]) -> FsResult<()> {
+        self.request(CMD_WRITE, CMD_FLAG_FUA, offset, data.len() as u32, data)
+    }
+
+    pub fn flush(&mut self) -> FsResult<()> {
+        self.request(CMD_FLUSH, 0, 0, 0, &[])
+    }
+
+    pub fn trim(&mut self, offset: u64, len: u32) -> FsResult<()> {
+        self.request(CMD_TRIM, 0, offset, len, &[])
+    }
+
+    pub fn write_zeroes(&mut self, offset: u64, len: u32) -> FsResult<()> {
+        self.request(CMD_WRITE_ZEROES, 0, offset, len, &[])
+    }
+
+    /// Tell the server to close the connection
+    pub fn disconnect(mut self) -> FsResult<()> {
+        self.send(CMD_DISC, 0, 0, 0, &[])?;
+        Ok(())
+    }
+
+    fn send(&mut self, command: u16, flags: u16, offset: u64, len: u32, data: &[u8]) -> FsResult<u64> {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+
+        let mut request = Vec::with_capacity(28 + data.len());
+        request.extend_from_slice(&REQUEST_MAGIC.to_be_bytes());
+        request.extend_from_slice(&flags.to_be_bytes());
+        request.extend_from_slice(&command.to_be_bytes());
+        request.extend_from_slice(&handle.to_be_bytes());
+        request.extend_from_slice(&offset.to_be_bytes());
+        request.extend_from_slice(&len.to_be_bytes());
+        request.extend_from_slice(data);
+        self.stream.write_all(&request)?;
+        self.stream.flush()?;
+        Ok(handle)
+    }
+
+    /// Send a request and check the reply header
+    fn request(&mut self, command: u16, flags: u16, offset: u64, len: u32, data: &[u8]) -> FsResult<()> {
+        let handle = self.send(command, flags, offset, len, data)?;
+        if read_u32(&mut self.stream)? != SIMPLE_REPLY_MAGIC {
+            return Err(io_error("Bad NBD reply magic"));
+        }
+        let error = read_u32(&mut self.stream)?;
+        if read_u64(&mut self.stream)? != handle {
+            return Err(io_error("NBD reply for an unexpected request"));
+        }
+        if error != 0 {
+            return Err(FsError::Io(std::io::Error::from_raw_os_error(error as i32)));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,510 @@
+use crate::{
+    error::{FsError, FsResult},
+    serialization::{DirectoryEntry, FileType, Inode, Permissions},
+    virtual_disk::VirtualDisk,
+};
+use std::collections::VecDeque;
+
+/// Maximum number of symlinks followed while resolving one path
+const MAX_SYMLINK_DEPTH: usize = 40;
+
+/// Split a path into its non-empty components
+pub(crate) fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/').filter(|c| !c.is_empty())
+}
+
+/// Split a path into its parent path and final component
+pub(crate) fn split_parent(path: &str) -> FsResult<(&str, &str)> {
+    let trimmed = path.trim_end_matches('/');
+    let (parent, name) = match trimmed.rfind('/') {
+        Some(i) => (&trimmed[..i], &trimmed[i + 1..]),
+        None => ("", trimmed),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidPath(path.to_string()));
+    }
+    Ok((parent, name))
+}
+
+/// Join a directory path and a name
+pub(crate) fn join(dir: &str, name: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), name)
+}
+
+/// Result of resolving a path
+pub(crate) struct Resolved {
+    /// Inode blocks from the root down to the resolved inode
+    pub(crate) inodes: Vec<u64>,
+    /// Names of the components below the root
+    pub(crate) names: Vec<String>,
+}
+
+impl Resolved {
+    /// The inode block the path resolved to
+    pub(crate) fn inode(&self) -> u64 {
+        *self.inodes.last().unwrap()
+    }
+
+    /// The directory holding the resolved entry, or `None` for the root
+    pub(crate) fn parent(&self) -> Option<u64> {
+        self.inodes.len().checked_sub(2).map(|i| self.inodes[i])
+    }
+}
+
+/// Directory and symlink reads used to resolve a path
+pub(crate) type FindEntry<'a> = &'a dyn Fn(u64, &str) -> FsResult<DirectoryEntry>;
+pub(crate) type ReadLink<'a> = &'a dyn Fn(u64) -> FsResult<String>;
+
+impl VirtualDisk {
+    // ==================== PATH RESOLUTION ====================
+    //
+    // Paths are resolved from the root directory recorded in the superblock,
+    // so relative paths are treated as relative to the root. Directory
+    // entries created here store the inode block as their inode number, and
+    // the inode's own `inode_number` is set to the same value.
+
+    /// Get the inode block at `path`, following symlinks
+    pub fn lookup_path(&self, path: &str) -> FsResult<u64> {
+        Ok(self.resolve(path, true)?.inode())
+    }
+
+    /// Get the inode block at `path`, without following a final symlink
+    pub fn lookup_path_nofollow(&self, path: &str) -> FsResult<u64> {
+        Ok(self.resolve(path, false)?.inode())
+    }
+
+    /// Get the absolute path of `path` with `.`, `..` and symlinks resolved
+    pub fn canonicalize_path(&self, path: &str) -> FsResult<String> {
+        let resolved = self.resolve(path, true)?;
+        Ok(format!("/{}", resolved.names.join("/")))
+    }
+
+    /// Walk `path` from the root directory
+    ///
+    /// Symlinks in the middle of the path are always followed; the final
+    /// component is followed only with `follow_last`. `..` moves to the
+    /// parent of the directory actually reached, so it undoes symlinks.
+    fn resolve(&self, path: &str, follow_last: bool) -> FsResult<Resolved> {
+        self.resolve_with(
+            path,
+            follow_last,
+            &|dir, name| self.find_directory_entry(dir, name),
+            &|inode_block| self.read_link_inode(inode_block),
+        )
+    }
+
+    /// Walk `path` like `resolve`, reading directories and symlinks through
+    /// `find` and `read_link`
+    pub(crate) fn resolve_with(
+        &self,
+        path: &str,
+        follow_last: bool,
+        find: FindEntry,
+        read_link: ReadLink,
+    ) -> FsResult<Resolved> {
+        let root = self
+            .root_directory()
+            .ok_or_else(|| FsError::DirectoryNotFound("/".to_string()))?;
+        let mut resolved = Resolved {
+            inodes: vec![root],
+            names: Vec::new(),
+        };
+        let mut pending: VecDeque<String> = components(path).map(str::to_string).collect();
+        let mut links = 0;
+
+        while let Some(name) = pending.pop_front() {
+            match name.as_str() {
+                "." => continue,
+                ".." => {
+                    if resolved.names.pop().is_some() {
+                        resolved.inodes.pop();
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+
+            let dir = *resolved.inodes.last().unwrap();
+            let entry = match find(dir, &name) {
+                Ok(entry) => entry,
+                Err(FsError::FileNotFound(_)) => return Err(FsError::FileNotFound(path.to_string())),
+                Err(FsError::NotADirectory(_)) => return Err(FsError::NotADirectory(path.to_string())),
+                Err(e) => return Err(e),
+            };
+
+            if entry.file_type == FileType::Symlink && (follow_last || !pending.is_empty()) {
+                links += 1;
+                if links > MAX_SYMLINK_DEPTH {
+                    return Err(FsError::InvalidPath(format!(
+                        "Too many levels of symbolic links: {}",
+                        path
+                    )));
+                }
+                let target = read_link(entry.inode_number)?;
+                if target.starts_with('/') {
+                    resolved.inodes.truncate(1);
+                    resolved.names.clear();
+                }
+                for component in components(&target).rev() {
+                    pending.push_front(component.to_string());
+                }
+            } else {
+                resolved.inodes.push(entry.inode_number);
+                resolved.names.push(name);
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    /// Resolve the parent directory of `path` and return it with the final name
+    fn resolve_parent<'a>(&self, path: &'a str) -> FsResult<(u64, &'a str)> {
+        let (parent, name) = split_parent(path)?;
+        let dir = self.lookup_path(parent)?;
+        if self.read_inode(dir)?.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory(parent.to_string()));
+        }
+        Ok((dir, name))
+    }
+
+    /// Check that `name` does not exist in `dir`
+    fn ensure_absent(&self, dir: u64, name: &str, path: &str) -> FsResult<()> {
+        match self.find_directory_entry(dir, name) {
+            Ok(_) => Err(FsError::AlreadyExists(path.to_string())),
+            Err(FsError::FileNotFound(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Link a newly created inode into `dir` under `name`
+    ///
+    /// The inode number is set to the inode block so that directory entries
+    /// can be followed directly, and a new directory inherits compression
+    /// from `dir`. On failure the inode is freed again.
+    pub(crate) fn link_new_inode(&self, dir: u64, name: &str, inode_block: u64) -> FsResult<u64> {
+        let mut inode = self.read_inode(inode_block)?;
+        inode.inode_number = inode_block;
+        if inode.file_type == FileType::Directory && self.read_inode(dir)?.has_flag(Inode::FLAG_COMPRESS) {
+            inode.flags |= Inode::FLAG_COMPRESS;
+        }
+        self.write_inode(inode_block, &inode)?;
+
+        let entry = DirectoryEntry::new(inode_block, inode.file_type, name.to_string())
+            .and_then(|entry| self.add_directory_entry(dir, entry));
+        if let Err(e) = entry {
+            match inode.file_type {
+                FileType::Directory => self.delete_directory(inode_block)?,
+                _ => self.delete_file(inode_block)?,
+            }
+            return Err(e);
+        }
+        Ok(inode_block)
+    }
+
+    // ==================== PATH OPERATIONS ====================
+
+    /// Create an empty file at `path`
+    pub fn create_file_at(&self, path: &str, permissions: Permissions) -> FsResult<u64> {
+        let (dir, name) = self.resolve_parent(path)?;
+        self.ensure_absent(dir, name, path)?;
+        let inode_block = self.create_file_in(dir, 0, permissions)?;
+        self.link_new_inode(dir, name, inode_block)
+    }
+
+    /// Create a directory at `path`
+    pub fn create_directory_at(&self, path: &str, permissions: Permissions) -> FsResult<u64> {
+        let (dir, name) = self.resolve_parent(path)?;
+        self.ensure_absent(dir, name, path)?;
+        let inode_block = self.create_directory_in(dir, 0, permissions)?;
+        self.link_new_inode(dir, name, inode_block)
+    }
+
+    /// Create a directory and any missing parents, like `mkdir -p`
+    ///
+    /// Existing directories along the path are left alone.
+    pub fn create_directories(&self, path: &str, permissions: Permissions) -> FsResult<u64> {
+        let mut current = String::new();
+        let mut inode_block = self.lookup_path("/")?;
+        for name in components(path) {
+            current = join(&current, name);
+            inode_block = match self.lookup_path(&current) {
+                Ok(block) if self.read_inode(block)?.file_type == FileType::Directory => block,
+                Ok(_) => return Err(FsError::NotADirectory(current)),
+                Err(FsError::FileNotFound(_)) => self.create_directory_at(&current, permissions)?,
+                Err(e) => return Err(e),
+            };
+        }
+        Ok(inode_block)
+    }
+
+    /// Create a symlink at `link_path` pointing to `target`
+    ///
+    /// The target is stored as-is and is not required to exist.
+    pub fn create_symlink(&self, target: &str, link_path: &str) -> FsResult<u64> {
+        if target.is_empty() {
+            return Err(FsError::InvalidPath("Empty symlink target".to_string()));
+        }
+        let (dir, name) = self.resolve_parent(link_path)?;
+        self.ensure_absent(dir, name, link_path)?;
+
+        let group = self.group_of(dir);
+        let perms = Permissions::new(true, true, true);
+        let project = self.read_inode(dir)?.project;
+        let inode_block = self.create_inode_in_group(0, FileType::Symlink, perms, group, project)?;
+        let inode = self.read_inode(inode_block)?;
+        self.write_inode_data(inode_block, inode, target.as_bytes())?;
+        self.link_new_inode(dir, name, inode_block)
+    }
+
+    /// Create a hard link at `link_path` to the file or symlink at `existing`
+    ///
+    /// Both names refer to the same inode afterwards; its data is only freed
+    /// when the last name is removed. Directories cannot be hard linked.
+    pub fn create_hard_link(&self, existing: &str, link_path: &str) -> FsResult<u64> {
+        let inode_block = self.lookup_path_nofollow(existing)?;
+        let mut inode = self.read_inode(inode_block)?;
+        if inode.file_type == FileType::Directory {
+            return Err(FsError::NotAFile(format!("Cannot hard link directory {}", existing)));
+        }
+        if inode.link_count == u16::MAX {
+            return Err(FsError::NotSupported(format!("Too many links to {}", existing)));
+        }
+        let (dir, name) = self.resolve_parent(link_path)?;
+        self.ensure_absent(dir, name, link_path)?;
+        self.check_project(dir, inode_block, existing)?;
+
+        let entry = DirectoryEntry::new(inode_block, inode.file_type, name.to_string())?;
+        self.add_directory_entry(dir, entry)?;
+        inode.link_count += 1;
+        self.write_inode(inode_block, &inode)?;
+        Ok(inode_block)
+    }
+
+    /// Read the target of the symlink at `path`
+    pub fn read_link(&self, path: &str) -> FsResult<String> {
+        let inode_block = self.lookup_path_nofollow(path)?;
+        self.read_link_inode(inode_block)
+    }
+
+    pub(crate) fn read_link_inode(&self, inode_block: u64) -> FsResult<String> {
+        let inode = self.read_inode(inode_block)?;
+        if inode.file_type != FileType::Symlink {
+            return Err(FsError::InvalidPath(format!(
+                "Inode {} is not a symlink",
+                inode.inode_number
+            )));
+        }
+        Ok(String::from_utf8(self.read_inode_data(&inode)?)?)
+    }
+
+    /// Get the inode at `path`, without following a final symlink
+    pub fn stat_path(&self, path: &str) -> FsResult<Inode> {
+        let inode_block = self.lookup_path_nofollow(path)?;
+        self.read_inode(inode_block)
+    }
+
+    /// List the directory at `path`
+    pub fn list_directory_at(&self, path: &str) -> FsResult<Vec<DirectoryEntry>> {
+        let inode_block = self.lookup_path(path)?;
+        self.list_directory(inode_block)
+    }
+
+    /// Read the whole file at `path`
+    pub fn read_file_at(&self, path: &str) -> FsResult<Vec<u8>> {
+        let inode_block = self.lookup_path(path)?;
+        self.read_file(inode_block)
+    }
+
+    /// Replace the contents of the file at `path`, creating it if needed
+    ///
+    /// See `write_file` for the history kept of the old contents.
+    pub fn write_file_at(&self, path: &str, data: &[u8]) -> FsResult<u64> {
+        let inode_block = match self.lookup_path(path) {
+            Ok(block) => block,
+            Err(FsError::FileNotFound(_)) => {
+                self.reclaiming(|disk| disk.create_file_at(path, Permissions::new(true, true, false)))?
+            }
+            Err(e) => return Err(e),
+        };
+        self.write_file(inode_block, data)?;
+        Ok(inode_block)
+    }
+
+    /// Append to the file at `path`, creating it if needed
+    pub fn append_file_at(&self, path: &str, data: &[u8]) -> FsResult<u64> {
+        let mut contents = match self.read_file_at(path) {
+            Ok(contents) => contents,
+            Err(FsError::FileNotFound(_)) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        contents.extend_from_slice(data);
+        self.write_file_at(path, &contents)
+    }
+
+    /// Remove the file, symlink or empty directory at `path`
+    ///
+    /// See `unlink_entry` for the history kept of removed files.
+    pub fn remove_path(&self, path: &str) -> FsResult<()> {
+        let (dir, name) = self.resolve_parent(path)?;
+        let entry = self.find_directory_entry(dir, name)
+            .map_err(|_| FsError::FileNotFound(path.to_string()))?;
+        self.unlink_entry(dir, name, &entry, path)
+    }
+
+    /// Remove `entry`, named `name` in `dir` and reached through `path`,
+    /// and free what it refers to
+    ///
+    /// Directories must be empty. A file or symlink is only deleted when
+    /// its last name goes away. If the image keeps history (see
+    /// `HistoryPolicy`), it is moved to the trash instead, unless there
+    /// is no room left for it there.
+    pub(crate) fn unlink_entry(&self, dir: u64, name: &str, entry: &DirectoryEntry, path: &str) -> FsResult<()> {
+        if entry.file_type == FileType::Directory {
+            let entries = self.list_directory(entry.inode_number)?;
+            if !entries.is_empty() {
+                return Err(FsError::DirectoryNotEmpty(path.to_string()));
+            }
+            self.remove_directory_entry(dir, name)?;
+            self.delete_directory(entry.inode_number)
+        } else {
+            let mut inode = self.read_inode(entry.inode_number)?;
+            if inode.link_count <= 1 && self.keeps_history(&inode) {
+                match self.reclaiming(|disk| disk.move_to_trash(dir, name, entry, path)) {
+                    Ok(()) => return self.prune_history(),
+                    Err(e) if e.is_out_of_space() => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            self.remove_directory_entry(dir, name)?;
+            if inode.link_count > 1 {
+                // Other names still refer to the inode
+                inode.link_count -= 1;
+                self.write_inode(entry.inode_number, &inode)
+            } else {
+                self.delete_file(entry.inode_number)
+            }
+        }
+    }
+
+    /// Remove `path` and, for directories, everything below it
+    ///
+    /// Symlinks are removed, not followed.
+    pub fn remove_tree(&self, path: &str) -> FsResult<()> {
+        let inode = self.stat_path(path)?;
+        if inode.file_type == FileType::Directory {
+            for entry in self.list_directory_at(path)? {
+                self.remove_tree(&join(path, &entry.name))?;
+            }
+        }
+        self.remove_path(path)
+    }
+
+    /// Move or rename `from` to `to`
+    ///
+    /// If `to` is an existing directory, `from` is moved into it. An
+    /// existing file at the destination is replaced; a directory can only
+    /// replace an empty directory. A file or symlink moved into another
+    /// project is charged to it from then on; directories and files with
+    /// several names cannot change project (see `entering_project`).
+    pub fn rename_path(&self, from: &str, to: &str) -> FsResult<()> {
+        let (from_dir, from_name) = self.resolve_parent(from)?;
+        let entry = self.find_directory_entry(from_dir, from_name)
+            .map_err(|_| FsError::FileNotFound(from.to_string()))?;
+
+        let to = self.destination_path(to, from_name)?;
+        let (to_dir, to_name) = self.resolve_parent(&to)?;
+
+        // A directory must not be moved below itself
+        if entry.file_type == FileType::Directory {
+            let source = self.canonicalize_path(from)?;
+            let target_dir = self.canonicalize_path(split_parent(&to)?.0)?;
+            if target_dir == source || target_dir.starts_with(&format!("{}/", source)) {
+                return Err(FsError::InvalidPath(format!(
+                    "Cannot move {} into itself",
+                    from
+                )));
+            }
+        }
+
+        let replaces = match self.find_directory_entry(to_dir, to_name) {
+            Ok(existing) if existing.inode_number == entry.inode_number => return Ok(()),
+            Ok(existing) => {
+                if (existing.file_type == FileType::Directory) != (entry.file_type == FileType::Directory) {
+                    return Err(FsError::AlreadyExists(to));
+                }
+                true
+            }
+            Err(FsError::FileNotFound(_)) => false,
+            Err(e) => return Err(e),
+        };
+        // A file moved into another project takes its charge along
+        let project = self.entering_project(from_dir, to_dir, entry.inode_number, from)?;
+        if replaces {
+            self.remove_path(&to)?;
+        }
+
+        let moved = DirectoryEntry::new(entry.inode_number, entry.file_type, to_name.to_string())?;
+        self.add_directory_entry(to_dir, moved)?;
+        self.remove_directory_entry(from_dir, from_name)?;
+        if let Some(project) = project {
+            self.set_project(entry.inode_number, project)?;
+        }
+        Ok(())
+    }
+
+    /// Copy `from` to `to`, recursing into directories
+    ///
+    /// If `to` is an existing directory, the copy is placed inside it.
+    /// Files are cloned, sharing their data blocks with the original until
+    /// either is written (see `clone_file`). Symlinks are copied as
+    /// symlinks.
+    pub fn copy_path(&self, from: &str, to: &str) -> FsResult<()> {
+        let inode = self.stat_path(from)?;
+        let (_, name) = split_parent(from)?;
+        let to = self.destination_path(to, name)?;
+
+        match inode.file_type {
+            FileType::File => {
+                let source = self.lookup_path(from)?;
+                let (inode_block, kept) = match self.lookup_path(&to) {
+                    Ok(block) => (block, self.reclaiming(|disk| disk.keep_version(block))?),
+                    Err(FsError::FileNotFound(_)) => (self.create_file_at(&to, inode.permissions)?, false),
+                    Err(e) => return Err(e),
+                };
+                self.reclaiming(|disk| disk.clone_file(source, inode_block))?;
+                if kept {
+                    self.prune_history()?;
+                }
+                Ok(())
+            }
+            FileType::Symlink => {
+                let target = self.read_link(from)?;
+                self.create_symlink(&target, &to).map(|_| ())
+            }
+            FileType::Directory => {
+                let source = self.canonicalize_path(from)?;
+                let target_parent = self.canonicalize_path(split_parent(&to)?.0)?;
+                if target_parent == source || target_parent.starts_with(&format!("{}/", source)) {
+                    return Err(FsError::InvalidPath(format!(
+                        "Cannot copy {} into itself",
+                        from
+                    )));
+                }
+                self.create_directory_at(&to, inode.permissions)?;
+                for entry in self.list_directory_at(from)? {
+                    self.copy_path(&join(from, &entry.name), &join(&to, &entry.name))?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Destination for `mv`/`cp`: inside `to` if it is a directory
+    fn destination_path(&self, to: &str, name: &str) -> FsResult<String> {
+        match self.lookup_path(to) {
+            Ok(block) if self.read_inode(block)?.file_type == FileType::Directory => Ok(join(to, name)),
+            Ok(_) | Err(FsError::FileNotFound(_)) => Ok(to.to_string()),
+            Err(e) => Err(e),
+        }
+    }
+}
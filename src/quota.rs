@@ -0,0 +1,921 @@
+use crate::{
+    error::{FsError, FsResult},
+    path::join,
+    serialization::{FileType, Inode, Permissions, Superblock},
+    table::{Reader, TableFormat},
+    virtual_disk::VirtualDisk,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::thread::{self, ThreadId};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Grace period of a new quota file, for blocks and inodes alike: a week
+pub const DEFAULT_GRACE_PERIOD: u64 = 7 * 24 * 60 * 60;
+
+/// Who a quota limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum QuotaKind {
+    /// Everything owned by a user id
+    User,
+    /// Everything owned by a group id
+    Group,
+    /// Everything with a project id, usually a directory tree: new inodes
+    /// take the project of the directory they are created in
+    Project,
+}
+
+impl QuotaKind {
+    pub const ALL: [QuotaKind; 3] = [QuotaKind::User, QuotaKind::Group, QuotaKind::Project];
+
+    /// Lowercase name, as used in messages and by `fssim`
+    pub fn name(self) -> &'static str {
+        match self {
+            QuotaKind::User => "user",
+            QuotaKind::Group => "group",
+            QuotaKind::Project => "project",
+        }
+    }
+
+    /// The id `inode` is charged to under this kind of quota
+    pub fn id_of(self, inode: &Inode) -> u32 {
+        match self {
+            QuotaKind::User => inode.uid,
+            QuotaKind::Group => inode.gid,
+            QuotaKind::Project => inode.project,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Limits of one id; 0 leaves a limit off
+///
+/// A soft limit may be exceeded for the grace period of the quota file;
+/// once that runs out, it is enforced like a hard limit until usage drops
+/// to it again. Hard limits are never exceeded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaLimits {
+    pub block_soft: u64,
+    pub block_hard: u64,
+    pub inode_soft: u64,
+    pub inode_hard: u64,
+}
+
+impl QuotaLimits {
+    /// True if no limit is set
+    pub fn is_unlimited(&self) -> bool {
+        *self == QuotaLimits::default()
+    }
+
+    fn soft(&self, resource: Resource) -> u64 {
+        match resource {
+            Resource::Blocks => self.block_soft,
+            Resource::Inodes => self.inode_soft,
+        }
+    }
+
+    fn hard(&self, resource: Resource) -> u64 {
+        match resource {
+            Resource::Blocks => self.block_hard,
+            Resource::Inodes => self.inode_hard,
+        }
+    }
+}
+
+/// How long soft limits of one kind may be exceeded, in seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GracePeriods {
+    pub blocks: u64,
+    pub inodes: u64,
+}
+
+impl Default for GracePeriods {
+    fn default() -> Self {
+        GracePeriods { blocks: DEFAULT_GRACE_PERIOD, inodes: DEFAULT_GRACE_PERIOD }
+    }
+}
+
+impl GracePeriods {
+    fn get(&self, resource: Resource) -> u64 {
+        match resource {
+            Resource::Blocks => self.blocks,
+            Resource::Inodes => self.inodes,
+        }
+    }
+}
+
+/// Usage and limits of one id, from `VirtualDisk::quota_report`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub kind: QuotaKind,
+    pub id: u32,
+    /// Data and mapping blocks of the files owned, counting a block
+    /// shared between files once for each
+    pub blocks: u64,
+    pub inodes: u64,
+    pub limits: QuotaLimits,
+    /// When the exceeded block soft limit starts to be enforced, in
+    /// seconds since the Unix epoch
+    pub block_grace_ends: Option<u64>,
+    /// When the exceeded inode soft limit starts to be enforced
+    pub inode_grace_ends: Option<u64>,
+}
+
+impl QuotaUsage {
+    /// True if blocks or inodes are over a soft or hard limit
+    pub fn is_over(&self) -> bool {
+        let over = |used: u64, limit: u64| limit > 0 && used > limit;
+        over(self.blocks, self.limits.block_soft)
+            || over(self.blocks, self.limits.block_hard)
+            || over(self.inodes, self.limits.inode_soft)
+            || over(self.inodes, self.limits.inode_hard)
+    }
+}
+
+/// What quotas limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resource {
+    Blocks,
+    Inodes,
+}
+
+impl Resource {
+    const ALL: [Resource; 2] = [Resource::Blocks, Resource::Inodes];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Resource::Blocks => "block",
+            Resource::Inodes => "inode",
+        }
+    }
+}
+
+/// An id charged for an inode, under one kind of quota
+pub(crate) type Owner = (QuotaKind, u32);
+
+/// The ids charged for `inode`
+pub(crate) fn owners(inode: &Inode) -> Vec<Owner> {
+    QuotaKind::ALL.iter().map(|&kind| (kind, kind.id_of(inode))).collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Limits of one id and when its exceeded soft limits run out of grace
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct QuotaRecord {
+    limits: QuotaLimits,
+    /// Per resource, 0 while the soft limit is not exceeded
+    grace_ends: [u64; 2],
+}
+
+/// The contents of the quota file of one kind
+#[derive(Debug, Clone, Default)]
+struct QuotaFile {
+    /// Inode block of the file
+    inode_block: u64,
+    grace: GracePeriods,
+    records: BTreeMap<u32, QuotaRecord>,
+}
+
+impl QuotaFile {
+    /// Record: id, reserved, four limits and two grace deadlines
+    const RECORD_SIZE: usize = 8 + 6 * 8;
+
+    fn body(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(32 + self.records.len() * Self::RECORD_SIZE);
+        body.extend_from_slice(&Quotas::FORMAT.magic.to_le_bytes());
+        body.extend_from_slice(&Quotas::FORMAT.version.to_le_bytes());
+        body.extend_from_slice(&self.grace.blocks.to_le_bytes());
+        body.extend_from_slice(&self.grace.inodes.to_le_bytes());
+        body.extend_from_slice(&(self.records.len() as u64).to_le_bytes());
+        for (&id, record) in &self.records {
+            let limits = &record.limits;
+            body.extend_from_slice(&id.to_le_bytes());
+            body.extend_from_slice(&0u32.to_le_bytes());
+            for value in [limits.block_soft, limits.block_hard, limits.inode_soft, limits.inode_hard] {
+                body.extend_from_slice(&value.to_le_bytes());
+            }
+            for value in record.grace_ends {
+                body.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        body
+    }
+
+    fn parse(inode_block: u64, body: &[u8]) -> FsResult<Self> {
+        let format = &Quotas::FORMAT;
+        let mut reader = Reader::new(body, format);
+        let magic = reader.u32()?;
+        if magic != format.magic {
+            return Err(format.corrupted(format!("has an invalid magic number: 0x{:08X}", magic)));
+        }
+        let version = reader.u32()?;
+        if version != format.version {
+            return Err(FsError::NotSupported(format!("Unsupported quota file version: {}", version)));
+        }
+        let grace = GracePeriods { blocks: reader.u64()?, inodes: reader.u64()? };
+        let mut file = QuotaFile { inode_block, grace, records: BTreeMap::new() };
+        for _ in 0..reader.u64()? {
+            let id = reader.u32()?;
+            reader.u32()?;
+            let limits = QuotaLimits {
+                block_soft: reader.u64()?,
+                block_hard: reader.u64()?,
+                inode_soft: reader.u64()?,
+                inode_hard: reader.u64()?,
+            };
+            let grace_ends = [reader.u64()?, reader.u64()?];
+            if file.records.insert(id, QuotaRecord { limits, grace_ends }).is_some() {
+                return Err(format.corrupted(format!("records id {} twice", id)));
+            }
+        }
+        Ok(file)
+    }
+}
+
+/// An operation charging the blocks it allocates and frees to the owners
+/// of the inode it changes
+#[derive(Debug)]
+struct Charge {
+    inode_block: u64,
+    owners: Vec<Owner>,
+    /// Blocks charged so far, less those credited
+    net: i64,
+    /// The blocks the operation needs were checked up front, so those it
+    /// allocates are only counted
+    admitted: bool,
+}
+
+/// Quota limits and the usage they are checked against
+///
+/// Usage is not stored: it is counted from the inodes when the image is
+/// opened, and followed from then on. Blocks are charged as they are
+/// allocated, to the owners of the inode being changed by the operation
+/// running on the same thread (see `VirtualDisk::charging`); when the
+/// operation ends, usage is corrected by what the inode actually gained
+/// or lost, so blocks freed into snapshots or shared with clones end up
+/// counted right too.
+#[derive(Debug, Default)]
+pub(crate) struct Quotas {
+    enabled: bool,
+    /// Per kind
+    files: [QuotaFile; 3],
+    /// Blocks and inodes charged to each owner
+    usage: HashMap<Owner, [u64; 2]>,
+    /// Operations in progress on each thread, innermost last
+    charges: HashMap<ThreadId, Vec<Charge>>,
+    /// Grace deadlines changed since the quota files were last written
+    dirty: bool,
+}
+
+impl Quotas {
+    /// Header fields of a quota file; its contents are read and written
+    /// like any other file, so only the magic and version are used
+    pub(crate) const FORMAT: TableFormat = TableFormat {
+        magic: 0x51554F54, // "QUOT" in ASCII
+        version: 1,
+        name: "Quota file",
+    };
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub(crate) fn set_dirty(&mut self, dirty: bool) {
+        self.dirty = dirty;
+    }
+
+    /// Inode block of the quota file of `kind`
+    pub(crate) fn file_inode(&self, kind: QuotaKind) -> u64 {
+        self.files[kind.index()].inode_block
+    }
+
+    pub(crate) fn is_quota_inode(&self, inode_block: u64) -> bool {
+        self.enabled && self.files.iter().any(|file| file.inode_block == inode_block)
+    }
+
+    /// Contents of the quota file of `kind`
+    pub(crate) fn body(&self, kind: QuotaKind) -> Vec<u8> {
+        self.files[kind.index()].body()
+    }
+
+    /// Turn quotas on with the quota files read back, before usage is
+    /// counted
+    fn load(&mut self, files: [QuotaFile; 3]) {
+        self.enabled = true;
+        self.files = files;
+    }
+
+    /// Replace the usage followed with `usage`, as counted
+    fn set_usage(&mut self, usage: HashMap<Owner, [u64; 2]>) {
+        self.usage = usage;
+        let now = now();
+        for kind in QuotaKind::ALL {
+            let ids: Vec<u32> = self.files[kind.index()].records.keys().copied().collect();
+            for id in ids {
+                for resource in Resource::ALL {
+                    self.update_grace((kind, id), resource, now);
+                }
+            }
+        }
+    }
+
+    /// Usage followed for every owner
+    pub(crate) fn usage(&self) -> HashMap<Owner, [u64; 2]> {
+        self.usage.clone()
+    }
+
+    /// Start or stop the grace period of `owner` for `resource` as its
+    /// usage went over or back under the soft limit
+    fn update_grace(&mut self, owner: Owner, resource: Resource, now: u64) {
+        let used = self.usage.get(&owner).map_or(0, |usage| usage[resource.index()]);
+        let file = &mut self.files[owner.0.index()];
+        let period = file.grace.get(resource);
+        let Some(record) = file.records.get_mut(&owner.1) else {
+            return;
+        };
+        let soft = record.limits.soft(resource);
+        let ends = &mut record.grace_ends[resource.index()];
+        let over = soft > 0 && used > soft;
+        if over && *ends == 0 {
+            *ends = now.saturating_add(period).max(1);
+            self.dirty = true;
+        } else if !over && *ends != 0 {
+            *ends = 0;
+            self.dirty = true;
+        }
+    }
+
+    /// Fail if taking `count` more of `resource` would put one of
+    /// `owners` over a hard limit, or over a soft limit out of grace
+    fn check(&self, owners: &[Owner], resource: Resource, count: u64, now: u64) -> FsResult<()> {
+        if count == 0 || !self.enabled {
+            return Ok(());
+        }
+        for &(kind, id) in owners {
+            let Some(record) = self.files[kind.index()].records.get(&id) else {
+                continue;
+            };
+            let used = self.usage.get(&(kind, id)).map_or(0, |usage| usage[resource.index()]);
+            let wanted = used.saturating_add(count);
+            let (soft, hard) = (record.limits.soft(resource), record.limits.hard(resource));
+            // Going over the soft limit starts the grace period, which
+            // may be over at once
+            let ends = match record.grace_ends[resource.index()] {
+                0 => now.saturating_add(self.files[kind.index()].grace.get(resource)),
+                ends => ends,
+            };
+            let limit = if hard > 0 && wanted > hard {
+                Some(("hard", hard))
+            } else if soft > 0 && wanted > soft && now >= ends {
+                Some(("soft", soft))
+            } else {
+                None
+            };
+            if let Some((which, limit)) = limit {
+                return Err(FsError::QuotaExceeded(format!(
+                    "{} {} is at its {} {} limit of {}",
+                    kind.name(),
+                    id,
+                    resource.name(),
+                    which,
+                    limit
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Add `delta` of `resource` to the usage of each of `owners`
+    fn add(&mut self, owners: &[Owner], resource: Resource, delta: i64) {
+        if delta == 0 || !self.enabled {
+            return;
+        }
+        let now = now();
+        for &owner in owners {
+            let used = &mut self.usage.entry(owner).or_default()[resource.index()];
+            *used = used.saturating_add_signed(delta);
+            self.update_grace(owner, resource, now);
+        }
+    }
+
+    /// The innermost operation on the current thread
+    fn current(&mut self) -> Option<&mut Charge> {
+        self.charges.get_mut(&thread::current().id()).and_then(|stack| stack.last_mut())
+    }
+
+    /// Start charging the current thread's allocations to `owners`, for
+    /// an operation on `inode_block`
+    ///
+    /// Returns false, changing nothing, if an operation on the same inode
+    /// is already in progress on this thread: the outer one accounts.
+    pub(crate) fn begin(&mut self, inode_block: u64, owners: Vec<Owner>) -> bool {
+        let stack = self.charges.entry(thread::current().id()).or_default();
+        if stack.iter().any(|charge| charge.inode_block == inode_block) {
+            return false;
+        }
+        stack.push(Charge { inode_block, owners, net: 0, admitted: false });
+        true
+    }
+
+    /// End the innermost operation on the current thread, in which the
+    /// inode gained `gained` blocks if that could be measured
+    pub(crate) fn end(&mut self, gained: Option<i64>) {
+        let id = thread::current().id();
+        let Some(charge) = self.charges.get_mut(&id).and_then(Vec::pop) else {
+            return;
+        };
+        if self.charges[&id].is_empty() {
+            self.charges.remove(&id);
+        }
+        if let Some(gained) = gained {
+            self.add(&charge.owners, Resource::Blocks, gained - charge.net);
+        }
+    }
+
+    /// Fail if the current operation may not take `count` more blocks in
+    /// all, or else let it allocate without checking again
+    ///
+    /// Blocks an operation frees are not always credited while it runs,
+    /// when snapshots or other files keep them, so checking each
+    /// allocation could fail it halfway.
+    pub(crate) fn admit_blocks(&mut self, count: u64) -> FsResult<()> {
+        let Some(charge) = self.current() else {
+            return Ok(());
+        };
+        let owners = charge.owners.clone();
+        self.check(&owners, Resource::Blocks, count, now())?;
+        if let Some(charge) = self.current() {
+            charge.admitted = true;
+        }
+        Ok(())
+    }
+
+    /// Charge `count` blocks about to be allocated to the current
+    /// operation, failing if its owners may not take them
+    pub(crate) fn charge_blocks(&mut self, count: u64) -> FsResult<()> {
+        let Some(charge) = self.current() else {
+            return Ok(());
+        };
+        let (owners, admitted) = (charge.owners.clone(), charge.admitted);
+        if !admitted {
+            self.check(&owners, Resource::Blocks, count, now())?;
+        }
+        if let Some(charge) = self.current() {
+            charge.net += count as i64;
+        }
+        self.add(&owners, Resource::Blocks, count as i64);
+        Ok(())
+    }
+
+    /// Credit `count` blocks freed, or not allocated after all, to the
+    /// current operation
+    pub(crate) fn credit_blocks(&mut self, count: u64) {
+        let Some(charge) = self.current() else {
+            return;
+        };
+        charge.net -= count as i64;
+        let owners = charge.owners.clone();
+        self.add(&owners, Resource::Blocks, -(count as i64));
+    }
+
+    /// Charge a new inode to `owners`, failing if they may not take it
+    pub(crate) fn charge_inode(&mut self, owners: &[Owner]) -> FsResult<()> {
+        self.check(owners, Resource::Inodes, 1, now())?;
+        self.add(owners, Resource::Inodes, 1);
+        Ok(())
+    }
+
+    /// Credit an inode freed to `owners`
+    pub(crate) fn credit_inode(&mut self, owners: &[Owner]) {
+        self.add(owners, Resource::Inodes, -1);
+    }
+
+    /// Fail if `owners` may not take an inode holding `blocks` blocks
+    /// more
+    pub(crate) fn check_inode(&self, owners: &[Owner], blocks: u64) -> FsResult<()> {
+        let now = now();
+        self.check(owners, Resource::Blocks, blocks, now)?;
+        self.check(owners, Resource::Inodes, 1, now)
+    }
+
+    /// Move the charge for an inode holding `blocks` blocks from `from`
+    /// to `to`, whatever their limits
+    pub(crate) fn transfer(&mut self, from: &[Owner], to: &[Owner], blocks: u64) {
+        self.add(from, Resource::Blocks, -(blocks as i64));
+        self.add(from, Resource::Inodes, -1);
+        self.add(to, Resource::Blocks, blocks as i64);
+        self.add(to, Resource::Inodes, 1);
+    }
+
+    fn report(&self, kind: QuotaKind, id: u32) -> QuotaUsage {
+        let file = &self.files[kind.index()];
+        let record = file.records.get(&id).copied().unwrap_or_default();
+        let usage = self.usage.get(&(kind, id)).copied().unwrap_or_default();
+        let grace_ends = |resource: Resource| Some(record.grace_ends[resource.index()]).filter(|&ends| ends != 0);
+        QuotaUsage {
+            kind,
+            id,
+            blocks: usage[Resource::Blocks.index()],
+            inodes: usage[Resource::Inodes.index()],
+            limits: record.limits,
+            block_grace_ends: grace_ends(Resource::Blocks),
+            inode_grace_ends: grace_ends(Resource::Inodes),
+        }
+    }
+}
+
+impl VirtualDisk {
+    // ==================== QUOTAS ====================
+    //
+    // Each inode is owned by a user and a group id, taken from the
+    // credentials set on the disk when it is created, and belongs to the
+    // project of the directory it was created in. With
+    // `Superblock::FEATURE_QUOTA`, the blocks and inodes held are
+    // accounted per id, and limited by the records in three quota files,
+    // one for each kind. These are ordinary files without a name, found
+    // through the superblock, and are charged to nobody. Blocks count
+    // like `held_blocks`: data and mapping blocks, with a block shared
+    // between files counted for each of them.
+    //
+    // Limits are enforced wherever blocks are allocated for a file and
+    // where clones take references to blocks, and where inodes are
+    // allocated. Operations that could fail halfway check the blocks they
+    // will need up front, like they do for the free space. Moving a file
+    // into another project moves its charge, within the limits of the new
+    // project; directories and files with several names cannot move
+    // between projects, except for the root of a project tree.
+
+    /// Whether usage is accounted and limited
+    pub fn is_quota_enabled(&self) -> bool {
+        self.with_quotas(|quotas| quotas.is_enabled())
+    }
+
+    /// Set the limits of user, group or project `id`, turning quotas on
+    /// first if they are off
+    ///
+    /// Limits of all zeros remove the id's record. Turning quotas on
+    /// creates the quota files and counts usage from every inode.
+    pub fn set_quota(&mut self, kind: QuotaKind, id: u32, limits: QuotaLimits) -> FsResult<()> {
+        self.enable_quotas()?;
+        self.with_quotas(|quotas| {
+            let records = &mut quotas.files[kind.index()].records;
+            match limits.is_unlimited() {
+                true => {
+                    records.remove(&id);
+                }
+                false => records.entry(id).or_default().limits = limits,
+            }
+            for resource in Resource::ALL {
+                quotas.update_grace((kind, id), resource, now());
+            }
+        });
+        self.save_quotas()?;
+        self.sync_bitmap()
+    }
+
+    /// How long soft limits of `kind` may be exceeded
+    pub fn grace_periods(&self, kind: QuotaKind) -> GracePeriods {
+        self.with_quotas(|quotas| quotas.files[kind.index()].grace)
+    }
+
+    /// Set how long soft limits of `kind` may be exceeded, turning quotas
+    /// on first if they are off
+    ///
+    /// Grace periods already running keep their deadline.
+    pub fn set_grace_periods(&mut self, kind: QuotaKind, grace: GracePeriods) -> FsResult<()> {
+        self.enable_quotas()?;
+        self.with_quotas(|quotas| quotas.files[kind.index()].grace = grace);
+        self.save_quotas()?;
+        self.sync_bitmap()
+    }
+
+    /// Usage and limits of `kind` quota `id`
+    pub fn quota(&self, kind: QuotaKind, id: u32) -> QuotaUsage {
+        self.with_quotas(|quotas| quotas.report(kind, id))
+    }
+
+    /// Usage and limits of every id that owns something or has limits,
+    /// users first, then groups and projects, in order of id
+    ///
+    /// Empty while quotas are off.
+    pub fn quota_report(&self) -> Vec<QuotaUsage> {
+        self.with_quotas(|quotas| {
+            if !quotas.is_enabled() {
+                return Vec::new();
+            }
+            let mut ids: BTreeSet<Owner> = quotas.usage.keys().copied().collect();
+            for kind in QuotaKind::ALL {
+                ids.extend(quotas.files[kind.index()].records.keys().map(|&id| (kind, id)));
+            }
+            ids.into_iter().map(|(kind, id)| quotas.report(kind, id)).collect()
+        })
+    }
+
+    /// Count usage from the inodes again and replace what was followed
+    ///
+    /// Grace periods start or stop to match. `fsck` reports when the two
+    /// differ.
+    pub fn quota_recount(&mut self) -> FsResult<()> {
+        if !self.is_quota_enabled() {
+            return Ok(());
+        }
+        let usage = self.count_quota_usage()?;
+        self.with_quotas(|quotas| quotas.set_usage(usage));
+        self.save_quotas()?;
+        self.sync_bitmap()
+    }
+
+    /// Give the inode at `inode_block` to user `uid` and group `gid`
+    ///
+    /// The inode and its blocks are charged to the new owners whatever
+    /// their limits, like `chown` by root.
+    pub fn set_owner(&self, inode_block: u64, uid: u32, gid: u32) -> FsResult<()> {
+        self.reown(inode_block, |inode| {
+            inode.uid = uid;
+            inode.gid = gid;
+        })
+    }
+
+    /// Put the inode at `inode_block` in project `project`
+    ///
+    /// Like `set_owner`, the charge moves whatever the limits. Only the
+    /// inode itself changes; what is created in a directory afterwards
+    /// takes its new project. See `set_project_tree` for a whole tree.
+    pub fn set_project(&self, inode_block: u64, project: u32) -> FsResult<()> {
+        self.reown(inode_block, |inode| inode.project = project)
+    }
+
+    /// Put everything at and below `path` in project `project`, without
+    /// following symlinks
+    ///
+    /// Returns the number of inodes changed.
+    pub fn set_project_tree(&mut self, path: &str, project: u32) -> FsResult<u64> {
+        let inode_block = self.lookup_path_nofollow(path)?;
+        let inode = self.read_inode(inode_block)?;
+        let mut changed = 0;
+        if inode.file_type == FileType::Directory {
+            for entry in self.list_directory_at(path)? {
+                changed += self.set_project_tree(&join(path, &entry.name), project)?;
+            }
+        }
+        if inode.project != project {
+            self.set_project(inode_block, project)?;
+            changed += 1;
+        }
+        Ok(changed)
+    }
+
+    /// The project the inode at `inode_block`, reached through `path`,
+    /// enters when it is moved from directory `from` into directory `to`,
+    /// if that is another one; pass it to `set_project` once the move is
+    /// done
+    ///
+    /// The new project must have room for the file or symlink. A
+    /// directory in a project of its own, such as the root of a project
+    /// tree, keeps it. Fails with `CrossesProjects` for any other
+    /// directory, whose tree would need accounting again, and for a file
+    /// with other names.
+    pub(crate) fn entering_project(&self, from: u64, to: u64, inode_block: u64, path: &str) -> FsResult<Option<u32>> {
+        let project = self.read_inode(to)?.project;
+        let inode = self.read_inode(inode_block)?;
+        if inode.project == project {
+            return Ok(None);
+        }
+        let own_project = inode.project != self.read_inode(from)?.project;
+        if inode.file_type == FileType::Directory && own_project {
+            return Ok(None);
+        }
+        if inode.file_type == FileType::Directory || inode.link_count > 1 {
+            return Err(FsError::CrossesProjects(format!(
+                "{} is in project {}, its destination in project {}",
+                path, inode.project, project
+            )));
+        }
+        let mut moved = inode.clone();
+        moved.project = project;
+        if self.is_quota_enabled() {
+            let blocks = self.held_blocks(&inode)?;
+            let (before, after) = (owners(&inode), owners(&moved));
+            let entering: Vec<Owner> = after.into_iter().filter(|owner| !before.contains(owner)).collect();
+            self.with_quotas(|quotas| quotas.check_inode(&entering, blocks))?;
+        }
+        Ok(Some(project))
+    }
+
+    /// Fail with `CrossesProjects` unless the inode at `inode_block` is in
+    /// the project of directory `dir`, for a new name there
+    pub(crate) fn check_project(&self, dir: u64, inode_block: u64, path: &str) -> FsResult<()> {
+        let project = self.read_inode(dir)?.project;
+        let inode = self.read_inode(inode_block)?;
+        match inode.project == project {
+            true => Ok(()),
+            false => Err(FsError::CrossesProjects(format!(
+                "{} is in project {}, its new directory in project {}",
+                path, inode.project, project
+            ))),
+        }
+    }
+
+    /// Change the owners or project of the inode at `inode_block` with
+    /// `change`, moving its charge
+    fn reown(&self, inode_block: u64, change: impl FnOnce(&mut Inode)) -> FsResult<()> {
+        let mut inode = self.read_inode(inode_block)?;
+        let before = owners(&inode);
+        change(&mut inode);
+        if self.is_quota_enabled() && !self.is_quota_inode(inode_block) {
+            let blocks = self.held_blocks(&inode)?;
+            let after = owners(&inode);
+            self.with_quotas(|quotas| quotas.transfer(&before, &after, blocks));
+        }
+        self.write_inode(inode_block, &inode)?;
+        self.save_quotas_if_dirty()
+    }
+
+    /// Whether `inode_block` holds a quota file
+    pub(crate) fn is_quota_inode(&self, inode_block: u64) -> bool {
+        self.with_quotas(|quotas| quotas.is_quota_inode(inode_block))
+    }
+
+    /// Count the blocks and inodes owned by each id, from every inode
+    /// other than the quota files
+    ///
+    /// Inodes that cannot be read are left out; fsck reports them.
+    pub(crate) fn count_quota_usage(&self) -> FsResult<HashMap<Owner, [u64; 2]>> {
+        let mut usage: HashMap<Owner, [u64; 2]> = HashMap::new();
+        for inode_block in self.used_inodes() {
+            if self.is_quota_inode(inode_block) {
+                continue;
+            }
+            let Ok(inode) = self.read_inode(inode_block) else {
+                continue;
+            };
+            let Ok(blocks) = self.held_blocks(&inode) else {
+                continue;
+            };
+            for owner in owners(&inode) {
+                let counted = usage.entry(owner).or_default();
+                counted[Resource::Blocks.index()] += blocks;
+                counted[Resource::Inodes.index()] += 1;
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Read the quota files and count usage, if quotas are on
+    pub(crate) fn load_quotas(&self) -> FsResult<()> {
+        let superblock = self.superblock();
+        if !superblock.has_feature(Superblock::FEATURE_QUOTA) {
+            return Ok(());
+        }
+        let mut files: [QuotaFile; 3] = Default::default();
+        for kind in QuotaKind::ALL {
+            let inode_block = superblock.quota_inode(kind);
+            // Images from before project quotas have no project quota
+            // file until a project limit is set
+            if inode_block == 0 && kind == QuotaKind::Project {
+                continue;
+            }
+            if !self.is_inode_used(inode_block) {
+                return Err(Quotas::FORMAT.corrupted(format!("of {}s is not at an inode in use: {}", kind.name(), inode_block)));
+            }
+            let inode = self.read_inode(inode_block)?;
+            files[kind.index()] = QuotaFile::parse(inode_block, &self.read_inode_data(&inode)?)?;
+        }
+        self.with_quotas(|quotas| quotas.load(files));
+        let usage = self.count_quota_usage()?;
+        self.with_quotas(|quotas| quotas.set_usage(usage));
+        Ok(())
+    }
+
+    /// Turn quotas on, creating empty quota files and counting usage
+    ///
+    /// With quotas on, only creates the quota files missing.
+    fn enable_quotas(&mut self) -> FsResult<()> {
+        let (enabled, mut files) = self.with_quotas(|quotas| (quotas.is_enabled(), quotas.files.clone()));
+        let missing: Vec<QuotaKind> = QuotaKind::ALL
+            .into_iter()
+            .filter(|&kind| !enabled || files[kind.index()].inode_block == 0)
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        for kind in missing {
+            let inode_block = self.create_quota_inode()?;
+            self.block_groups_mut().set_quota_inode(kind, inode_block);
+            files[kind.index()] = QuotaFile { inode_block, ..Default::default() };
+        }
+        self.block_groups_mut().set_feature(Superblock::FEATURE_QUOTA, true);
+        self.with_quotas(|quotas| quotas.load(files));
+        let usage = self.count_quota_usage()?;
+        self.with_quotas(|quotas| quotas.set_usage(usage));
+        self.save_quotas()
+    }
+
+    /// Create an empty file for a quota file, without a name and owned
+    /// by nobody's quota
+    fn create_quota_inode(&self) -> FsResult<u64> {
+        let mut inode = Inode::new(0, FileType::File, Permissions::new(true, true, false));
+        if self.has_feature(Superblock::FEATURE_EXTENTS) {
+            inode.flags |= Inode::FLAG_EXTENTS;
+        }
+        let inode_block = self.allocate_inode(0, &inode)?;
+        self.map_file_blocks(&mut inode, &[], 0)?;
+        self.write_inode(inode_block, &inode)?;
+        Ok(inode_block)
+    }
+
+    /// Write the quota files
+    pub(crate) fn save_quotas(&self) -> FsResult<()> {
+        self.with_quotas(|quotas| quotas.set_dirty(false));
+        for kind in QuotaKind::ALL {
+            let (inode_block, body) = self.with_quotas(|quotas| (quotas.file_inode(kind), quotas.body(kind)));
+            if inode_block == 0 {
+                continue;
+            }
+            let written = self.read_inode(inode_block).and_then(|inode| self.write_inode_data(inode_block, inode, &body));
+            if let Err(e) = written {
+                self.with_quotas(|quotas| quotas.set_dirty(true));
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the quota files if a grace period started or stopped
+    pub(crate) fn save_quotas_if_dirty(&self) -> FsResult<()> {
+        match self.with_quotas(|quotas| quotas.is_dirty()) {
+            true => self.save_quotas(),
+            false => Ok(()),
+        }
+    }
+
+    /// Charge the blocks allocated and freed on this thread to the owners
+    /// of the inode at `inode_block`, until the guard is dropped
+    ///
+    /// Limits are checked as blocks are allocated. When the guard drops,
+    /// usage is corrected by the blocks the inode actually gained or
+    /// lost. Nothing happens while quotas are off, or if an operation on
+    /// the same inode is already being charged on this thread.
+    pub(crate) fn charging(&self, inode_block: u64) -> FsResult<Charging<'_>> {
+        let mut charging = Charging { disk: self, inode_block, before: None };
+        if !self.is_quota_enabled() {
+            return Ok(charging);
+        }
+        let inode = self.read_inode(inode_block)?;
+        let before = self.held_blocks(&inode)?;
+        let owners = match self.is_quota_inode(inode_block) {
+            true => Vec::new(),
+            false => owners(&inode),
+        };
+        if self.with_quotas(|quotas| quotas.begin(inode_block, owners)) {
+            charging.before = Some(before);
+        }
+        Ok(charging)
+    }
+
+    /// Fail if the operation being charged on this thread may not take
+    /// `count` more blocks, before it changes anything
+    ///
+    /// Once this passes, the operation's allocations are not checked
+    /// against the limits again.
+    pub(crate) fn check_quota(&self, count: u64) -> FsResult<()> {
+        self.with_quotas(|quotas| quotas.admit_blocks(count))
+    }
+}
+
+/// Guard returned by `VirtualDisk::charging`
+pub(crate) struct Charging<'a> {
+    disk: &'a VirtualDisk,
+    inode_block: u64,
+    /// Blocks the inode held at the start, if this guard accounts
+    before: Option<u64>,
+}
+
+impl Drop for Charging<'_> {
+    fn drop(&mut self) {
+        let Some(before) = self.before else {
+            return;
+        };
+        let disk = self.disk;
+        let after = match disk.is_inode_used(self.inode_block) {
+            true => disk.read_inode(self.inode_block).and_then(|inode| disk.held_blocks(&inode)).ok(),
+            false => Some(0),
+        };
+        disk.with_quotas(|quotas| quotas.end(after.map(|after| after as i64 - before as i64)));
+        // A failure leaves the files dirty, to be written next time
+        let _ = disk.save_quotas_if_dirty();
+    }
+}
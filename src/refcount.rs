@@ -0,0 +1,124 @@
+use crate::{
+    device::BlockDevice,
+    error::FsResult,
+    table::{Reader, TableFormat},
+};
+use std::collections::BTreeMap;
+
+/// Reference counts of data blocks shared between files
+///
+/// Cloning a file, or a range of one, points the clone at the same data
+/// blocks instead of copying them. Only blocks with more than one
+/// reference are recorded; every other block in use has exactly one.
+/// Freeing a shared block drops a reference, and the block goes back to
+/// the allocator with the last one. Shared blocks are never written in
+/// place: a file about to write to one is first moved onto a copy.
+///
+/// The table is stored in a chain of blocks (see `TableFormat`). Unlike
+/// the snapshot table, these are ordinary file system blocks, which
+/// snapshots freeze and roll back along with everything else.
+#[derive(Debug, Default)]
+pub(crate) struct RefCounts {
+    /// References to each shared block, always at least two
+    counts: BTreeMap<u64, u64>,
+    /// Blocks the table is stored in
+    store: Vec<u64>,
+    /// Changed since it was last written
+    dirty: bool,
+}
+
+impl RefCounts {
+    pub(crate) const FORMAT: TableFormat = TableFormat {
+        magic: 0x52454643, // "REFC" in ASCII
+        version: 1,
+        name: "Reference count table",
+    };
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// References to a block in use
+    pub(crate) fn references(&self, block: u64) -> u64 {
+        self.counts.get(&block).copied().unwrap_or(1)
+    }
+
+    pub(crate) fn is_shared(&self, block: u64) -> bool {
+        self.counts.contains_key(&block)
+    }
+
+    /// Shared blocks with their reference counts
+    pub(crate) fn shared(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.counts.iter().map(|(&block, &refs)| (block, refs))
+    }
+
+    /// Add a reference to a block in use
+    pub(crate) fn add(&mut self, block: u64) {
+        *self.counts.entry(block).or_insert(1) += 1;
+        self.dirty = true;
+    }
+
+    /// Drop a reference to a block, returning whether others remain
+    pub(crate) fn remove(&mut self, block: u64) -> bool {
+        match self.counts.get_mut(&block) {
+            Some(refs) if *refs > 2 => *refs -= 1,
+            Some(_) => {
+                self.counts.remove(&block);
+            }
+            None => return false,
+        }
+        self.dirty = true;
+        true
+    }
+
+    /// Blocks the table is stored in
+    pub(crate) fn store(&self) -> &[u64] {
+        &self.store
+    }
+
+    /// Blocks the table is stored in, which the caller frees
+    pub(crate) fn take_store(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.store)
+    }
+
+    /// Record that the table was written to `blocks`
+    pub(crate) fn stored(&mut self, blocks: Vec<u64>) {
+        self.store = blocks;
+        self.dirty = false;
+    }
+
+    /// Serialize the table
+    pub(crate) fn body(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(8 + self.counts.len() * 16);
+        body.extend_from_slice(&(self.counts.len() as u64).to_le_bytes());
+        for (&block, &refs) in &self.counts {
+            body.extend_from_slice(&block.to_le_bytes());
+            body.extend_from_slice(&refs.to_le_bytes());
+        }
+        body
+    }
+
+    /// Load the table stored from block `first`, 0 for none
+    pub(crate) fn load(device: &dyn BlockDevice, block_size: u64, total_blocks: u64, first: u64) -> FsResult<Self> {
+        let (store, body) = Self::FORMAT.read(device, block_size, total_blocks, first)?;
+        let mut refcounts = RefCounts { store, ..RefCounts::default() };
+        if refcounts.store.is_empty() {
+            return Ok(refcounts);
+        }
+
+        let mut reader = Reader::new(&body, &Self::FORMAT);
+        for _ in 0..reader.u64()? {
+            let block = reader.u64()?;
+            let refs = reader.u64()?;
+            if block >= total_blocks || refs < 2 {
+                return Err(Self::FORMAT.corrupted(format!("records {} references to block {}", refs, block)));
+            }
+            refcounts.counts.insert(block, refs);
+        }
+        Ok(refcounts)
+    }
+}
@@ -0,0 +1,205 @@
+use crate::{
+    checksum::BlockClass,
+    error::{FsError, FsResult},
+    extent::HOLE,
+    serialization::{DirectoryEntry, FileType, Inode},
+    virtual_disk::VirtualDisk,
+};
+use std::collections::{HashSet, VecDeque};
+
+/// A block that could not be read or failed verification
+#[derive(Debug)]
+pub struct BadBlock {
+    pub block: u64,
+    /// Inode block of the file or directory the block belongs to, if any
+    pub owner: Option<u64>,
+    /// Path of the owner, if it is reachable from the root directory
+    pub path: Option<String>,
+    pub error: FsError,
+}
+
+/// Result of `VirtualDisk::scrub`
+#[derive(Debug, Default)]
+pub struct ScrubReport {
+    /// Blocks read, and verified where the image keeps checksums
+    pub blocks_checked: u64,
+    pub bad_blocks: Vec<BadBlock>,
+}
+
+impl ScrubReport {
+    /// True if every block read back intact
+    pub fn is_clean(&self) -> bool {
+        self.bad_blocks.is_empty()
+    }
+
+    /// Paths of the files and directories with bad blocks, sorted
+    pub fn bad_files(&self) -> Vec<&str> {
+        let mut paths: Vec<&str> = self.bad_blocks.iter().filter_map(|b| b.path.as_deref()).collect();
+        paths.sort_unstable();
+        paths.dedup();
+        paths
+    }
+}
+
+/// Path of `name` in the directory at `dir`
+fn child_path(dir: &str, name: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), name)
+}
+
+/// One pass over a disk, shared by the plain and the shared-disk scrub
+///
+/// Remembers which blocks were read as part of an inode, so that the
+/// remaining allocated blocks can be read on their own at the end.
+pub(crate) struct Scrubber<'a> {
+    disk: &'a VirtualDisk,
+    report: ScrubReport,
+    visited: HashSet<u64>,
+}
+
+impl<'a> Scrubber<'a> {
+    pub(crate) fn new(disk: &'a VirtualDisk) -> Self {
+        Scrubber {
+            disk,
+            report: ScrubReport::default(),
+            visited: HashSet::new(),
+        }
+    }
+
+    /// Count a block as checked and record it if reading it failed
+    fn check<T>(&mut self, block: u64, owner: Option<u64>, path: Option<&str>, result: FsResult<T>) -> Option<T> {
+        self.report.blocks_checked += 1;
+        self.visited.insert(block);
+        match result {
+            Ok(value) => Some(value),
+            Err(error) => {
+                let path = path.map(str::to_string);
+                self.report.bad_blocks.push(BadBlock { block, owner, path, error });
+                None
+            }
+        }
+    }
+
+    /// Read the superblock and bitmaps
+    ///
+    /// The checksum table itself has no checksums, so it is left to
+    /// `unowned`.
+    pub(crate) fn layout(&mut self) {
+        let superblock = self.disk.superblock();
+        let layout_end = superblock.inode_bitmap_start + superblock.inode_bitmap_blocks;
+        for block in 0..layout_end {
+            let result = self.disk.read_layout_block(block);
+            self.check(block, None, None, result);
+        }
+    }
+
+    /// Read an inode with its mapping and data blocks
+    ///
+    /// Returns the inode if every one of its blocks read back intact; a
+    /// file whose mapping cannot be walked is skipped after its first bad
+    /// block.
+    pub(crate) fn inode(&mut self, inode_block: u64, path: Option<&str>) -> Option<Inode> {
+        if self.visited.contains(&inode_block) {
+            return None;
+        }
+        let owner = Some(inode_block);
+        let result = self.disk.read_inode(inode_block);
+        let inode = self.check(inode_block, owner, path, result)?;
+
+        // Mapping blocks are verified while they are walked
+        let (data, metadata) = match self.disk.walk_mapping(&inode) {
+            Ok(mapping) => mapping,
+            Err(error) => {
+                let block = match error {
+                    FsError::ChecksumMismatch { block, .. } => block,
+                    _ => inode_block,
+                };
+                self.check::<()>(block, owner, path, Err(error));
+                return None;
+            }
+        };
+        self.report.blocks_checked += metadata.len() as u64;
+        self.visited.extend(metadata);
+
+        let class = match inode.file_type {
+            FileType::Directory => BlockClass::Metadata,
+            _ => BlockClass::Data,
+        };
+        let mut intact = true;
+        for block in data.into_iter().filter(|&b| b != HOLE) {
+            let result = self.disk.read_block(block, class);
+            intact &= self.check(block, owner, path, result).is_some();
+        }
+        intact.then_some(inode)
+    }
+
+    /// Entries of a directory whose blocks all read back intact
+    pub(crate) fn entries(&mut self, dir: u64, path: &str) -> Vec<DirectoryEntry> {
+        match self.disk.list_directory(dir) {
+            Ok(entries) => entries,
+            Err(error) => {
+                self.check::<()>(dir, Some(dir), Some(path), Err(error));
+                Vec::new()
+            }
+        }
+    }
+
+    /// Read allocated blocks not read so far, such as the checksum table
+    /// and blocks of files the scrub did not reach
+    ///
+    /// They cannot be told apart from free space by their contents, so
+    /// only read errors are found here.
+    pub(crate) fn unowned(&mut self) {
+        let block_size = self.disk.block_size() as usize;
+        let mut buffer = vec![0u8; block_size];
+        for block in 0..self.disk.total_blocks() {
+            if self.visited.contains(&block) || !self.disk.is_block_used(block) {
+                continue;
+            }
+            let result = self.disk.read_raw(block * block_size as u64, &mut buffer);
+            self.check(block, None, None, result);
+        }
+    }
+
+    pub(crate) fn finish(self) -> ScrubReport {
+        self.report
+    }
+}
+
+impl VirtualDisk {
+    /// Read every block in use and verify it against its checksum
+    ///
+    /// Covers the superblock and bitmaps, then every file and directory
+    /// reachable from the root, then inodes no directory points at, and
+    /// finally any other allocated block. Bad blocks are collected in the
+    /// report, with the path of the file they belong to, rather than
+    /// stopping the scrub. On images without checksums this only finds
+    /// blocks that cannot be read, or inodes too damaged to parse. Unlike
+    /// `fsck`, the structure of the file system is not checked.
+    pub fn scrub(&self) -> FsResult<ScrubReport> {
+        let mut scrubber = Scrubber::new(self);
+        scrubber.layout();
+
+        if let Some(root) = self.root_directory() {
+            let mut queue = VecDeque::from([(root, "/".to_string())]);
+            while let Some((inode_block, path)) = queue.pop_front() {
+                let Some(inode) = scrubber.inode(inode_block, Some(&path)) else {
+                    continue;
+                };
+                if inode.file_type != FileType::Directory {
+                    continue;
+                }
+                // Entries pointing at unused inodes are left to fsck
+                let entries = scrubber.entries(inode_block, &path);
+                for entry in entries.into_iter().filter(|e| self.is_inode_used(e.inode_number)) {
+                    queue.push_back((entry.inode_number, child_path(&path, &entry.name)));
+                }
+            }
+        }
+
+        for inode_block in self.used_inodes() {
+            scrubber.inode(inode_block, None);
+        }
+        scrubber.unowned();
+        Ok(scrubber.finish())
+    }
+}
@@ -1,4 +1,8 @@
-use crate::error::{FsError, FsResult};
+use crate::{
+    encryption::{KEY_CHECK_SIZE, SALT_SIZE, WRAPPED_KEY_SIZE},
+    error::{FsError, FsResult},
+    quota::QuotaKind,
+};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Maximum file name length in bytes
@@ -13,6 +17,9 @@ pub const DIRECT_POINTERS: usize = 12;
 /// Maximum number of indirect block pointers
 pub const INDIRECT_POINTERS: usize = 3;
 
+/// Bytes of file data or directory entries an inode can hold itself
+pub const INLINE_DATA_SIZE: usize = 256;
+
 /// Binary serialization and deserialization for file system structures
 /// 
 /// This module provides fixed-size binary formats for efficient storage
@@ -24,6 +31,8 @@ pub const INDIRECT_POINTERS: usize = 3;
 pub enum FileType {
     File = 1,
     Directory = 2,
+    /// Symbolic link; the data holds the target path
+    Symlink = 3,
 }
 
 impl FileType {
@@ -31,6 +40,7 @@ impl FileType {
         match value {
             1 => Ok(FileType::File),
             2 => Ok(FileType::Directory),
+            3 => Ok(FileType::Symlink),
             _ => Err(FsError::InvalidMetadata(format!(
                 "Invalid file type: {}",
                 value
@@ -87,6 +97,28 @@ impl Permissions {
     pub fn to_u8(self) -> u8 {
         self.flags
     }
+
+    /// Take the owner read/write/execute bits of a Unix mode
+    pub fn from_mode(mode: u32) -> Self {
+        Self::new(mode & 0o400 != 0, mode & 0o200 != 0, mode & 0o100 != 0)
+    }
+
+    /// Unix mode bits for these permissions
+    ///
+    /// Group and others get the read and execute bits, but not write.
+    pub fn to_mode(self) -> u32 {
+        let mut mode = 0;
+        if self.read() {
+            mode |= 0o444;
+        }
+        if self.write() {
+            mode |= 0o200;
+        }
+        if self.execute() {
+            mode |= 0o111;
+        }
+        mode
+    }
 }
 
 /// Inode structure - fixed size metadata for files and directories
@@ -104,7 +136,20 @@ impl Permissions {
 /// - Accessed time: 8 bytes
 /// - Direct pointers: 12 * 8 = 96 bytes
 /// - Indirect pointers: 3 * 8 = 24 bytes
-/// - Reserved: 336 bytes (for future use)
+/// - Flags: 4 bytes
+/// - Wrapped file key: 60 bytes (zero unless encrypted)
+/// - Inline data: 256 bytes (zero unless `FLAG_INLINE_DATA` is set)
+/// - Owner user and group ids: 4 + 4 bytes
+/// - Project id: 4 bytes
+/// - Reserved: 4 bytes (for future use)
+///
+/// When `FLAG_EXTENTS` is set, the 120 bytes of direct and indirect
+/// pointers hold the root of an extent tree instead (see `extent.rs`).
+/// When `FLAG_INLINE_DATA` is set, no blocks are mapped: the data of a
+/// file or symlink, or the packed entries of a directory (see
+/// `DirectoryEntry::pack`), are the first `size` bytes of the inline data.
+/// When `FLAG_COMPRESSED` or `FLAG_ENCRYPTED` is set, the data is stored
+/// in clusters (see `compression.rs` and `encryption.rs`).
 #[derive(Debug, Clone)]
 pub struct Inode {
     pub inode_number: u64,
@@ -118,11 +163,45 @@ pub struct Inode {
     pub accessed: u64,     // Unix timestamp
     pub direct_blocks: [u64; DIRECT_POINTERS],
     pub indirect_blocks: [u64; INDIRECT_POINTERS],
+    pub flags: u32,
+    /// Key of the file's data, encrypted with the volume key
+    pub wrapped_key: [u8; WRAPPED_KEY_SIZE],
+    /// Data or packed directory entries, when `FLAG_INLINE_DATA` is set
+    pub inline_data: [u8; INLINE_DATA_SIZE],
+    /// User owning the inode, charged for it in user quotas
+    pub uid: u32,
+    /// Group owning the inode, charged for it in group quotas
+    pub gid: u32,
+    /// Project the inode belongs to, charged for it in project quotas;
+    /// taken from the directory it is created in
+    pub project: u32,
 }
 
 impl Inode {
     const MAGIC: u32 = 0x494E4F44; // "INOD" in ASCII
 
+    /// Size of the block pointer area (direct + indirect pointers)
+    pub const POINTER_AREA_SIZE: usize = (DIRECT_POINTERS + INDIRECT_POINTERS) * 8;
+
+    /// File data is mapped by an extent tree rooted in the pointer area
+    pub const FLAG_EXTENTS: u32 = 0x0000_0001;
+
+    /// File data is stored in compressed clusters
+    pub const FLAG_COMPRESSED: u32 = 0x0000_0002;
+
+    /// Files and directories created in this directory are compressed
+    pub const FLAG_COMPRESS: u32 = 0x0000_0004;
+
+    /// File data is encrypted with the key in `wrapped_key`
+    pub const FLAG_ENCRYPTED: u32 = 0x0000_0008;
+
+    /// Data or directory entries are stored in the inode itself
+    pub const FLAG_INLINE_DATA: u32 = 0x0000_0010;
+
+    /// A version, or a file or symlink in the trash, which is never kept
+    /// as history itself
+    pub const FLAG_HISTORY: u32 = 0x0000_0020;
+
     pub fn new(inode_number: u64, file_type: FileType, permissions: Permissions) -> Self {
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -141,6 +220,76 @@ impl Inode {
             accessed: now,
             direct_blocks: [0; DIRECT_POINTERS],
             indirect_blocks: [0; INDIRECT_POINTERS],
+            flags: 0,
+            wrapped_key: [0; WRAPPED_KEY_SIZE],
+            inline_data: [0; INLINE_DATA_SIZE],
+            uid: 0,
+            gid: 0,
+            project: 0,
+        }
+    }
+
+    /// Check whether an inode flag is set
+    pub fn has_flag(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// True if the data is stored in clusters, being compressed or encrypted
+    pub fn is_clustered(&self) -> bool {
+        self.has_flag(Self::FLAG_COMPRESSED | Self::FLAG_ENCRYPTED)
+    }
+
+    /// True if the data is stored in the inode itself
+    pub fn is_inline(&self) -> bool {
+        self.has_flag(Self::FLAG_INLINE_DATA)
+    }
+
+    /// The data stored in the inode, empty unless inline
+    pub fn inline_data(&self) -> &[u8] {
+        match self.is_inline() {
+            true => &self.inline_data[..(self.size as usize).min(INLINE_DATA_SIZE)],
+            false => &[],
+        }
+    }
+
+    /// Store `data` in the inode and set the size to match
+    /// 
+    /// Any blocks must have been released first; `data` must fit in
+    /// `INLINE_DATA_SIZE` bytes.
+    pub fn set_inline_data(&mut self, data: &[u8]) {
+        self.inline_data = [0; INLINE_DATA_SIZE];
+        self.inline_data[..data.len()].copy_from_slice(data);
+        self.size = data.len() as u64;
+        self.flags |= Self::FLAG_INLINE_DATA;
+    }
+
+    /// Drop the data stored in the inode, keeping the size
+    pub fn clear_inline_data(&mut self) {
+        self.inline_data = [0; INLINE_DATA_SIZE];
+        self.flags &= !Self::FLAG_INLINE_DATA;
+    }
+
+    /// Raw bytes of the block pointer area
+    pub fn pointer_area(&self) -> [u8; Self::POINTER_AREA_SIZE] {
+        let mut bytes = [0u8; Self::POINTER_AREA_SIZE];
+        let pointers = self.direct_blocks.iter().chain(self.indirect_blocks.iter());
+        for (i, pointer) in pointers.enumerate() {
+            bytes[i * 8..i * 8 + 8].copy_from_slice(&pointer.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Replace the block pointer area with raw bytes
+    pub fn set_pointer_area(&mut self, bytes: &[u8]) {
+        let mut area = [0u8; Self::POINTER_AREA_SIZE];
+        let len = bytes.len().min(Self::POINTER_AREA_SIZE);
+        area[..len].copy_from_slice(&bytes[..len]);
+
+        for (i, pointer) in self.direct_blocks.iter_mut().enumerate() {
+            *pointer = read_u64(&area, i * 8);
+        }
+        for (i, pointer) in self.indirect_blocks.iter_mut().enumerate() {
+            *pointer = read_u64(&area, (DIRECT_POINTERS + i) * 8);
         }
     }
 
@@ -197,6 +346,26 @@ impl Inode {
             offset += 8;
         }
 
+        // Flags
+        bytes[offset..offset + 4].copy_from_slice(&self.flags.to_le_bytes());
+        offset += 4;
+
+        // Wrapped file key
+        bytes[offset..offset + WRAPPED_KEY_SIZE].copy_from_slice(&self.wrapped_key);
+        offset += WRAPPED_KEY_SIZE;
+
+        // Inline data
+        bytes[offset..offset + INLINE_DATA_SIZE].copy_from_slice(&self.inline_data);
+        offset += INLINE_DATA_SIZE;
+
+        // Owner
+        bytes[offset..offset + 4].copy_from_slice(&self.uid.to_le_bytes());
+        bytes[offset + 4..offset + 8].copy_from_slice(&self.gid.to_le_bytes());
+        offset += 8;
+
+        // Project
+        bytes[offset..offset + 4].copy_from_slice(&self.project.to_le_bytes());
+
         // Remaining bytes are reserved (already zeroed)
 
         bytes
@@ -348,6 +517,28 @@ impl Inode {
             offset += 8;
         }
 
+        // Flags
+        let flags = read_u32(bytes, offset);
+        offset += 4;
+
+        // Wrapped file key
+        let mut wrapped_key = [0u8; WRAPPED_KEY_SIZE];
+        wrapped_key.copy_from_slice(&bytes[offset..offset + WRAPPED_KEY_SIZE]);
+        offset += WRAPPED_KEY_SIZE;
+
+        // Inline data
+        let mut inline_data = [0u8; INLINE_DATA_SIZE];
+        inline_data.copy_from_slice(&bytes[offset..offset + INLINE_DATA_SIZE]);
+        offset += INLINE_DATA_SIZE;
+
+        // Owner
+        let uid = read_u32(bytes, offset);
+        let gid = read_u32(bytes, offset + 4);
+        offset += 8;
+
+        // Project
+        let project = read_u32(bytes, offset);
+
         Ok(Inode {
             inode_number,
             file_type,
@@ -360,6 +551,12 @@ impl Inode {
             accessed,
             direct_blocks,
             indirect_blocks,
+            flags,
+            wrapped_key,
+            inline_data,
+            uid,
+            gid,
+            project,
         })
     }
 }
@@ -476,4 +673,375 @@ impl DirectoryEntry {
             name,
         })
     }
-}
\ No newline at end of file
+
+    /// Bytes the entry takes when packed: inode number, entry type, name
+    /// length and the name itself
+    pub fn packed_size(&self) -> usize {
+        10 + self.name.len()
+    }
+
+    /// Serialize entries one after another without padding, as stored in
+    /// an inline directory
+    pub fn pack(entries: &[DirectoryEntry]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(entries.iter().map(Self::packed_size).sum());
+        for entry in entries {
+            bytes.extend_from_slice(&entry.to_bytes()[..entry.packed_size()]);
+        }
+        bytes
+    }
+
+    /// Deserialize entries serialized by `pack`
+    pub fn unpack(mut bytes: &[u8]) -> FsResult<Vec<DirectoryEntry>> {
+        let mut entries = Vec::new();
+        while !bytes.is_empty() {
+            let len = match bytes.get(9) {
+                Some(&name_len) if bytes.len() >= 10 + name_len as usize => 10 + name_len as usize,
+                _ => return Err(FsError::CorruptedFileSystem("Inline directory entry is cut short".to_string())),
+            };
+            let mut entry = [0u8; Self::ENTRY_SIZE];
+            entry[..len].copy_from_slice(&bytes[..len]);
+            entries.push(Self::from_bytes(&entry)?);
+            bytes = &bytes[len..];
+        }
+        Ok(entries)
+    }
+}
+/// Read a little-endian u64 at `offset`
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
+/// Read a little-endian u32 at `offset`
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(buf)
+}
+
+/// Block group descriptor - per-group bookkeeping stored in the superblock
+///
+/// Layout (56 bytes total):
+/// - First block of the group: 8 bytes
+/// - Number of blocks in the group: 8 bytes
+/// - First block of the group's inode table: 8 bytes
+/// - Number of inodes (inode table blocks): 8 bytes
+/// - Free blocks: 8 bytes
+/// - Free inodes: 8 bytes
+/// - Directories: 8 bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupDescriptor {
+    pub start_block: u64,
+    pub block_count: u64,
+    pub inode_table_start: u64,
+    pub inode_count: u64,
+    pub free_blocks: u64,
+    pub free_inodes: u64,
+    pub directories: u64,
+}
+
+impl GroupDescriptor {
+    pub const SIZE: usize = 56;
+
+    /// First block after the inode table, where file data should go
+    pub fn data_start(&self) -> u64 {
+        self.inode_table_start + self.inode_count
+    }
+
+    /// One past the last block of the group
+    pub fn end_block(&self) -> u64 {
+        self.start_block + self.block_count
+    }
+
+    /// Serialize descriptor to fixed-size binary format
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        let fields = [
+            self.start_block,
+            self.block_count,
+            self.inode_table_start,
+            self.inode_count,
+            self.free_blocks,
+            self.free_inodes,
+            self.directories,
+        ];
+        for (i, field) in fields.iter().enumerate() {
+            bytes[i * 8..i * 8 + 8].copy_from_slice(&field.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Deserialize descriptor from binary format
+    pub fn from_bytes(bytes: &[u8]) -> FsResult<Self> {
+        if bytes.len() < Self::SIZE {
+            return Err(FsError::InvalidMetadata(format!(
+                "Group descriptor data too short: {} bytes",
+                bytes.len()
+            )));
+        }
+
+        Ok(GroupDescriptor {
+            start_block: read_u64(bytes, 0),
+            block_count: read_u64(bytes, 8),
+            inode_table_start: read_u64(bytes, 16),
+            inode_count: read_u64(bytes, 24),
+            free_blocks: read_u64(bytes, 32),
+            free_inodes: read_u64(bytes, 40),
+            directories: read_u64(bytes, 48),
+        })
+    }
+}
+
+/// Superblock - describes the layout of the whole file system (block 0)
+///
+/// Layout:
+/// - Magic number: 4 bytes
+/// - Version: 4 bytes
+/// - Block size: 8 bytes
+/// - Total blocks: 8 bytes
+/// - Blocks per group: 8 bytes
+/// - Inodes per group: 8 bytes
+/// - Group count: 8 bytes
+/// - Block bitmap start / length: 8 + 8 bytes
+/// - Inode bitmap start / length: 8 + 8 bytes
+/// - Root directory inode block (0 = none): 8 bytes
+/// - Feature flags: 8 bytes
+/// - Allocator kind: 8 bytes
+/// - Checksum table start / length (0 = no table): 8 + 8 bytes
+/// - First block of the snapshot table (0 = no snapshots): 8 bytes
+/// - First block of the reference count table (0 = no shared blocks): 8 bytes
+/// - History policy: versions kept per file (0 = no history), maximum age
+///   in seconds and maximum blocks (0 = no limit): 8 + 8 + 8 bytes
+/// - Key derivation: PBKDF2 iterations (0 = not encrypted), salt, and a
+///   check value to recognise the derived key: 8 + 16 + 16 bytes
+/// - First block of the dedup table (0 = empty): 8 bytes
+/// - Inode blocks of the user and group quota files (0 = none): 8 + 8 bytes
+/// - Inode block of the project quota file (0 = none): 8 bytes
+/// - Reserved up to `HEADER_SIZE`
+/// - Group descriptors: GroupDescriptor::SIZE bytes each
+#[derive(Debug, Clone)]
+pub struct Superblock {
+    pub block_size: u64,
+    pub total_blocks: u64,
+    pub blocks_per_group: u64,
+    pub inodes_per_group: u64,
+    pub block_bitmap_start: u64,
+    pub block_bitmap_blocks: u64,
+    pub inode_bitmap_start: u64,
+    pub inode_bitmap_blocks: u64,
+    pub root_inode: u64,
+    pub features: u64,
+    pub allocator: u64,
+    pub checksum_table_start: u64,
+    pub checksum_table_blocks: u64,
+    pub snapshot_table: u64,
+    pub refcount_table: u64,
+    pub history_versions: u64,
+    pub history_max_age: u64,
+    pub history_max_blocks: u64,
+    pub kdf_iterations: u64,
+    pub kdf_salt: [u8; SALT_SIZE],
+    pub key_check: [u8; KEY_CHECK_SIZE],
+    pub dedup_table: u64,
+    pub user_quota_inode: u64,
+    pub group_quota_inode: u64,
+    pub project_quota_inode: u64,
+    pub groups: Vec<GroupDescriptor>,
+}
+
+impl Superblock {
+    const MAGIC: u32 = 0x53555042; // "SUPB" in ASCII
+    const VERSION: u32 = 2;
+
+    /// New files map their data with extents instead of block pointers
+    pub const FEATURE_EXTENTS: u64 = 1 << 0;
+
+    /// Metadata blocks are checksummed in the checksum table
+    pub const FEATURE_CHECKSUMS: u64 = 1 << 1;
+
+    /// File data blocks are checksummed as well; requires `FEATURE_CHECKSUMS`
+    pub const FEATURE_DATA_CHECKSUMS: u64 = 1 << 2;
+
+    /// File data is encrypted, and the checksum table holds the nonce and
+    /// tag of each encrypted block; requires `FEATURE_CHECKSUMS`
+    pub const FEATURE_ENCRYPTION: u64 = 1 << 3;
+
+    /// Names in directory entries are encrypted too; requires
+    /// `FEATURE_ENCRYPTION`
+    pub const FEATURE_ENCRYPTED_NAMES: u64 = 1 << 4;
+
+    /// Written data blocks are hashed and shared with identical blocks
+    /// already on disk
+    pub const FEATURE_DEDUP: u64 = 1 << 5;
+
+    /// Small files and directories are stored in their inodes
+    pub const FEATURE_INLINE_DATA: u64 = 1 << 6;
+
+    /// Blocks and inodes are accounted per user, group and project and
+    /// limited by the quota files
+    pub const FEATURE_QUOTA: u64 = 1 << 7;
+
+    /// Check whether a feature flag is set
+    pub fn has_feature(&self, feature: u64) -> bool {
+        self.features & feature != 0
+    }
+
+    /// Inode block of the quota file of `kind`, 0 if there is none
+    pub fn quota_inode(&self, kind: QuotaKind) -> u64 {
+        match kind {
+            QuotaKind::User => self.user_quota_inode,
+            QuotaKind::Group => self.group_quota_inode,
+            QuotaKind::Project => self.project_quota_inode,
+        }
+    }
+
+    /// Size of the fixed header before the group descriptor table
+    pub const HEADER_SIZE: usize = 256;
+
+    /// Byte offset of a group descriptor within the superblock
+    pub fn descriptor_offset(group: usize) -> usize {
+        Self::HEADER_SIZE + group * GroupDescriptor::SIZE
+    }
+
+    /// Maximum number of groups whose descriptors fit in one block
+    pub fn max_groups(block_size: u64) -> u64 {
+        (block_size - Self::HEADER_SIZE as u64) / GroupDescriptor::SIZE as u64
+    }
+
+    /// Serialize superblock and group descriptors to one block
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; self.block_size as usize];
+
+        bytes[0..4].copy_from_slice(&Self::MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&Self::VERSION.to_le_bytes());
+
+        let fields = [
+            self.block_size,
+            self.total_blocks,
+            self.blocks_per_group,
+            self.inodes_per_group,
+            self.groups.len() as u64,
+            self.block_bitmap_start,
+            self.block_bitmap_blocks,
+            self.inode_bitmap_start,
+            self.inode_bitmap_blocks,
+            self.root_inode,
+            self.features,
+            self.allocator,
+            self.checksum_table_start,
+            self.checksum_table_blocks,
+            self.snapshot_table,
+            self.refcount_table,
+            self.history_versions,
+            self.history_max_age,
+            self.history_max_blocks,
+            self.kdf_iterations,
+        ];
+        for (i, field) in fields.iter().enumerate() {
+            let offset = 8 + i * 8;
+            bytes[offset..offset + 8].copy_from_slice(&field.to_le_bytes());
+        }
+        bytes[168..168 + SALT_SIZE].copy_from_slice(&self.kdf_salt);
+        bytes[184..184 + KEY_CHECK_SIZE].copy_from_slice(&self.key_check);
+        bytes[200..208].copy_from_slice(&self.dedup_table.to_le_bytes());
+        bytes[208..216].copy_from_slice(&self.user_quota_inode.to_le_bytes());
+        bytes[216..224].copy_from_slice(&self.group_quota_inode.to_le_bytes());
+        bytes[224..232].copy_from_slice(&self.project_quota_inode.to_le_bytes());
+
+        for (i, group) in self.groups.iter().enumerate() {
+            let offset = Self::descriptor_offset(i);
+            bytes[offset..offset + GroupDescriptor::SIZE].copy_from_slice(&group.to_bytes());
+        }
+
+        bytes
+    }
+
+    /// Read the block size from a superblock header
+    ///
+    /// Needed before the whole superblock block can be read.
+    pub fn read_block_size(header: &[u8]) -> FsResult<u64> {
+        if header.len() < Self::HEADER_SIZE || read_u32(header, 0) != Self::MAGIC {
+            return Err(FsError::CorruptedFileSystem(
+                "Invalid superblock magic number".to_string(),
+            ));
+        }
+
+        let block_size = read_u64(header, 8);
+        if !block_size.is_power_of_two() || !(1024..=65536).contains(&block_size) {
+            return Err(FsError::CorruptedFileSystem(format!(
+                "Invalid block size in superblock: {}",
+                block_size
+            )));
+        }
+        Ok(block_size)
+    }
+
+    /// Deserialize superblock and group descriptors
+    pub fn from_bytes(bytes: &[u8]) -> FsResult<Self> {
+        if bytes.len() < Self::HEADER_SIZE {
+            return Err(FsError::InvalidMetadata(format!(
+                "Superblock data too short: {} bytes",
+                bytes.len()
+            )));
+        }
+
+        let magic = read_u32(bytes, 0);
+        if magic != Self::MAGIC {
+            return Err(FsError::CorruptedFileSystem(format!(
+                "Invalid superblock magic number: 0x{:08X}",
+                magic
+            )));
+        }
+
+        let version = read_u32(bytes, 4);
+        if version != Self::VERSION {
+            return Err(FsError::NotSupported(format!(
+                "Unsupported file system version: {}",
+                version
+            )));
+        }
+
+        let group_count = read_u64(bytes, 40) as usize;
+        if Self::descriptor_offset(group_count) > bytes.len() {
+            return Err(FsError::CorruptedFileSystem(format!(
+                "Superblock claims {} groups",
+                group_count
+            )));
+        }
+
+        let mut groups = Vec::with_capacity(group_count);
+        for i in 0..group_count {
+            groups.push(GroupDescriptor::from_bytes(&bytes[Self::descriptor_offset(i)..])?);
+        }
+
+        Ok(Superblock {
+            block_size: read_u64(bytes, 8),
+            total_blocks: read_u64(bytes, 16),
+            blocks_per_group: read_u64(bytes, 24),
+            inodes_per_group: read_u64(bytes, 32),
+            block_bitmap_start: read_u64(bytes, 48),
+            block_bitmap_blocks: read_u64(bytes, 56),
+            inode_bitmap_start: read_u64(bytes, 64),
+            inode_bitmap_blocks: read_u64(bytes, 72),
+            root_inode: read_u64(bytes, 80),
+            features: read_u64(bytes, 88),
+            allocator: read_u64(bytes, 96),
+            checksum_table_start: read_u64(bytes, 104),
+            checksum_table_blocks: read_u64(bytes, 112),
+            snapshot_table: read_u64(bytes, 120),
+            refcount_table: read_u64(bytes, 128),
+            history_versions: read_u64(bytes, 136),
+            history_max_age: read_u64(bytes, 144),
+            history_max_blocks: read_u64(bytes, 152),
+            kdf_iterations: read_u64(bytes, 160),
+            kdf_salt: bytes[168..168 + SALT_SIZE].try_into().unwrap(),
+            key_check: bytes[184..184 + KEY_CHECK_SIZE].try_into().unwrap(),
+            dedup_table: read_u64(bytes, 200),
+            user_quota_inode: read_u64(bytes, 208),
+            group_quota_inode: read_u64(bytes, 216),
+            project_quota_inode: read_u64(bytes, 224),
+            groups,
+        })
+    }
+}
@@ -0,0 +1,496 @@
+use crate::{
+    error::{FsError, FsResult},
+    path::{split_parent, Resolved},
+    scrub::{ScrubReport, Scrubber},
+    serialization::{DirectoryEntry, FileType, Inode, Permissions},
+    virtual_disk::VirtualDisk,
+};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+
+/// How an inode is locked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Default)]
+struct LockState {
+    readers: usize,
+    writer: bool,
+    /// Writers waiting for this inode; new readers queue behind them
+    waiting_writers: usize,
+}
+
+impl LockState {
+    fn is_free(&self) -> bool {
+        self.readers == 0 && !self.writer && self.waiting_writers == 0
+    }
+}
+
+/// Reader/writer locks on inodes, keyed by inode block
+///
+/// A lock only has an entry in the table while it is held or waited for.
+/// Every caller that needs several inodes asks for them in one `lock`
+/// call, which takes them in block order, so two threads can never each
+/// hold an inode the other is waiting for.
+#[derive(Debug, Default)]
+struct InodeLocks {
+    states: Mutex<HashMap<u64, LockState>>,
+    released: Condvar,
+}
+
+impl InodeLocks {
+    fn states(&self) -> MutexGuard<'_, HashMap<u64, LockState>> {
+        self.states.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock every inode in `wanted`, waiting as needed
+    ///
+    /// An inode listed twice is locked once, for writing if either entry
+    /// asks for it.
+    fn lock(&self, wanted: &[(u64, Access)]) -> InodeGuard<'_> {
+        let mut wanted = wanted.to_vec();
+        wanted.sort_by_key(|&(block, access)| (block, access == Access::Read));
+        wanted.dedup_by_key(|&mut (block, _)| block);
+
+        let mut states = self.states();
+        for &(block, access) in &wanted {
+            if access == Access::Write {
+                states.entry(block).or_default().waiting_writers += 1;
+            }
+            loop {
+                let state = states.entry(block).or_default();
+                match access {
+                    Access::Read if !state.writer && state.waiting_writers == 0 => {
+                        state.readers += 1;
+                        break;
+                    }
+                    Access::Write if !state.writer && state.readers == 0 => {
+                        state.waiting_writers -= 1;
+                        state.writer = true;
+                        break;
+                    }
+                    _ => {}
+                }
+                states = self.released.wait(states).unwrap_or_else(PoisonError::into_inner);
+            }
+        }
+        InodeGuard { locks: self, held: wanted }
+    }
+
+    fn unlock(&self, block: u64, access: Access) {
+        let mut states = self.states();
+        if let Some(state) = states.get_mut(&block) {
+            match access {
+                Access::Read => state.readers -= 1,
+                Access::Write => state.writer = false,
+            }
+            if state.is_free() {
+                states.remove(&block);
+            }
+        }
+        self.released.notify_all();
+    }
+}
+
+/// Inode locks held by one operation, released on drop
+struct InodeGuard<'a> {
+    locks: &'a InodeLocks,
+    held: Vec<(u64, Access)>,
+}
+
+impl InodeGuard<'_> {
+    /// Release one inode early
+    fn release(&mut self, block: u64) {
+        if let Some(index) = self.held.iter().position(|&(b, _)| b == block) {
+            let (block, access) = self.held.remove(index);
+            self.locks.unlock(block, access);
+        }
+    }
+}
+
+impl Drop for InodeGuard<'_> {
+    fn drop(&mut self) {
+        for (block, access) in self.held.drain(..) {
+            self.locks.unlock(block, access);
+        }
+    }
+}
+
+/// A directory entry locked for changing, together with its directory
+struct LockedEntry<'a, 'p> {
+    _guard: InodeGuard<'a>,
+    dir: u64,
+    name: &'p str,
+    entry: Option<DirectoryEntry>,
+}
+
+/// Turn "not found" into `None`
+fn optional(result: FsResult<DirectoryEntry>) -> FsResult<Option<DirectoryEntry>> {
+    match result {
+        Ok(entry) => Ok(Some(entry)),
+        Err(FsError::FileNotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// A disk that many threads can use at once
+///
+/// Operations lock only the inodes they touch: threads reading a file or
+/// directory share its lock, and a thread changing one holds it alone.
+/// Block allocation is serialized separately inside `VirtualDisk`.
+///
+/// Paths are resolved one directory at a time, holding each directory's
+/// read lock only while its entries are searched. The inodes an operation
+/// works on are then locked together and the entry is looked up again;
+/// if another thread changed it in the meantime, the operation starts
+/// over.
+#[derive(Debug)]
+pub struct SharedDisk {
+    disk: VirtualDisk,
+    locks: InodeLocks,
+    /// Held while moving a directory, so that the check that it is not
+    /// moved below itself cannot race with another directory move
+    rename_lock: Mutex<()>,
+}
+
+impl SharedDisk {
+    /// Share a disk; it must have a root directory
+    pub fn new(disk: VirtualDisk) -> Self {
+        SharedDisk {
+            disk,
+            locks: InodeLocks::default(),
+            rename_lock: Mutex::new(()),
+        }
+    }
+
+    /// Give back the disk once no other thread uses it
+    pub fn into_disk(self) -> VirtualDisk {
+        self.disk
+    }
+
+    // ==================== LOCKING ====================
+
+    /// Resolve `path`, read-locking each directory while it is searched
+    fn resolve(&self, path: &str, follow_last: bool) -> FsResult<Resolved> {
+        let find = |dir: u64, name: &str| {
+            let _guard = self.locks.lock(&[(dir, Access::Read)]);
+            if !self.disk.is_inode_used(dir) {
+                return Err(FsError::FileNotFound(name.to_string()));
+            }
+            self.disk.find_directory_entry(dir, name)
+        };
+        let read_link = |inode_block: u64| {
+            let _guard = self.locks.lock(&[(inode_block, Access::Read)]);
+            if !self.disk.is_inode_used(inode_block) {
+                return Err(FsError::FileNotFound(path.to_string()));
+            }
+            self.disk.read_link_inode(inode_block)
+        };
+        self.disk.resolve_with(path, follow_last, &find, &read_link)
+    }
+
+    /// Check that `inode_block` still holds a directory
+    ///
+    /// A directory found during path resolution may have been removed,
+    /// and its inode reused, before it is locked.
+    fn is_directory(&self, inode_block: u64) -> FsResult<bool> {
+        Ok(self.disk.is_inode_used(inode_block)
+            && self.disk.read_inode(inode_block)?.file_type == FileType::Directory)
+    }
+
+    /// Look up `name` in `dir` under a short read lock
+    ///
+    /// Returns `None` if `dir` is no longer a directory.
+    fn find_entry(&self, dir: u64, name: &str) -> FsResult<Option<Option<DirectoryEntry>>> {
+        let _guard = self.locks.lock(&[(dir, Access::Read)]);
+        if !self.is_directory(dir)? {
+            return Ok(None);
+        }
+        optional(self.disk.find_directory_entry(dir, name)).map(Some)
+    }
+
+    /// Check that `name` in the locked directory `dir` still is `expected`
+    fn entry_unchanged(&self, dir: u64, name: &str, expected: Option<u64>) -> FsResult<bool> {
+        if !self.is_directory(dir)? {
+            return Ok(false);
+        }
+        let entry = optional(self.disk.find_directory_entry(dir, name))?;
+        Ok(entry.map(|e| e.inode_number) == expected)
+    }
+
+    /// Resolve `path` and lock the inode it names
+    fn lock_path(&self, path: &str, follow_last: bool, access: Access) -> FsResult<(InodeGuard<'_>, u64)> {
+        loop {
+            let resolved = self.resolve(path, follow_last)?;
+            let inode_block = resolved.inode();
+            let Some(parent) = resolved.parent() else {
+                // The root directory is never removed
+                return Ok((self.locks.lock(&[(inode_block, access)]), inode_block));
+            };
+
+            // Hold the directory while checking the entry, so the inode
+            // cannot be unlinked and reused between lookup and lock
+            let name = resolved.names.last().unwrap();
+            let mut guard = self.locks.lock(&[(parent, Access::Read), (inode_block, access)]);
+            if self.entry_unchanged(parent, name, Some(inode_block))? {
+                guard.release(parent);
+                return Ok((guard, inode_block));
+            }
+        }
+    }
+
+    /// Write-lock the parent directory of `path` and the entry it names
+    fn lock_entry<'p>(&self, path: &'p str) -> FsResult<LockedEntry<'_, 'p>> {
+        let (parent, name) = split_parent(path)?;
+        loop {
+            let dir = self.resolve(parent, true)?.inode();
+            let Some(entry) = self.find_entry(dir, name)? else {
+                continue;
+            };
+            let mut wanted = vec![(dir, Access::Write)];
+            wanted.extend(entry.as_ref().map(|e| (e.inode_number, Access::Write)));
+
+            let guard = self.locks.lock(&wanted);
+            if self.entry_unchanged(dir, name, entry.as_ref().map(|e| e.inode_number))? {
+                return Ok(LockedEntry { _guard: guard, dir, name, entry });
+            }
+        }
+    }
+
+    // ==================== READING ====================
+
+    /// Get the inode block at `path`, following symlinks
+    pub fn lookup(&self, path: &str) -> FsResult<u64> {
+        Ok(self.resolve(path, true)?.inode())
+    }
+
+    /// Get the inode at `path`, without following a final symlink
+    pub fn stat(&self, path: &str) -> FsResult<Inode> {
+        let (_guard, inode_block) = self.lock_path(path, false, Access::Read)?;
+        self.disk.read_inode(inode_block)
+    }
+
+    /// Get the inode at `path`, following symlinks
+    pub fn metadata(&self, path: &str) -> FsResult<Inode> {
+        let (_guard, inode_block) = self.lock_path(path, true, Access::Read)?;
+        self.disk.read_inode(inode_block)
+    }
+
+    /// List the directory at `path`
+    pub fn list_directory(&self, path: &str) -> FsResult<Vec<DirectoryEntry>> {
+        let (_guard, inode_block) = self.lock_path(path, true, Access::Read)?;
+        self.disk.list_directory(inode_block)
+    }
+
+    /// Read the whole file at `path`
+    pub fn read_file(&self, path: &str) -> FsResult<Vec<u8>> {
+        let (_guard, inode_block) = self.lock_path(path, true, Access::Read)?;
+        self.disk.read_file(inode_block)
+    }
+
+    /// Read from the file at `path` at byte `offset`, see `VirtualDisk::read_at`
+    pub fn read_at(&self, path: &str, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        let (_guard, inode_block) = self.lock_path(path, true, Access::Read)?;
+        self.disk.read_at(inode_block, offset, buf)
+    }
+
+    // ==================== WRITING ====================
+
+    /// Replace the contents of the file at `path`, creating it if needed
+    ///
+    /// A new file is filled before it is linked into its directory, so
+    /// other threads never see it partly written.
+    pub fn write_file(&self, path: &str, data: &[u8]) -> FsResult<u64> {
+        loop {
+            match self.lock_path(path, true, Access::Write) {
+                Ok((_guard, inode_block)) => {
+                    self.disk.write_file(inode_block, data)?;
+                    return Ok(inode_block);
+                }
+                Err(FsError::FileNotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+
+            let locked = self.lock_entry(path)?;
+            match &locked.entry {
+                None => {
+                    let perms = Permissions::new(true, true, false);
+                    let inode_block = self.disk.create_file_in(locked.dir, 0, perms)?;
+                    if let Err(e) = self.disk.write_file(inode_block, data) {
+                        self.disk.delete_file(inode_block)?;
+                        return Err(e);
+                    }
+                    return self.disk.link_new_inode(locked.dir, locked.name, inode_block);
+                }
+                // A symlink to nothing
+                Some(entry) if entry.file_type == FileType::Symlink => {
+                    return Err(FsError::FileNotFound(path.to_string()));
+                }
+                // Created by another thread since the first lookup
+                Some(_) => {}
+            }
+        }
+    }
+
+    /// Write to the file at `path` at byte `offset`, see `VirtualDisk::write_at`
+    pub fn write_at(&self, path: &str, offset: u64, data: &[u8]) -> FsResult<()> {
+        let (_guard, inode_block) = self.lock_path(path, true, Access::Write)?;
+        self.disk.write_at(inode_block, offset, data)
+    }
+
+    /// Create an empty file at `path`
+    pub fn create_file(&self, path: &str, permissions: Permissions) -> FsResult<u64> {
+        let locked = self.lock_entry(path)?;
+        if locked.entry.is_some() {
+            return Err(FsError::AlreadyExists(path.to_string()));
+        }
+        let inode_block = self.disk.create_file_in(locked.dir, 0, permissions)?;
+        self.disk.link_new_inode(locked.dir, locked.name, inode_block)
+    }
+
+    /// Create a directory at `path`
+    pub fn create_directory(&self, path: &str, permissions: Permissions) -> FsResult<u64> {
+        let locked = self.lock_entry(path)?;
+        if locked.entry.is_some() {
+            return Err(FsError::AlreadyExists(path.to_string()));
+        }
+        let inode_block = self.disk.create_directory_in(locked.dir, 0, permissions)?;
+        self.disk.link_new_inode(locked.dir, locked.name, inode_block)
+    }
+
+    /// Remove the file, symlink or empty directory at `path`
+    pub fn remove(&self, path: &str) -> FsResult<()> {
+        let locked = self.lock_entry(path)?;
+        let entry = locked.entry.as_ref().ok_or_else(|| FsError::FileNotFound(path.to_string()))?;
+        self.disk.unlink_entry(locked.dir, locked.name, entry, path)
+    }
+
+    /// Rename `from` to `to`
+    ///
+    /// Unlike `VirtualDisk::rename_path`, `to` is always the new name, never
+    /// a directory to move into. An existing file at `to` is replaced; a
+    /// directory can only replace an empty directory. Moves between
+    /// projects are limited like there.
+    ///
+    /// Both directories, the moved inode and any replaced inode are locked
+    /// together, in block order.
+    pub fn rename(&self, from: &str, to: &str) -> FsResult<()> {
+        let (from_parent, from_name) = split_parent(from)?;
+        let (to_parent, to_name) = split_parent(to)?;
+        let mut moves_directory = false;
+
+        loop {
+            let _rename = moves_directory
+                .then(|| self.rename_lock.lock().unwrap_or_else(PoisonError::into_inner));
+
+            let from_dir = self.resolve(from_parent, true)?.inode();
+            let to_path = self.resolve(to_parent, true)?;
+            let to_dir = to_path.inode();
+            let Some(entry) = self.find_entry(from_dir, from_name)? else {
+                continue;
+            };
+            let entry = entry.ok_or_else(|| FsError::FileNotFound(from.to_string()))?;
+
+            if entry.file_type == FileType::Directory {
+                if !moves_directory {
+                    moves_directory = true;
+                    continue;
+                }
+                // Directory moves are serialized, so the chain of
+                // directories above the destination cannot change now
+                if to_path.inodes.contains(&entry.inode_number) {
+                    return Err(FsError::InvalidPath(format!("Cannot move {} into itself", from)));
+                }
+            }
+
+            let Some(existing) = self.find_entry(to_dir, to_name)? else {
+                continue;
+            };
+            let mut wanted = vec![
+                (from_dir, Access::Write),
+                (to_dir, Access::Write),
+                (entry.inode_number, Access::Write),
+            ];
+            wanted.extend(existing.as_ref().map(|e| (e.inode_number, Access::Write)));
+
+            let _guard = self.locks.lock(&wanted);
+            if !self.entry_unchanged(from_dir, from_name, Some(entry.inode_number))?
+                || !self.entry_unchanged(to_dir, to_name, existing.as_ref().map(|e| e.inode_number))?
+            {
+                continue;
+            }
+
+            if let Some(existing) = &existing {
+                if existing.inode_number == entry.inode_number {
+                    return Ok(());
+                }
+                if (existing.file_type == FileType::Directory) != (entry.file_type == FileType::Directory) {
+                    return Err(FsError::AlreadyExists(to.to_string()));
+                }
+            }
+            let project = self.disk.entering_project(from_dir, to_dir, entry.inode_number, from)?;
+            if let Some(existing) = existing {
+                self.disk.unlink_entry(to_dir, to_name, &existing, to)?;
+            }
+
+            let moved = DirectoryEntry::new(entry.inode_number, entry.file_type, to_name.to_string())?;
+            self.disk.add_directory_entry(to_dir, moved)?;
+            self.disk.remove_directory_entry(from_dir, from_name)?;
+            if let Some(project) = project {
+                self.disk.set_project(entry.inode_number, project)?;
+            }
+            return Ok(());
+        }
+    }
+
+    /// Save allocator state and flush the image to stable storage
+    pub fn sync(&self) -> FsResult<()> {
+        self.disk.sync()
+    }
+
+    // ==================== SCRUB ====================
+
+    /// Read every block in use and verify it, see `VirtualDisk::scrub`
+    ///
+    /// Each file is read-locked while it is scrubbed, so this can run in
+    /// a background thread while others use the disk. Files created,
+    /// removed or renamed during the scrub may be missed, and inodes no
+    /// directory points at are not read as inodes: a new file is set up
+    /// before it is linked, without holding its lock.
+    pub fn scrub(&self) -> FsResult<ScrubReport> {
+        let mut scrubber = Scrubber::new(&self.disk);
+        scrubber.layout();
+
+        let root = self.resolve("/", true)?.inode();
+        let mut queue = VecDeque::from([(None, root, "/".to_string())]);
+        while let Some((parent, inode_block, path)) = queue.pop_front() {
+            let mut wanted = vec![(inode_block, Access::Read)];
+            wanted.extend(parent.map(|dir| (dir, Access::Read)));
+            let _guard = self.locks.lock(&wanted);
+
+            // Skip entries removed or replaced since their directory was read
+            if let Some(dir) = parent {
+                let name = path.rsplit('/').next().unwrap_or_default();
+                if !matches!(self.entry_unchanged(dir, name, Some(inode_block)), Ok(true)) {
+                    continue;
+                }
+            }
+            let Some(inode) = scrubber.inode(inode_block, Some(&path)) else {
+                continue;
+            };
+            if inode.file_type != FileType::Directory {
+                continue;
+            }
+            for entry in scrubber.entries(inode_block, &path) {
+                let child_path = format!("{}/{}", path.trim_end_matches('/'), entry.name);
+                queue.push_back((Some(inode_block), entry.inode_number, child_path));
+            }
+        }
+
+        scrubber.unowned();
+        Ok(scrubber.finish())
+    }
+}
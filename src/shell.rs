@@ -0,0 +1,735 @@
+use crate::{
+    error::{FsError, FsResult},
+    serialization::{FileType, Inode, Permissions},
+    transfer::TransferReport,
+    virtual_disk::VirtualDisk,
+};
+use std::io::Write;
+
+/// Commands understood by `Shell::execute`, with a one-line usage each
+pub const COMMANDS: &[(&str, &str)] = &[
+    ("ls", "ls [-l] [path...]            list directory contents"),
+    ("cd", "cd [path]                    change the current directory"),
+    ("pwd", "pwd                          print the current directory"),
+    ("mkdir", "mkdir [-p] path...           create directories"),
+    ("touch", "touch path...                create empty files"),
+    ("cat", "cat path...                  print file contents"),
+    ("echo", "echo [text...]               print text (use > or >> to write a file)"),
+    ("rm", "rm [-r] path...              remove files or directories"),
+    ("mv", "mv from to                   move or rename"),
+    ("cp", "cp [-r] from to              copy files or directories"),
+    ("ln", "ln [-s] target link          create a hard or symbolic link"),
+    ("stat", "stat path...                 show inode details"),
+    ("df", "df                           show disk usage"),
+    ("layout", "layout                       show the block map and fragmentation"),
+    ("tree", "tree [path]                  show a directory tree"),
+    ("put", "put host_file [path]         copy a host file into the image"),
+    ("get", "get path [host_file]         copy a file out of the image"),
+    ("import", "import host_dir [path]       copy a host directory tree into the image"),
+    ("export", "export path host_dir         copy a directory tree out of the image"),
+    ("versions", "versions path                list the kept versions of a file"),
+    ("restore", "restore path number          bring back a version of a file"),
+    ("trash", "trash                        list deleted files"),
+    ("undelete", "undelete path                move a deleted file back"),
+    ("compress", "compress [-d] path...        compress files, or stop compressing them"),
+    ("unlock", "unlock passphrase            unlock an encrypted image"),
+    ("help", "help                         show this list"),
+];
+
+/// Cells per line and lines of the `layout` block map
+const LAYOUT_WIDTH: usize = 64;
+const LAYOUT_ROWS: usize = 32;
+
+/// A word of a command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Word {
+    Text(String),
+    /// `>`: replace the target file with the command's output
+    Redirect,
+    /// `>>`: append the command's output to the target file
+    Append,
+}
+
+/// Split a command line into words
+///
+/// Supports single quotes, double quotes with backslash escapes, and
+/// unquoted `>` / `>>` redirections.
+pub fn tokenize(line: &str) -> FsResult<Vec<Word>> {
+    let mut words = Vec::new();
+    let mut current: Option<String> = None;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                words.extend(current.take().map(Word::Text));
+            }
+            '>' => {
+                words.extend(current.take().map(Word::Text));
+                if chars.peek() == Some(&'>') {
+                    chars.next();
+                    words.push(Word::Append);
+                } else {
+                    words.push(Word::Redirect);
+                }
+            }
+            '\'' => {
+                let text = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => text.push(c),
+                        None => return Err(FsError::InvalidPath("Unterminated quote".to_string())),
+                    }
+                }
+            }
+            '"' => {
+                let text = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => text.extend(chars.next()),
+                        Some(c) => text.push(c),
+                        None => return Err(FsError::InvalidPath("Unterminated quote".to_string())),
+                    }
+                }
+            }
+            '\\' => current.get_or_insert_with(String::new).extend(chars.next()),
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(current.take().map(Word::Text));
+    Ok(words)
+}
+
+/// Interactive shell state: an open disk and a current directory
+#[derive(Debug)]
+pub struct Shell {
+    disk: VirtualDisk,
+    cwd: String,
+}
+
+impl Shell {
+    /// Open (or create) a disk image and start in its root directory
+    ///
+    /// A root directory is created if the image does not have one yet.
+    pub fn open(path: &str) -> FsResult<Self> {
+        let disk = VirtualDisk::new(path)?;
+        if disk.root_directory().is_none() {
+            disk.initialize_root_dir()?;
+        }
+        Ok(Self::new(disk))
+    }
+
+    pub fn new(disk: VirtualDisk) -> Self {
+        Shell {
+            disk,
+            cwd: "/".to_string(),
+        }
+    }
+
+    /// Current directory
+    pub fn cwd(&self) -> &str {
+        &self.cwd
+    }
+
+    pub fn disk(&mut self) -> &mut VirtualDisk {
+        &mut self.disk
+    }
+
+    /// Turn a path typed by the user into an absolute path
+    fn absolute(&self, path: &str) -> String {
+        if path.starts_with('/') {
+            path.to_string()
+        } else {
+            format!("{}/{}", self.cwd.trim_end_matches('/'), path)
+        }
+    }
+
+    /// Run one command line, writing its output to `out`
+    ///
+    /// A trailing `> file` or `>> file` sends the output to a file in the
+    /// image instead.
+    pub fn execute(&mut self, line: &str, out: &mut dyn Write) -> FsResult<()> {
+        let mut words = tokenize(line)?;
+
+        let redirect = match words.iter().position(|w| !matches!(w, Word::Text(_))) {
+            Some(i) => {
+                let target = match words.get(i + 1..) {
+                    Some([Word::Text(target)]) => target.clone(),
+                    _ => return Err(FsError::InvalidPath("Expected one file after redirection".to_string())),
+                };
+                let append = words[i] == Word::Append;
+                words.truncate(i);
+                Some((self.absolute(&target), append))
+            }
+            None => None,
+        };
+
+        let args: Vec<String> = words
+            .into_iter()
+            .map(|w| match w {
+                Word::Text(text) => text,
+                _ => unreachable!(),
+            })
+            .collect();
+        let Some((command, args)) = args.split_first() else {
+            return Ok(());
+        };
+
+        match redirect {
+            None => self.run(command, args, out),
+            Some((target, append)) => {
+                let mut buffer = Vec::new();
+                self.run(command, args, &mut buffer)?;
+                if append {
+                    self.disk.append_file_at(&target, &buffer)?;
+                } else {
+                    self.disk.write_file_at(&target, &buffer)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn run(&mut self, command: &str, args: &[String], out: &mut dyn Write) -> FsResult<()> {
+        let (flags, paths) = split_flags(args);
+        match command {
+            "ls" => self.ls(&flags, &paths, out),
+            "cd" => self.cd(paths.first().map_or("/", String::as_str)),
+            "pwd" => Ok(writeln!(out, "{}", self.cwd)?),
+            "mkdir" => self.mkdir(&flags, &paths),
+            "touch" => self.touch(&paths),
+            "cat" => self.cat(&paths, out),
+            "echo" => Ok(writeln!(out, "{}", args.join(" "))?),
+            "rm" => self.rm(&flags, &paths),
+            "mv" => {
+                let [from, to] = two_paths(&paths, "mv from to")?;
+                self.disk.rename_path(&self.absolute(from), &self.absolute(to))
+            }
+            "cp" => self.cp(&flags, &paths),
+            "ln" => {
+                let [target, link] = two_paths(&paths, "ln [-s] target link")?;
+                if !flags.contains(&'s') {
+                    let target = self.absolute(target);
+                    return self.disk.create_hard_link(&target, &self.absolute(link)).map(|_| ());
+                }
+                self.disk.create_symlink(target, &self.absolute(link)).map(|_| ())
+            }
+            "stat" => self.stat(&paths, out),
+            "df" => self.df(out),
+            "layout" => self.layout(out),
+            "tree" => self.tree(paths.first().map_or(".", String::as_str), out),
+            "put" => self.put(&paths),
+            "get" => self.get(&paths),
+            "import" => self.import(&paths, out),
+            "export" => self.export(&paths, out),
+            "versions" => self.versions(&paths, out),
+            "restore" => {
+                let [path, number] = two_paths(&paths, "restore path number")?;
+                let number = number
+                    .parse()
+                    .map_err(|_| FsError::InvalidPath(format!("Invalid version number: {}", number)))?;
+                self.disk.restore_version(&self.absolute(path), number)
+            }
+            "trash" => self.trash(out),
+            "undelete" => {
+                require_paths(&paths, "undelete path")?;
+                for path in &paths {
+                    self.disk.undelete(&self.absolute(path))?;
+                }
+                Ok(())
+            }
+            "compress" => {
+                require_paths(&paths, "compress [-d] path...")?;
+                for path in &paths {
+                    let inode_block = self.disk.lookup_path(&self.absolute(path))?;
+                    self.disk.set_compressed(inode_block, !flags.contains(&'d'))?;
+                }
+                Ok(())
+            }
+            "unlock" => {
+                let [passphrase] = args else {
+                    return Err(FsError::InvalidPath("Usage: unlock passphrase".to_string()));
+                };
+                self.disk.unlock(passphrase)
+            }
+            "help" => {
+                for (_, usage) in COMMANDS {
+                    writeln!(out, "  {}", usage)?;
+                }
+                Ok(())
+            }
+            _ => Err(FsError::NotSupported(format!("Unknown command: {}", command))),
+        }
+    }
+
+    fn ls(&mut self, flags: &[char], paths: &[String], out: &mut dyn Write) -> FsResult<()> {
+        let long = flags.contains(&'l');
+        let paths = if paths.is_empty() { vec![".".to_string()] } else { paths.to_vec() };
+
+        for (i, path) in paths.iter().enumerate() {
+            let absolute = self.absolute(path);
+            let inode_block = self.disk.lookup_path(&absolute)?;
+            let inode = self.disk.read_inode(inode_block)?;
+            if inode.file_type != FileType::Directory {
+                self.ls_entry(&absolute, path, long, out)?;
+                continue;
+            }
+
+            if paths.len() > 1 {
+                if i > 0 {
+                    writeln!(out)?;
+                }
+                writeln!(out, "{}:", path)?;
+            }
+            let mut entries = self.disk.list_directory_at(&absolute)?;
+            entries.sort_by(|a, b| a.name.cmp(&b.name));
+            for entry in entries {
+                let child = format!("{}/{}", absolute.trim_end_matches('/'), entry.name);
+                self.ls_entry(&child, &entry.name, long, out)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn ls_entry(&mut self, path: &str, name: &str, long: bool, out: &mut dyn Write) -> FsResult<()> {
+        if !long {
+            writeln!(out, "{}", name)?;
+            return Ok(());
+        }
+
+        let inode = self.disk.stat_path(path)?;
+        write!(
+            out,
+            "{} {:>2} {:>10} {} {}",
+            mode_string(&inode),
+            inode.link_count,
+            inode.size,
+            format_time(inode.modified),
+            name
+        )?;
+        if inode.file_type == FileType::Symlink {
+            write!(out, " -> {}", self.disk.read_link(path)?)?;
+        }
+        writeln!(out)?;
+        Ok(())
+    }
+
+    fn cd(&mut self, path: &str) -> FsResult<()> {
+        let absolute = self.absolute(path);
+        let inode_block = self.disk.lookup_path(&absolute)?;
+        if self.disk.read_inode(inode_block)?.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory(path.to_string()));
+        }
+        self.cwd = self.disk.canonicalize_path(&absolute)?;
+        Ok(())
+    }
+
+    fn mkdir(&mut self, flags: &[char], paths: &[String]) -> FsResult<()> {
+        require_paths(paths, "mkdir [-p] path...")?;
+        let perms = Permissions::new(true, true, true);
+        for path in paths {
+            let absolute = self.absolute(path);
+            if flags.contains(&'p') {
+                self.disk.create_directories(&absolute, perms)?;
+            } else {
+                self.disk.create_directory_at(&absolute, perms)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn touch(&mut self, paths: &[String]) -> FsResult<()> {
+        require_paths(paths, "touch path...")?;
+        for path in paths {
+            let absolute = self.absolute(path);
+            match self.disk.lookup_path(&absolute) {
+                Ok(inode_block) => {
+                    let mut inode = self.disk.read_inode(inode_block)?;
+                    inode.modified = now();
+                    inode.accessed = inode.modified;
+                    self.disk.write_inode(inode_block, &inode)?;
+                }
+                Err(FsError::FileNotFound(_)) => {
+                    self.disk.create_file_at(&absolute, Permissions::new(true, true, false))?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn cat(&mut self, paths: &[String], out: &mut dyn Write) -> FsResult<()> {
+        require_paths(paths, "cat path...")?;
+        for path in paths {
+            let data = self.disk.read_file_at(&self.absolute(path))?;
+            out.write_all(&data)?;
+        }
+        Ok(())
+    }
+
+    fn rm(&mut self, flags: &[char], paths: &[String]) -> FsResult<()> {
+        require_paths(paths, "rm [-r] path...")?;
+        for path in paths {
+            let absolute = self.absolute(path);
+            if self.disk.canonicalize_path(&absolute).ok().as_deref() == Some("/") {
+                return Err(FsError::PermissionDenied("Cannot remove the root directory".to_string()));
+            }
+            if flags.contains(&'r') {
+                self.disk.remove_tree(&absolute)?;
+            } else {
+                if self.disk.stat_path(&absolute)?.file_type == FileType::Directory {
+                    return Err(FsError::NotAFile(format!("{} is a directory (use rm -r)", path)));
+                }
+                self.disk.remove_path(&absolute)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn cp(&mut self, flags: &[char], paths: &[String]) -> FsResult<()> {
+        let [from, to] = two_paths(paths, "cp [-r] from to")?;
+        let from = self.absolute(from);
+        if !flags.contains(&'r') && self.disk.stat_path(&from)?.file_type == FileType::Directory {
+            return Err(FsError::NotAFile(format!("{} is a directory (use cp -r)", from)));
+        }
+        self.disk.copy_path(&from, &self.absolute(to))
+    }
+
+    fn stat(&mut self, paths: &[String], out: &mut dyn Write) -> FsResult<()> {
+        require_paths(paths, "stat path...")?;
+        for path in paths {
+            let absolute = self.absolute(path);
+            let inode_block = self.disk.lookup_path_nofollow(&absolute)?;
+            let inode = self.disk.read_inode(inode_block)?;
+            let mapping = self.disk.file_mapping(inode_block)?;
+
+            write!(out, "  File: {}", path)?;
+            if inode.file_type == FileType::Symlink {
+                write!(out, " -> {}", self.disk.read_link(&absolute)?)?;
+            }
+            writeln!(out)?;
+            writeln!(out, "  Type: {}", type_name(inode.file_type))?;
+            writeln!(out, " Inode: {}  Links: {}  Mode: {}", inode_block, inode.link_count, mode_string(&inode))?;
+            writeln!(out, " Owner: {}:{}  Project: {}", inode.uid, inode.gid, inode.project)?;
+            writeln!(
+                out,
+                "  Size: {}  Blocks: {}  Mapping blocks: {}  Fragments: {}",
+                inode.size,
+                inode.block_count,
+                mapping.metadata_blocks.len(),
+                mapping.extents.len()
+            )?;
+            let kind = if inode.has_flag(Inode::FLAG_EXTENTS) { "extents" } else { "block pointers" };
+            let encrypted = if inode.has_flag(Inode::FLAG_ENCRYPTED) { ", encrypted" } else { "" };
+            if inode.has_flag(Inode::FLAG_COMPRESSED) {
+                let physical = self.disk.physical_size(inode_block)?;
+                writeln!(out, "Layout: {}, compressed to {} bytes{}", kind, physical, encrypted)?;
+            } else if inode.has_flag(Inode::FLAG_COMPRESS) {
+                writeln!(out, "Layout: {}, compressing new files", kind)?;
+            } else if inode.is_inline() {
+                writeln!(out, "Layout: inline")?;
+            } else {
+                writeln!(out, "Layout: {}{}", kind, encrypted)?;
+            }
+            writeln!(out, "Access: {}", format_time(inode.accessed))?;
+            writeln!(out, "Modify: {}", format_time(inode.modified))?;
+            writeln!(out, " Birth: {}", format_time(inode.created))?;
+        }
+        Ok(())
+    }
+
+    fn df(&mut self, out: &mut dyn Write) -> FsResult<()> {
+        let superblock = self.disk.block_groups().superblock().clone();
+        let used_inodes = self.disk.block_groups().used_inodes().len() as u64;
+        let total_inodes = self.disk.block_groups().group_count() as u64 * superblock.inodes_per_group;
+        let kib = |blocks: u64| blocks * superblock.block_size / 1024;
+
+        writeln!(out, "{:>12} {:>12} {:>12} {:>6}", "1K-blocks", "Used", "Available", "Use%")?;
+        writeln!(
+            out,
+            "{:>12} {:>12} {:>12} {:>5.1}%",
+            kib(self.disk.total_blocks()),
+            kib(self.disk.used_blocks_count()),
+            kib(self.disk.free_blocks_count()),
+            self.disk.utilization()
+        )?;
+        writeln!(
+            out,
+            "Inodes: {} used, {} free, {} total",
+            used_inodes,
+            total_inodes - used_inodes,
+            total_inodes
+        )?;
+        let sharing = self.disk.sharing_stats();
+        writeln!(
+            out,
+            "Shared: {}K in {} blocks used by cloned files, saving {}K",
+            kib(sharing.shared_blocks),
+            sharing.shared_blocks,
+            kib(sharing.saved_blocks)
+        )?;
+        if self.disk.history_policy().is_enabled() {
+            let blocks = self.disk.history_blocks()?;
+            writeln!(out, "History: {}K in {} blocks of old versions and deleted files", kib(blocks), blocks)?;
+        }
+        Ok(())
+    }
+
+    fn layout(&mut self, out: &mut dyn Write) -> FsResult<()> {
+        let layout = self.disk.disk_layout()?;
+        let blocks_per_cell = layout.blocks_per_cell(LAYOUT_WIDTH, LAYOUT_ROWS);
+        writeln!(out, "{} blocks per cell", blocks_per_cell)?;
+        writeln!(out, "{}", layout.render_ascii(LAYOUT_WIDTH, blocks_per_cell, false))?;
+        write!(out, "{}", layout.render_report())?;
+        Ok(())
+    }
+
+    fn versions(&mut self, paths: &[String], out: &mut dyn Write) -> FsResult<()> {
+        require_paths(paths, "versions path")?;
+        for path in paths {
+            let versions = self.disk.list_versions(&self.absolute(path))?;
+            if paths.len() > 1 {
+                writeln!(out, "{}:", path)?;
+            }
+            for version in versions {
+                writeln!(
+                    out,
+                    "{:>4} {:>10} {}  replaced {}",
+                    version.number,
+                    version.size,
+                    format_time(version.modified),
+                    format_time(version.replaced)
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn trash(&mut self, out: &mut dyn Write) -> FsResult<()> {
+        for entry in self.disk.list_trash()? {
+            writeln!(
+                out,
+                "{:>10} {}  {}{}",
+                entry.size,
+                format_time(entry.deleted),
+                entry.path,
+                if entry.file_type == FileType::Symlink { " (symlink)" } else { "" }
+            )?;
+        }
+        Ok(())
+    }
+
+    fn tree(&mut self, path: &str, out: &mut dyn Write) -> FsResult<()> {
+        let absolute = self.absolute(path);
+        self.disk.lookup_path(&absolute)?;
+        writeln!(out, "{}", path)?;
+        let mut counts = (0, 0);
+        self.tree_level(&absolute, "", &mut counts, out)?;
+        writeln!(out, "\n{} directories, {} files", counts.0, counts.1)?;
+        Ok(())
+    }
+
+    fn tree_level(
+        &mut self,
+        path: &str,
+        prefix: &str,
+        counts: &mut (u64, u64),
+        out: &mut dyn Write,
+    ) -> FsResult<()> {
+        let mut entries = self.disk.list_directory_at(path)?;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for (i, entry) in entries.iter().enumerate() {
+            let last = i + 1 == entries.len();
+            let child = format!("{}/{}", path.trim_end_matches('/'), entry.name);
+            write!(out, "{}{}{}", prefix, if last { "└── " } else { "├── " }, entry.name)?;
+            match entry.file_type {
+                FileType::Directory => {
+                    writeln!(out)?;
+                    counts.0 += 1;
+                    let prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+                    self.tree_level(&child, &prefix, counts, out)?;
+                }
+                FileType::Symlink => {
+                    writeln!(out, " -> {}", self.disk.read_link(&child)?)?;
+                    counts.1 += 1;
+                }
+                FileType::File => {
+                    writeln!(out)?;
+                    counts.1 += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn put(&mut self, paths: &[String]) -> FsResult<()> {
+        let (host, dest) = match paths {
+            [host] => (host, base_name(host).to_string()),
+            [host, dest] => (host, dest.clone()),
+            _ => return Err(FsError::InvalidPath("Usage: put host_file [path]".to_string())),
+        };
+        let data = std::fs::read(host)?;
+
+        let mut dest = self.absolute(&dest);
+        if let Ok(block) = self.disk.lookup_path(&dest) {
+            if self.disk.read_inode(block)?.file_type == FileType::Directory {
+                dest = format!("{}/{}", dest.trim_end_matches('/'), base_name(host));
+            }
+        }
+        self.disk.write_file_at(&dest, &data).map(|_| ())
+    }
+
+    fn get(&mut self, paths: &[String]) -> FsResult<()> {
+        let (source, host) = match paths {
+            [source] => (source, base_name(source).to_string()),
+            [source, host] => (source, host.clone()),
+            _ => return Err(FsError::InvalidPath("Usage: get path [host_file]".to_string())),
+        };
+        let data = self.disk.read_file_at(&self.absolute(source))?;
+        std::fs::write(host, data)?;
+        Ok(())
+    }
+
+    fn import(&mut self, paths: &[String], out: &mut dyn Write) -> FsResult<()> {
+        let (host, dest) = match paths {
+            [host] => (host, ".".to_string()),
+            [host, dest] => (host, dest.clone()),
+            _ => return Err(FsError::InvalidPath("Usage: import host_dir [path]".to_string())),
+        };
+        let dest = self.absolute(&dest);
+        let mut written = Ok(());
+        let report = self.disk.import_tree(std::path::Path::new(host), &dest, &mut |entry| {
+            if written.is_ok() {
+                written = writeln!(out, "{}", entry.image_path);
+            }
+        })?;
+        written?;
+        print_transfer_summary(&report, out)
+    }
+
+    fn export(&mut self, paths: &[String], out: &mut dyn Write) -> FsResult<()> {
+        let [source, host] = two_paths(paths, "export path host_dir")?;
+        let source = self.absolute(source);
+        let mut written = Ok(());
+        let report = self.disk.export_tree(&source, std::path::Path::new(host), &mut |entry| {
+            if written.is_ok() {
+                written = writeln!(out, "{}", entry.host_path.display());
+            }
+        })?;
+        written?;
+        print_transfer_summary(&report, out)
+    }
+}
+
+fn print_transfer_summary(report: &TransferReport, out: &mut dyn Write) -> FsResult<()> {
+    for (path, reason) in &report.skipped {
+        writeln!(out, "skipped {}: {}", path.display(), reason)?;
+    }
+    writeln!(
+        out,
+        "{} directories, {} files ({} bytes), {} symlinks",
+        report.directories(),
+        report.files(),
+        report.bytes(),
+        report.symlinks()
+    )?;
+    if let Some(e) = &report.stopped {
+        writeln!(out, "stopped: {}", e)?;
+    }
+    Ok(())
+}
+
+/// Separate single-letter flags (`-rf`) from the other arguments
+fn split_flags(args: &[String]) -> (Vec<char>, Vec<String>) {
+    let mut flags = Vec::new();
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.strip_prefix('-') {
+            Some(letters) if !letters.is_empty() => flags.extend(letters.chars()),
+            _ => paths.push(arg.clone()),
+        }
+    }
+    (flags, paths)
+}
+
+fn require_paths(paths: &[String], usage: &str) -> FsResult<()> {
+    if paths.is_empty() {
+        return Err(FsError::InvalidPath(format!("Usage: {}", usage)));
+    }
+    Ok(())
+}
+
+fn two_paths<'a>(paths: &'a [String], usage: &str) -> FsResult<[&'a str; 2]> {
+    match paths {
+        [a, b] => Ok([a, b]),
+        _ => Err(FsError::InvalidPath(format!("Usage: {}", usage))),
+    }
+}
+
+fn base_name(path: &str) -> &str {
+    path.trim_end_matches('/').rsplit('/').next().unwrap_or(path)
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Human-readable file type
+pub fn type_name(file_type: FileType) -> &'static str {
+    match file_type {
+        FileType::File => "regular file",
+        FileType::Directory => "directory",
+        FileType::Symlink => "symbolic link",
+    }
+}
+
+/// `ls -l` style mode string, e.g. `drwx`
+pub fn mode_string(inode: &Inode) -> String {
+    let kind = match inode.file_type {
+        FileType::File => '-',
+        FileType::Directory => 'd',
+        FileType::Symlink => 'l',
+    };
+    let p = inode.permissions;
+    format!(
+        "{}{}{}{}",
+        kind,
+        if p.read() { 'r' } else { '-' },
+        if p.write() { 'w' } else { '-' },
+        if p.execute() { 'x' } else { '-' }
+    )
+}
+
+/// Format a Unix timestamp as `YYYY-MM-DD HH:MM` (UTC)
+pub fn format_time(secs: u64) -> String {
+    let (year, month, day) = civil_date(secs);
+    let rest = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest % 3600 / 60
+    )
+}
+
+/// Year, month and day (UTC) of a Unix timestamp
+pub(crate) fn civil_date(secs: u64) -> (i64, i64, i64) {
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (year_of_era + era * 400 + i64::from(month <= 2), month, day)
+}
@@ -0,0 +1,368 @@
+use crate::{
+    device::BlockDevice,
+    error::{FsError, FsResult},
+    table::{Reader, TableFormat},
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+
+/// Longest snapshot name, in bytes
+pub const MAX_SNAPSHOT_NAME: usize = 64;
+
+/// A snapshot, as listed by `VirtualDisk::snapshot_list`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    pub name: String,
+    /// Seconds since the Unix epoch
+    pub created: u64,
+    /// Blocks in use when the snapshot was taken
+    pub blocks: u64,
+    /// Blocks overwritten since, whose old contents were copied aside
+    pub copied: u64,
+}
+
+/// A set of block numbers, one bit per block
+#[derive(Debug, Clone)]
+pub(crate) struct BlockSet {
+    bits: Vec<u8>,
+}
+
+impl BlockSet {
+    pub(crate) fn new(total_blocks: u64) -> Self {
+        BlockSet { bits: vec![0; total_blocks.div_ceil(8) as usize] }
+    }
+
+    pub(crate) fn insert(&mut self, block: u64) {
+        self.bits[(block / 8) as usize] |= 1 << (block % 8);
+    }
+
+    pub(crate) fn contains(&self, block: u64) -> bool {
+        self.bits.get((block / 8) as usize).is_some_and(|byte| byte & (1 << (block % 8)) != 0)
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.bits.iter().map(|byte| u64::from(byte.count_ones())).sum()
+    }
+}
+
+/// One snapshot of the whole file system
+#[derive(Debug, Clone)]
+struct Snapshot {
+    name: String,
+    created: u64,
+    /// Blocks in use when the snapshot was taken
+    frozen: BlockSet,
+    /// Frozen blocks overwritten since, mapped to the copies of their
+    /// old contents
+    remap: BTreeMap<u64, u64>,
+}
+
+/// Snapshots of a disk and the blocks kept for them
+///
+/// A snapshot shares every block that was in use when it was taken with
+/// the live file system (copy-on-write). Before a shared block is
+/// overwritten, its contents are copied to a new block and the snapshot
+/// is remapped to the copy; one copy serves every snapshot that still
+/// shared the block, and is freed once no snapshot refers to it. Frozen
+/// blocks that the live file system frees stay allocated ("held") until
+/// no snapshot froze them any more, so they are never handed out again
+/// and reused as copies while a rollback could still write to them.
+///
+/// The table is stored in a chain of blocks (see `TableFormat`). Its
+/// blocks, the copies and the held blocks
+/// are the blocks that snapshots use themselves; none of them is frozen
+/// by a later snapshot.
+#[derive(Debug, Default)]
+pub(crate) struct Snapshots {
+    list: Vec<Snapshot>,
+    /// Copy blocks, with the number of snapshots remapped to each
+    copies: BTreeMap<u64, u64>,
+    /// Blocks freed by the live file system that snapshots still hold
+    held: BTreeSet<u64>,
+    /// Blocks the table is stored in
+    store: Vec<u64>,
+    /// Changed since it was last written
+    dirty: bool,
+}
+
+impl Snapshots {
+    pub(crate) const FORMAT: TableFormat = TableFormat {
+        magic: 0x534E4150, // "SNAP" in ASCII
+        version: 1,
+        name: "Snapshot table",
+    };
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Every block used by the snapshots themselves
+    pub(crate) fn own_blocks(&self) -> BTreeSet<u64> {
+        let mut blocks: BTreeSet<u64> = self.copies.keys().copied().collect();
+        blocks.extend(&self.held);
+        blocks.extend(&self.store);
+        blocks
+    }
+
+    pub(crate) fn info(&self) -> Vec<SnapshotInfo> {
+        self.list
+            .iter()
+            .map(|s| SnapshotInfo {
+                name: s.name.clone(),
+                created: s.created,
+                blocks: s.frozen.len(),
+                copied: s.remap.len() as u64,
+            })
+            .collect()
+    }
+
+    /// Index of the snapshot called `name`
+    pub(crate) fn find(&self, name: &str) -> FsResult<usize> {
+        self.list
+            .iter()
+            .position(|s| s.name == name)
+            .ok_or_else(|| FsError::SnapshotNotFound(name.to_string()))
+    }
+
+    /// Blocks frozen by a snapshot, and where their old contents are now
+    pub(crate) fn view(&self, index: usize) -> (&BlockSet, &BTreeMap<u64, u64>) {
+        let snapshot = &self.list[index];
+        (&snapshot.frozen, &snapshot.remap)
+    }
+
+    /// Add a snapshot of the blocks in `frozen`
+    pub(crate) fn create(&mut self, name: &str, created: u64, frozen: BlockSet) -> FsResult<SnapshotInfo> {
+        if name.is_empty() || name.len() > MAX_SNAPSHOT_NAME || name.contains(['/', '@', '\0']) {
+            return Err(FsError::InvalidFileName(format!("Invalid snapshot name '{}'", name)));
+        }
+        if self.list.iter().any(|s| s.name == name) {
+            return Err(FsError::AlreadyExists(format!("snapshot {}", name)));
+        }
+        self.list.push(Snapshot {
+            name: name.to_string(),
+            created,
+            frozen,
+            remap: BTreeMap::new(),
+        });
+        self.dirty = true;
+        Ok(self.info().pop().unwrap())
+    }
+
+    /// Snapshots that still share the current contents of `block`
+    pub(crate) fn sharing(&self, block: u64) -> Vec<usize> {
+        (0..self.list.len())
+            .filter(|&i| self.list[i].frozen.contains(block) && !self.list[i].remap.contains_key(&block))
+            .collect()
+    }
+
+    /// Record that the old contents of `block` were copied to `copy` for
+    /// the snapshots in `sharing`
+    pub(crate) fn add_copy(&mut self, sharing: &[usize], block: u64, copy: u64) {
+        for &index in sharing {
+            self.list[index].remap.insert(block, copy);
+        }
+        self.copies.insert(copy, sharing.len() as u64);
+        self.dirty = true;
+    }
+
+    /// Keep a block the live file system frees, if a snapshot froze it
+    pub(crate) fn hold(&mut self, block: u64) -> bool {
+        if !self.list.iter().any(|s| s.frozen.contains(block)) {
+            return false;
+        }
+        self.held.insert(block);
+        self.dirty = true;
+        true
+    }
+
+    /// Drop the references of a snapshot to its copies, returning the
+    /// copies no snapshot refers to any more
+    fn drop_remap(&mut self, index: usize) -> Vec<u64> {
+        let mut unused = Vec::new();
+        for copy in std::mem::take(&mut self.list[index].remap).into_values() {
+            match self.copies.get_mut(&copy) {
+                Some(refs) if *refs > 1 => *refs -= 1,
+                _ => {
+                    self.copies.remove(&copy);
+                    unused.push(copy);
+                }
+            }
+        }
+        self.dirty = true;
+        unused
+    }
+
+    /// Remove a snapshot, returning the blocks that can be freed
+    pub(crate) fn remove(&mut self, index: usize) -> Vec<u64> {
+        let mut unused = self.drop_remap(index);
+        self.list.remove(index);
+        let held = std::mem::take(&mut self.held);
+        let (kept, released): (BTreeSet<u64>, BTreeSet<u64>) =
+            held.into_iter().partition(|&b| self.list.iter().any(|s| s.frozen.contains(b)));
+        self.held = kept;
+        unused.extend(released);
+        unused
+    }
+
+    /// Forget the copies of a snapshot whose blocks were written back,
+    /// so that it shares everything with the live file system again
+    ///
+    /// Blocks that only other snapshots froze are held from now on. The
+    /// caller updates the allocator from `own_blocks`.
+    pub(crate) fn rolled_back(&mut self, index: usize) {
+        self.drop_remap(index);
+        let current = &self.list[index].frozen;
+        let mut held = BTreeSet::new();
+        for (_, snapshot) in self.list.iter().enumerate().filter(|&(i, _)| i != index) {
+            let blocks = 0..snapshot.frozen.bits.len() as u64 * 8;
+            held.extend(blocks.filter(|&b| snapshot.frozen.contains(b) && !current.contains(b)));
+        }
+        self.held = held;
+    }
+
+    // ==================== STORAGE ====================
+
+    /// Blocks the table is stored in, which the caller frees
+    pub(crate) fn take_store(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.store)
+    }
+
+    /// Record that the table was written to `blocks`
+    pub(crate) fn stored(&mut self, blocks: Vec<u64>) {
+        self.store = blocks;
+        self.dirty = false;
+    }
+
+    /// Serialize the table
+    pub(crate) fn body(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        let mut put = |value: u64| body.extend_from_slice(&value.to_le_bytes());
+        put(self.copies.len() as u64);
+        for (&copy, &refs) in &self.copies {
+            put(copy);
+            put(refs);
+        }
+        put(self.held.len() as u64);
+        for &block in &self.held {
+            put(block);
+        }
+        put(self.list.len() as u64);
+        for snapshot in &self.list {
+            body.extend_from_slice(&(snapshot.name.len() as u64).to_le_bytes());
+            body.extend_from_slice(snapshot.name.as_bytes());
+            body.extend_from_slice(&snapshot.created.to_le_bytes());
+            body.extend_from_slice(&(snapshot.frozen.bits.len() as u64).to_le_bytes());
+            body.extend_from_slice(&snapshot.frozen.bits);
+            body.extend_from_slice(&(snapshot.remap.len() as u64).to_le_bytes());
+            for (&block, &copy) in &snapshot.remap {
+                body.extend_from_slice(&block.to_le_bytes());
+                body.extend_from_slice(&copy.to_le_bytes());
+            }
+        }
+        body
+    }
+
+    /// Load the table stored from block `first`, 0 for none
+    pub(crate) fn load(device: &dyn BlockDevice, block_size: u64, total_blocks: u64, first: u64) -> FsResult<Self> {
+        let (store, body) = Self::FORMAT.read(device, block_size, total_blocks, first)?;
+        let mut snapshots = Snapshots { store, ..Snapshots::default() };
+        if snapshots.store.is_empty() {
+            return Ok(snapshots);
+        }
+
+        let mut reader = Reader::new(&body, &Self::FORMAT);
+        for _ in 0..reader.u64()? {
+            let copy = reader.u64()?;
+            snapshots.copies.insert(copy, reader.u64()?);
+        }
+        for _ in 0..reader.u64()? {
+            snapshots.held.insert(reader.u64()?);
+        }
+        for _ in 0..reader.u64()? {
+            let name_len = reader.u64()? as usize;
+            let name = String::from_utf8(reader.take(name_len)?.to_vec())
+                .map_err(|_| Self::FORMAT.corrupted("holds a name that is not valid UTF-8"))?;
+            let created = reader.u64()?;
+            let bits_len = reader.u64()? as usize;
+            if bits_len != total_blocks.div_ceil(8) as usize {
+                return Err(FsError::CorruptedFileSystem(format!(
+                    "Snapshot {} covers {} blocks, the disk has {}",
+                    name,
+                    bits_len * 8,
+                    total_blocks
+                )));
+            }
+            let frozen = BlockSet { bits: reader.take(bits_len)?.to_vec() };
+            let mut remap = BTreeMap::new();
+            for _ in 0..reader.u64()? {
+                let block = reader.u64()?;
+                remap.insert(block, reader.u64()?);
+            }
+            snapshots.list.push(Snapshot { name, created, frozen, remap });
+        }
+        Ok(snapshots)
+    }
+}
+
+/// Read-only view of a disk as it was when a snapshot was taken
+///
+/// Reads of blocks overwritten since go to the copies of their old
+/// contents; every other block is read from the live image.
+#[derive(Debug)]
+pub(crate) struct SnapshotDevice {
+    inner: Box<dyn BlockDevice>,
+    block_size: u64,
+    remap: BTreeMap<u64, u64>,
+}
+
+impl SnapshotDevice {
+    pub(crate) fn new(inner: Box<dyn BlockDevice>, block_size: u64, remap: BTreeMap<u64, u64>) -> Self {
+        SnapshotDevice { inner, block_size, remap }
+    }
+
+    fn read_only() -> io::Error {
+        io::Error::new(io::ErrorKind::ReadOnlyFilesystem, "snapshots are read-only")
+    }
+}
+
+impl BlockDevice for SnapshotDevice {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let end = offset + buf.len() as u64;
+        let (first, last) = (offset / self.block_size, end.div_ceil(self.block_size));
+        if self.remap.range(first..last).next().is_none() {
+            return self.inner.read_exact_at(buf, offset);
+        }
+
+        let mut position = offset;
+        while position < end {
+            let block = position / self.block_size;
+            let within = position % self.block_size;
+            let len = (self.block_size - within).min(end - position);
+            let source = self.remap.get(&block).copied().unwrap_or(block);
+            let start = (position - offset) as usize;
+            self.inner.read_exact_at(&mut buf[start..start + len as usize], source * self.block_size + within)?;
+            position += len;
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&self, _data: &[u8], _offset: u64) -> io::Result<()> {
+        Err(Self::read_only())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.inner.size()
+    }
+
+    fn set_size(&self, _size: u64) -> io::Result<()> {
+        Err(Self::read_only())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use crate::{
+    checksum::crc32c,
+    device::BlockDevice,
+    error::{FsError, FsResult},
+};
+
+/// Format of a variable-size table stored in a chain of blocks
+///
+/// Each block of the chain starts with the number of the next one (0
+/// ends the chain). The table starts with a magic number, a version, the
+/// length of its body and a CRC32C of the body. Tables are written to new
+/// blocks whenever they change, and the superblock points at the first.
+#[derive(Debug)]
+pub(crate) struct TableFormat {
+    pub(crate) magic: u32,
+    pub(crate) version: u32,
+    /// Name used in error messages, such as "Snapshot table"
+    pub(crate) name: &'static str,
+}
+
+impl TableFormat {
+    /// Magic, version, body length and body checksum
+    const HEADER_SIZE: usize = 20;
+
+    /// Number of blocks needed to store a table with `len` bytes of body
+    pub(crate) fn blocks_needed(&self, len: usize, block_size: u64) -> u64 {
+        ((Self::HEADER_SIZE + len) as u64).div_ceil(block_size - 8)
+    }
+
+    /// Write a table holding `body` to the chain of `blocks`
+    pub(crate) fn write(&self, device: &dyn BlockDevice, block_size: u64, blocks: &[u64], body: &[u8]) -> FsResult<()> {
+        let mut bytes = Vec::with_capacity(Self::HEADER_SIZE + body.len());
+        bytes.extend_from_slice(&self.magic.to_le_bytes());
+        bytes.extend_from_slice(&self.version.to_le_bytes());
+        bytes.extend_from_slice(&(body.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&crc32c(body).to_le_bytes());
+        bytes.extend_from_slice(body);
+
+        let payload = (block_size - 8) as usize;
+        for (i, chunk) in bytes.chunks(payload).enumerate() {
+            let next = blocks.get(i + 1).copied().unwrap_or(0);
+            let mut buffer = vec![0u8; block_size as usize];
+            buffer[..8].copy_from_slice(&next.to_le_bytes());
+            buffer[8..8 + chunk.len()].copy_from_slice(chunk);
+            device.write_all_at(&buffer, blocks[i] * block_size)?;
+        }
+        Ok(())
+    }
+
+    /// Read the table stored from block `first`
+    ///
+    /// Returns the blocks of the chain and the body of the table, both
+    /// empty if `first` is 0.
+    pub(crate) fn read(
+        &self,
+        device: &dyn BlockDevice,
+        block_size: u64,
+        total_blocks: u64,
+        first: u64,
+    ) -> FsResult<(Vec<u64>, Vec<u8>)> {
+        let mut blocks = Vec::new();
+        let mut bytes = Vec::new();
+        let mut block = first;
+        let mut buffer = vec![0u8; block_size as usize];
+        while block != 0 {
+            if block >= total_blocks || blocks.contains(&block) {
+                return Err(self.corrupted(format!("chain is broken at block {}", block)));
+            }
+            device.read_exact_at(&mut buffer, block * block_size)?;
+            blocks.push(block);
+            bytes.extend_from_slice(&buffer[8..]);
+            block = u64::from_le_bytes(buffer[..8].try_into().unwrap());
+        }
+        if blocks.is_empty() {
+            return Ok((blocks, bytes));
+        }
+
+        let mut reader = Reader::new(&bytes, self);
+        let magic = reader.u32()?;
+        let version = reader.u32()?;
+        if magic != self.magic {
+            return Err(self.corrupted(format!("has an invalid magic number: 0x{:08X}", magic)));
+        }
+        if version != self.version {
+            return Err(FsError::NotSupported(format!("Unsupported {} version: {}", self.name.to_lowercase(), version)));
+        }
+        let len = reader.u64()? as usize;
+        let checksum = reader.u32()?;
+        let body = reader.take(len)?.to_vec();
+        if crc32c(&body) != checksum {
+            return Err(self.corrupted("checksum does not match"));
+        }
+        Ok((blocks, body))
+    }
+
+    /// Error for a table that cannot be read back
+    pub(crate) fn corrupted(&self, problem: impl std::fmt::Display) -> FsError {
+        FsError::CorruptedFileSystem(format!("{} {}", self.name, problem))
+    }
+}
+
+/// Reads little-endian fields from a table
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    format: &'a TableFormat,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8], format: &'a TableFormat) -> Self {
+        Reader { bytes, pos: 0, format }
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> FsResult<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| self.format.corrupted("is truncated"))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u32(&mut self) -> FsResult<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> FsResult<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
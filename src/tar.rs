@@ -0,0 +1,517 @@
+use crate::{
+    error::{FsError, FsResult},
+    serialization::{FileType, Inode, Permissions},
+    virtual_disk::VirtualDisk,
+};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+/// Size of a tar header and of the data padding unit
+const TAR_BLOCK: usize = 512;
+
+/// Type flags of the tar entries written and understood here
+const TYPE_FILE: u8 = b'0';
+const TYPE_FILE_OLD: u8 = 0;
+const TYPE_HARD_LINK: u8 = b'1';
+const TYPE_SYMLINK: u8 = b'2';
+const TYPE_DIRECTORY: u8 = b'5';
+const TYPE_PAX: u8 = b'x';
+const TYPE_PAX_GLOBAL: u8 = b'g';
+const TYPE_GNU_LONG_NAME: u8 = b'L';
+const TYPE_GNU_LONG_LINK: u8 = b'K';
+
+/// Largest value an 11-digit octal header field can hold
+const OCTAL_11_MAX: u64 = 0o77777777777;
+
+/// A tar member, independent of how its header was encoded
+#[derive(Debug, Default)]
+struct TarEntry {
+    path: String,
+    mode: u32,
+    size: u64,
+    mtime: u64,
+    atime: Option<u64>,
+    type_flag: u8,
+    link_target: String,
+}
+
+impl TarEntry {
+    /// Build the ustar header for this entry
+    ///
+    /// Layout (512 bytes, POSIX ustar):
+    /// - name: 100 bytes at 0
+    /// - mode, uid, gid: 8 bytes each at 100, 108, 116 (octal)
+    /// - size: 12 bytes at 124 (octal)
+    /// - mtime: 12 bytes at 136 (octal)
+    /// - checksum: 8 bytes at 148
+    /// - type flag: 1 byte at 156
+    /// - link name: 100 bytes at 157
+    /// - magic "ustar\0" and version "00" at 257
+    /// - user and group names: 32 bytes each at 265, 297
+    /// - device major/minor: 8 bytes each at 329, 337
+    /// - prefix: 155 bytes at 345
+    ///
+    /// Fields that do not fit are truncated here and carried in full by a
+    /// preceding pax header; see `pax_records`.
+    fn to_header(&self) -> [u8; TAR_BLOCK] {
+        let mut header = [0u8; TAR_BLOCK];
+        let (prefix, name) = split_ustar_name(&self.path).unwrap_or(("", &self.path));
+        put_str(&mut header[0..100], name);
+        put_octal(&mut header[100..108], u64::from(self.mode & 0o7777));
+        put_octal(&mut header[108..116], 0);
+        put_octal(&mut header[116..124], 0);
+        put_octal(&mut header[124..136], self.size.min(OCTAL_11_MAX));
+        put_octal(&mut header[136..148], self.mtime.min(OCTAL_11_MAX));
+        header[156] = self.type_flag;
+        put_str(&mut header[157..257], &self.link_target);
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        put_octal(&mut header[329..337], 0);
+        put_octal(&mut header[337..345], 0);
+        put_str(&mut header[345..500], prefix);
+
+        let checksum = header_checksum(&header);
+        put_str(&mut header[148..156], &format!("{:06o}\0 ", checksum));
+        header
+    }
+
+    /// Parse a ustar (or pre-POSIX) header, checking its checksum
+    fn from_header(header: &[u8; TAR_BLOCK]) -> FsResult<Self> {
+        let recorded = parse_number(&header[148..156])?;
+        if recorded != header_checksum(header) {
+            return Err(FsError::DeserializationError(format!(
+                "Tar header checksum mismatch: recorded {}, computed {}",
+                recorded,
+                header_checksum(header)
+            )));
+        }
+
+        let name = get_str(&header[0..100]);
+        let path = if &header[257..262] == b"ustar" {
+            let prefix = get_str(&header[345..500]);
+            if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) }
+        } else {
+            name
+        };
+
+        Ok(TarEntry {
+            path,
+            mode: parse_number(&header[100..108])? as u32,
+            size: parse_number(&header[124..136])?,
+            mtime: parse_number(&header[136..148])?,
+            atime: None,
+            type_flag: header[156],
+            link_target: get_str(&header[157..257]),
+        })
+    }
+
+    /// Pax records needed for fields the ustar header cannot hold
+    fn pax_records(&self) -> Vec<(&'static str, String)> {
+        let mut records = Vec::new();
+        if split_ustar_name(&self.path).is_none() {
+            records.push(("path", self.path.clone()));
+        }
+        if self.link_target.len() > 100 {
+            records.push(("linkpath", self.link_target.clone()));
+        }
+        if self.size > OCTAL_11_MAX {
+            records.push(("size", self.size.to_string()));
+        }
+        if self.mtime > OCTAL_11_MAX {
+            records.push(("mtime", self.mtime.to_string()));
+        }
+        records
+    }
+
+    /// Apply pax records (or GNU long names) read before this header
+    fn apply_overrides(&mut self, overrides: &HashMap<String, String>) -> FsResult<()> {
+        for (key, value) in overrides {
+            match key.as_str() {
+                "path" => self.path = value.clone(),
+                "linkpath" => self.link_target = value.clone(),
+                "size" => self.size = parse_pax_number(key, value)?,
+                "mtime" => self.mtime = parse_pax_number(key, value)?,
+                "atime" => self.atime = Some(parse_pax_number(key, value)?),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Split a path into ustar prefix and name, or `None` if it does not fit
+fn split_ustar_name(path: &str) -> Option<(&str, &str)> {
+    if !path.is_ascii() {
+        return None;
+    }
+    if path.len() <= 100 {
+        return Some(("", path));
+    }
+    // Split at a '/' so that the prefix fits in 155 bytes and the name in 100
+    path.char_indices()
+        .filter(|&(i, c)| c == '/' && i <= 155 && path.len() - i - 1 <= 100 && i + 1 < path.len())
+        .map(|(i, _)| (&path[..i], &path[i + 1..]))
+        .next()
+}
+
+fn put_str(field: &mut [u8], value: &str) {
+    let bytes = value.as_bytes();
+    let len = bytes.len().min(field.len());
+    field[..len].copy_from_slice(&bytes[..len]);
+}
+
+fn put_octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    put_str(field, &format!("{:0width$o}", value, width = digits));
+}
+
+fn get_str(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Parse an octal header field, or a GNU base-256 one
+fn parse_number(field: &[u8]) -> FsResult<u64> {
+    if field.first().is_some_and(|&b| b & 0x80 != 0) {
+        let mut value = u64::from(field[0] & 0x7f);
+        for &b in &field[1..] {
+            value = value
+                .checked_mul(256)
+                .map(|v| v + u64::from(b))
+                .ok_or_else(|| FsError::DeserializationError("Tar number field overflows".to_string()))?;
+        }
+        return Ok(value);
+    }
+    let text = get_str(field);
+    let text = text.trim_matches(|c: char| c == ' ' || c == '\0');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8)
+        .map_err(|_| FsError::DeserializationError(format!("Invalid tar number field: {:?}", text)))
+}
+
+fn parse_pax_number(key: &str, value: &str) -> FsResult<u64> {
+    // Pax times may carry a fractional part, which inodes cannot store
+    let whole = value.split('.').next().unwrap_or(value);
+    whole
+        .parse()
+        .map_err(|_| FsError::DeserializationError(format!("Invalid pax {} value: {}", key, value)))
+}
+
+/// Sum of the header bytes with the checksum field counted as spaces
+fn header_checksum(header: &[u8; TAR_BLOCK]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { u64::from(b' ') } else { u64::from(b) })
+        .sum()
+}
+
+/// Encode pax records as "<length> <key>=<value>\n" lines
+fn encode_pax(records: &[(&str, String)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (key, value) in records {
+        let body = format!(" {}={}\n", key, value);
+        // The length field counts its own digits
+        let mut len = body.len() + 1;
+        while len.to_string().len() + body.len() != len {
+            len = len.to_string().len() + body.len();
+        }
+        data.extend_from_slice(format!("{}{}", len, body).as_bytes());
+    }
+    data
+}
+
+fn decode_pax(data: &[u8]) -> FsResult<HashMap<String, String>> {
+    let invalid = || FsError::DeserializationError("Malformed pax header".to_string());
+    let mut records = HashMap::new();
+    let mut rest = data;
+    while !rest.is_empty() && rest[0] != 0 {
+        let space = rest.iter().position(|&b| b == b' ').ok_or_else(invalid)?;
+        let len: usize = std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(invalid)?;
+        if len <= space + 1 || len > rest.len() || rest[len - 1] != b'\n' {
+            return Err(invalid());
+        }
+        let record = std::str::from_utf8(&rest[space + 1..len - 1]).map_err(|_| invalid())?;
+        let (key, value) = record.split_once('=').ok_or_else(invalid)?;
+        records.insert(key.to_string(), value.to_string());
+        rest = &rest[len..];
+    }
+    Ok(records)
+}
+
+fn padding(size: u64) -> usize {
+    (TAR_BLOCK - (size as usize % TAR_BLOCK)) % TAR_BLOCK
+}
+
+fn write_entry(writer: &mut dyn Write, entry: &TarEntry, data: &[u8]) -> FsResult<()> {
+    let records = entry.pax_records();
+    if !records.is_empty() {
+        let pax = encode_pax(&records);
+        let name: String = format!("PaxHeaders/{}", entry.path).chars().filter(char::is_ascii).take(100).collect();
+        let header = TarEntry {
+            path: name,
+            mode: 0o644,
+            size: pax.len() as u64,
+            mtime: entry.mtime.min(OCTAL_11_MAX),
+            type_flag: TYPE_PAX,
+            ..TarEntry::default()
+        };
+        writer.write_all(&header.to_header())?;
+        writer.write_all(&pax)?;
+        writer.write_all(&[0; TAR_BLOCK][..padding(pax.len() as u64)])?;
+    }
+    writer.write_all(&entry.to_header())?;
+    writer.write_all(data)?;
+    writer.write_all(&[0; TAR_BLOCK][..padding(data.len() as u64)])?;
+    Ok(())
+}
+
+/// Read a member's data and skip its padding
+fn read_data(reader: &mut dyn Read, size: u64) -> FsResult<Vec<u8>> {
+    let mut data = Vec::new();
+    reader.take(size).read_to_end(&mut data)?;
+    if data.len() as u64 != size {
+        return Err(FsError::DeserializationError("Tar archive is truncated".to_string()));
+    }
+    let mut pad = [0u8; TAR_BLOCK];
+    reader.read_exact(&mut pad[..padding(size)])?;
+    Ok(data)
+}
+
+/// Make an archive member name safe to place below the import directory
+fn member_path(name: &str) -> FsResult<Option<String>> {
+    let mut parts = Vec::new();
+    for part in name.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                return Err(FsError::InvalidPath(format!(
+                    "Tar member escapes the target directory: {}",
+                    name
+                )))
+            }
+            part => parts.push(part),
+        }
+    }
+    Ok(if parts.is_empty() { None } else { Some(parts.join("/")) })
+}
+
+fn join(dir: &str, name: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), name)
+}
+
+impl VirtualDisk {
+    // ==================== TAR EXPORT ====================
+
+    /// Write the tree below `path` to `writer` as a tar archive
+    ///
+    /// Members are named relative to `path` and written in name order, so
+    /// the same tree always gives the same archive. Headers are ustar, with
+    /// pax records for long names, long link targets and large sizes. Mode
+    /// bits, modification times and symlink targets are kept; a file with
+    /// several names is stored once and its other names as hard links.
+    /// Returns the number of members written.
+    pub fn export_tar(&mut self, path: &str, writer: &mut dyn Write) -> FsResult<usize> {
+        let inode_block = self.lookup_path(path)?;
+        if self.read_inode(inode_block)?.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory(path.to_string()));
+        }
+
+        let mut linked = HashMap::new();
+        let count = self.export_tar_directory(path, "", writer, &mut linked)?;
+        writer.write_all(&[0; TAR_BLOCK * 2])?;
+        writer.flush()?;
+        Ok(count)
+    }
+
+    fn export_tar_directory(
+        &mut self,
+        dir: &str,
+        prefix: &str,
+        writer: &mut dyn Write,
+        linked: &mut HashMap<u64, String>,
+    ) -> FsResult<usize> {
+        let mut entries = self.list_directory_at(dir)?;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut count = 0;
+        for entry in entries {
+            let image_path = join(dir, &entry.name);
+            let member = format!("{}{}", prefix, entry.name);
+            let inode = self.read_inode(entry.inode_number)?;
+            let mut tar = TarEntry {
+                path: member.clone(),
+                mode: inode.permissions.to_mode(),
+                mtime: inode.modified,
+                ..TarEntry::default()
+            };
+
+            if inode.file_type != FileType::Directory && inode.link_count > 1 {
+                if let Some(first) = linked.get(&entry.inode_number) {
+                    tar.type_flag = TYPE_HARD_LINK;
+                    tar.link_target = first.clone();
+                    write_entry(writer, &tar, &[])?;
+                    count += 1;
+                    continue;
+                }
+                linked.insert(entry.inode_number, member.clone());
+            }
+
+            match inode.file_type {
+                FileType::Directory => {
+                    tar.path.push('/');
+                    tar.type_flag = TYPE_DIRECTORY;
+                    write_entry(writer, &tar, &[])?;
+                    count += 1 + self.export_tar_directory(&image_path, &tar.path, writer, linked)?;
+                }
+                FileType::File => {
+                    let data = self.read_inode_data(&inode)?;
+                    tar.type_flag = TYPE_FILE;
+                    tar.size = data.len() as u64;
+                    write_entry(writer, &tar, &data)?;
+                    count += 1;
+                }
+                FileType::Symlink => {
+                    tar.type_flag = TYPE_SYMLINK;
+                    tar.link_target = self.read_link(&image_path)?;
+                    write_entry(writer, &tar, &[])?;
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    // ==================== TAR IMPORT ====================
+
+    /// Unpack a tar archive from `reader` below `path`
+    ///
+    /// `path` is created if needed and existing files are replaced. Regular
+    /// files, directories, symlinks and hard links are restored with their
+    /// owner mode bits and modification time; other member types, such as
+    /// devices and FIFOs, are skipped. Members with `..` in their name are
+    /// rejected. Returns the number of members imported.
+    pub fn import_tar(&mut self, reader: &mut dyn Read, path: &str) -> FsResult<usize> {
+        self.create_directories(path, Permissions::new(true, true, true))?;
+
+        let mut count = 0;
+        let mut overrides = HashMap::new();
+        // Directory times are set at the end, once nothing more is added to them
+        let mut directory_times = Vec::new();
+
+        loop {
+            let mut header = [0u8; TAR_BLOCK];
+            match reader.read_exact(&mut header) {
+                Ok(()) => {}
+                // Some writers omit the trailing zero blocks
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+            if header.iter().all(|&b| b == 0) {
+                break;
+            }
+
+            let mut entry = TarEntry::from_header(&header)?;
+            match entry.type_flag {
+                TYPE_PAX => {
+                    overrides.extend(decode_pax(&read_data(reader, entry.size)?)?);
+                    continue;
+                }
+                TYPE_GNU_LONG_NAME | TYPE_GNU_LONG_LINK => {
+                    let data = read_data(reader, entry.size)?;
+                    let value = get_str(&data);
+                    let key = if entry.type_flag == TYPE_GNU_LONG_NAME { "path" } else { "linkpath" };
+                    overrides.insert(key.to_string(), value);
+                    continue;
+                }
+                TYPE_PAX_GLOBAL => {
+                    read_data(reader, entry.size)?;
+                    continue;
+                }
+                _ => {}
+            }
+            entry.apply_overrides(&std::mem::take(&mut overrides))?;
+
+            let data = match entry.type_flag {
+                TYPE_FILE | TYPE_FILE_OLD => read_data(reader, entry.size)?,
+                TYPE_HARD_LINK | TYPE_SYMLINK | TYPE_DIRECTORY => Vec::new(),
+                _ => {
+                    read_data(reader, entry.size)?;
+                    continue;
+                }
+            };
+            let Some(member) = member_path(&entry.path)? else {
+                continue;
+            };
+            let target = join(path, &member);
+            // Archives need not list parent directories before their contents
+            if let Some((parent, _)) = member.rsplit_once('/') {
+                self.create_directories(&join(path, parent), Permissions::new(true, true, true))?;
+            }
+
+            let inode_block = match entry.type_flag {
+                TYPE_DIRECTORY => {
+                    let inode_block = self.create_directories(&target, Permissions::from_mode(entry.mode))?;
+                    directory_times.push((inode_block, entry.mtime, entry.atime));
+                    count += 1;
+                    continue;
+                }
+                TYPE_SYMLINK => {
+                    self.remove_existing(&target)?;
+                    self.create_symlink(&entry.link_target, &target)?
+                }
+                TYPE_HARD_LINK => {
+                    let Some(source) = member_path(&entry.link_target)? else {
+                        return Err(FsError::InvalidPath(format!(
+                            "Hard link {} has no target",
+                            entry.path
+                        )));
+                    };
+                    self.remove_existing(&target)?;
+                    self.create_hard_link(&join(path, &source), &target)?;
+                    count += 1;
+                    continue;
+                }
+                _ => {
+                    self.remove_existing(&target)?;
+                    self.write_file_at(&target, &data)?
+                }
+            };
+
+            let mut inode = self.read_inode(inode_block)?;
+            if inode.file_type == FileType::File {
+                inode.permissions = Permissions::from_mode(entry.mode);
+            }
+            set_times(&mut inode, entry.mtime, entry.atime);
+            self.write_inode(inode_block, &inode)?;
+            count += 1;
+        }
+
+        for (inode_block, mtime, atime) in directory_times.into_iter().rev() {
+            let mut inode = self.read_inode(inode_block)?;
+            set_times(&mut inode, mtime, atime);
+            self.write_inode(inode_block, &inode)?;
+        }
+        Ok(count)
+    }
+
+    /// Remove a file or symlink about to be replaced by an archive member
+    fn remove_existing(&mut self, path: &str) -> FsResult<()> {
+        match self.stat_path(path) {
+            Ok(inode) if inode.file_type == FileType::Directory => Err(FsError::AlreadyExists(path.to_string())),
+            Ok(_) => self.remove_path(path),
+            Err(FsError::FileNotFound(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn set_times(inode: &mut Inode, mtime: u64, atime: Option<u64>) {
+    inode.modified = mtime;
+    inode.accessed = atime.unwrap_or(mtime);
+}
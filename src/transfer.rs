@@ -0,0 +1,379 @@
+use crate::{
+    error::{FsError, FsResult},
+    serialization::{FileType, Inode, Permissions},
+    virtual_disk::VirtualDisk,
+};
+use std::fs::{self, FileTimes, Metadata};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// One file, directory or symlink copied by `import_tree` or `export_tree`
+#[derive(Debug, Clone)]
+pub struct TransferredEntry {
+    pub host_path: PathBuf,
+    pub image_path: String,
+    pub file_type: FileType,
+    /// File size, or target length for symlinks; 0 for directories
+    pub bytes: u64,
+}
+
+/// Result of copying a directory tree between the host and an image
+#[derive(Debug, Default)]
+pub struct TransferReport {
+    /// Entries copied completely, in the order they were copied
+    pub entries: Vec<TransferredEntry>,
+    /// Host entries that were left out, with the reason
+    pub skipped: Vec<(PathBuf, String)>,
+    /// Set if the copy stopped early because the image ran out of space
+    pub stopped: Option<FsError>,
+}
+
+impl TransferReport {
+    fn count(&self, file_type: FileType) -> usize {
+        self.entries.iter().filter(|e| e.file_type == file_type).count()
+    }
+
+    pub fn files(&self) -> usize {
+        self.count(FileType::File)
+    }
+
+    pub fn directories(&self) -> usize {
+        self.count(FileType::Directory)
+    }
+
+    pub fn symlinks(&self) -> usize {
+        self.count(FileType::Symlink)
+    }
+
+    /// Total bytes of file data copied
+    pub fn bytes(&self) -> u64 {
+        self.entries
+            .iter()
+            .filter(|e| e.file_type == FileType::File)
+            .map(|e| e.bytes)
+            .sum()
+    }
+
+    /// True if everything was copied
+    pub fn is_complete(&self) -> bool {
+        self.stopped.is_none() && self.skipped.is_empty()
+    }
+}
+
+/// Progress callback, called after each entry is copied
+pub type Progress<'a> = &'a mut dyn FnMut(&TransferredEntry);
+
+impl VirtualDisk {
+    // ==================== IMPORT ====================
+
+    /// Copy the contents of a host directory into the image
+    ///
+    /// `image_path` is created (with parents) if needed, and existing files
+    /// below it are overwritten. The owner read/write/execute bits and the
+    /// modification, access and (where the host has it) creation times are
+    /// kept. Sockets, FIFOs and devices are skipped.
+    ///
+    /// If the image runs out of blocks or inodes, the copy stops: the file
+    /// being written is removed, `stopped` holds the error, and `entries`
+    /// lists what was copied.
+    pub fn import_tree(
+        &mut self,
+        host_dir: &Path,
+        image_path: &str,
+        progress: Progress,
+    ) -> FsResult<TransferReport> {
+        let metadata = fs::metadata(host_dir)?;
+        if !metadata.is_dir() {
+            return Err(FsError::NotADirectory(host_dir.display().to_string()));
+        }
+
+        let mut report = TransferReport::default();
+        match self.import_directory(host_dir, image_path, &metadata, &mut report, progress) {
+            Err(e) if e.is_out_of_space() => report.stopped = Some(e),
+            result => result?,
+        }
+        Ok(report)
+    }
+
+    fn import_directory(
+        &mut self,
+        host_dir: &Path,
+        image_path: &str,
+        metadata: &Metadata,
+        report: &mut TransferReport,
+        progress: Progress,
+    ) -> FsResult<()> {
+        let inode_block = self.create_directories(image_path, permissions_from_host(metadata))?;
+        let entry = TransferredEntry {
+            host_path: host_dir.to_path_buf(),
+            image_path: image_path.to_string(),
+            file_type: FileType::Directory,
+            bytes: 0,
+        };
+        progress(&entry);
+        report.entries.push(entry);
+
+        let mut children: Vec<_> = fs::read_dir(host_dir)?.collect::<Result<_, _>>()?;
+        children.sort_by_key(|c| c.file_name());
+
+        for child in children {
+            let host_path = child.path();
+            let Some(name) = child.file_name().to_str().map(str::to_string) else {
+                report.skipped.push((host_path, "name is not valid UTF-8".to_string()));
+                continue;
+            };
+            let child_path = format!("{}/{}", image_path.trim_end_matches('/'), name);
+            let metadata = fs::symlink_metadata(&host_path)?;
+            let file_type = metadata.file_type();
+
+            if file_type.is_dir() {
+                self.import_directory(&host_path, &child_path, &metadata, report, progress)?;
+            } else if file_type.is_file() {
+                self.import_file(&host_path, &child_path, &metadata, report, progress)?;
+            } else if file_type.is_symlink() {
+                let target = fs::read_link(&host_path)?;
+                let Some(target) = target.to_str() else {
+                    report.skipped.push((host_path, "symlink target is not valid UTF-8".to_string()));
+                    continue;
+                };
+                if self.lookup_path_nofollow(&child_path).is_ok() {
+                    self.remove_path(&child_path)?;
+                }
+                self.create_symlink(target, &child_path)?;
+                let entry = TransferredEntry {
+                    host_path,
+                    image_path: child_path,
+                    file_type: FileType::Symlink,
+                    bytes: target.len() as u64,
+                };
+                progress(&entry);
+                report.entries.push(entry);
+            } else {
+                report.skipped.push((host_path, "not a regular file, directory or symlink".to_string()));
+            }
+        }
+
+        // Set directory times last, after its entries have been added
+        let mut inode = self.read_inode(inode_block)?;
+        times_from_host(&mut inode, metadata);
+        self.write_inode(inode_block, &inode)
+    }
+
+    fn import_file(
+        &mut self,
+        host_path: &Path,
+        image_path: &str,
+        metadata: &Metadata,
+        report: &mut TransferReport,
+        progress: Progress,
+    ) -> FsResult<()> {
+        let data = fs::read(host_path)?;
+        let existed = self.lookup_path(image_path).is_ok();
+
+        let inode_block = match self.write_file_at(image_path, &data) {
+            Ok(inode_block) => inode_block,
+            Err(e) => {
+                // Do not leave a half-copied new file behind
+                if !existed && self.lookup_path_nofollow(image_path).is_ok() {
+                    self.remove_path(image_path)?;
+                }
+                return Err(e);
+            }
+        };
+
+        let mut inode = self.read_inode(inode_block)?;
+        inode.permissions = permissions_from_host(metadata);
+        times_from_host(&mut inode, metadata);
+        self.write_inode(inode_block, &inode)?;
+
+        let entry = TransferredEntry {
+            host_path: host_path.to_path_buf(),
+            image_path: image_path.to_string(),
+            file_type: FileType::File,
+            bytes: data.len() as u64,
+        };
+        progress(&entry);
+        report.entries.push(entry);
+        Ok(())
+    }
+
+    // ==================== EXPORT ====================
+
+    /// Copy the contents of an image directory to a host directory
+    ///
+    /// `host_dir` is created (with parents) if needed, and existing host
+    /// files are overwritten. Permission bits and modification and access
+    /// times are restored; symlinks are recreated on Unix hosts and
+    /// skipped elsewhere.
+    pub fn export_tree(
+        &mut self,
+        image_path: &str,
+        host_dir: &Path,
+        progress: Progress,
+    ) -> FsResult<TransferReport> {
+        let inode_block = self.lookup_path(image_path)?;
+        let inode = self.read_inode(inode_block)?;
+        if inode.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory(image_path.to_string()));
+        }
+
+        let mut report = TransferReport::default();
+        self.export_directory(image_path, &inode, host_dir, &mut report, progress)?;
+        Ok(report)
+    }
+
+    fn export_directory(
+        &mut self,
+        image_path: &str,
+        inode: &Inode,
+        host_dir: &Path,
+        report: &mut TransferReport,
+        progress: Progress,
+    ) -> FsResult<()> {
+        fs::create_dir_all(host_dir)?;
+        let entry = TransferredEntry {
+            host_path: host_dir.to_path_buf(),
+            image_path: image_path.to_string(),
+            file_type: FileType::Directory,
+            bytes: 0,
+        };
+        progress(&entry);
+        report.entries.push(entry);
+
+        let mut children = self.list_directory_at(image_path)?;
+        children.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for child in children {
+            let child_path = format!("{}/{}", image_path.trim_end_matches('/'), child.name);
+            let host_path = host_dir.join(&child.name);
+            let child_inode = self.read_inode(child.inode_number)?;
+
+            let bytes = match child_inode.file_type {
+                FileType::Directory => {
+                    self.export_directory(&child_path, &child_inode, &host_path, report, progress)?;
+                    continue;
+                }
+                FileType::File => {
+                    let data = self.read_file(child.inode_number)?;
+                    write_host_file(&host_path, &data)?;
+                    apply_to_host(&host_path, &child_inode)?;
+                    data.len() as u64
+                }
+                FileType::Symlink => {
+                    let target = self.read_link(&child_path)?;
+                    if !create_host_symlink(&target, &host_path)? {
+                        report.skipped.push((host_path, "symlinks are not supported on this host".to_string()));
+                        continue;
+                    }
+                    target.len() as u64
+                }
+            };
+
+            let entry = TransferredEntry {
+                host_path,
+                image_path: child_path,
+                file_type: child_inode.file_type,
+                bytes,
+            };
+            progress(&entry);
+            report.entries.push(entry);
+        }
+
+        // Restore directory metadata after its contents are written
+        apply_to_host(host_dir, inode)?;
+        Ok(())
+    }
+}
+
+/// Map host mode bits to the image's owner read/write/execute flags
+fn permissions_from_host(metadata: &Metadata) -> Permissions {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Permissions::from_mode(metadata.permissions().mode())
+    }
+    #[cfg(not(unix))]
+    {
+        Permissions::new(true, !metadata.permissions().readonly(), metadata.is_dir())
+    }
+}
+
+fn unix_seconds(time: std::io::Result<SystemTime>) -> Option<u64> {
+    time.ok()?.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+/// Copy host timestamps into an inode, keeping fields the host lacks
+fn times_from_host(inode: &mut Inode, metadata: &Metadata) {
+    if let Some(modified) = unix_seconds(metadata.modified()) {
+        inode.modified = modified;
+    }
+    if let Some(accessed) = unix_seconds(metadata.accessed()) {
+        inode.accessed = accessed;
+    }
+    if let Some(created) = unix_seconds(metadata.created()) {
+        inode.created = created;
+    }
+}
+
+/// Restore permissions and times of an exported file or directory
+///
+/// Times are set first, through a handle opened while the entry still has
+/// the owner access it was created with; the mode, which may take that
+/// access away, comes last.
+fn apply_to_host(path: &Path, inode: &Inode) -> FsResult<()> {
+    let times = FileTimes::new()
+        .set_modified(UNIX_EPOCH + Duration::from_secs(inode.modified))
+        .set_accessed(UNIX_EPOCH + Duration::from_secs(inode.accessed));
+    let is_dir = inode.file_type == FileType::Directory;
+    let file = fs::File::options().read(is_dir).write(!is_dir).open(path)?;
+    file.set_times(times)?;
+    drop(file);
+
+    let perms = inode.permissions;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mut mode = perms.to_mode();
+        // Keep exported directories traversable so the copy can be cleaned up
+        if is_dir {
+            mode |= 0o700;
+        }
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    #[cfg(not(unix))]
+    {
+        let mut host = fs::metadata(path)?.permissions();
+        host.set_readonly(!perms.write());
+        fs::set_permissions(path, host)?;
+    }
+    Ok(())
+}
+
+/// Write a host file from scratch, replacing whatever is at `path`
+///
+/// An earlier export may have left a read-only file there, which cannot
+/// be opened for writing but can be removed.
+fn write_host_file(path: &Path, data: &[u8]) -> FsResult<()> {
+    if fs::symlink_metadata(path).is_ok_and(|m| !m.is_dir()) {
+        fs::remove_file(path)?;
+    }
+    fs::write(path, data)?;
+    Ok(())
+}
+
+/// Create a symlink on the host; returns false if the host cannot
+fn create_host_symlink(target: &str, path: &Path) -> FsResult<bool> {
+    if fs::symlink_metadata(path).is_ok() {
+        fs::remove_file(path)?;
+    }
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(target, path)?;
+        Ok(true)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = target;
+        Ok(false)
+    }
+}
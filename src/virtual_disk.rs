@@ -1,121 +1,706 @@
 use crate::{
-    bitmap::BlockBitmap, 
+    allocator::{AllocationStats, Allocator, AllocatorKind},
+    block_group::{BlockGroups, FragmentationStats},
+    checksum::{BlockClass, ChecksumTable},
+    dedup::DedupTable,
+    device::BlockDevice,
+    encryption::{EncryptionOptions, Keyring, SEAL_SIZE},
     error::{FsError, FsResult}, 
-    serialization::{Inode, DirectoryEntry, FileType, Permissions, INODE_SIZE, DIRECT_POINTERS},
+    extent::{Extent, ExtentEntry, ExtentNode, FileMapping, HOLE},
+    history::{HistoryGuard, HistoryLock},
+    quota::{owners, Quotas},
+    serialization::{Inode, DirectoryEntry, FileType, Permissions, Superblock, INODE_SIZE, DIRECT_POINTERS, INLINE_DATA_SIZE},
+    refcount::RefCounts,
+    snapshot::{BlockSet, SnapshotDevice, SnapshotInfo, Snapshots},
+    table::TableFormat,
 };
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::collections::BTreeSet;
+use std::fs::OpenOptions;
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard};
 
-const DISK_SIZE: u64 = 100 * 1024 * 1024;
-const BLOCK_SIZE: u64 = 4 * 1024;
-const TOTAL_BLOCKS: u64 = (DISK_SIZE) / (BLOCK_SIZE);
+/// Default size of a new disk image
+pub const DEFAULT_DISK_SIZE: u64 = 100 * 1024 * 1024;
 
+/// Default block size of a new disk image
+pub const DEFAULT_BLOCK_SIZE: u64 = 4 * 1024;
+
+/// Smallest and largest supported block sizes
+pub const MIN_BLOCK_SIZE: u64 = 1024;
+pub const MAX_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Smallest disk image, in blocks
+const MIN_TOTAL_BLOCKS: u64 = 512;
+
+/// Options used when formatting a new disk image
+/// 
+/// They are recorded in the superblock, so an existing image always keeps
+/// the options it was formatted with.
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    /// Image size in bytes, rounded down to whole blocks
+    pub size: u64,
+    /// Block size in bytes: a power of two from 1 KiB to 64 KiB
+    pub block_size: u64,
+    /// Map file data with extents instead of direct/indirect block pointers
+    pub extents: bool,
+    /// Block allocation strategy
+    pub allocator: AllocatorKind,
+    /// Checksum metadata blocks: superblock, bitmaps, inodes, directory
+    /// entries and mapping blocks
+    pub checksums: bool,
+    /// Checksum file data blocks too; implies `checksums`
+    pub data_checksums: bool,
+    /// Encrypt file data with a passphrase; implies `checksums`, whose
+    /// table keeps the nonce and tag of each encrypted block
+    pub encryption: Option<EncryptionOptions>,
+    /// Share data blocks identical to ones already on disk between files
+    pub dedup: bool,
+    /// Store files and directories small enough in their inodes instead
+    /// of in blocks
+    pub inline_data: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            size: DEFAULT_DISK_SIZE,
+            block_size: DEFAULT_BLOCK_SIZE,
+            extents: false,
+            allocator: AllocatorKind::default(),
+            checksums: false,
+            data_checksums: false,
+            encryption: None,
+            dedup: false,
+            inline_data: false,
+        }
+    }
+}
+
+impl FormatOptions {
+    fn features(&self) -> u64 {
+        let mut features = 0;
+        if self.extents {
+            features |= Superblock::FEATURE_EXTENTS;
+        }
+        if self.checksums || self.data_checksums || self.encryption.is_some() {
+            features |= Superblock::FEATURE_CHECKSUMS;
+        }
+        if self.data_checksums {
+            features |= Superblock::FEATURE_DATA_CHECKSUMS;
+        }
+        if let Some(encryption) = &self.encryption {
+            features |= Superblock::FEATURE_ENCRYPTION;
+            if encryption.encrypt_names {
+                features |= Superblock::FEATURE_ENCRYPTED_NAMES;
+            }
+        }
+        if self.dedup {
+            features |= Superblock::FEATURE_DEDUP;
+        }
+        if self.inline_data {
+            features |= Superblock::FEATURE_INLINE_DATA;
+        }
+        features
+    }
+
+    /// Number of blocks in the image
+    fn total_blocks(&self) -> u64 {
+        self.size / self.block_size
+    }
+
+    fn validate(&self) -> FsResult<()> {
+        if !self.block_size.is_power_of_two()
+            || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&self.block_size)
+        {
+            return Err(FsError::NotSupported(format!(
+                "Block size {} (must be a power of two from {} to {})",
+                self.block_size, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE
+            )));
+        }
+        if self.total_blocks() < MIN_TOTAL_BLOCKS {
+            return Err(FsError::NotSupported(format!(
+                "Disk of {} bytes is too small (at least {} blocks)",
+                self.size, MIN_TOTAL_BLOCKS
+            )));
+        }
+        if let Some(encryption) = &self.encryption {
+            if !crate::encryption::AVAILABLE {
+                return Err(FsError::NotSupported(
+                    "Encrypted images (built without the encryption feature)".to_string(),
+                ));
+            }
+            if encryption.kdf_iterations == 0 || encryption.kdf_iterations > u64::from(u32::MAX) {
+                return Err(FsError::NotSupported(format!(
+                    "{} key derivation iterations (must be from 1 to {})",
+                    encryption.kdf_iterations,
+                    u32::MAX
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Space shared between cloned files, from `VirtualDisk::sharing_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SharingStats {
+    /// Blocks referenced by more than one file
+    pub shared_blocks: u64,
+    /// References to shared blocks beyond the first: the blocks the
+    /// clones would take up if they were copies
+    pub saved_blocks: u64,
+}
+
+/// A file system stored in a disk image file
+///
+/// Image I/O is positional and the allocation state sits behind a mutex,
+/// so the block-level methods take `&self` and the disk can be shared
+/// between threads. They do not lock files or directories, though:
+/// changing the same inode from two threads at once must be prevented by
+/// the caller, which is what `SharedDisk` does.
+///
+/// On images with checksums, every block read is verified against the
+/// checksum table and fails with `FsError::ChecksumMismatch` if it does
+/// not match; the raw image access methods are the only exception.
+///
+/// While snapshots exist, every block write first copies the old
+/// contents aside for the snapshots that share the block; see
+/// `snapshot_create`.
 #[derive(Debug)]
 pub struct VirtualDisk {
-    file: File,
-    bitmap: BlockBitmap,
+    device: Box<dyn BlockDevice>,
+    block_size: u64,
+    space: Mutex<Space>,
+    /// Held shared by writes in place to data blocks, and exclusively
+    /// while a block is compared and shared by dedup
+    in_place: RwLock<()>,
+    /// Held while versions or the trash change
+    history: HistoryLock,
+    checksums: Option<ChecksumTable>,
+    /// Whether any snapshot exists, so writes can skip the check otherwise
+    has_snapshots: bool,
+    keyring: Keyring,
+    /// User and group ids new inodes are owned by
+    credentials: (u32, u32),
+}
+
+/// Allocation state, changed together under one lock
+#[derive(Debug)]
+struct Space {
+    allocator: Box<dyn Allocator>,
+    groups: BlockGroups,
+    refcounts: RefCounts,
+    dedup: DedupTable,
+    snapshots: Snapshots,
+    quotas: Quotas,
+}
+
+impl Space {
+    /// Mark blocks handed out by the allocator in their groups
+    fn allocated(&mut self, start: u64, count: u64) {
+        for block in start..start + count {
+            self.groups.block_allocated(block);
+        }
+    }
+
+    /// Run `allocate`, which hands out `count` blocks, charging them to
+    /// the quotas of the operation in progress on this thread
+    fn charged<T>(&mut self, count: u64, allocate: impl FnOnce(&mut Self) -> FsResult<T>) -> FsResult<T> {
+        self.quotas.charge_blocks(count)?;
+        let result = allocate(self);
+        if result.is_err() {
+            self.quotas.credit_blocks(count);
+        }
+        result
+    }
+
+    fn free_block(&mut self, block: u64) -> FsResult<()> {
+        if self.groups.is_inode_block(block) {
+            return Err(FsError::PermissionDenied(format!(
+                "Block {} belongs to an inode table",
+                block
+            )));
+        }
+        if self.refcounts.remove(block) {
+            return Ok(());
+        }
+        self.dedup.forget(block);
+        if self.allocator.is_block_used(block) && !self.snapshots.hold(block) {
+            self.release(block);
+        }
+        Ok(())
+    }
+
+    /// Return a block to the allocator, whatever snapshots think of it
+    fn release(&mut self, block: u64) {
+        self.dedup.forget(block);
+        self.allocator.free_block(block);
+        self.groups.block_freed(block);
+    }
+
+    /// Copy the contents of `blocks` to new blocks for the snapshots that
+    /// still share them, before they are overwritten
+    /// 
+    /// Returns whether anything was copied.
+    fn preserve(
+        &mut self,
+        device: &dyn BlockDevice,
+        block_size: u64,
+        blocks: impl IntoIterator<Item = u64>,
+    ) -> FsResult<bool> {
+        let mut copied = false;
+        let mut buffer = vec![0u8; block_size as usize];
+        for block in blocks {
+            let sharing = self.snapshots.sharing(block);
+            if sharing.is_empty() {
+                continue;
+            }
+            let copy = self.allocator.allocate_block_near(block)?;
+            self.allocated(copy, 1);
+            device.read_exact_at(&mut buffer, block * block_size)?;
+            device.write_all_at(&buffer, copy * block_size)?;
+            self.snapshots.add_copy(&sharing, block, copy);
+            copied = true;
+        }
+        Ok(copied)
+    }
+
+    /// Write a table holding `body` to newly allocated blocks
+    fn write_table(
+        &mut self,
+        device: &dyn BlockDevice,
+        block_size: u64,
+        format: &TableFormat,
+        body: &[u8],
+    ) -> FsResult<Vec<u64>> {
+        let mut blocks = Vec::new();
+        let mut goal = 0;
+        for _ in 0..format.blocks_needed(body.len(), block_size) {
+            let block = self.allocator.allocate_block_near(goal)?;
+            self.allocated(block, 1);
+            blocks.push(block);
+            goal = block + 1;
+        }
+        format.write(device, block_size, &blocks, body)?;
+        Ok(blocks)
+    }
+
+    /// Write the snapshot table to new blocks and point the superblock
+    /// at it
+    fn save_snapshots(&mut self, device: &dyn BlockDevice, block_size: u64) -> FsResult<()> {
+        for block in self.snapshots.take_store() {
+            self.release(block);
+        }
+        if self.snapshots.is_empty() {
+            self.groups.set_snapshot_table(0);
+            self.snapshots.stored(Vec::new());
+            return Ok(());
+        }
+
+        let body = self.snapshots.body();
+        let blocks = self.write_table(device, block_size, &Snapshots::FORMAT, &body)?;
+        self.groups.set_snapshot_table(blocks[0]);
+        self.snapshots.stored(blocks);
+        Ok(())
+    }
+
+    /// Write the reference count table to new blocks and point the
+    /// superblock at it
+    fn save_refcounts(&mut self, device: &dyn BlockDevice, block_size: u64) -> FsResult<()> {
+        for block in self.refcounts.take_store() {
+            self.free_block(block)?;
+        }
+        if self.refcounts.is_empty() {
+            self.groups.set_refcount_table(0);
+            self.refcounts.stored(Vec::new());
+            return Ok(());
+        }
+
+        let body = self.refcounts.body();
+        let blocks = self.write_table(device, block_size, &RefCounts::FORMAT, &body)?;
+        self.groups.set_refcount_table(blocks[0]);
+        self.refcounts.stored(blocks);
+        Ok(())
+    }
+
+    /// Write the dedup table to new blocks and point the superblock at it
+    fn save_dedup(&mut self, device: &dyn BlockDevice, block_size: u64) -> FsResult<()> {
+        for block in self.dedup.take_store() {
+            self.free_block(block)?;
+        }
+        if self.dedup.is_empty() {
+            self.groups.set_dedup_table(0);
+            self.dedup.stored(Vec::new());
+            return Ok(());
+        }
+
+        let body = self.dedup.body();
+        let blocks = self.write_table(device, block_size, &DedupTable::FORMAT, &body)?;
+        self.groups.set_dedup_table(blocks[0]);
+        self.dedup.stored(blocks);
+        Ok(())
+    }
+
+    /// Write back changed allocation state, updating the checksums of
+    /// the blocks written
+    /// 
+    /// With snapshots, the blocks about to be written are preserved
+    /// first. Copying them allocates blocks and changes the snapshot
+    /// table, which dirties more allocation state, so this repeats until
+    /// nothing else needs copying.
+    fn save(&mut self, device: &dyn BlockDevice, block_size: u64, checksums: Option<&ChecksumTable>) -> FsResult<()> {
+        if self.dedup.is_dirty() {
+            self.save_dedup(device, block_size)?;
+        }
+        if self.refcounts.is_dirty() {
+            self.save_refcounts(device, block_size)?;
+        }
+        loop {
+            if self.snapshots.is_dirty() {
+                self.save_snapshots(device, block_size)?;
+            }
+            if self.snapshots.is_empty() {
+                break;
+            }
+            let mut dirty = self.allocator.dirty_blocks(block_size);
+            dirty.append(&mut self.groups.dirty_blocks());
+            if let Some(table) = checksums {
+                let entries: Vec<u64> = dirty.iter().map(|&b| table.entry_block(b, block_size)).collect();
+                dirty.extend(entries);
+            }
+            if !self.preserve(device, block_size, dirty)? {
+                break;
+            }
+        }
+
+        let Some(table) = checksums else {
+            self.allocator.save(device, block_size)?;
+            return self.groups.save(device);
+        };
+
+        let mut dirty = self.allocator.dirty_blocks(block_size);
+        dirty.append(&mut self.groups.dirty_blocks());
+        self.allocator.save(device, block_size)?;
+        self.groups.save(device)?;
+
+        let mut buffer = vec![0u8; block_size as usize];
+        for block in dirty {
+            device.read_exact_at(&mut buffer, block * block_size)?;
+            table.update(device, block, &buffer)?;
+        }
+        Ok(())
+    }
 }
 
 impl VirtualDisk {
     pub fn new(path: &str) -> FsResult<VirtualDisk> {
-        let mut file = OpenOptions::new()
+        Self::new_with_options(path, FormatOptions::default())
+    }
+
+    /// Open a disk image, formatting it with `options` if it is new
+    pub fn new_with_options(path: &str, options: FormatOptions) -> FsResult<VirtualDisk> {
+        let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(path)?;
         
-        let file_metadata = file.metadata()?;
-        let is_new_disk = file_metadata.len() == 0;
-        
-        file.set_len(DISK_SIZE)?;
+        if file.metadata()?.len() == 0 {
+            return Self::format_device(Box::new(file), options);
+        }
+        Self::open_device(Box::new(file))
+    }
 
-        let bitmap = if is_new_disk {
-            // Create new bitmap for fresh disk
-            let bitmap = BlockBitmap::new(TOTAL_BLOCKS, BLOCK_SIZE);
-            bitmap.save(&mut file, BLOCK_SIZE)?;
-            bitmap
-        } else {
-            // Load existing bitmap from disk
-            BlockBitmap::load(&mut file, TOTAL_BLOCKS, BLOCK_SIZE)?
+    /// Create a new disk image, overwriting any existing file at `path`
+    pub fn format(path: &str, options: FormatOptions) -> FsResult<VirtualDisk> {
+        options.validate()?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Self::format_device(Box::new(file), options)
+    }
+
+    /// Open the file system on `device`
+    pub fn open_device(device: Box<dyn BlockDevice>) -> FsResult<VirtualDisk> {
+        Self::load(device, true)
+    }
+
+    /// Open the file system on `device`, with its snapshots unless it is
+    /// a view of one
+    fn load(device: Box<dyn BlockDevice>, with_snapshots: bool) -> FsResult<VirtualDisk> {
+        // Load existing superblock and the allocator it was formatted with
+        let groups = BlockGroups::load(device.as_ref())?;
+        let superblock = groups.superblock();
+        let (total_blocks, block_size) = (superblock.total_blocks, superblock.block_size);
+        let device_size = device.size()?;
+        if device_size < total_blocks * block_size {
+            return Err(FsError::CorruptedFileSystem(format!(
+                "Image is {} bytes, but the superblock describes {} blocks of {} bytes",
+                device_size,
+                total_blocks,
+                block_size
+            )));
+        }
+        let kind = AllocatorKind::from_u64(superblock.allocator)?;
+        let allocator = kind.load(device.as_ref(), total_blocks, block_size)?;
+        let snapshots = match with_snapshots {
+            true => Snapshots::load(device.as_ref(), block_size, total_blocks, superblock.snapshot_table)?,
+            false => Snapshots::default(),
         };
+        let refcounts = RefCounts::load(device.as_ref(), block_size, total_blocks, superblock.refcount_table)?;
+        let dedup = DedupTable::load(device.as_ref(), block_size, total_blocks, superblock.dedup_table)?;
+        let layout_end = superblock.checksum_table_start;
 
-        Ok(VirtualDisk { file, bitmap })
+        // The superblock and bitmaps are only read here, so check them now
+        let disk = Self::from_parts(device, block_size, allocator, groups, refcounts, dedup, snapshots);
+        if disk.checksums.is_some() {
+            for block in 0..layout_end {
+                disk.verify_block(block, BlockClass::Metadata)?;
+            }
+        }
+        disk.load_quotas()?;
+        Ok(disk)
     }
 
-    pub fn initialize_root_dir(&mut self) -> FsResult<()> {
-        // Allocate a block for root directory inode
-        let root_block = self.allocate_block()?;
-        
-        // Create root directory inode (inode 0)
+    /// Format `device` with a new file system, resizing it to fit
+    pub fn format_device(device: Box<dyn BlockDevice>, options: FormatOptions) -> FsResult<VirtualDisk> {
+        options.validate()?;
+        let block_size = options.block_size;
+        let total_blocks = options.total_blocks();
+        device.set_size(total_blocks * block_size)?;
+
+        // Create new allocator and block groups for fresh disk
+        let mut allocator = options.allocator.create(total_blocks, block_size);
+        let groups = BlockGroups::format(allocator.as_mut(), block_size, options.features())?;
+
+        let mut disk = Self::from_parts(
+            device,
+            block_size,
+            allocator,
+            groups,
+            RefCounts::default(),
+            DedupTable::default(),
+            Snapshots::default(),
+        );
+        if let Some(encryption) = &options.encryption {
+            let (salt, check) = disk.keyring.create(encryption)?;
+            disk.space_mut().groups.set_key_derivation(encryption.kdf_iterations, salt, check);
+        }
+        disk.sync_bitmap()?;
+        Ok(disk)
+    }
+
+    fn from_parts(
+        device: Box<dyn BlockDevice>,
+        block_size: u64,
+        allocator: Box<dyn Allocator>,
+        groups: BlockGroups,
+        refcounts: RefCounts,
+        dedup: DedupTable,
+        snapshots: Snapshots,
+    ) -> VirtualDisk {
+        let superblock = groups.superblock();
+        let checksums = superblock.has_feature(Superblock::FEATURE_CHECKSUMS).then(|| {
+            let data = superblock.has_feature(Superblock::FEATURE_DATA_CHECKSUMS);
+            let sealed = superblock.has_feature(Superblock::FEATURE_ENCRYPTION);
+            ChecksumTable::new(superblock.checksum_table_start, block_size, data, sealed)
+        });
+        let has_snapshots = !snapshots.is_empty();
+        let keyring = Keyring::new(superblock);
+        let quotas = Quotas::default();
+        let space = Mutex::new(Space { allocator, groups, refcounts, dedup, snapshots, quotas });
+        VirtualDisk {
+            device,
+            block_size,
+            space,
+            in_place: RwLock::new(()),
+            history: HistoryLock::default(),
+            checksums,
+            has_snapshots,
+            keyring,
+            credentials: (0, 0),
+        }
+    }
+
+    /// Lock the allocation state
+    /// 
+    /// The guard must not be held across calls that lock it again.
+    fn space(&self) -> MutexGuard<'_, Space> {
+        self.space.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The allocation state, without locking
+    fn space_mut(&mut self) -> &mut Space {
+        self.space.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Run `f` on the quota state, locking the allocation state
+    pub(crate) fn with_quotas<T>(&self, f: impl FnOnce(&mut Quotas) -> T) -> T {
+        f(&mut self.space().quotas)
+    }
+
+    /// Write back changed allocation state from a locked `space`
+    fn save_space(&self, space: &mut Space) -> FsResult<()> {
+        space.save(self.device.as_ref(), self.block_size, self.checksums.as_ref())
+    }
+
+    /// Size of a block in bytes
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    /// Encryption state and keys
+    pub(crate) fn keyring(&self) -> &Keyring {
+        &self.keyring
+    }
+
+    pub(crate) fn keyring_mut(&mut self) -> &mut Keyring {
+        &mut self.keyring
+    }
+
+    /// Set the user and group ids new files and directories are owned by
+    pub fn set_credentials(&mut self, uid: u32, gid: u32) {
+        self.credentials = (uid, gid);
+    }
+
+    /// The user and group ids new files and directories are owned by
+    pub fn credentials(&self) -> (u32, u32) {
+        self.credentials
+    }
+
+    /// Number of block pointers that fit in one indirect block
+    fn pointers_per_block(&self) -> u64 {
+        self.block_size / 8
+    }
+
+    pub fn initialize_root_dir(&self) -> FsResult<()> {
+        // Create root directory inode (inode 0) in the first group
         let perms = Permissions::new(true, true, true);
-        let root_inode = Inode::new(0, FileType::Directory, perms);
+        let root_block = self.create_directory_in_group(0, perms, 0, 0)?;
         
-        // Write root inode to disk
-        self.write_inode(root_block, &root_inode)?;
+        // Record the root directory in the superblock
+        self.space().groups.set_root_inode(root_block);
+        self.sync_bitmap()?;
         
         Ok(())
     }
 
+    /// Get the inode block of the root directory, if it has been initialized
+    pub fn root_directory(&self) -> Option<u64> {
+        self.space().groups.root_inode()
+    }
+
     /// Write an inode to a specific block
-    pub fn write_inode(&mut self, block_number: u64, inode: &Inode) -> FsResult<()> {
-        let bytes = inode.to_bytes();
-        self.file.seek(SeekFrom::Start(block_number * BLOCK_SIZE))?;
-        self.file.write_all(&bytes)?;
-        self.file.flush()?;
-        Ok(())
+    pub fn write_inode(&self, block_number: u64, inode: &Inode) -> FsResult<()> {
+        self.write_block(block_number, &inode.to_bytes(), BlockClass::Metadata)
     }
 
     /// Read an inode from a specific block
-    pub fn read_inode(&mut self, block_number: u64) -> FsResult<Inode> {
+    pub fn read_inode(&self, block_number: u64) -> FsResult<Inode> {
         let mut buffer = [0u8; INODE_SIZE];
-        self.file.seek(SeekFrom::Start(block_number * BLOCK_SIZE))?;
-        self.file.read_exact(&mut buffer)?;
+        self.read_partial(block_number, 0, &mut buffer, BlockClass::Metadata)?;
         Inode::from_bytes(&buffer)
     }
 
     /// Write a directory entry to a specific offset in a block
     pub fn write_dir_entry(
-        &mut self,
+        &self,
         block_number: u64,
         entry_index: usize,
         entry: &DirectoryEntry,
     ) -> FsResult<()> {
-        let bytes = entry.to_bytes();
-        let offset = block_number * BLOCK_SIZE + (entry_index * DirectoryEntry::ENTRY_SIZE) as u64;
-        self.file.seek(SeekFrom::Start(offset))?;
-        self.file.write_all(&bytes)?;
-        self.file.flush()?;
-        Ok(())
+        let offset = (entry_index * DirectoryEntry::ENTRY_SIZE) as u64;
+        let entry = self.keyring.conceal(entry)?;
+        self.write_partial(block_number, offset, &entry.to_bytes(), BlockClass::Metadata)
     }
 
     /// Read a directory entry from a specific offset in a block
     pub fn read_dir_entry(
-        &mut self,
+        &self,
         block_number: u64,
         entry_index: usize,
     ) -> FsResult<DirectoryEntry> {
         let mut buffer = [0u8; DirectoryEntry::ENTRY_SIZE];
-        let offset = block_number * BLOCK_SIZE + (entry_index * DirectoryEntry::ENTRY_SIZE) as u64;
-        self.file.seek(SeekFrom::Start(offset))?;
-        self.file.read_exact(&mut buffer)?;
-        DirectoryEntry::from_bytes(&buffer)
+        let offset = (entry_index * DirectoryEntry::ENTRY_SIZE) as u64;
+        self.read_partial(block_number, offset, &mut buffer, BlockClass::Metadata)?;
+        self.keyring.reveal(DirectoryEntry::from_bytes(&buffer)?)
     }
 
     // ==================== FILE OPERATIONS ====================
 
     /// Create a new file and return its inode block number
     /// 
-    /// This allocates an inode block and initializes it with file metadata
+    /// This allocates an inode block and initializes it with file metadata.
+    /// The inode is placed in the group of the most recently created
+    /// directory; use `create_file_in` when the parent is known.
     pub fn create_file(
-        &mut self,
+        &self,
         inode_number: u64,
         permissions: Permissions,
     ) -> FsResult<u64> {
-        // Allocate a block for the inode
-        let inode_block = self.allocate_block()?;
+        let group = self.space().groups.last_directory_group();
+        self.create_inode_in_group(inode_number, FileType::File, permissions, group, 0)
+    }
+
+    /// Create a new file next to its parent directory
+    /// 
+    /// The inode is allocated in the same block group as the directory
+    /// inode, so the file's metadata and data stay close to the directory.
+    /// The file is in the directory's project, and compressed if the
+    /// directory says so.
+    pub fn create_file_in(
+        &self,
+        dir_inode_block: u64,
+        inode_number: u64,
+        permissions: Permissions,
+    ) -> FsResult<u64> {
+        let group = self.space().groups.group_of(dir_inode_block);
+        let dir = self.read_inode(dir_inode_block)?;
+        let inode_block = self.create_inode_in_group(inode_number, FileType::File, permissions, group, dir.project)?;
+        if dir.has_flag(Inode::FLAG_COMPRESS) {
+            let mut inode = self.read_inode(inode_block)?;
+            inode.flags |= Inode::FLAG_COMPRESSED;
+            self.write_inode(inode_block, &inode)?;
+        }
+        Ok(inode_block)
+    }
+
+    /// Create a file or symlink inode with no data in `group`
+    /// 
+    /// On an encrypted image the inode gets a new file key, which needs
+    /// the image to be unlocked. The inode is owned by the credentials
+    /// set on the disk, in project `project`.
+    pub(crate) fn create_inode_in_group(
+        &self,
+        inode_number: u64,
+        file_type: FileType,
+        permissions: Permissions,
+        group: usize,
+        project: u32,
+    ) -> FsResult<u64> {
+        let wrapped_key = match self.is_encrypted() {
+            true => Some(self.new_file_key()?),
+            false => None,
+        };
+
+        // Create the inode, mapped with extents if the image uses them
+        let mut inode = self.new_inode(inode_number, file_type, permissions, project);
+        let extents = self.space().groups.superblock().has_feature(Superblock::FEATURE_EXTENTS);
+        if extents {
+            inode.flags |= Inode::FLAG_EXTENTS;
+        }
+        if let Some(wrapped_key) = wrapped_key {
+            inode.flags |= Inode::FLAG_ENCRYPTED;
+            inode.wrapped_key = wrapped_key;
+        }
+        self.map_file_blocks(&mut inode, &[], 0)?;
         
-        // Create the inode
-        let inode = Inode::new(inode_number, FileType::File, permissions);
+        // Allocate a block for the inode
+        let inode_block = self.allocate_inode(group, &inode)?;
         
         // Write inode to disk
         self.write_inode(inode_block, &inode)?;
@@ -123,61 +708,130 @@ impl VirtualDisk {
         Ok(inode_block)
     }
 
+    /// A new inode owned by the credentials set on the disk
+    fn new_inode(&self, inode_number: u64, file_type: FileType, permissions: Permissions, project: u32) -> Inode {
+        let mut inode = Inode::new(inode_number, file_type, permissions);
+        (inode.uid, inode.gid) = self.credentials;
+        inode.project = project;
+        inode
+    }
+
     /// Write data to a file
     /// 
     /// This handles multi-block files by allocating blocks as needed
-    /// and updating the inode's block pointers
+    /// and updating the inode's block pointers. If the image keeps
+    /// history (see `HistoryPolicy`), the old contents are kept as a
+    /// version of the file.
     pub fn write_file(
-        &mut self,
+        &self,
         inode_block: u64,
         data: &[u8],
     ) -> FsResult<()> {
         // Read the current inode
-        let mut inode = self.read_inode(inode_block)?;
+        let inode = self.read_inode(inode_block)?;
         
         // Verify it's a file
         if inode.file_type != FileType::File {
             return Err(FsError::NotAFile(format!("Inode {} is not a file", inode.inode_number)));
         }
         
+        let kept = self.reclaiming(|disk| disk.keep_version(inode_block))?;
+        self.reclaiming(|disk| disk.write_inode_data(inode_block, disk.read_inode(inode_block)?, data))?;
+        if kept {
+            self.prune_history()?;
+        }
+        Ok(())
+    }
+
+    /// Replace the data of a file or symlink inode
+    pub(crate) fn write_inode_data(
+        &self,
+        inode_block: u64,
+        mut inode: Inode,
+        data: &[u8],
+    ) -> FsResult<()> {
+        let _charge = self.charging(inode_block)?;
+        if inode.is_clustered() {
+            return self.write_compressed(inode_block, inode, data);
+        }
+        if self.fits_inline(&inode, data.len() as u64) {
+            self.release_file_blocks(&mut inode)?;
+            inode.set_inline_data(data);
+            inode.modified = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            return self.write_inode(inode_block, &inode);
+        }
+        
         // Calculate how many blocks we need
-        let blocks_needed = ((data.len() as u64 + BLOCK_SIZE - 1) / BLOCK_SIZE) as usize;
+        let blocks_mapped = (data.len() as u64).div_ceil(self.block_size);
         
-        if blocks_needed > DIRECT_POINTERS {
-            return Err(FsError::NotSupported(
-                format!("File size {} bytes requires {} blocks, but only {} direct pointers supported", 
-                        data.len(), blocks_needed, DIRECT_POINTERS)
-            ));
-        }
+        // With dedup, blocks identical to ones on disk are shared before
+        // the old data is released, which keeps the file's own unchanged
+        // blocks too; only the rest need new blocks
+        let plan = match self.is_dedup_enabled() {
+            true => Some(self.plan_dedup(data)?),
+            false => None,
+        };
+        let blocks_needed = plan.as_ref().map_or(blocks_mapped, |plan| plan.new_blocks());
         
-        // Free old blocks if they exist
-        for i in 0..inode.block_count as usize {
-            if inode.direct_blocks[i] != 0 {
-                self.free_block(inode.direct_blocks[i])?;
-                inode.direct_blocks[i] = 0;
+        // Fail before touching the old data if the new data cannot fit;
+        // shared blocks stay in use by the other files
+        let (old_data, old_metadata) = self.walk_mapping(&inode)?;
+        let old_mapped = old_data.iter().filter(|&&b| b != HOLE && !self.is_block_shared(b)).count();
+        let available = self.free_blocks_count() + (old_mapped + old_metadata.len()) as u64;
+        let headroom = plan.as_ref().map_or(0, |_| self.dedup_headroom(blocks_mapped));
+        let overhead = self.mapping_overhead(&inode, blocks_mapped);
+        let needed = blocks_needed + overhead + headroom;
+        // Quotas count shared blocks in full, for each file
+        let held = (old_data.iter().filter(|&&b| b != HOLE).count() + old_metadata.len()) as u64;
+        let ready = match needed > available {
+            true => Err(FsError::DiskFull),
+            false => self.check_quota((blocks_mapped + overhead).saturating_sub(held)).and_then(|_| match plan {
+                // Write back the references taken before anything maps them
+                Some(_) => self.sync_bitmap(),
+                None => Ok(()),
+            }),
+        };
+        if let Err(e) = ready {
+            if let Some(plan) = &plan {
+                self.drop_shared(plan)?;
             }
+            return Err(e);
         }
         
-        // Allocate new blocks and write data
-        let mut offset = 0;
-        for i in 0..blocks_needed {
-            let block = self.allocate_block()?;
-            inode.direct_blocks[i] = block;
-            
-            // Calculate how much data to write to this block
-            let remaining = data.len() - offset;
-            let to_write = remaining.min(BLOCK_SIZE as usize);
-            
-            // Write data to block
-            self.file.seek(SeekFrom::Start(block * BLOCK_SIZE))?;
-            self.file.write_all(&data[offset..offset + to_write])?;
-            
-            offset += to_write;
-        }
+        // Free old data blocks and mapping metadata
+        self.release_file_blocks(&mut inode)?;
+        
+        // Allocate new blocks, keeping them after the inode and after each
+        // other where possible
+        let goal = self.space().groups.data_goal(inode_block);
+        let new_blocks = if inode.has_flag(Inode::FLAG_EXTENTS) {
+            self.allocate_extents(goal, blocks_needed)?
+        } else {
+            self.allocate_blocks(goal, blocks_needed)?
+        };
+        
+        // Write data, one contiguous run at a time
+        let blocks = match plan {
+            Some(plan) => self.store_planned(plan, data, &new_blocks)?,
+            None => {
+                for extent in Extent::from_blocks(&new_blocks) {
+                    let start = (extent.logical * self.block_size) as usize;
+                    let end = ((extent.logical + extent.length) * self.block_size).min(data.len() as u64) as usize;
+                    self.write_run(extent.physical, &data[start..end], BlockClass::Data)?;
+                }
+                new_blocks
+            }
+        };
+        
+        // Record the new blocks in the inode
+        let metadata_goal = blocks.last().map_or(goal, |&b| b + 1);
+        self.map_file_blocks(&mut inode, &blocks, metadata_goal)?;
         
         // Update inode metadata
         inode.size = data.len() as u64;
-        inode.block_count = blocks_needed as u64;
         inode.modified = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
@@ -185,7 +839,6 @@ impl VirtualDisk {
         
         // Write updated inode back to disk
         self.write_inode(inode_block, &inode)?;
-        self.file.flush()?;
         
         Ok(())
     }
@@ -193,7 +846,7 @@ impl VirtualDisk {
     /// Read data from a file
     /// 
     /// Reads the entire file contents by following the inode's block pointers
-    pub fn read_file(&mut self, inode_block: u64) -> FsResult<Vec<u8>> {
+    pub fn read_file(&self, inode_block: u64) -> FsResult<Vec<u8>> {
         // Read the inode
         let inode = self.read_inode(inode_block)?;
         
@@ -202,173 +855,630 @@ impl VirtualDisk {
             return Err(FsError::NotAFile(format!("Inode {} is not a file", inode.inode_number)));
         }
         
+        self.read_inode_data(&inode)
+    }
+
+    /// Read the whole data of a file or symlink inode
+    pub(crate) fn read_inode_data(&self, inode: &Inode) -> FsResult<Vec<u8>> {
+        if inode.is_inline() {
+            return Ok(inode.inline_data().to_vec());
+        }
+        
         // Allocate buffer for file data
-        let mut data = Vec::with_capacity(inode.size as usize);
-        
-        // Read each block
-        let mut remaining = inode.size;
-        for i in 0..inode.block_count as usize {
-            let block = inode.direct_blocks[i];
-            if block == 0 {
-                return Err(FsError::CorruptedFileSystem(
-                    format!("Inode {} has null block pointer at index {}", inode.inode_number, i)
-                ));
+        let mut data = vec![0u8; inode.size as usize];
+        if inode.is_clustered() {
+            self.read_clusters(inode, 0, &mut data)?;
+            return Ok(data);
+        }
+        
+        // Read each contiguous run of blocks
+        let blocks = self.file_blocks(inode)?;
+        for extent in Extent::from_blocks(&blocks) {
+            let start = (extent.logical * self.block_size) as usize;
+            if start >= data.len() {
+                break;
             }
-            
-            // Read block data
-            let to_read = remaining.min(BLOCK_SIZE);
-            let mut buffer = vec![0u8; to_read as usize];
-            
-            self.file.seek(SeekFrom::Start(block * BLOCK_SIZE))?;
-            self.file.read_exact(&mut buffer)?;
-            
-            data.extend_from_slice(&buffer);
-            remaining -= to_read;
+            let end = ((extent.logical + extent.length) * self.block_size).min(inode.size) as usize;
+            self.read_run(extent.physical, &mut data[start..end], BlockClass::Data)?;
         }
         
         Ok(data)
     }
 
-    /// Delete a file
+    /// Read from a file at byte `offset` into `buf`
     /// 
-    /// Frees all blocks used by the file including the inode block
-    pub fn delete_file(&mut self, inode_block: u64) -> FsResult<()> {
-        // Read the inode
+    /// Returns the number of bytes read, which is less than `buf.len()`
+    /// only at the end of the file. Holes read as zeros.
+    pub fn read_at(&self, inode_block: u64, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
         let inode = self.read_inode(inode_block)?;
-        
-        // Verify it's a file
         if inode.file_type != FileType::File {
             return Err(FsError::NotAFile(format!("Inode {} is not a file", inode.inode_number)));
         }
-        
-        // Free all data blocks
-        for i in 0..inode.block_count as usize {
-            if inode.direct_blocks[i] != 0 {
-                self.free_block(inode.direct_blocks[i])?;
+        if offset >= inode.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let len = (inode.size - offset).min(buf.len() as u64) as usize;
+        if inode.is_inline() {
+            buf[..len].copy_from_slice(&inode.inline_data()[offset as usize..offset as usize + len]);
+            return Ok(len);
+        }
+        if inode.is_clustered() {
+            self.read_clusters(&inode, offset, &mut buf[..len])?;
+            return Ok(len);
+        }
+        let blocks = self.file_blocks(&inode)?;
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let within = (position % self.block_size) as usize;
+            let chunk = (self.block_size as usize - within).min(len - done);
+            let block = blocks[(position / self.block_size) as usize];
+            if block == HOLE {
+                buf[done..done + chunk].fill(0);
+            } else {
+                self.read_partial(block, within as u64, &mut buf[done..done + chunk], BlockClass::Data)?;
             }
+            done += chunk;
         }
-        
-        // Free the inode block itself
-        self.free_block(inode_block)?;
-        
-        Ok(())
+        Ok(len)
     }
 
-    /// Get file information
-    pub fn get_file_info(&mut self, inode_block: u64) -> FsResult<Inode> {
-        let inode = self.read_inode(inode_block)?;
-        
+    /// Write `data` to a file at byte `offset`, growing the file if needed
+    /// 
+    /// Only the blocks covered by the write are touched. Blocks are
+    /// allocated for holes that the write lands in; a gap between the old
+    /// end of the file and `offset` is left as a hole.
+    pub fn write_at(&self, inode_block: u64, offset: u64, data: &[u8]) -> FsResult<()> {
+        let mut inode = self.read_inode(inode_block)?;
         if inode.file_type != FileType::File {
             return Err(FsError::NotAFile(format!("Inode {} is not a file", inode.inode_number)));
         }
-        
-        Ok(inode)
-    }
+        if data.is_empty() {
+            return Ok(());
+        }
+        let end = offset
+            .checked_add(data.len() as u64)
+            .ok_or(FsError::InvalidOffsetOrSize { offset, size: data.len() as u64 })?;
+        let _charge = self.charging(inode_block)?;
+        if inode.is_clustered() {
+            return self.write_clusters(inode_block, inode, offset, data);
+        }
+        let size = end.max(inode.size);
+        if (inode.is_inline() || inode.block_count == 0) && self.fits_inline(&inode, size) {
+            let mut contents = inode.inline_data().to_vec();
+            contents.resize(size as usize, 0);
+            contents[offset as usize..end as usize].copy_from_slice(data);
+            inode.set_inline_data(&contents);
+            inode.modified = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            return self.write_inode(inode_block, &inode);
+        }
+        if inode.is_inline() {
+            self.move_inline_data(inode_block, &mut inode)?;
+        }
 
-    // ==================== DIRECTORY OPERATIONS ====================
+        let in_place = self.writing_in_place();
+        let (mut blocks, metadata) = self.walk_mapping(&inode)?;
+        let first = offset / self.block_size;
+        let last = (end - 1) / self.block_size;
+        let count = (blocks.len() as u64).max(last + 1);
+        let missing = (first..=last)
+            .filter(|&l| blocks.get(l as usize).is_none_or(|&b| b == HOLE))
+            .count() as u64;
 
-    /// Create a new directory and return its inode block number
-    pub fn create_directory(
-        &mut self,
-        inode_number: u64,
-        permissions: Permissions,
-    ) -> FsResult<u64> {
-        // Allocate a block for the directory inode
-        let inode_block = self.allocate_block()?;
-        
-        // Allocate a block for directory entries
-        let entries_block = self.allocate_block()?;
-        
-        // Create the directory inode
-        let mut inode = Inode::new(inode_number, FileType::Directory, permissions);
-        inode.direct_blocks[0] = entries_block;
-        inode.block_count = 1;
-        
-        // Write inode to disk
-        self.write_inode(inode_block, &inode)?;
-        
-        Ok(inode_block)
-    }
+        // Blocks shared with clones get copied before they are written,
+        // including the old last block if its tail is cleared below
+        let tail = (offset > inode.size && inode.size % self.block_size != 0).then_some(inode.size / self.block_size);
+        let written: Vec<u64> = (first..=last).chain(tail.filter(|&t| t < first)).collect();
+        let shared = written
+            .iter()
+            .filter(|&&l| blocks.get(l as usize).is_some_and(|&b| b != HOLE && self.is_block_shared(b)))
+            .count() as u64;
+        let remap = missing > 0 || shared > 0 || count != blocks.len() as u64;
 
-    /// Add an entry to a directory
-    pub fn add_directory_entry(
-        &mut self,
-        dir_inode_block: u64,
-        entry: DirectoryEntry,
-    ) -> FsResult<()> {
-        // Read the directory inode
-        let inode = self.read_inode(dir_inode_block)?;
-        
-        // Verify it's a directory
-        if inode.file_type != FileType::Directory {
-            return Err(FsError::NotADirectory(format!("Inode {} is not a directory", inode.inode_number)));
+        if remap {
+            // Fail before changing anything if the write cannot fit
+            let overhead = self.mapping_overhead(&inode, count);
+            if missing + shared + overhead > self.free_blocks_count() + metadata.len() as u64 {
+                return Err(FsError::DiskFull);
+            }
+            // Copies of shared blocks replace blocks already counted
+            self.check_quota((missing + overhead).saturating_sub(metadata.len() as u64))?;
+        }
+        if shared > 0 {
+            for &logical in &written {
+                self.unshare_block(&mut blocks, logical as usize)?;
+            }
+        }
+
+        // Bytes past the old end of the last block may be stale; clear
+        // the part the file grows over without writing
+        if let Some(tail) = tail {
+            let block = blocks[tail as usize];
+            let block_end = inode.size.next_multiple_of(self.block_size);
+            if block != HOLE {
+                let gap = (offset.min(block_end) - inode.size) as usize;
+                self.write_partial(block, inode.size % self.block_size, &vec![0u8; gap], BlockClass::Data)?;
+            }
+        }
+
+        blocks.resize(count as usize, HOLE);
+        let mut goal = blocks[..first as usize]
+            .iter()
+            .rev()
+            .find(|&&b| b != HOLE)
+            .map_or_else(|| self.space().groups.data_goal(inode_block), |&b| b + 1);
+        for logical in first..=last {
+            let slot = &mut blocks[logical as usize];
+            if *slot != HOLE {
+                goal = *slot + 1;
+                continue;
+            }
+            let block = self.allocate_block_near(goal)?;
+            goal = block + 1;
+            *slot = block;
+            // A block only partly covered by the write must read as zeros elsewhere
+            let start = logical * self.block_size;
+            if start < offset || start + self.block_size > end {
+                self.write_block(block, &vec![0u8; self.block_size as usize], BlockClass::Data)?;
+            }
+        }
+
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u64;
+            let within = position % self.block_size;
+            let chunk = ((self.block_size - within) as usize).min(data.len() - done);
+            let block = blocks[(position / self.block_size) as usize];
+            self.write_partial(block, within, &data[done..done + chunk], BlockClass::Data)?;
+            done += chunk;
+        }
+
+        if remap {
+            for block in metadata {
+                self.free_block(block)?;
+            }
+            self.map_file_blocks(&mut inode, &blocks, goal)?;
+        }
+
+        inode.size = inode.size.max(end);
+        inode.modified = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.write_inode(inode_block, &inode)?;
+        drop(in_place);
+
+        // Share the blocks written with identical ones elsewhere, now
+        // that their contents are known; the write itself is done, so
+        // running short of space only means less is shared
+        if self.is_dedup_enabled() {
+            match self.dedup_file(inode_block, first..last + 1) {
+                Ok(_) | Err(FsError::DiskFull | FsError::QuotaExceeded(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Deallocate the byte range `offset..offset + len` of a file
+    /// 
+    /// Blocks entirely inside the range are freed and become holes; the
+    /// parts of blocks at either edge are zeroed. The file size does not
+    /// change, and the range is clipped to it.
+    pub fn punch_hole(&self, inode_block: u64, offset: u64, len: u64) -> FsResult<()> {
+        let mut inode = self.read_inode(inode_block)?;
+        if inode.file_type != FileType::File {
+            return Err(FsError::NotAFile(format!("Inode {} is not a file", inode.inode_number)));
+        }
+        let end = offset.saturating_add(len).min(inode.size);
+        if offset >= end {
+            return Ok(());
+        }
+        let _charge = self.charging(inode_block)?;
+        if inode.is_clustered() {
+            return self.punch_clusters(inode_block, inode, offset, end);
+        }
+        if inode.is_inline() {
+            let mut contents = inode.inline_data().to_vec();
+            contents[offset as usize..end as usize].fill(0);
+            inode.set_inline_data(&contents);
+            return self.write_inode(inode_block, &inode);
+        }
+
+        let _in_place = self.writing_in_place();
+        let (mut blocks, metadata) = self.walk_mapping(&inode)?;
+        let mut freed = Vec::new();
+        let mut unshared = false;
+        let mut position = offset;
+        while position < end {
+            let logical = (position / self.block_size) as usize;
+            let block_start = logical as u64 * self.block_size;
+            let block_end = block_start + self.block_size;
+            let chunk_end = end.min(block_end);
+            let block = blocks[logical];
+
+            if block != HOLE {
+                // The last block counts as whole if the range reaches the end of the file
+                let whole = position == block_start && (chunk_end == block_end || end == inode.size);
+                if whole {
+                    freed.push(block);
+                    blocks[logical] = HOLE;
+                } else {
+                    unshared |= self.unshare_block(&mut blocks, logical)?;
+                    let block = blocks[logical];
+                    self.write_partial(block, position - block_start, &vec![0u8; (chunk_end - position) as usize], BlockClass::Data)?;
+                }
+            }
+            position = chunk_end;
+        }
+
+        if !freed.is_empty() || unshared {
+            for block in freed.into_iter().chain(metadata) {
+                self.free_block(block)?;
+            }
+            let goal = self.space().groups.data_goal(inode_block);
+            self.map_file_blocks(&mut inode, &blocks, goal)?;
+            self.write_inode(inode_block, &inode)?;
+        }
+        Ok(())
+    }
+
+    /// Make the file at `dst` a copy of the file at `src` that shares
+    /// its data blocks
+    /// 
+    /// Only a new mapping is written for `dst`; the data stays where it
+    /// is until either file writes to a shared block, which first moves
+    /// that file onto a copy of the block. Whatever `dst` held before is
+    /// released.
+    pub fn clone_file(&self, src: u64, dst: u64) -> FsResult<()> {
+        let source = self.read_inode(src)?;
+        let mut inode = self.read_inode(dst)?;
+        for (block, inode) in [(src, &source), (dst, &inode)] {
+            if inode.file_type != FileType::File {
+                return Err(FsError::NotAFile(format!("Inode {} is not a file", block)));
+            }
+        }
+        if src == dst {
+            return Ok(());
+        }
+        let _charge = self.charging(dst)?;
+
+        // Only the mapping needs new blocks; the old data may be shared,
+        // so it does not count as freed
+        let blocks = self.file_blocks(&source)?;
+        let (old_data, old_metadata) = self.walk_mapping(&inode)?;
+        let needed = self.mapping_overhead(&inode, blocks.len() as u64);
+        if needed > self.free_blocks_count() + old_metadata.len() as u64 {
+            return Err(FsError::DiskFull);
+        }
+        // Quotas count the shared data in full for the clone, and what
+        // it held before as freed
+        let cloned = blocks.iter().filter(|&&b| b != HOLE).count() as u64 + needed;
+        let held = (old_data.iter().filter(|&&b| b != HOLE).count() + old_metadata.len()) as u64;
+        self.check_quota(cloned.saturating_sub(held))?;
+
+        self.share_blocks(&blocks)?;
+        self.release_file_blocks(&mut inode)?;
+        let goal = self.space().groups.data_goal(dst);
+        self.map_file_blocks(&mut inode, &blocks, goal)?;
+        inode.size = source.size;
+        // Data stored in the inode cannot be shared, so it is copied
+        if source.is_inline() {
+            inode.set_inline_data(source.inline_data());
+        }
+        // The data stays encrypted with the source's key, so it comes along
+        let carried = Inode::FLAG_COMPRESSED | Inode::FLAG_ENCRYPTED;
+        inode.flags = (inode.flags & !carried) | (source.flags & carried);
+        inode.wrapped_key = source.wrapped_key;
+        inode.modified = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.write_inode(dst, &inode)
+    }
+
+    /// Share `len` bytes of the file at `src` from `src_offset` into the
+    /// file at `dst` at `dst_offset`
+    /// 
+    /// Like `clone_file` for part of a file: the blocks of `dst` in the
+    /// range are released and replaced by those of `src`, and `dst` grows
+    /// if the range ends past its end. Offsets and length must be
+    /// multiples of the block size, except that a range ending at the end
+    /// of `src` may end mid-block if it also reaches the end of `dst`.
+    /// Both ranges may be in the same file if they do not overlap.
+    pub fn clone_range(&self, src: u64, src_offset: u64, dst: u64, dst_offset: u64, len: u64) -> FsResult<()> {
+        let source = self.read_inode(src)?;
+        let inode = self.read_inode(dst)?;
+        for (block, inode) in [(src, &source), (dst, &inode)] {
+            if inode.file_type != FileType::File {
+                return Err(FsError::NotAFile(format!("Inode {} is not a file", block)));
+            }
+            if inode.is_clustered() {
+                return Err(FsError::NotSupported(format!("Cloning part of compressed or encrypted file {}", block)));
+            }
+        }
+
+        let invalid = FsError::InvalidOffsetOrSize { offset: src_offset, size: len };
+        let src_end = src_offset.checked_add(len).filter(|&end| end <= source.size);
+        let dst_end = dst_offset.checked_add(len);
+        let (Some(src_end), Some(dst_end)) = (src_end, dst_end) else {
+            return Err(invalid);
+        };
+        let aligned = src_offset.is_multiple_of(self.block_size)
+            && dst_offset.is_multiple_of(self.block_size)
+            && (len.is_multiple_of(self.block_size) || (src_end == source.size && dst_end >= inode.size));
+        let overlap = src == dst && src_offset < dst_end && dst_offset < src_end;
+        if !aligned || overlap {
+            return Err(invalid);
+        }
+        if len == 0 {
+            return Ok(());
+        }
+        let _charge = self.charging(dst)?;
+
+        // Only data in blocks can be shared
+        let (source, mut inode) = match source.is_inline() || inode.is_inline() {
+            true => {
+                for block in [src, dst] {
+                    let _charge = self.charging(block)?;
+                    let mut inode = self.read_inode(block)?;
+                    if inode.is_inline() {
+                        self.move_inline_data(block, &mut inode)?;
+                    }
+                }
+                (self.read_inode(src)?, self.read_inode(dst)?)
+            }
+            false => (source, inode),
+        };
+
+        let source_blocks = self.file_blocks(&source)?;
+        let shared = &source_blocks[(src_offset / self.block_size) as usize..src_end.div_ceil(self.block_size) as usize];
+        let (mut blocks, metadata) = self.walk_mapping(&inode)?;
+        let first = (dst_offset / self.block_size) as usize;
+        let count = blocks.len().max(first + shared.len());
+        let overhead = self.mapping_overhead(&inode, count as u64);
+        if 1 + overhead > self.free_blocks_count() + metadata.len() as u64 {
+            return Err(FsError::DiskFull);
+        }
+        let replaced = blocks.iter().skip(first).take(shared.len()).filter(|&&b| b != HOLE).count();
+        let cloned = shared.iter().filter(|&&b| b != HOLE).count() as u64 + 1 + overhead;
+        self.check_quota(cloned.saturating_sub((replaced + metadata.len()) as u64))?;
+
+        // Bytes past the old end of the last block may be stale, and the
+        // file now grows over them
+        if dst_offset > inode.size && inode.size % self.block_size != 0 {
+            let _in_place = self.writing_in_place();
+            let tail = (inode.size / self.block_size) as usize;
+            self.unshare_block(&mut blocks, tail)?;
+            if blocks[tail] != HOLE {
+                let gap = (self.block_size - inode.size % self.block_size) as usize;
+                self.write_partial(blocks[tail], inode.size % self.block_size, &vec![0u8; gap], BlockClass::Data)?;
+            }
+        }
+
+        self.share_blocks(shared)?;
+        blocks.resize(count, HOLE);
+        let replaced: Vec<u64> = blocks.splice(first..first + shared.len(), shared.iter().copied()).collect();
+        for block in replaced.into_iter().filter(|&b| b != HOLE).chain(metadata) {
+            self.free_block(block)?;
+        }
+        let goal = self.space().groups.data_goal(dst);
+        self.map_file_blocks(&mut inode, &blocks, goal)?;
+        inode.size = inode.size.max(dst_end);
+        inode.modified = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.write_inode(dst, &inode)
+    }
+
+    /// Delete a file or symlink
+    /// 
+    /// Frees all blocks used by the file including the inode block, and
+    /// any versions kept of it
+    pub fn delete_file(&self, inode_block: u64) -> FsResult<()> {
+        // Read the inode
+        let mut inode = self.read_inode(inode_block)?;
+        
+        // Verify it's not a directory
+        if inode.file_type == FileType::Directory {
+            return Err(FsError::NotAFile(format!("Inode {} is not a file", inode.inode_number)));
+        }
+        if inode.file_type == FileType::File {
+            self.drop_versions(inode_block)?;
         }
         
-        // Get the directory entries block
-        let entries_block = inode.direct_blocks[0];
-        if entries_block == 0 {
-            return Err(FsError::CorruptedFileSystem("Directory has no entries block".to_string()));
+        // Free all data blocks and mapping metadata
+        let _charge = self.charging(inode_block)?;
+        self.release_file_blocks(&mut inode)?;
+        
+        // Free the inode block itself
+        self.free_inode(inode_block, &inode)?;
+        
+        Ok(())
+    }
+
+    /// Get file information
+    pub fn get_file_info(&self, inode_block: u64) -> FsResult<Inode> {
+        let inode = self.read_inode(inode_block)?;
+        
+        if inode.file_type != FileType::File {
+            return Err(FsError::NotAFile(format!("Inode {} is not a file", inode.inode_number)));
+        }
+        
+        Ok(inode)
+    }
+
+    // ==================== DIRECTORY OPERATIONS ====================
+
+    /// Create a new directory and return its inode block number
+    /// 
+    /// New directories are spread across block groups (see
+    /// `BlockGroups::directory_group`) so that each gets room for its files.
+    pub fn create_directory(
+        &self,
+        inode_number: u64,
+        permissions: Permissions,
+    ) -> FsResult<u64> {
+        let group = self.space().groups.directory_group();
+        self.create_directory_in_group(inode_number, permissions, group, 0)
+    }
+
+    /// Create a new directory for a name in directory `dir_inode_block`
+    /// 
+    /// Like `create_directory`, but the directory is in the project of
+    /// its parent.
+    pub fn create_directory_in(
+        &self,
+        dir_inode_block: u64,
+        inode_number: u64,
+        permissions: Permissions,
+    ) -> FsResult<u64> {
+        let project = self.read_inode(dir_inode_block)?.project;
+        let group = self.space().groups.directory_group();
+        self.create_directory_in_group(inode_number, permissions, group, project)
+    }
+
+    fn create_directory_in_group(
+        &self,
+        inode_number: u64,
+        permissions: Permissions,
+        group: usize,
+        project: u32,
+    ) -> FsResult<u64> {
+        // Build the directory inode first: its owners are charged for the slot
+        let mut inode = self.new_inode(inode_number, FileType::Directory, permissions, project);
+        let inline = self.has_feature(Superblock::FEATURE_INLINE_DATA);
+        if inline {
+            // Entries are kept in the inode until they outgrow it
+            inode.set_inline_data(&[]);
+        }
+        let inode_block = self.allocate_inode(group, &inode)?;
+        
+        // Write inode to disk
+        self.write_inode(inode_block, &inode)?;
+        if inline {
+            return Ok(inode_block);
         }
         
-        // Calculate how many entries fit in a block
-        let entries_per_block = (BLOCK_SIZE as usize) / DirectoryEntry::ENTRY_SIZE;
+        // Allocate a block for directory entries, charged to the directory
+        let charge = self.charging(inode_block)?;
+        let goal = self.space().groups.data_goal(inode_block);
+        let entries_block = match self.allocate_directory_block(goal) {
+            Ok(block) => block,
+            Err(e) => {
+                drop(charge);
+                self.free_inode(inode_block, &inode)?;
+                return Err(e);
+            }
+        };
+        inode.direct_blocks[0] = entries_block;
+        inode.block_count = 1;
+        inode.size = self.block_size;
+        self.write_inode(inode_block, &inode)?;
+        
+        Ok(inode_block)
+    }
+
+    /// Add an entry to a directory
+    /// 
+    /// The entry goes into the first free slot; when every entries block
+    /// is full, the directory grows by one block. An inline directory
+    /// moves to blocks when the entry does not fit in its inode.
+    pub fn add_directory_entry(
+        &self,
+        dir_inode_block: u64,
+        entry: DirectoryEntry,
+    ) -> FsResult<()> {
+        let (mut inode, blocks) = self.directory_blocks(dir_inode_block)?;
+        let _charge = self.charging(dir_inode_block)?;
+        
+        if inode.is_inline() {
+            let mut entries = DirectoryEntry::unpack(inode.inline_data())?;
+            entries.push(self.keyring.conceal(&entry)?);
+            let packed = DirectoryEntry::pack(&entries);
+            if packed.len() <= INLINE_DATA_SIZE {
+                inode.set_inline_data(&packed);
+                return self.write_inode(dir_inode_block, &inode);
+            }
+            return self.move_inline_entries(dir_inode_block, &mut inode, &entries);
+        }
         
         // Find first empty slot
-        for i in 0..entries_per_block {
-            // Try to read existing entry
-            match self.read_dir_entry(entries_block, i) {
-                Ok(_) => continue, // Slot occupied
-                Err(FsError::InvalidMetadata(_)) => {
-                    // Empty slot found, write new entry
-                    self.write_dir_entry(entries_block, i, &entry)?;
-                    return Ok(());
-                }
-                Err(e) => return Err(e),
+        for &entries_block in &blocks {
+            let slots = self.read_dir_block(entries_block)?;
+            if let Some(i) = slots.iter().position(Option::is_none) {
+                return self.write_dir_entry(entries_block, i, &entry);
             }
         }
         
-        Err(FsError::NotSupported("Directory is full".to_string()))
+        // All blocks are full: append a new entries block
+        let goal = blocks.last().map_or(self.space().groups.data_goal(dir_inode_block), |&b| b + 1);
+        let new_block = self.allocate_directory_block(goal)?;
+        let (mut data, metadata) = self.walk_mapping(&inode)?;
+        for block in metadata {
+            self.free_block(block)?;
+        }
+        data.push(new_block);
+        self.map_file_blocks(&mut inode, &data, new_block + 1)?;
+        inode.size = data.len() as u64 * self.block_size;
+        self.write_inode(dir_inode_block, &inode)?;
+        
+        self.write_dir_entry(new_block, 0, &entry)
     }
 
     /// Remove an entry from a directory by name
+    /// 
+    /// A directory in blocks moves back into its inode once the remaining
+    /// entries fit there.
     pub fn remove_directory_entry(
-        &mut self,
+        &self,
         dir_inode_block: u64,
         name: &str,
     ) -> FsResult<u64> {
-        // Read the directory inode
-        let inode = self.read_inode(dir_inode_block)?;
-        
-        // Verify it's a directory
-        if inode.file_type != FileType::Directory {
-            return Err(FsError::NotADirectory(format!("Inode {} is not a directory", inode.inode_number)));
-        }
+        let (mut inode, blocks) = self.directory_blocks(dir_inode_block)?;
+        let _charge = self.charging(dir_inode_block)?;
         
-        // Get the directory entries block
-        let entries_block = inode.direct_blocks[0];
-        if entries_block == 0 {
-            return Err(FsError::CorruptedFileSystem("Directory has no entries block".to_string()));
+        if inode.is_inline() {
+            let mut entries = DirectoryEntry::unpack(inode.inline_data())?;
+            for (i, stored) in entries.iter().enumerate() {
+                let entry = self.keyring.reveal(stored.clone())?;
+                if entry.name == name {
+                    entries.remove(i);
+                    inode.set_inline_data(&DirectoryEntry::pack(&entries));
+                    self.write_inode(dir_inode_block, &inode)?;
+                    return Ok(entry.inode_number);
+                }
+            }
+            return Err(FsError::FileNotFound(name.to_string()));
         }
         
-        // Calculate how many entries fit in a block
-        let entries_per_block = (BLOCK_SIZE as usize) / DirectoryEntry::ENTRY_SIZE;
-        
         // Find and remove the entry
-        for i in 0..entries_per_block {
-            match self.read_dir_entry(entries_block, i) {
-                Ok(entry) => {
-                    if entry.name == name {
-                        // Found it! Clear the entry by writing zeros
-                        let empty_entry = [0u8; DirectoryEntry::ENTRY_SIZE];
-                        let offset = entries_block * BLOCK_SIZE + (i * DirectoryEntry::ENTRY_SIZE) as u64;
-                        self.file.seek(SeekFrom::Start(offset))?;
-                        self.file.write_all(&empty_entry)?;
-                        self.file.flush()?;
-                        return Ok(entry.inode_number);
-                    }
+        for &entries_block in &blocks {
+            let slots = self.read_dir_slots(entries_block)?;
+            for (i, slot) in slots.iter().enumerate() {
+                let Some(entry) = slot.clone().map(|entry| self.keyring.reveal(entry)).transpose()? else {
+                    continue;
+                };
+                if entry.name != name {
+                    continue;
                 }
-                Err(FsError::InvalidMetadata(_)) => continue, // Empty slot
-                Err(e) => return Err(e),
+                
+                if let Some(packed) = self.packed_rest(&blocks, entries_block, i)? {
+                    self.release_file_blocks(&mut inode)?;
+                    inode.set_inline_data(&packed);
+                    self.write_inode(dir_inode_block, &inode)?;
+                } else {
+                    // Clear the entry by writing zeros
+                    let empty_entry = [0u8; DirectoryEntry::ENTRY_SIZE];
+                    let offset = (i * DirectoryEntry::ENTRY_SIZE) as u64;
+                    self.write_partial(entries_block, offset, &empty_entry, BlockClass::Metadata)?;
+                }
+                return Ok(entry.inode_number);
             }
         }
         
@@ -376,43 +1486,37 @@ impl VirtualDisk {
     }
 
     /// List all entries in a directory
-    pub fn list_directory(&mut self, dir_inode_block: u64) -> FsResult<Vec<DirectoryEntry>> {
-        // Read the directory inode
-        let inode = self.read_inode(dir_inode_block)?;
-        
-        // Verify it's a directory
-        if inode.file_type != FileType::Directory {
-            return Err(FsError::NotADirectory(format!("Inode {} is not a directory", inode.inode_number)));
-        }
-        
-        // Get the directory entries block
-        let entries_block = inode.direct_blocks[0];
-        if entries_block == 0 {
-            return Err(FsError::CorruptedFileSystem("Directory has no entries block".to_string()));
+    /// 
+    /// On a locked image with encrypted names, the names are listed as
+    /// stored.
+    pub fn list_directory(&self, dir_inode_block: u64) -> FsResult<Vec<DirectoryEntry>> {
+        let (inode, blocks) = self.directory_blocks(dir_inode_block)?;
+        if inode.is_inline() {
+            return DirectoryEntry::unpack(inode.inline_data())?
+                .into_iter()
+                .map(|entry| self.keyring.reveal(entry))
+                .collect();
         }
         
-        // Calculate how many entries fit in a block
-        let entries_per_block = (BLOCK_SIZE as usize) / DirectoryEntry::ENTRY_SIZE;
-        
         // Collect all valid entries
         let mut entries = Vec::new();
-        for i in 0..entries_per_block {
-            match self.read_dir_entry(entries_block, i) {
-                Ok(entry) => entries.push(entry),
-                Err(FsError::InvalidMetadata(_)) => continue, // Empty slot
-                Err(e) => return Err(e),
-            }
+        for &entries_block in &blocks {
+            entries.extend(self.read_dir_block(entries_block)?.into_iter().flatten());
         }
         
         Ok(entries)
     }
 
     /// Find an entry in a directory by name
+    /// 
+    /// Fails with `FsError::WrongKey` on a locked image with encrypted
+    /// names, where no name can be found.
     pub fn find_directory_entry(
-        &mut self,
+        &self,
         dir_inode_block: u64,
         name: &str,
     ) -> FsResult<DirectoryEntry> {
+        self.keyring.require_names()?;
         let entries = self.list_directory(dir_inode_block)?;
         
         for entry in entries {
@@ -425,9 +1529,9 @@ impl VirtualDisk {
     }
 
     /// Delete a directory (must be empty)
-    pub fn delete_directory(&mut self, dir_inode_block: u64) -> FsResult<()> {
+    pub fn delete_directory(&self, dir_inode_block: u64) -> FsResult<()> {
         // Read the directory inode
-        let inode = self.read_inode(dir_inode_block)?;
+        let mut inode = self.read_inode(dir_inode_block)?;
         
         // Verify it's a directory
         if inode.file_type != FileType::Directory {
@@ -440,85 +1544,1314 @@ impl VirtualDisk {
             return Err(FsError::DirectoryNotEmpty(format!("Directory has {} entries", entries.len())));
         }
         
-        // Free the entries block
-        if inode.direct_blocks[0] != 0 {
-            self.free_block(inode.direct_blocks[0])?;
-        }
+        // Free the entries blocks
+        let _charge = self.charging(dir_inode_block)?;
+        self.release_file_blocks(&mut inode)?;
         
         // Free the inode block
-        self.free_block(dir_inode_block)?;
+        self.free_inode(dir_inode_block, &inode)?;
         
         Ok(())
     }
 
-    /// Get directory information
-    pub fn get_directory_info(&mut self, dir_inode_block: u64) -> FsResult<Inode> {
+    /// Read a directory inode and the blocks holding its entries, none if
+    /// the entries are inline
+    fn directory_blocks(&self, dir_inode_block: u64) -> FsResult<(Inode, Vec<u64>)> {
+        // Read the directory inode
         let inode = self.read_inode(dir_inode_block)?;
         
+        // Verify it's a directory
         if inode.file_type != FileType::Directory {
             return Err(FsError::NotADirectory(format!("Inode {} is not a directory", inode.inode_number)));
         }
         
-        Ok(inode)
+        let blocks = self.file_blocks(&inode)?;
+        if blocks.is_empty() && !inode.is_inline() {
+            return Err(FsError::CorruptedFileSystem("Directory has no entries block".to_string()));
+        }
+        
+        Ok((inode, blocks))
     }
 
-    // ==================== BLOCK ALLOCATION ====================
-
-    /// Allocate a single free block
-    pub fn allocate_block(&mut self) -> FsResult<u64> {
-        let block = self.bitmap.allocate_block()?;
-        self.bitmap.save(&mut self.file, BLOCK_SIZE)?;
-        Ok(block)
+    /// Read every slot of a directory entries block, `None` where empty
+    fn read_dir_block(&self, block: u64) -> FsResult<Vec<Option<DirectoryEntry>>> {
+        self.read_dir_slots(block)?
+            .into_iter()
+            .map(|slot| slot.map(|entry| self.keyring.reveal(entry)).transpose())
+            .collect()
     }
 
-    /// Allocate multiple contiguous blocks
-    pub fn allocate_contiguous_blocks(&mut self, count: u64) -> FsResult<u64> {
-        let start = self.bitmap.allocate_contiguous(count)?;
-        self.bitmap.save(&mut self.file, BLOCK_SIZE)?;
-        Ok(start)
+    /// Read every slot of a directory entries block as stored, with names
+    /// still encrypted
+    fn read_dir_slots(&self, block: u64) -> FsResult<Vec<Option<DirectoryEntry>>> {
+        let contents = self.read_block(block, BlockClass::Metadata)?;
+        contents
+            .chunks_exact(DirectoryEntry::ENTRY_SIZE)
+            .map(|slot| match DirectoryEntry::from_bytes(slot) {
+                Ok(entry) => Ok(Some(entry)),
+                Err(FsError::InvalidMetadata(_)) => Ok(None), // Empty slot
+                Err(e) => Err(e),
+            })
+            .collect()
     }
 
-    /// Free a previously allocated block
-    pub fn free_block(&mut self, block: u64) -> FsResult<()> {
-        self.bitmap.free_block(block);
-        self.bitmap.save(&mut self.file, BLOCK_SIZE)?;
-        Ok(())
+    /// The entries of a directory in `blocks` other than slot `slot` of
+    /// `block`, packed for the inode, or `None` if they do not fit there
+    fn packed_rest(&self, blocks: &[u64], block: u64, slot: usize) -> FsResult<Option<Vec<u8>>> {
+        if !self.has_feature(Superblock::FEATURE_INLINE_DATA) {
+            return Ok(None);
+        }
+        let mut rest = Vec::new();
+        let mut size = 0;
+        for &entries_block in blocks {
+            for (i, entry) in self.read_dir_slots(entries_block)?.into_iter().enumerate() {
+                let Some(entry) = entry.filter(|_| (entries_block, i) != (block, slot)) else {
+                    continue;
+                };
+                size += entry.packed_size();
+                if size > INLINE_DATA_SIZE {
+                    return Ok(None);
+                }
+                rest.push(entry);
+            }
+        }
+        Ok(Some(DirectoryEntry::pack(&rest)))
     }
 
-    /// Free multiple contiguous blocks
-    pub fn free_blocks(&mut self, start: u64, count: u64) -> FsResult<()> {
-        self.bitmap.free_blocks(start, count);
-        self.bitmap.save(&mut self.file, BLOCK_SIZE)?;
-        Ok(())
+    /// Move the entries of an inline directory, as stored, to blocks
+    fn move_inline_entries(&self, dir_inode_block: u64, inode: &mut Inode, entries: &[DirectoryEntry]) -> FsResult<()> {
+        let per_block = self.block_size as usize / DirectoryEntry::ENTRY_SIZE;
+        let mut blocks = Vec::new();
+        let mut goal = self.space().groups.data_goal(dir_inode_block);
+        for chunk in entries.chunks(per_block) {
+            let block = match self.allocate_block_near(goal) {
+                Ok(block) => block,
+                Err(e) => {
+                    for &block in &blocks {
+                        self.free_block(block)?;
+                    }
+                    return Err(e);
+                }
+            };
+            let mut contents = vec![0u8; self.block_size as usize];
+            for (slot, entry) in contents.chunks_exact_mut(DirectoryEntry::ENTRY_SIZE).zip(chunk) {
+                slot.copy_from_slice(&entry.to_bytes());
+            }
+            blocks.push(block);
+            self.write_block(block, &contents, BlockClass::Metadata)?;
+            goal = block + 1;
+        }
+        self.map_file_blocks(inode, &blocks, goal)?;
+        inode.size = blocks.len() as u64 * self.block_size;
+        self.write_inode(dir_inode_block, inode)
     }
 
-    /// Check if a block is currently in use
-    pub fn is_block_used(&self, block: u64) -> bool {
-        self.bitmap.is_block_used(block)
+    /// Allocate a zero-filled block for directory entries
+    fn allocate_directory_block(&self, goal: u64) -> FsResult<u64> {
+        let block = self.allocate_block_near(goal)?;
+        self.write_block(block, &vec![0u8; self.block_size as usize], BlockClass::Metadata)?;
+        Ok(block)
     }
 
-    /// Get the total number of blocks in the file system
-    pub fn total_blocks(&self) -> u64 {
-        self.bitmap.total_blocks()
+    /// Get directory information
+    pub fn get_directory_info(&self, dir_inode_block: u64) -> FsResult<Inode> {
+        let inode = self.read_inode(dir_inode_block)?;
+        
+        if inode.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory(format!("Inode {} is not a directory", inode.inode_number)));
+        }
+        
+        Ok(inode)
     }
 
-    /// Get the number of free blocks available
-    pub fn free_blocks_count(&self) -> u64 {
-        self.bitmap.count_free_blocks()
+    // ==================== BLOCK MAPPING ====================
+
+    /// Get the physical data blocks of an inode, in logical order
+    /// 
+    /// Works for both block-pointer and extent-mapped inodes. Unmapped
+    /// logical blocks are returned as `HOLE`.
+    pub fn file_blocks(&self, inode: &Inode) -> FsResult<Vec<u64>> {
+        Ok(self.walk_mapping(inode)?.0)
     }
 
-    /// Get the number of used blocks
-    pub fn used_blocks_count(&self) -> u64 {
-        self.bitmap.count_used_blocks()
+    /// Describe how a file's data is mapped
+    /// 
+    /// Returns the data as extents together with the blocks spent on
+    /// mapping metadata (indirect blocks or extent tree nodes).
+    pub fn file_mapping(&self, inode_block: u64) -> FsResult<FileMapping> {
+        let inode = self.read_inode(inode_block)?;
+        let (blocks, metadata_blocks) = self.walk_mapping(&inode)?;
+        Ok(FileMapping {
+            extents: Extent::from_blocks(&blocks),
+            metadata_blocks,
+        })
     }
 
-    /// Get disk utilization as a percentage (0.0 to 100.0)
-    pub fn utilization(&self) -> f64 {
-        self.bitmap.utilization()
+    /// Collect an inode's data blocks and mapping metadata blocks
+    ///
+    /// The data list has one entry per logical block, `HOLE` where
+    /// nothing is mapped.
+    pub(crate) fn walk_mapping(&self, inode: &Inode) -> FsResult<(Vec<u64>, Vec<u64>)> {
+        // Nothing is mapped past the end of the file
+        let limit = inode.size.div_ceil(self.block_size);
+        if inode.block_count > limit {
+            return Err(FsError::CorruptedFileSystem(format!(
+                "Inode {} maps {} blocks, but is only {} bytes",
+                inode.inode_number, inode.block_count, inode.size
+            )));
+        }
+        let mut data = Vec::with_capacity(inode.block_count as usize);
+        let mut metadata = Vec::new();
+
+        if inode.has_flag(Inode::FLAG_EXTENTS) {
+            let root = ExtentNode::from_bytes(&inode.pointer_area())?;
+            let mut extents = Vec::new();
+            self.walk_extent_node(&root, &mut extents, &mut metadata)?;
+            data = Extent::to_blocks(&extents, limit)?;
+            // Trailing holes are not recorded in the tree
+            if (data.len() as u64) < inode.block_count {
+                data.resize(inode.block_count as usize, HOLE);
+            }
+        } else {
+            let direct = (inode.block_count as usize).min(DIRECT_POINTERS);
+            data.extend_from_slice(&inode.direct_blocks[..direct]);
+
+            let mut remaining = inode.block_count - direct as u64;
+            for (level, &root) in inode.indirect_blocks.iter().enumerate() {
+                if remaining == 0 {
+                    break;
+                }
+                let depth = level as u32 + 1;
+                let count = remaining.min(self.pointers_per_block().pow(depth));
+                self.walk_indirect(root, depth, count, &mut data, &mut metadata)?;
+                remaining -= count;
+            }
+        }
+
+        if data.len() as u64 != inode.block_count {
+            return Err(FsError::CorruptedFileSystem(format!(
+                "Inode {} maps {} blocks, expected {}",
+                inode.inode_number,
+                data.len(),
+                inode.block_count
+            )));
+        }
+
+        Ok((data, metadata))
     }
 
-    /// Save the current bitmap state to disk
-    pub fn sync_bitmap(&mut self) -> FsResult<()> {
-        self.bitmap.save(&mut self.file, BLOCK_SIZE)
+    /// Collect `count` data blocks below an indirect block of `depth` levels
+    fn walk_indirect(
+        &self,
+        block: u64,
+        depth: u32,
+        count: u64,
+        data: &mut Vec<u64>,
+        metadata: &mut Vec<u64>,
+    ) -> FsResult<()> {
+        if block == 0 {
+            return Err(FsError::CorruptedFileSystem("Null indirect block pointer".to_string()));
+        }
+        metadata.push(block);
+
+        let pointers = self.read_pointer_block(block)?;
+        if depth == 1 {
+            data.extend_from_slice(&pointers[..count as usize]);
+            return Ok(());
+        }
+
+        let per_child = self.pointers_per_block().pow(depth - 1);
+        let mut remaining = count;
+        for &child in &pointers {
+            if remaining == 0 {
+                break;
+            }
+            let child_count = remaining.min(per_child);
+            self.walk_indirect(child, depth - 1, child_count, data, metadata)?;
+            remaining -= child_count;
+        }
+        Ok(())
+    }
+
+    /// Collect the extents below an extent tree node
+    /// 
+    /// Each child must be exactly one level below its parent, so the walk
+    /// ends within `ExtentNode::MAX_DEPTH` levels even if the tree on
+    /// disk points back at itself.
+    fn walk_extent_node(
+        &self,
+        node: &ExtentNode,
+        extents: &mut Vec<Extent>,
+        metadata: &mut Vec<u64>,
+    ) -> FsResult<()> {
+        for entry in &node.entries {
+            match *entry {
+                ExtentEntry::Leaf(extent) => extents.push(extent),
+                ExtentEntry::Index { child, .. } => {
+                    metadata.push(child);
+                    let buffer = self.read_block(child, BlockClass::Metadata)?;
+                    let child_node = ExtentNode::from_bytes(&buffer)?;
+                    if child_node.depth + 1 != node.depth {
+                        return Err(FsError::CorruptedFileSystem(format!(
+                            "Extent node in block {} has depth {} below a node of depth {}",
+                            child, child_node.depth, node.depth
+                        )));
+                    }
+                    self.walk_extent_node(&child_node, extents, metadata)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Point an inode at `blocks`, allocating mapping metadata near `goal`
+    /// 
+    /// The inode must not map any blocks yet (see `release_file_blocks`).
+    /// Data stored in the inode itself is dropped.
+    pub(crate) fn map_file_blocks(&self, inode: &mut Inode, blocks: &[u64], goal: u64) -> FsResult<()> {
+        inode.clear_inline_data();
+        inode.direct_blocks = [0; DIRECT_POINTERS];
+        inode.indirect_blocks = Default::default();
+        inode.block_count = blocks.len() as u64;
+
+        if inode.has_flag(Inode::FLAG_EXTENTS) {
+            return self.map_extents(inode, blocks, goal);
+        }
+
+        let direct = blocks.len().min(DIRECT_POINTERS);
+        inode.direct_blocks[..direct].copy_from_slice(&blocks[..direct]);
+
+        let mut rest = &blocks[direct..];
+        let mut goal = goal;
+        for level in 0..inode.indirect_blocks.len() {
+            if rest.is_empty() {
+                break;
+            }
+            let depth = level as u32 + 1;
+            let count = (rest.len() as u64).min(self.pointers_per_block().pow(depth)) as usize;
+            inode.indirect_blocks[level] = self.build_indirect(&rest[..count], depth, &mut goal)?;
+            rest = &rest[count..];
+        }
+
+        if !rest.is_empty() {
+            return Err(FsError::NotSupported(format!(
+                "File of {} blocks exceeds the triple indirect limit",
+                blocks.len()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Write an indirect block of `depth` levels covering `blocks`
+    fn build_indirect(&self, blocks: &[u64], depth: u32, goal: &mut u64) -> FsResult<u64> {
+        let block = self.allocate_block_near(*goal)?;
+        *goal = block + 1;
+
+        let pointers = if depth == 1 {
+            blocks.to_vec()
+        } else {
+            let per_child = self.pointers_per_block().pow(depth - 1) as usize;
+            let mut children = Vec::new();
+            for chunk in blocks.chunks(per_child) {
+                children.push(self.build_indirect(chunk, depth - 1, goal)?);
+            }
+            children
+        };
+
+        self.write_pointer_block(block, &pointers)?;
+        Ok(block)
+    }
+
+    /// Store `blocks` as an extent tree rooted in the inode
+    /// 
+    /// Up to seven extents fit in the inode itself; beyond that, extents
+    /// spill into leaf blocks indexed from the inode, adding index levels
+    /// until the root fits again.
+    fn map_extents(&self, inode: &mut Inode, blocks: &[u64], goal: u64) -> FsResult<()> {
+        let root_capacity = ExtentNode::capacity(Inode::POINTER_AREA_SIZE);
+        let node_capacity = ExtentNode::capacity(self.block_size as usize);
+
+        let mut entries: Vec<ExtentEntry> = Extent::from_blocks(blocks)
+            .into_iter()
+            .map(ExtentEntry::Leaf)
+            .collect();
+        let mut depth = 0;
+        let mut goal = goal;
+
+        while entries.len() > root_capacity {
+            let mut parents = Vec::new();
+            for chunk in entries.chunks(node_capacity) {
+                let block = self.allocate_block_near(goal)?;
+                goal = block + 1;
+
+                let node = ExtentNode { depth, entries: chunk.to_vec() };
+                self.write_block(block, &node.to_bytes(self.block_size as usize)?, BlockClass::Metadata)?;
+
+                let logical = match chunk[0] {
+                    ExtentEntry::Leaf(extent) => extent.logical,
+                    ExtentEntry::Index { logical, .. } => logical,
+                };
+                parents.push(ExtentEntry::Index { logical, child: block });
+            }
+            entries = parents;
+            depth += 1;
+        }
+
+        let root = ExtentNode { depth, entries };
+        inode.set_pointer_area(&root.to_bytes(Inode::POINTER_AREA_SIZE)?);
+        Ok(())
+    }
+
+    /// Upper bound on the mapping blocks needed to map `count` data blocks
+    /// 
+    /// For extent-mapped inodes this assumes the worst case of one extent
+    /// per block.
+    pub(crate) fn mapping_overhead(&self, inode: &Inode, count: u64) -> u64 {
+        if inode.has_flag(Inode::FLAG_EXTENTS) {
+            let root_capacity = ExtentNode::capacity(Inode::POINTER_AREA_SIZE) as u64;
+            let node_capacity = ExtentNode::capacity(self.block_size as usize) as u64;
+            let mut entries = count;
+            let mut blocks = 0;
+            while entries > root_capacity {
+                entries = entries.div_ceil(node_capacity);
+                blocks += entries;
+            }
+            return blocks;
+        }
+
+        let per_block = self.pointers_per_block();
+        let mut remaining = count.saturating_sub(DIRECT_POINTERS as u64);
+        let mut blocks = 0;
+        for depth in 1..=3 {
+            if remaining == 0 {
+                break;
+            }
+            let covered = remaining.min(per_block.pow(depth));
+            // One pointer block per level for each group of pointers below it
+            for level in 0..depth {
+                blocks += covered.div_ceil(per_block.pow(level + 1));
+            }
+            remaining -= covered;
+        }
+        blocks
+    }
+
+    /// Free an inode's data blocks and mapping metadata
+    pub(crate) fn release_file_blocks(&self, inode: &mut Inode) -> FsResult<()> {
+        let (data, metadata) = self.walk_mapping(inode)?;
+        for block in data.into_iter().filter(|&b| b != HOLE).chain(metadata) {
+            self.free_block(block)?;
+        }
+
+        inode.block_count = 0;
+        self.map_file_blocks(inode, &[], 0)
+    }
+
+    /// Data and mapping blocks of `inode`, counting shared blocks in full
+    pub(crate) fn held_blocks(&self, inode: &Inode) -> FsResult<u64> {
+        let (data, metadata) = self.walk_mapping(inode)?;
+        Ok(data.iter().filter(|&&b| b != HOLE).count() as u64 + metadata.len() as u64)
+    }
+
+    /// Whether `size` bytes of data of `inode` are stored in the inode
+    /// itself rather than in blocks
+    fn fits_inline(&self, inode: &Inode, size: u64) -> bool {
+        size <= INLINE_DATA_SIZE as u64 && !inode.is_clustered() && self.has_feature(Superblock::FEATURE_INLINE_DATA)
+    }
+
+    /// Move the data of an inline file or symlink to a block, for a write
+    /// that takes it past what the inode holds
+    fn move_inline_data(&self, inode_block: u64, inode: &mut Inode) -> FsResult<()> {
+        let mut contents = inode.inline_data().to_vec();
+        let blocks = match contents.is_empty() {
+            true => Vec::new(),
+            false => {
+                let block = self.allocate_block_near(self.data_goal(inode_block))?;
+                contents.resize(self.block_size as usize, 0);
+                self.write_block(block, &contents, BlockClass::Data)?;
+                vec![block]
+            }
+        };
+        let goal = blocks.last().map_or(0, |&b| b + 1);
+        self.map_file_blocks(inode, &blocks, goal)?;
+        self.write_inode(inode_block, inode)
+    }
+
+    /// Copy the contents of one block to another
+    /// 
+    /// The checksum is copied along unchecked, so a corrupt block stays
+    /// detectable at its new place, and so is the seal of an encrypted
+    /// block.
+    pub(crate) fn copy_block(&self, from: u64, to: u64) -> FsResult<()> {
+        self.preserve(to, 1)?;
+        let mut buffer = vec![0u8; self.block_size as usize];
+        self.device.read_exact_at(&mut buffer, from * self.block_size)?;
+        self.device.write_all_at(&buffer, to * self.block_size)?;
+        if let Some(table) = &self.checksums {
+            table.store(self.device.as_ref(), to, table.load(self.device.as_ref(), from)?)?;
+            if table.has_seals() {
+                table.store_seal(self.device.as_ref(), to, &table.load_seal(self.device.as_ref(), from)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The checksum table of an encrypted image, which keeps the seals
+    fn seals(&self) -> FsResult<&ChecksumTable> {
+        self.checksums
+            .as_ref()
+            .filter(|table| table.has_seals())
+            .ok_or_else(|| FsError::CorruptedFileSystem("Encrypted file on an unencrypted image".to_string()))
+    }
+
+    /// Nonce and tag of encrypted `block`
+    pub(crate) fn block_seal(&self, block: u64) -> FsResult<[u8; SEAL_SIZE]> {
+        self.seals()?.load_seal(self.device.as_ref(), block)
+    }
+
+    /// Record the nonce and tag of encrypted `block`, after writing it
+    pub(crate) fn set_block_seal(&self, block: u64, seal: &[u8; SEAL_SIZE]) -> FsResult<()> {
+        self.seals()?.store_seal(self.device.as_ref(), block, seal)
+    }
+
+    /// Give a file its own copy of `blocks[logical]` if the block is
+    /// shared with a clone
+    /// 
+    /// The copy replaces the block in `blocks` and the file's reference
+    /// to the old one is dropped. Returns whether the block was replaced,
+    /// in which case the caller maps the file again.
+    fn unshare_block(&self, blocks: &mut [u64], logical: usize) -> FsResult<bool> {
+        let Some(&block) = blocks.get(logical).filter(|&&b| b != HOLE && self.is_block_shared(b)) else {
+            return Ok(false);
+        };
+        let copy = self.allocate_block_near(block)?;
+        self.copy_block(block, copy)?;
+        self.free_block(block)?;
+        blocks[logical] = copy;
+        Ok(true)
+    }
+
+    /// Read the pointers stored in an indirect block
+    fn read_pointer_block(&self, block: u64) -> FsResult<Vec<u64>> {
+        let buffer = self.read_block(block, BlockClass::Metadata)?;
+        Ok(buffer
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect())
+    }
+
+    /// Write pointers to an indirect block, zero-filling the rest
+    fn write_pointer_block(&self, block: u64, pointers: &[u64]) -> FsResult<()> {
+        let mut buffer = vec![0u8; self.block_size as usize];
+        for (i, pointer) in pointers.iter().enumerate() {
+            buffer[i * 8..i * 8 + 8].copy_from_slice(&pointer.to_le_bytes());
+        }
+        self.write_block(block, &buffer, BlockClass::Metadata)
+    }
+
+    // ==================== BLOCK ALLOCATION ====================
+
+    /// Allocate a single free block
+    pub fn allocate_block(&self) -> FsResult<u64> {
+        let mut space = self.space();
+        let block = space.charged(1, |space| space.allocator.allocate_block())?;
+        space.allocated(block, 1);
+        self.save_space(&mut space)?;
+        Ok(block)
+    }
+
+    /// Allocate a single free block at or after `goal`, wrapping around
+    pub fn allocate_block_near(&self, goal: u64) -> FsResult<u64> {
+        let mut space = self.space();
+        let block = space.charged(1, |space| space.allocator.allocate_block_near(goal))?;
+        space.allocated(block, 1);
+        self.save_space(&mut space)?;
+        Ok(block)
+    }
+
+    /// Allocate `count` blocks one at a time, each after the previous one
+    /// 
+    /// Blocks already allocated are released again if the disk fills up.
+    fn allocate_blocks(&self, goal: u64, count: u64) -> FsResult<Vec<u64>> {
+        let mut blocks = Vec::with_capacity(count as usize);
+        let mut goal = goal;
+        while (blocks.len() as u64) < count {
+            match self.allocate_block_near(goal) {
+                Ok(block) => {
+                    blocks.push(block);
+                    goal = block + 1;
+                }
+                Err(e) => {
+                    for block in blocks {
+                        self.free_block(block)?;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// Allocate `count` blocks in as few contiguous runs as possible
+    /// 
+    /// A single run for the whole request is preferred; if none exists,
+    /// the request is filled run by run starting at `goal`. Blocks already
+    /// allocated are released again if the disk fills up.
+    pub(crate) fn allocate_extents(&self, goal: u64, count: u64) -> FsResult<Vec<u64>> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+
+        if let Ok(start) = self.allocate_contiguous_blocks_near(goal, count) {
+            return Ok((start..start + count).collect());
+        }
+
+        let mut space = self.space();
+        let blocks = space.charged(count, |space| {
+            let mut blocks = Vec::with_capacity(count as usize);
+            let mut goal = goal;
+            while (blocks.len() as u64) < count {
+                let remaining = count - blocks.len() as u64;
+                match space.allocator.allocate_extent_near(goal, remaining) {
+                    Ok((start, length)) => {
+                        space.allocated(start, length);
+                        blocks.extend(start..start + length);
+                        goal = start + length;
+                    }
+                    Err(e) => {
+                        for block in blocks {
+                            space.free_block(block)?;
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            Ok(blocks)
+        });
+        self.save_space(&mut space)?;
+        blocks
+    }
+
+    /// Allocate multiple contiguous blocks
+    pub fn allocate_contiguous_blocks(&self, count: u64) -> FsResult<u64> {
+        self.allocate_contiguous_blocks_near(0, count)
+    }
+
+    /// Allocate multiple contiguous blocks, preferring a run at or after `goal`
+    pub fn allocate_contiguous_blocks_near(&self, goal: u64, count: u64) -> FsResult<u64> {
+        let mut space = self.space();
+        let start = space.charged(count, |space| space.allocator.allocate_contiguous_near(goal, count))?;
+        space.allocated(start, count);
+        self.save_space(&mut space)?;
+        Ok(start)
+    }
+
+    /// Free a previously allocated block
+    /// 
+    /// Inode table blocks cannot be freed here; they are released through
+    /// `delete_file`/`delete_directory`.
+    pub fn free_block(&self, block: u64) -> FsResult<()> {
+        let mut space = self.space();
+        space.free_block(block)?;
+        space.quotas.credit_blocks(1);
+        self.save_space(&mut space)
+    }
+
+    /// Free multiple contiguous blocks
+    pub fn free_blocks(&self, start: u64, count: u64) -> FsResult<()> {
+        for block in start..start + count {
+            self.free_block(block)?;
+        }
+        Ok(())
+    }
+
+    /// Allocate an inode block from a group's inode table for `inode`,
+    /// charging it to the inode's owners
+    pub(crate) fn allocate_inode(&self, group: usize, inode: &Inode) -> FsResult<u64> {
+        let mut space = self.space();
+        let owners = owners(inode);
+        space.quotas.charge_inode(&owners)?;
+        let block = match space.groups.allocate_inode(group, inode.file_type == FileType::Directory) {
+            Ok(block) => block,
+            Err(e) => {
+                space.quotas.credit_inode(&owners);
+                return Err(e);
+            }
+        };
+        self.save_space(&mut space)?;
+        Ok(block)
+    }
+
+    /// Release the inode block of `inode` back to its inode table
+    fn free_inode(&self, inode_block: u64, inode: &Inode) -> FsResult<()> {
+        let mut space = self.space();
+        space.groups.free_inode(inode_block, inode.file_type == FileType::Directory)?;
+        space.quotas.credit_inode(&owners(inode));
+        self.save_space(&mut space)
+    }
+
+    /// Check if an inode block is allocated
+    pub(crate) fn is_inode_used(&self, inode_block: u64) -> bool {
+        let space = self.space();
+        space.groups.is_inode_block(inode_block) && space.groups.is_inode_used(inode_block)
+    }
+
+    /// Check if a block is currently in use
+    pub fn is_block_used(&self, block: u64) -> bool {
+        self.space().allocator.is_block_used(block)
+    }
+
+    /// Get the total number of blocks in the file system
+    pub fn total_blocks(&self) -> u64 {
+        self.space().allocator.total_blocks()
+    }
+
+    /// Get the number of free blocks available
+    pub fn free_blocks_count(&self) -> u64 {
+        self.space().allocator.count_free_blocks()
+    }
+
+    /// Get the number of used blocks
+    pub fn used_blocks_count(&self) -> u64 {
+        self.space().allocator.count_used_blocks()
+    }
+
+    /// Get disk utilization as a percentage (0.0 to 100.0)
+    pub fn utilization(&self) -> f64 {
+        self.space().allocator.utilization()
+    }
+
+    /// Add a reference to each mapped block in `blocks`, for a clone
+    /// 
+    /// The references are charged to the quotas of the operation in
+    /// progress like new blocks.
+    pub(crate) fn share_blocks(&self, blocks: &[u64]) -> FsResult<()> {
+        let mut space = self.space();
+        space.quotas.charge_blocks(blocks.iter().filter(|&&b| b != HOLE).count() as u64)?;
+        for &block in blocks.iter().filter(|&&b| b != HOLE) {
+            space.refcounts.add(block);
+        }
+        self.save_space(&mut space)
+    }
+
+    /// Find a block recorded with `hash` other than `except` that holds
+    /// exactly `contents`, and take a reference to it
+    /// 
+    /// Writes in place to data blocks are held off while the candidate
+    /// is compared, so it cannot change before the new reference makes
+    /// writers copy it first. The reference is only written back with
+    /// the next sync, which the caller does before mapping the block.
+    pub(crate) fn share_duplicate(&self, hash: u64, contents: &[u8], except: u64) -> FsResult<Option<u64>> {
+        let _exclusive = self.in_place.write().unwrap_or_else(PoisonError::into_inner);
+        let mut space = self.space();
+        let candidate = space.dedup.lookup(hash).filter(|&b| b != except && space.allocator.is_block_used(b));
+        let Some(block) = candidate else {
+            return Ok(None);
+        };
+        match self.read_block(block, BlockClass::Data) {
+            Ok(candidate) if candidate == contents => {}
+            Ok(_) | Err(FsError::ChecksumMismatch { .. }) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        space.refcounts.add(block);
+        Ok(Some(block))
+    }
+
+    /// Hold off `share_duplicate` while writing data blocks in place
+    fn writing_in_place(&self) -> RwLockReadGuard<'_, ()> {
+        self.in_place.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Hold off other threads changing versions or the trash
+    pub(crate) fn lock_history(&self) -> HistoryGuard<'_> {
+        self.history.lock()
+    }
+
+    /// Record that `block` holds data with `hash`, for dedup
+    /// 
+    /// The table is written back with the next sync; losing an entry
+    /// only loses a chance to share.
+    pub(crate) fn record_block_hash(&self, hash: u64, block: u64) {
+        self.space().dedup.insert(hash, block);
+    }
+
+    /// Drop the whole dedup table
+    pub(crate) fn clear_dedup_table(&self) {
+        self.space().dedup.clear();
+    }
+
+    /// Number of blocks in the dedup table
+    pub(crate) fn dedup_entries(&self) -> u64 {
+        self.space().dedup.len() as u64
+    }
+
+    /// Blocks holding the dedup table
+    pub(crate) fn dedup_table_blocks(&self) -> Vec<u64> {
+        self.space().dedup.store().to_vec()
+    }
+
+    /// Check whether a block is shared between files by cloning
+    pub fn is_block_shared(&self, block: u64) -> bool {
+        self.space().refcounts.is_shared(block)
+    }
+
+    /// Number of references to a block in use
+    pub(crate) fn block_references(&self, block: u64) -> u64 {
+        self.space().refcounts.references(block)
+    }
+
+    /// Blocks shared by cloned files, with their reference counts
+    pub(crate) fn shared_blocks(&self) -> Vec<(u64, u64)> {
+        self.space().refcounts.shared().collect()
+    }
+
+    /// Blocks holding the reference count table
+    pub(crate) fn refcount_table_blocks(&self) -> Vec<u64> {
+        self.space().refcounts.store().to_vec()
+    }
+
+    /// How much space cloned files share
+    pub fn sharing_stats(&self) -> SharingStats {
+        let space = self.space();
+        let mut stats = SharingStats::default();
+        for (_, refs) in space.refcounts.shared() {
+            stats.shared_blocks += 1;
+            stats.saved_blocks += refs - 1;
+        }
+        stats
+    }
+
+    /// The block allocator, for read-only inspection
+    pub(crate) fn allocator(&mut self) -> &dyn Allocator {
+        self.space_mut().allocator.as_ref()
+    }
+
+    /// The block groups and the allocator together, for read-only inspection
+    pub(crate) fn groups_and_allocator(&mut self) -> (&BlockGroups, &dyn Allocator) {
+        let space = self.space_mut();
+        (&space.groups, space.allocator.as_ref())
+    }
+
+    /// Get the active block allocation strategy
+    pub fn allocator_kind(&self) -> AllocatorKind {
+        self.space().allocator.kind()
+    }
+
+    /// Get internal and external fragmentation of the block space
+    pub fn allocation_stats(&self) -> AllocationStats {
+        self.space().allocator.stats()
+    }
+
+    /// Save the current bitmap state to disk
+    pub fn sync_bitmap(&self) -> FsResult<()> {
+        self.save_space(&mut self.space())
+    }
+
+    /// Save allocator state and flush the image to stable storage
+    pub fn sync(&self) -> FsResult<()> {
+        self.sync_bitmap()?;
+        self.device.sync()?;
+        Ok(())
+    }
+
+    // ==================== BLOCK I/O ====================
+
+    /// The checksum table, if blocks of `class` are checksummed
+    fn checksums_for(&self, class: BlockClass) -> Option<&ChecksumTable> {
+        self.checksums.as_ref().filter(|table| table.covers(class))
+    }
+
+    /// Whether the image keeps checksums of blocks of `class`
+    pub fn has_checksums(&self, class: BlockClass) -> bool {
+        self.checksums_for(class).is_some()
+    }
+
+    /// Read a whole block, verifying its checksum
+    pub(crate) fn read_block(&self, block: u64, class: BlockClass) -> FsResult<Vec<u8>> {
+        let mut buffer = vec![0u8; self.block_size as usize];
+        self.device.read_exact_at(&mut buffer, block * self.block_size)?;
+        if let Some(table) = self.checksums_for(class) {
+            table.verify(self.device.as_ref(), block, &buffer)?;
+        }
+        Ok(buffer)
+    }
+
+    /// Copy blocks `start..start + count`, and the checksum table blocks
+    /// holding their checksums, aside for the snapshots that share them
+    /// before they are overwritten
+    fn preserve(&self, start: u64, count: u64) -> FsResult<()> {
+        if !self.has_snapshots {
+            return Ok(());
+        }
+        let mut blocks: Vec<u64> = (start..start + count).collect();
+        if let Some(table) = &self.checksums {
+            blocks.extend((start..start + count).map(|b| table.entry_block(b, self.block_size)));
+        }
+        let mut space = self.space();
+        if space.preserve(self.device.as_ref(), self.block_size, blocks)? {
+            self.save_space(&mut space)?;
+        }
+        Ok(())
+    }
+
+    /// Write `data` to the start of a block
+    /// 
+    /// With checksums the rest of the block is zeroed, so that the whole
+    /// block matches the new checksum; without, it is left alone.
+    pub(crate) fn write_block(&self, block: u64, data: &[u8], class: BlockClass) -> FsResult<()> {
+        self.preserve(block, 1)?;
+        match self.checksums_for(class) {
+            Some(table) => {
+                let mut buffer = vec![0u8; self.block_size as usize];
+                buffer[..data.len()].copy_from_slice(data);
+                self.device.write_all_at(&buffer, block * self.block_size)?;
+                table.update(self.device.as_ref(), block, &buffer)
+            }
+            None => Ok(self.device.write_all_at(data, block * self.block_size)?),
+        }
+    }
+
+    /// Read `buf.len()` bytes from `within` bytes into a block
+    /// 
+    /// With checksums the whole block is read to verify it.
+    fn read_partial(&self, block: u64, within: u64, buf: &mut [u8], class: BlockClass) -> FsResult<()> {
+        if self.checksums_for(class).is_none() {
+            return Ok(self.device.read_exact_at(buf, block * self.block_size + within)?);
+        }
+        let contents = self.read_block(block, class)?;
+        let start = within as usize;
+        buf.copy_from_slice(&contents[start..start + buf.len()]);
+        Ok(())
+    }
+
+    /// Overwrite part of a block from `within` bytes into it
+    /// 
+    /// With checksums the block is read and verified, patched, and
+    /// written back whole with its new checksum. A block written whole is
+    /// not read, as it may not hold anything valid yet.
+    fn write_partial(&self, block: u64, within: u64, data: &[u8], class: BlockClass) -> FsResult<()> {
+        self.preserve(block, 1)?;
+        let Some(table) = self.checksums_for(class) else {
+            return Ok(self.device.write_all_at(data, block * self.block_size + within)?);
+        };
+        if data.len() as u64 == self.block_size {
+            self.device.write_all_at(data, block * self.block_size)?;
+            return table.update(self.device.as_ref(), block, data);
+        }
+        let mut contents = self.read_block(block, class)?;
+        let start = within as usize;
+        contents[start..start + data.len()].copy_from_slice(data);
+        self.device.write_all_at(&contents, block * self.block_size)?;
+        table.update(self.device.as_ref(), block, &contents)
+    }
+
+    /// Read consecutive blocks from `start` into `buf`, which may end
+    /// part way into the last block
+    pub(crate) fn read_run(&self, start: u64, buf: &mut [u8], class: BlockClass) -> FsResult<()> {
+        let Some(table) = self.checksums_for(class) else {
+            return Ok(self.device.read_exact_at(buf, start * self.block_size)?);
+        };
+        let block_size = self.block_size as usize;
+        let whole = buf.len() / block_size * block_size;
+        self.device.read_exact_at(&mut buf[..whole], start * self.block_size)?;
+        for (i, contents) in buf[..whole].chunks_exact(block_size).enumerate() {
+            table.verify(self.device.as_ref(), start + i as u64, contents)?;
+        }
+        if whole < buf.len() {
+            let last = start + (whole / block_size) as u64;
+            self.read_partial(last, 0, &mut buf[whole..], class)?;
+        }
+        Ok(())
+    }
+
+    /// Write `data` to consecutive blocks from `start`
+    /// 
+    /// With checksums a partly covered last block is zero-filled.
+    pub(crate) fn write_run(&self, start: u64, data: &[u8], class: BlockClass) -> FsResult<()> {
+        self.preserve(start, (data.len() as u64).div_ceil(self.block_size))?;
+        let Some(table) = self.checksums_for(class) else {
+            return Ok(self.device.write_all_at(data, start * self.block_size)?);
+        };
+        let block_size = self.block_size as usize;
+        let whole = data.len() / block_size * block_size;
+        self.device.write_all_at(&data[..whole], start * self.block_size)?;
+        for (i, contents) in data[..whole].chunks_exact(block_size).enumerate() {
+            table.update(self.device.as_ref(), start + i as u64, contents)?;
+        }
+        if whole < data.len() {
+            let last = start + (whole / block_size) as u64;
+            self.write_block(last, &data[whole..], class)?;
+        }
+        Ok(())
+    }
+
+    /// Check a block against its checksum, if blocks of `class` have one
+    pub fn verify_block(&self, block: u64, class: BlockClass) -> FsResult<()> {
+        if self.checksums_for(class).is_some() {
+            self.read_block(block, class)?;
+        }
+        Ok(())
+    }
+
+    // ==================== RAW IMAGE ACCESS ====================
+
+    /// Size of the image in bytes
+    pub fn image_size(&self) -> u64 {
+        self.total_blocks() * self.block_size
+    }
+
+    /// Read raw image bytes at `offset`, bypassing the file system
+    pub fn read_raw(&self, offset: u64, buf: &mut [u8]) -> FsResult<()> {
+        self.check_raw_range(offset, buf.len())?;
+        self.device.read_exact_at(buf, offset)?;
+        Ok(())
+    }
+
+    /// Write raw image bytes at `offset`, bypassing the file system
+    /// 
+    /// Nothing in memory is updated, so writes over metadata are only
+    /// seen after the image is reopened. Checksums are not updated either,
+    /// so changed blocks fail verification afterwards, and snapshots see
+    /// the change too.
+    pub fn write_raw(&self, offset: u64, data: &[u8]) -> FsResult<()> {
+        self.check_raw_range(offset, data.len())?;
+        self.device.write_all_at(data, offset)?;
+        Ok(())
+    }
+
+    fn check_raw_range(&self, offset: u64, len: usize) -> FsResult<()> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.image_size() => Ok(()),
+            _ => Err(FsError::InvalidOffsetOrSize { offset, size: len as u64 }),
+        }
+    }
+
+    // ==================== SNAPSHOTS ====================
+
+    /// Take a named snapshot of the whole file system
+    /// 
+    /// Nothing is copied up front: the snapshot shares every block in use
+    /// with the live file system, and a block is only copied the first
+    /// time it is overwritten afterwards. Blocks the live file system
+    /// frees stay allocated while a snapshot still needs them, so a
+    /// snapshot takes about as much space as what changed since.
+    pub fn snapshot_create(&mut self, name: &str) -> FsResult<SnapshotInfo> {
+        self.sync_bitmap()?;
+        let created = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let space = self.space_mut();
+        let own = space.snapshots.own_blocks();
+        let total_blocks = space.allocator.total_blocks();
+        let mut frozen = BlockSet::new(total_blocks);
+        for block in 0..total_blocks {
+            if space.allocator.is_block_used(block) && !own.contains(&block) {
+                frozen.insert(block);
+            }
+        }
+        let info = space.snapshots.create(name, created, frozen)?;
+        self.has_snapshots = true;
+        self.sync_bitmap()?;
+        Ok(info)
+    }
+
+    /// Snapshots of the file system, oldest first
+    pub fn snapshot_list(&self) -> Vec<SnapshotInfo> {
+        self.space().snapshots.info()
+    }
+
+    /// Delete a snapshot, freeing the blocks only it was keeping
+    pub fn snapshot_delete(&mut self, name: &str) -> FsResult<()> {
+        let space = self.space_mut();
+        let index = space.snapshots.find(name)?;
+        for block in space.snapshots.remove(index) {
+            space.release(block);
+        }
+        self.has_snapshots = !space.snapshots.is_empty();
+        self.sync_bitmap()
+    }
+
+    /// Return the live file system to the state of a snapshot
+    /// 
+    /// The blocks overwritten since the snapshot was taken are copied
+    /// back, which restores the allocation state along with everything
+    /// else, so all changes made since are lost. The snapshot is kept.
+    /// Other snapshots are unaffected: blocks they share are preserved
+    /// before they are written back to.
+    pub fn snapshot_rollback(&mut self, name: &str) -> FsResult<()> {
+        let block_size = self.block_size;
+        let device = self.device.as_ref();
+        let checksums = self.checksums.as_ref();
+        let space = self.space.get_mut().unwrap_or_else(PoisonError::into_inner);
+        let index = space.snapshots.find(name)?;
+        let remap = space.snapshots.view(index).1.clone();
+
+        space.preserve(device, block_size, remap.keys().copied())?;
+        space.save(device, block_size, checksums)?;
+        let mut buffer = vec![0u8; block_size as usize];
+        for (&block, &copy) in &remap {
+            device.read_exact_at(&mut buffer, copy * block_size)?;
+            device.write_all_at(&buffer, block * block_size)?;
+        }
+
+        // The allocation state on disk is now the snapshot's; bring the
+        // blocks of the snapshots themselves up to date in it
+        space.snapshots.rolled_back(index);
+        space.groups = BlockGroups::load(device)?;
+        let superblock = space.groups.superblock();
+        let kind = AllocatorKind::from_u64(superblock.allocator)?;
+        space.allocator = kind.load(device, superblock.total_blocks, block_size)?;
+        space.refcounts = RefCounts::load(device, block_size, superblock.total_blocks, superblock.refcount_table)?;
+        space.dedup = DedupTable::load(device, block_size, superblock.total_blocks, superblock.dedup_table)?;
+        let frozen = space.snapshots.view(index).0.clone();
+        let own = space.snapshots.own_blocks();
+        for block in 0..space.allocator.total_blocks() {
+            let wanted = frozen.contains(block) || own.contains(&block);
+            match (space.allocator.is_block_used(block), wanted) {
+                (false, true) => {
+                    space.allocator.reserve(block, 1);
+                    space.allocated(block, 1);
+                }
+                (true, false) => space.release(block),
+                _ => {}
+            }
+        }
+        space.save(device, block_size, checksums)?;
+
+        // Usage is counted again from the inodes rolled back
+        space.quotas = Quotas::default();
+        self.load_quotas()
+    }
+
+    /// Open a snapshot of the image at `path`, read-only
+    /// 
+    /// The live image must not be changed while the snapshot is open.
+    pub fn open_snapshot(path: &str, name: &str) -> FsResult<VirtualDisk> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        Self::open_snapshot_device(Box::new(file), name)
+    }
+
+    /// Open a snapshot of the file system on `device`, read-only
+    /// 
+    /// Anything that would write to the disk fails with an I/O error of
+    /// kind `ReadOnlyFilesystem`.
+    pub fn open_snapshot_device(device: Box<dyn BlockDevice>, name: &str) -> FsResult<VirtualDisk> {
+        let groups = BlockGroups::load(device.as_ref())?;
+        let superblock = groups.superblock();
+        let (block_size, total_blocks) = (superblock.block_size, superblock.total_blocks);
+        let snapshots = Snapshots::load(device.as_ref(), block_size, total_blocks, superblock.snapshot_table)?;
+        let (frozen, remap) = snapshots.view(snapshots.find(name)?);
+        let frozen = frozen.clone();
+        let view = SnapshotDevice::new(device, block_size, remap.clone());
+        let mut disk = Self::load(Box::new(view), false)?;
+
+        // Blocks that snapshots used themselves at the time are not part
+        // of this one
+        let space = disk.space_mut();
+        for block in 0..total_blocks {
+            if space.allocator.is_block_used(block) && !frozen.contains(block) {
+                space.release(block);
+            }
+        }
+        Ok(disk)
+    }
+
+    /// Blocks used by the snapshots themselves: the snapshot table,
+    /// copies of overwritten blocks, and freed blocks kept for snapshots
+    pub(crate) fn snapshot_blocks(&self) -> BTreeSet<u64> {
+        self.space().snapshots.own_blocks()
+    }
+
+    // ==================== BLOCK GROUPS ====================
+
+    /// Get the block groups, including the superblock and descriptors
+    pub fn block_groups(&mut self) -> &BlockGroups {
+        &self.space_mut().groups
+    }
+
+    /// Get the block groups to change settings kept in the superblock,
+    /// which are written back on the next sync
+    pub(crate) fn block_groups_mut(&mut self) -> &mut BlockGroups {
+        &mut self.space_mut().groups
+    }
+
+    /// Block group holding `block`
+    pub(crate) fn group_of(&self, block: u64) -> usize {
+        self.space().groups.group_of(block)
+    }
+
+    /// Where data for the file at `inode_block` is best allocated
+    pub(crate) fn data_goal(&self, inode_block: u64) -> u64 {
+        self.space().groups.data_goal(inode_block)
+    }
+
+    /// A copy of the superblock, for passes that only have `&self`
+    pub(crate) fn has_feature(&self, feature: u64) -> bool {
+        self.space().groups.superblock().has_feature(feature)
+    }
+
+    pub(crate) fn superblock(&self) -> Superblock {
+        self.space().groups.superblock().clone()
+    }
+
+    /// Read a block of the superblock or bitmaps, verifying its checksum
+    /// 
+    /// They are written back while the allocation state is locked, so
+    /// this holds the lock to not see one half written.
+    pub(crate) fn read_layout_block(&self, block: u64) -> FsResult<Vec<u8>> {
+        let _space = self.space();
+        self.read_block(block, BlockClass::Metadata)
+    }
+
+    /// Inode blocks in use, for passes that only have `&self`
+    pub(crate) fn used_inodes(&self) -> Vec<u64> {
+        self.space().groups.used_inodes()
+    }
+
+    /// Measure how well file data is kept together and next to its inode
+    /// 
+    /// Walks every allocated inode and counts contiguous runs of data
+    /// blocks, data blocks outside the inode's group, and the distance
+    /// from each inode to its first data block.
+    pub fn fragmentation_stats(&self) -> FsResult<FragmentationStats> {
+        let mut stats = FragmentationStats::default();
+
+        for inode_block in self.used_inodes() {
+            let inode = self.read_inode(inode_block)?;
+            let mut blocks = self.file_blocks(&inode)?;
+            blocks.retain(|&b| b != HOLE);
+            stats.inodes += 1;
+
+            if blocks.is_empty() {
+                continue;
+            }
+
+            let space = self.space();
+            let inode_group = space.groups.group_of(inode_block);
+            let runs = 1 + blocks.windows(2).filter(|w| w[1] != w[0] + 1).count() as u64;
+
+            stats.inodes_with_data += 1;
+            stats.data_blocks += blocks.len() as u64;
+            stats.fragments += runs;
+            if runs > 1 {
+                stats.fragmented_inodes += 1;
+            }
+            stats.off_group_blocks += blocks
+                .iter()
+                .filter(|&&b| space.groups.group_of(b) != inode_group)
+                .count() as u64;
+            stats.total_inode_distance += blocks[0].abs_diff(inode_block);
+        }
+
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::crc32c;
+
+    /// An image holding `/docs/report.txt` over two data blocks, with
+    /// nothing stored inline and data checksums unless `metadata_only`
+    fn checksummed_disk(name: &str, metadata_only: bool) -> (VirtualDisk, String) {
+        let path = std::env::temp_dir().join(format!("fssim-unit-{}-{}.img", name, std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let options = FormatOptions {
+            size: 8 * 1024 * 1024,
+            checksums: true,
+            data_checksums: !metadata_only,
+            inline_data: false,
+            ..FormatOptions::default()
+        };
+        let disk = VirtualDisk::format(&path, options).unwrap();
+        disk.initialize_root_dir().unwrap();
+        disk.create_directory_at("/docs", Permissions::new(true, true, true)).unwrap();
+        disk.write_file_at("/docs/report.txt", &[0x5A; 6000]).unwrap();
+        (disk, path)
+    }
+
+    /// Flip a byte of `block` behind the disk's back; returns the error
+    /// reading it must give
+    fn corrupt(disk: &VirtualDisk, block: u64) -> FsError {
+        let mut contents = vec![0u8; disk.block_size() as usize];
+        disk.read_raw(block * disk.block_size(), &mut contents).unwrap();
+        let expected = crc32c(&contents);
+        contents[100] ^= 0x01;
+        disk.write_raw(block * disk.block_size(), &contents).unwrap();
+        FsError::ChecksumMismatch { block, expected, actual: crc32c(&contents) }
+    }
+
+    fn assert_mismatch<T: std::fmt::Debug>(result: FsResult<T>, expected: &FsError) {
+        let FsError::ChecksumMismatch { block, expected, actual } = *expected else {
+            unreachable!()
+        };
+        match result {
+            Err(FsError::ChecksumMismatch { block: b, expected: e, actual: a }) => {
+                assert_eq!((b, e, a), (block, expected, actual));
+            }
+            other => panic!("expected a checksum mismatch in block {}, got {:?}", block, other),
+        }
+    }
+
+    #[test]
+    fn corrupted_inode_fails_verification() {
+        let (disk, path) = checksummed_disk("verify-inode", false);
+        let inode_block = disk.lookup_path("/docs/report.txt").unwrap();
+        let error = corrupt(&disk, inode_block);
+
+        assert_mismatch(disk.verify_block(inode_block, BlockClass::Metadata), &error);
+        assert_mismatch(disk.read_inode(inode_block), &error);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn corrupted_directory_block_fails_verification() {
+        let (disk, path) = checksummed_disk("verify-directory", false);
+        let dir = disk.lookup_path("/docs").unwrap();
+        let entries = disk.file_blocks(&disk.read_inode(dir).unwrap()).unwrap()[0];
+        let error = corrupt(&disk, entries);
+
+        assert_mismatch(disk.verify_block(entries, BlockClass::Metadata), &error);
+        assert_mismatch(disk.list_directory(dir), &error);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn corrupted_bitmap_fails_verification() {
+        let (mut disk, path) = checksummed_disk("verify-bitmap", false);
+        let bitmap = disk.block_groups().superblock().block_bitmap_start;
+        let error = corrupt(&disk, bitmap);
+
+        assert_mismatch(disk.verify_block(bitmap, BlockClass::Metadata), &error);
+        drop(disk);
+        assert_mismatch(VirtualDisk::new(&path), &error);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn corrupted_data_block_fails_verification() {
+        let (disk, path) = checksummed_disk("verify-data", false);
+        let file = disk.lookup_path("/docs/report.txt").unwrap();
+        let data = disk.file_blocks(&disk.read_inode(file).unwrap()).unwrap()[1];
+        let error = corrupt(&disk, data);
+
+        assert_mismatch(disk.verify_block(data, BlockClass::Data), &error);
+        assert_mismatch(disk.read_file(file), &error);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn data_without_checksums_is_not_verified() {
+        let (disk, path) = checksummed_disk("verify-metadata-only", true);
+        let file = disk.lookup_path("/docs/report.txt").unwrap();
+        let data = disk.file_blocks(&disk.read_inode(file).unwrap()).unwrap()[0];
+        corrupt(&disk, data);
+
+        disk.verify_block(data, BlockClass::Data).unwrap();
+        assert_eq!(disk.read_file(file).unwrap()[100], 0x5B);
+        let _ = std::fs::remove_file(path);
     }
 }
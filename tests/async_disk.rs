@@ -0,0 +1,161 @@
+#![cfg(feature = "async")]
+
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    async_disk::{AsyncBlockDevice, AsyncDisk, BlockingDevice},
+    error::FsResult,
+    shared::SharedDisk,
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+use std::future::Future;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+const BLOCK: usize = 4096;
+
+/// Runs operations on the blocking pool like `BlockingDevice`, but never
+/// finishes on the first poll, and counts the operations still running
+struct Yielding {
+    inner: BlockingDevice,
+    running: Arc<AtomicUsize>,
+}
+
+impl Yielding {
+    /// Wait until every operation started has finished
+    fn settle(&self) {
+        while self.running.load(Ordering::SeqCst) > 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+impl AsyncBlockDevice for Yielding {
+    fn run<T, F>(&self, op: F) -> impl Future<Output = FsResult<T>> + Send
+    where
+        F: FnOnce(&SharedDisk) -> FsResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let running = Arc::clone(&self.running);
+        running.fetch_add(1, Ordering::SeqCst);
+        let task = self.inner.run(move |disk| {
+            let result = op(disk);
+            running.fetch_sub(1, Ordering::SeqCst);
+            result
+        });
+        async move {
+            tokio::task::yield_now().await;
+            task.await
+        }
+    }
+}
+
+fn setup(image: &TempImage) -> AsyncDisk<Yielding> {
+    let options = FormatOptions {
+        size: 8 * 1024 * 1024,
+        ..FormatOptions::default()
+    };
+    let disk = VirtualDisk::format(image.path(), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+    let device = Yielding { inner: BlockingDevice::new(SharedDisk::new(disk)), running: Arc::default() };
+    AsyncDisk::with_device(device)
+}
+
+fn pattern(size: usize, seed: u8) -> Vec<u8> {
+    (0..size).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+/// Poll `future` once; true if it finished
+async fn poll_once<F: Future + Unpin>(future: &mut F) -> bool {
+    std::future::poll_fn(|cx| Poll::Ready(Pin::new(&mut *future).poll(cx).is_ready())).await
+}
+
+#[tokio::test]
+async fn dropped_writes_still_complete() {
+    let image = TempImage::new("async-dropped-write");
+    let disk = setup(&image);
+    disk.write_file("/f", &pattern(4 * BLOCK, 1)).await.unwrap();
+
+    // A write dropped after it started is applied in full
+    let data = pattern(3 * BLOCK, 2);
+    let mut write = Box::pin(disk.write_at("/f", 100, &data));
+    assert!(!poll_once(&mut write).await);
+    drop(write);
+    disk.device().settle();
+    let mut expected = pattern(4 * BLOCK, 1);
+    expected[100..100 + data.len()].copy_from_slice(&data);
+    assert_eq!(disk.read_file("/f").await.unwrap(), expected);
+
+    // So is one still in the background when its handle goes
+    let mut file = disk.open("/f").await.unwrap();
+    file.seek(SeekFrom::Start(5)).await.unwrap();
+    assert_eq!(file.write(b"handle").await.unwrap(), 6);
+    drop(file);
+    disk.device().settle();
+    expected[5..11].copy_from_slice(b"handle");
+    assert_eq!(disk.read_file("/f").await.unwrap(), expected);
+}
+
+#[tokio::test]
+async fn seek_after_a_cancelled_read() {
+    let image = TempImage::new("async-cancelled-read");
+    let disk = setup(&image);
+    let data = pattern(2 * BLOCK, 3);
+    disk.write_file("/f", &data).await.unwrap();
+    let mut file = disk.open("/f").await.unwrap();
+
+    // Each kind of seek drops the read started at the old position
+    for (target, position) in [
+        (SeekFrom::Start(1000), 1000),
+        (SeekFrom::Current(500), 1516),
+        (SeekFrom::End(-16), data.len() - 16),
+    ] {
+        let mut buf = [0u8; 16];
+        let mut read = Box::pin(file.read(&mut buf));
+        assert!(!poll_once(&mut read).await);
+        drop(read);
+
+        assert_eq!(file.seek(target).await.unwrap(), position as u64);
+        file.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, data[position..position + 16], "{:?}", target);
+    }
+}
+
+#[tokio::test]
+async fn read_write_seek_round_trip() {
+    let image = TempImage::new("async-round-trip");
+    let disk = setup(&image);
+    let data = pattern(3 * BLOCK + 100, 4);
+
+    let mut file = disk.create("/f").await.unwrap();
+    file.write_all(&data).await.unwrap();
+    assert_eq!(file.position(), data.len() as u64);
+    file.seek(SeekFrom::Start(0)).await.unwrap();
+    let mut read = Vec::new();
+    file.read_to_end(&mut read).await.unwrap();
+    assert_eq!(read, data);
+
+    // Overwrite across a block boundary, then append past the end
+    file.seek(SeekFrom::Start(BLOCK as u64 - 3)).await.unwrap();
+    file.write_all(b"across").await.unwrap();
+    file.seek(SeekFrom::End(0)).await.unwrap();
+    file.write_all(b"tail").await.unwrap();
+    file.seek(SeekFrom::Current(-10)).await.unwrap();
+    let mut end = Vec::new();
+    file.read_to_end(&mut end).await.unwrap();
+    assert_eq!(end, [&data[data.len() - 6..], b"tail"].concat());
+    assert!(file.seek(SeekFrom::Current(-(10 * BLOCK as i64))).await.is_err());
+    file.shutdown().await.unwrap();
+
+    let mut expected = data;
+    expected[BLOCK - 3..BLOCK + 3].copy_from_slice(b"across");
+    expected.extend_from_slice(b"tail");
+    assert_eq!(disk.read_file("/f").await.unwrap(), expected);
+    assert_eq!(disk.metadata("/f").await.unwrap().size, expected.len() as u64);
+}
@@ -0,0 +1,123 @@
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    block_group::FragmentationStats,
+    error::{FsError, FsResult},
+    serialization::Permissions,
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+
+const DIRECTORIES: usize = 6;
+const FILES_PER_DIRECTORY: usize = 8;
+
+fn options() -> FormatOptions {
+    FormatOptions {
+        size: 32 * 1024 * 1024,
+        // `write_flat` maps data with block pointers only
+        inline_data: false,
+        ..FormatOptions::default()
+    }
+}
+
+fn contents(file: usize, blocks: usize) -> Vec<u8> {
+    vec![file as u8; blocks * 4096 - 100]
+}
+
+/// Write `data` the way the disk did before block groups: every block is
+/// taken from wherever the allocator's cursor is, ignoring the inode
+fn write_flat(disk: &VirtualDisk, inode_block: u64, data: &[u8]) -> FsResult<()> {
+    let mut inode = disk.read_inode(inode_block)?;
+    for block in disk.file_blocks(&inode)? {
+        disk.free_block(block)?;
+    }
+    inode.direct_blocks = [0; 12];
+    inode.block_count = 0;
+    for (i, chunk) in data.chunks(disk.block_size() as usize).enumerate() {
+        let block = disk.allocate_block()?;
+        disk.write_raw(block * disk.block_size(), chunk)?;
+        inode.direct_blocks[i] = block;
+        inode.block_count += 1;
+    }
+    inode.size = data.len() as u64;
+    disk.write_inode(inode_block, &inode)
+}
+
+/// Create files spread over several directories, write them round-robin
+/// so their allocations interleave, then rewrite every other one larger
+fn run_workload(image: &TempImage, flat: bool) -> FsResult<FragmentationStats> {
+    let disk = VirtualDisk::format(image.path(), options())?;
+    disk.initialize_root_dir()?;
+    let perms = Permissions::new(true, true, true);
+
+    let mut files = Vec::new();
+    for dir in 0..DIRECTORIES {
+        disk.create_directory_at(&format!("/d{}", dir), perms)?;
+        for file in 0..FILES_PER_DIRECTORY {
+            files.push(disk.create_file_at(&format!("/d{}/f{}", dir, file), perms)?);
+        }
+    }
+
+    let write = |disk: &VirtualDisk, i: usize, blocks: usize| match flat {
+        true => write_flat(disk, files[i], &contents(i, blocks)),
+        false => disk.write_file(files[i], &contents(i, blocks)),
+    };
+    for i in 0..files.len() {
+        let i = (i % DIRECTORIES) * FILES_PER_DIRECTORY + i / DIRECTORIES;
+        write(&disk, i, 3)?;
+    }
+    for i in (0..files.len()).step_by(2) {
+        write(&disk, i, 6)?;
+    }
+
+    for (i, &inode_block) in files.iter().enumerate() {
+        let blocks = if i % 2 == 0 { 6 } else { 3 };
+        assert_eq!(disk.read_file(inode_block)?, contents(i, blocks));
+    }
+    disk.fragmentation_stats()
+}
+
+#[test]
+fn block_groups_keep_data_near_its_inode() {
+    let grouped_image = TempImage::new("locality-grouped");
+    let flat_image = TempImage::new("locality-flat");
+    let grouped = run_workload(&grouped_image, false).unwrap();
+    let flat = run_workload(&flat_image, true).unwrap();
+
+    assert_eq!(grouped.data_blocks, flat.data_blocks);
+    assert!(
+        grouped.off_group_percentage() < flat.off_group_percentage(),
+        "off-group data: grouped {:.1}%, flat {:.1}%",
+        grouped.off_group_percentage(),
+        flat.off_group_percentage()
+    );
+    assert!(
+        grouped.average_inode_distance() < flat.average_inode_distance(),
+        "inode distance: grouped {:.1}, flat {:.1}",
+        grouped.average_inode_distance(),
+        flat.average_inode_distance()
+    );
+}
+
+#[test]
+fn pre_block_group_image_is_not_supported() {
+    let image = TempImage::new("legacy-layout");
+    // A 100 MiB image with no superblock and the old bitmap in block 1,
+    // marking the superblock, the bitmap and a root inode as used
+    let mut data = vec![0u8; 100 * 1024 * 1024];
+    data[4096] = 0b111;
+    std::fs::write(image.path(), &data).unwrap();
+
+    match VirtualDisk::new(image.path()) {
+        Err(FsError::NotSupported(message)) => assert!(message.contains("pre-block-group")),
+        other => panic!("expected NotSupported, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn image_without_superblock_is_corrupted() {
+    let image = TempImage::new("no-superblock");
+    std::fs::write(image.path(), vec![0u8; 8 * 1024 * 1024]).unwrap();
+
+    assert!(matches!(VirtualDisk::new(image.path()), Err(FsError::CorruptedFileSystem(_))));
+}
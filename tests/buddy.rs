@@ -0,0 +1,53 @@
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    allocator::AllocatorKind,
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+
+const BLOCK: usize = 4096;
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+fn assert_clean(disk: &mut VirtualDisk) {
+    let report = disk.fsck().unwrap();
+    assert!(report.is_clean(), "fsck: {:?}", report.issues);
+}
+
+#[test]
+fn images_reopen_with_their_allocator() {
+    for kind in [AllocatorKind::Buddy, AllocatorKind::Bitmap] {
+        let image = TempImage::new(&format!("buddy-reopen-{:?}", kind));
+        let options = FormatOptions {
+            size: 8 * 1024 * 1024,
+            allocator: kind,
+            extents: true,
+            ..FormatOptions::default()
+        };
+        let disk = VirtualDisk::format(image.path(), options).unwrap();
+        disk.initialize_root_dir().unwrap();
+        for i in 0..6u8 {
+            disk.write_file_at(&format!("/f{}", i), &pattern((3 + i as usize * 5) * BLOCK, i)).unwrap();
+        }
+        disk.remove_path("/f2").unwrap();
+        let stats = disk.allocation_stats();
+        assert_eq!(stats.kind, kind);
+        drop(disk);
+
+        // Formatting options are ignored for an existing image
+        let mut disk = VirtualDisk::new(image.path()).unwrap();
+        assert_eq!(disk.allocator_kind(), kind);
+        assert_eq!(disk.allocation_stats(), stats);
+        assert_eq!(disk.read_file_at("/f5").unwrap(), pattern(28 * BLOCK, 5));
+        assert_clean(&mut disk);
+
+        // And allocation carries on from where it was
+        disk.write_file_at("/f2", &pattern(7 * BLOCK, 9)).unwrap();
+        disk.remove_path("/f3").unwrap();
+        assert_eq!(disk.read_file_at("/f2").unwrap(), pattern(7 * BLOCK, 9));
+        assert_clean(&mut disk);
+    }
+}
@@ -0,0 +1,275 @@
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    error::FsError,
+    extent::HOLE,
+    quota::{QuotaKind, QuotaLimits},
+    serialization::Permissions,
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+
+const BLOCK: u64 = 4096;
+
+/// Three whole blocks and part of a fourth
+const SOURCE_SIZE: usize = 3 * BLOCK as usize + 100;
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+fn options() -> FormatOptions {
+    FormatOptions {
+        size: 8 * 1024 * 1024,
+        ..FormatOptions::default()
+    }
+}
+
+/// A fresh image holding `/src`, and an empty `/dst`
+fn setup(image: &TempImage, options: FormatOptions) -> (VirtualDisk, u64, u64) {
+    let disk = VirtualDisk::format(image.path(), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+    let src = disk.write_file_at("/src", &pattern(SOURCE_SIZE, 1)).unwrap();
+    let dst = disk.create_file_at("/dst", Permissions::new(true, true, false)).unwrap();
+    (disk, src, dst)
+}
+
+fn blocks_of(disk: &VirtualDisk, inode_block: u64) -> Vec<u64> {
+    disk.file_blocks(&disk.read_inode(inode_block).unwrap()).unwrap()
+}
+
+fn expect_invalid(result: Result<(), FsError>) {
+    assert!(matches!(result, Err(FsError::InvalidOffsetOrSize { .. })), "{:?}", result);
+}
+
+fn assert_clean(disk: &mut VirtualDisk) {
+    let report = disk.fsck().unwrap();
+    assert!(report.is_clean(), "fsck: {:?}", report.issues);
+}
+
+#[test]
+fn clone_file_shares_blocks_until_written() {
+    let image = TempImage::new("clone-file");
+    let (mut disk, src, dst) = setup(&image, options());
+    disk.write_file(dst, &pattern(2 * BLOCK as usize, 2)).unwrap();
+    let free = disk.free_blocks_count();
+
+    // The two blocks `dst` held are released, and the first shared
+    // blocks start the reference count table
+    disk.clone_file(src, dst).unwrap();
+    assert_eq!(disk.free_blocks_count(), free + 2 - 1);
+    let blocks = blocks_of(&disk, src);
+    assert_eq!(blocks_of(&disk, dst), blocks);
+    assert!(blocks.iter().all(|&b| disk.is_block_shared(b)));
+    assert_eq!(disk.sharing_stats().saved_blocks, 4);
+    assert_eq!(disk.read_file(dst).unwrap(), pattern(SOURCE_SIZE, 1));
+
+    // Writing moves the clone off that one block only
+    disk.write_at(dst, BLOCK, b"changed").unwrap();
+    let moved = blocks_of(&disk, dst);
+    assert_ne!(moved[1], blocks[1]);
+    assert_eq!((moved[0], moved[2], moved[3]), (blocks[0], blocks[2], blocks[3]));
+    assert!(!disk.is_block_shared(blocks[1]));
+    assert_eq!(disk.read_file(src).unwrap(), pattern(SOURCE_SIZE, 1));
+    assert_eq!(disk.read_file(dst).unwrap()[BLOCK as usize..][..7], *b"changed");
+    assert_clean(&mut disk);
+
+    let root = disk.lookup_path("/").unwrap();
+    assert!(matches!(disk.clone_file(root, dst), Err(FsError::NotAFile(_))));
+    assert!(matches!(disk.clone_file(src, root), Err(FsError::NotAFile(_))));
+}
+
+#[test]
+fn clone_file_copies_inline_data() {
+    let image = TempImage::new("clone-inline");
+    let (mut disk, _, dst) = setup(&image, FormatOptions { inline_data: true, ..options() });
+    let small = disk.write_file_at("/small", b"fits in the inode").unwrap();
+    assert!(disk.read_inode(small).unwrap().is_inline());
+
+    let free = disk.free_blocks_count();
+    disk.clone_file(small, dst).unwrap();
+    assert_eq!(disk.free_blocks_count(), free);
+    assert!(disk.read_inode(dst).unwrap().is_inline());
+    assert_eq!(disk.read_file(dst).unwrap(), b"fits in the inode");
+
+    // Nothing is shared, so the copies change independently
+    disk.write_at(dst, 0, b"FITS").unwrap();
+    assert_eq!(disk.read_file(small).unwrap(), b"fits in the inode");
+    assert_eq!(disk.read_file(dst).unwrap(), b"FITS in the inode");
+    assert_clean(&mut disk);
+}
+
+#[test]
+fn clone_range_shares_whole_blocks() {
+    let image = TempImage::new("clone-range");
+    let (mut disk, src, dst) = setup(&image, options());
+    disk.write_file(dst, &pattern(3 * BLOCK as usize, 2)).unwrap();
+
+    disk.clone_range(src, BLOCK, dst, 0, 2 * BLOCK).unwrap();
+    let (source, clone) = (blocks_of(&disk, src), blocks_of(&disk, dst));
+    assert_eq!(clone[..2], source[1..3]);
+    assert!(!disk.is_block_shared(clone[2]));
+    let mut expected = pattern(3 * BLOCK as usize, 2);
+    expected[..2 * BLOCK as usize].copy_from_slice(&pattern(SOURCE_SIZE, 1)[BLOCK as usize..3 * BLOCK as usize]);
+    assert_eq!(disk.read_file(dst).unwrap(), expected);
+
+    // A range ending at the end of the source may end mid-block when it
+    // also reaches the end of the destination, which grows to hold it
+    disk.clone_range(src, 3 * BLOCK, dst, 3 * BLOCK, 100).unwrap();
+    assert_eq!(disk.read_inode(dst).unwrap().size, SOURCE_SIZE as u64);
+    assert_eq!(blocks_of(&disk, dst)[3], source[3]);
+    assert_clean(&mut disk);
+}
+
+#[test]
+fn clone_range_rejects_bad_ranges() {
+    let image = TempImage::new("clone-range-bad");
+    let (mut disk, src, dst) = setup(&image, options());
+    disk.write_file(dst, &pattern(4 * BLOCK as usize, 2)).unwrap();
+
+    // Unaligned offsets or length, and a partial block not at both ends
+    expect_invalid(disk.clone_range(src, 1, dst, 0, BLOCK));
+    expect_invalid(disk.clone_range(src, 0, dst, 1, BLOCK));
+    expect_invalid(disk.clone_range(src, 0, dst, 0, 100));
+    expect_invalid(disk.clone_range(src, 3 * BLOCK, dst, 0, 100));
+    // Past the end of the source, or overflowing
+    expect_invalid(disk.clone_range(src, 0, dst, 0, 5 * BLOCK));
+    expect_invalid(disk.clone_range(src, BLOCK, dst, u64::MAX - BLOCK + 1, BLOCK));
+
+    // Overlapping ranges of the same file, either way round
+    expect_invalid(disk.clone_range(src, 0, src, BLOCK, 2 * BLOCK));
+    expect_invalid(disk.clone_range(src, BLOCK, src, 0, 2 * BLOCK));
+    expect_invalid(disk.clone_range(src, BLOCK, src, BLOCK, BLOCK));
+    assert_eq!(disk.read_file(src).unwrap(), pattern(SOURCE_SIZE, 1));
+
+    // Ranges that only touch are fine
+    disk.clone_range(src, 0, src, BLOCK, BLOCK).unwrap();
+    let blocks = blocks_of(&disk, src);
+    assert_eq!(blocks[0], blocks[1]);
+    assert_clean(&mut disk);
+}
+
+#[test]
+fn clone_range_zeroes_stale_tail() {
+    let image = TempImage::new("clone-stale-tail");
+    let (mut disk, src, dst) = setup(&image, FormatOptions { inline_data: false, ..options() });
+    disk.write_file(dst, &pattern(100, 2)).unwrap();
+
+    // Leftovers past the end of the file, as a shrinking write leaves
+    let tail = blocks_of(&disk, dst)[0];
+    disk.write_raw(tail * BLOCK + 100, &[0xEE; BLOCK as usize - 100]).unwrap();
+
+    // Growing the file over them with a clone past its end zeroes them
+    disk.clone_range(src, 0, dst, 2 * BLOCK, BLOCK).unwrap();
+    let data = disk.read_file(dst).unwrap();
+    assert_eq!(data.len(), 3 * BLOCK as usize);
+    assert_eq!(data[..100], pattern(100, 2));
+    assert!(data[100..2 * BLOCK as usize].iter().all(|&b| b == 0));
+    assert_eq!(data[2 * BLOCK as usize..], pattern(SOURCE_SIZE, 1)[..BLOCK as usize]);
+    assert_eq!(blocks_of(&disk, dst)[1], HOLE);
+    assert_clean(&mut disk);
+}
+
+#[test]
+fn clone_range_moves_inline_data_out() {
+    let image = TempImage::new("clone-range-inline");
+    let (mut disk, src, _) = setup(&image, FormatOptions { inline_data: true, ..options() });
+    let small = disk.write_file_at("/small", b"inline").unwrap();
+
+    disk.clone_range(src, 0, small, BLOCK, BLOCK).unwrap();
+    assert!(!disk.read_inode(small).unwrap().is_inline());
+    let data = disk.read_file(small).unwrap();
+    assert_eq!(data[..6], *b"inline");
+    assert!(data[6..BLOCK as usize].iter().all(|&b| b == 0));
+    assert_eq!(data[BLOCK as usize..], pattern(SOURCE_SIZE, 1)[..BLOCK as usize]);
+    assert_clean(&mut disk);
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn clone_carries_compression() {
+    use file_system_simulator::serialization::Inode;
+
+    let image = TempImage::new("clone-compressed");
+    let (mut disk, _, dst) = setup(&image, options());
+    let src = disk.write_file_at("/zeros", &vec![7u8; 16 * BLOCK as usize]).unwrap();
+    disk.set_compressed(src, true).unwrap();
+    let size = disk.physical_size(src).unwrap();
+    assert!(size < 16 * BLOCK);
+
+    disk.clone_file(src, dst).unwrap();
+    assert!(disk.read_inode(dst).unwrap().has_flag(Inode::FLAG_COMPRESSED));
+    assert_eq!(disk.physical_size(dst).unwrap(), size);
+    assert_eq!(disk.read_file(dst).unwrap(), vec![7u8; 16 * BLOCK as usize]);
+
+    // Clusters cannot be split
+    let other = disk.create_file_at("/other", Permissions::new(true, true, false)).unwrap();
+    assert!(matches!(disk.clone_range(src, 0, other, 0, BLOCK), Err(FsError::NotSupported(_))));
+
+    // A plain file cloned over the compressed one is plain again
+    let plain = disk.lookup_path("/src").unwrap();
+    disk.clone_file(plain, dst).unwrap();
+    assert!(!disk.read_inode(dst).unwrap().has_flag(Inode::FLAG_COMPRESSED));
+    assert_eq!(disk.read_file(dst).unwrap(), pattern(SOURCE_SIZE, 1));
+    assert_clean(&mut disk);
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn clone_carries_encryption_key() {
+    use file_system_simulator::{encryption::EncryptionOptions, serialization::Inode};
+
+    let image = TempImage::new("clone-encrypted");
+    let encryption = EncryptionOptions { kdf_iterations: 1000, ..EncryptionOptions::new("secret") };
+    let (mut disk, src, dst) = setup(&image, FormatOptions { encryption: Some(encryption), ..options() });
+    let source = disk.read_inode(src).unwrap();
+    assert!(source.has_flag(Inode::FLAG_ENCRYPTED));
+
+    disk.clone_file(src, dst).unwrap();
+    let clone = disk.read_inode(dst).unwrap();
+    assert!(clone.has_flag(Inode::FLAG_ENCRYPTED));
+    assert_eq!(clone.wrapped_key, source.wrapped_key);
+    assert_eq!(blocks_of(&disk, dst), blocks_of(&disk, src));
+    let other = disk.create_file_at("/other", Permissions::new(true, true, false)).unwrap();
+    assert!(matches!(disk.clone_range(src, 0, other, 0, BLOCK), Err(FsError::NotSupported(_))));
+    assert_clean(&mut disk);
+
+    drop(disk);
+    let disk = VirtualDisk::open_encrypted(image.path(), "secret").unwrap();
+    assert_eq!(disk.read_file_at("/dst").unwrap(), pattern(SOURCE_SIZE, 1));
+}
+
+#[test]
+fn clones_are_charged_in_full() {
+    let image = TempImage::new("clone-quota");
+    let mut disk = VirtualDisk::format(image.path(), options()).unwrap();
+    disk.initialize_root_dir().unwrap();
+    disk.set_credentials(1000, 1000);
+    let src = disk.write_file_at("/src", &pattern(SOURCE_SIZE, 1)).unwrap();
+    let dst = disk.create_file_at("/dst", Permissions::new(true, true, false)).unwrap();
+    let used = |disk: &VirtualDisk| disk.quota(QuotaKind::User, 1000).blocks;
+
+    // Room for three more blocks, not the four a clone takes
+    let limits = |hard| QuotaLimits { block_hard: hard, ..QuotaLimits::default() };
+    disk.set_quota(QuotaKind::User, 1000, limits(4 + 3)).unwrap();
+    assert_eq!(used(&disk), 4);
+    assert!(matches!(disk.clone_file(src, dst), Err(FsError::QuotaExceeded(_))));
+    assert!(matches!(disk.clone_range(src, 0, dst, 0, SOURCE_SIZE as u64), Err(FsError::QuotaExceeded(_))));
+    assert_eq!(used(&disk), 4);
+    assert_eq!(disk.read_file(dst).unwrap(), b"");
+
+    // Two blocks fit, and what the destination held before counts as
+    // freed
+    disk.clone_range(src, 0, dst, 0, 2 * BLOCK).unwrap();
+    assert_eq!(used(&disk), 6);
+    disk.set_quota(QuotaKind::User, 1000, limits(4 + 4)).unwrap();
+    disk.clone_file(src, dst).unwrap();
+    assert_eq!(used(&disk), 8);
+
+    // Shared blocks stay charged to the clone when the source goes
+    disk.remove_path("/src").unwrap();
+    assert_eq!(used(&disk), 4);
+    disk.quota_recount().unwrap();
+    assert_eq!(used(&disk), 4);
+    assert_clean(&mut disk);
+}
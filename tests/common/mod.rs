@@ -0,0 +1,25 @@
+//! Helpers shared by the integration tests
+
+use std::path::PathBuf;
+
+/// A disk image path in the temporary directory, removed when dropped
+pub struct TempImage(PathBuf);
+
+impl TempImage {
+    /// A path unique to `name` and this test process; any leftover file is removed
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("fssim-test-{}-{}.img", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        TempImage(path)
+    }
+
+    pub fn path(&self) -> &str {
+        self.0.to_str().expect("temporary directory is not valid UTF-8")
+    }
+}
+
+impl Drop for TempImage {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
@@ -0,0 +1,212 @@
+#![cfg(feature = "compression")]
+
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    compression::CLUSTER_BLOCKS,
+    extent::HOLE,
+    serialization::{Inode, Permissions},
+    shell::Shell,
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+use serde_json::Value;
+use std::process::Command;
+
+const BLOCK: usize = 4096;
+const CLUSTER: usize = CLUSTER_BLOCKS as usize * BLOCK;
+
+/// Text that compresses well but differs from line to line
+fn text(len: usize, seed: u32) -> Vec<u8> {
+    let mut data = Vec::with_capacity(len + 32);
+    let mut line = 0;
+    while data.len() < len {
+        data.extend_from_slice(format!("record {:08} of file {}\n", line, seed).as_bytes());
+        line += 1;
+    }
+    data.truncate(len);
+    data
+}
+
+/// Bytes that do not compress at all
+fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+fn setup(image: &TempImage) -> VirtualDisk {
+    let options = FormatOptions { size: 16 * 1024 * 1024, extents: true, ..FormatOptions::default() };
+    let disk = VirtualDisk::format(image.path(), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+    disk
+}
+
+/// A compressed file of `len` bytes of `text(len, seed)`
+fn compressed_file(disk: &VirtualDisk, path: &str, len: usize, seed: u32) -> u64 {
+    let inode_block = disk.write_file_at(path, &text(len, seed)).unwrap();
+    disk.set_compressed(inode_block, true).unwrap();
+    inode_block
+}
+
+fn blocks(disk: &VirtualDisk, inode_block: u64) -> Vec<u64> {
+    disk.file_blocks(&disk.read_inode(inode_block).unwrap()).unwrap()
+}
+
+fn cluster(blocks: &[u64], cluster: usize) -> &[u64] {
+    &blocks[cluster * CLUSTER_BLOCKS as usize..((cluster + 1) * CLUSTER_BLOCKS as usize).min(blocks.len())]
+}
+
+fn read(disk: &VirtualDisk, inode_block: u64, offset: u64, len: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; len];
+    let n = disk.read_at(inode_block, offset, &mut buf).unwrap();
+    buf.truncate(n);
+    buf
+}
+
+fn assert_clean(disk: &mut VirtualDisk) {
+    let report = disk.fsck().unwrap();
+    assert!(report.is_clean(), "fsck: {:?}", report.issues);
+}
+
+#[test]
+fn reads_start_anywhere_in_a_cluster() {
+    let image = TempImage::new("compression-read");
+    let disk = setup(&image);
+    let len = 4 * CLUSTER + 1000;
+    let file = compressed_file(&disk, "/text", len, 1);
+    let expected = text(len, 1);
+    assert!(disk.read_inode(file).unwrap().has_flag(Inode::FLAG_COMPRESSED));
+
+    for (offset, size) in [
+        (0, 10),
+        (CLUSTER + 3 * BLOCK + 17, 100),
+        // Across the boundary of two clusters
+        (2 * CLUSTER - 50, 100),
+        (CLUSTER / 2, 3 * CLUSTER),
+        // Into the short last cluster and past the end of the file
+        (4 * CLUSTER + 900, 500),
+    ] {
+        let got = read(&disk, file, offset as u64, size);
+        assert_eq!(got, expected[offset..(offset + size).min(len)], "{}+{}", offset, size);
+    }
+    assert!(read(&disk, file, len as u64 + 1, 10).is_empty());
+}
+
+#[test]
+fn writes_rewrite_only_the_clusters_they_touch() {
+    let image = TempImage::new("compression-write");
+    let mut disk = setup(&image);
+    let len = 5 * CLUSTER;
+    let file = compressed_file(&disk, "/text", len, 2);
+    let mut expected = text(len, 2);
+    let before = blocks(&disk, file);
+
+    // A few bytes in the middle of the third cluster
+    let offset = 2 * CLUSTER + 5 * BLOCK + 123;
+    disk.write_at(file, offset as u64, b"PATCHED").unwrap();
+    expected[offset..offset + 7].copy_from_slice(b"PATCHED");
+    let after = blocks(&disk, file);
+    for i in [0, 1, 3, 4] {
+        assert_eq!(cluster(&after, i), cluster(&before, i), "cluster {}", i);
+    }
+    assert_ne!(cluster(&after, 2), cluster(&before, 2));
+    assert_eq!(read(&disk, file, offset as u64 - 10, 30), expected[offset - 10..offset + 20]);
+
+    // Across two clusters, and growing the file by one more
+    let offset = 4 * CLUSTER - 100;
+    let patch = text(CLUSTER + 300, 9);
+    disk.write_at(file, offset as u64, &patch).unwrap();
+    expected.resize(offset + patch.len(), 0);
+    expected[offset..].copy_from_slice(&patch);
+    let grown = blocks(&disk, file);
+    for i in 0..3 {
+        assert_eq!(cluster(&grown, i), cluster(&after, i), "cluster {}", i);
+    }
+    assert_eq!(disk.read_inode(file).unwrap().size, expected.len() as u64);
+    assert_eq!(disk.read_file_at("/text").unwrap(), expected);
+    assert_clean(&mut disk);
+}
+
+#[test]
+fn logical_and_physical_sizes() {
+    let image = TempImage::new("compression-sizes");
+    let mut disk = setup(&image);
+    let dir = disk.create_directory_at("/packed", Permissions::new(true, true, true)).unwrap();
+    disk.set_compressed(dir, true).unwrap();
+
+    // New files in the directory are compressed
+    let len = 3 * CLUSTER + 10;
+    let file = disk.write_file_at("/packed/text", &text(len, 3)).unwrap();
+    let inode = disk.read_inode(file).unwrap();
+    assert!(inode.has_flag(Inode::FLAG_COMPRESSED));
+    assert_eq!(inode.size, len as u64);
+    let physical = disk.physical_size(file).unwrap();
+    assert!(physical < len as u64 / 2, "{} of {}", physical, len);
+    // The block count is logical too; the unused slots at the end of
+    // each cluster stay holes
+    let mapped = blocks(&disk, file);
+    assert_eq!(inode.block_count, len.div_ceil(BLOCK) as u64);
+    assert_eq!(mapped.len(), len.div_ceil(BLOCK));
+    assert_eq!(physical, mapped.iter().filter(|&&b| b != HOLE).count() as u64 * BLOCK as u64);
+    assert!(cluster(&mapped, 0).ends_with(&[HOLE]));
+
+    // Data that does not compress is stored as it is
+    let random = disk.write_file_at("/packed/noise", &noise(2 * CLUSTER, 7)).unwrap();
+    assert_eq!(disk.physical_size(random).unwrap(), 2 * CLUSTER as u64);
+    assert!(!blocks(&disk, random).contains(&HOLE));
+
+    // A cluster of zeros takes no blocks at all
+    let mut sparse = vec![0u8; 2 * CLUSTER];
+    sparse[CLUSTER..].copy_from_slice(&noise(CLUSTER, 8));
+    let zeros = disk.write_file_at("/packed/zeros", &sparse).unwrap();
+    assert_eq!(disk.physical_size(zeros).unwrap(), CLUSTER as u64);
+    assert_eq!(disk.read_file_at("/packed/zeros").unwrap(), sparse);
+
+    // Stored plainly again, the file takes its whole size
+    disk.set_compressed(file, false).unwrap();
+    assert!(!disk.read_inode(file).unwrap().has_flag(Inode::FLAG_COMPRESSED));
+    assert_eq!(disk.physical_size(file).unwrap(), len.div_ceil(BLOCK) as u64 * BLOCK as u64);
+    assert_eq!(disk.read_file_at("/packed/text").unwrap(), text(len, 3));
+    assert_clean(&mut disk);
+}
+
+#[test]
+fn sizes_are_reported_both_ways() {
+    let image = TempImage::new("compression-report");
+    let disk = setup(&image);
+    let len = 2 * CLUSTER + 5;
+    disk.write_file_at("/text", &text(len, 4)).unwrap();
+    drop(disk);
+
+    let fssim = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_fssim")).args(args).output().unwrap();
+        assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+        serde_json::from_slice::<Value>(&output.stdout).unwrap()
+    };
+    let spec = format!("{}:/text", image.path());
+    let compressed = fssim(&["--json", "compress", &spec]);
+    assert_eq!((compressed["compressed"].as_bool(), compressed["size"].as_u64()), (Some(true), Some(len as u64)));
+    let physical = compressed["physical_size"].as_u64().unwrap();
+    assert!(physical < len as u64 / 2 && physical % BLOCK as u64 == 0, "{}", physical);
+
+    let dump = fssim(&["--json", "dump-inode", image.path(), "/text"]);
+    assert_eq!((dump["size"].as_u64(), dump["physical_size"].as_u64()), (Some(len as u64), Some(physical)));
+    assert!(dump["flags"].as_u64().unwrap() & Inode::FLAG_COMPRESSED as u64 != 0);
+
+    let mut shell = Shell::open(image.path()).unwrap();
+    let mut out = Vec::new();
+    shell.execute("stat text", &mut out).unwrap();
+    let stat = String::from_utf8(out).unwrap();
+    assert!(stat.contains(&format!("  Size: {}  Blocks: {}", len, len.div_ceil(BLOCK))), "{}", stat);
+    assert!(stat.contains(&format!("Layout: extents, compressed to {} bytes\n", physical)), "{}", stat);
+
+    let plain = fssim(&["--json", "compress", "-d", &spec]);
+    assert_eq!(plain["physical_size"].as_u64(), Some(len.div_ceil(BLOCK) as u64 * BLOCK as u64));
+}
@@ -0,0 +1,112 @@
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    dedup::DedupStats,
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+
+const BLOCK: usize = 4096;
+
+/// Five blocks, each different from the others
+fn pattern(seed: u8) -> Vec<u8> {
+    (0..5 * BLOCK).map(|i| ((i / BLOCK) as u8).wrapping_mul(37) ^ (i % 251) as u8 ^ seed).collect()
+}
+
+/// A fresh image holding four copies of one file and a file of its own,
+/// written with dedup off
+fn setup(image: &TempImage, dedup: bool) -> VirtualDisk {
+    let options = FormatOptions {
+        size: 8 * 1024 * 1024,
+        ..FormatOptions::default()
+    };
+    let mut disk = VirtualDisk::format(image.path(), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+    for copy in 0..4 {
+        disk.write_file_at(&format!("/copy{}", copy), &pattern(1)).unwrap();
+    }
+    disk.write_file_at("/unique", &pattern(2)).unwrap();
+    disk.set_dedup(dedup).unwrap();
+    disk
+}
+
+fn assert_contents(disk: &mut VirtualDisk) {
+    for copy in 0..4 {
+        assert_eq!(disk.read_file_at(&format!("/copy{}", copy)).unwrap(), pattern(1));
+    }
+    assert_eq!(disk.read_file_at("/unique").unwrap(), pattern(2));
+    let report = disk.fsck().unwrap();
+    assert!(report.is_clean(), "fsck: {:?}", report.issues);
+}
+
+#[test]
+fn scan_reports_blocks_freed_as_free_space_gained() {
+    let image = TempImage::new("dedup-scan");
+    let mut disk = setup(&image, false);
+    let before = disk.dedup_stats().unwrap();
+    assert_eq!(before, DedupStats { hashed_blocks: 0, logical_blocks: 25, physical_blocks: 25 });
+
+    let free = disk.free_blocks_count();
+    let report = disk.dedup_scan().unwrap();
+    assert_eq!((report.files_scanned, report.blocks_scanned), (5, 25));
+    assert_eq!(report.blocks_shared, 15);
+    // The reference count table takes some of what sharing freed
+    assert_eq!(report.blocks_freed, disk.free_blocks_count() - free);
+    assert!(report.blocks_freed < report.blocks_shared);
+
+    let after = disk.dedup_stats().unwrap();
+    assert_eq!(after, DedupStats { hashed_blocks: 0, logical_blocks: 25, physical_blocks: 10 });
+    assert_eq!((after.saved_blocks(), after.ratio()), (15, 2.5));
+    assert_contents(&mut disk);
+
+    // Nothing is left to share
+    let free = disk.free_blocks_count();
+    let again = disk.dedup_scan().unwrap();
+    assert_eq!((again.blocks_shared, again.blocks_freed), (0, 0));
+    assert_eq!(disk.free_blocks_count(), free);
+}
+
+#[test]
+fn scan_with_dedup_on_keeps_the_table() {
+    let image = TempImage::new("dedup-scan-enabled");
+    let mut disk = setup(&image, true);
+    let free = disk.free_blocks_count();
+    let report = disk.dedup_scan().unwrap();
+    assert_eq!(report.blocks_shared, 15);
+    assert_eq!(report.blocks_freed, disk.free_blocks_count() - free);
+    assert_eq!(disk.dedup_stats().unwrap().hashed_blocks, 10);
+
+    // The table and the reference counts both take blocks, so even less
+    // is gained than without it
+    assert!(report.blocks_freed + 2 <= report.blocks_shared);
+    assert_contents(&mut disk);
+
+    // New writes find the blocks the scan recorded
+    let free = disk.free_blocks_count();
+    disk.write_file_at("/copy4", &pattern(1)).unwrap();
+    assert_eq!(disk.free_blocks_count(), free);
+    assert_eq!(disk.dedup_stats().unwrap().physical_blocks, 10);
+}
+
+#[test]
+fn scan_under_a_snapshot_frees_nothing() {
+    let image = TempImage::new("dedup-scan-snapshot");
+    let mut disk = setup(&image, false);
+    disk.snapshot_create("before").unwrap();
+
+    // The snapshot keeps the blocks the files no longer use
+    let free = disk.free_blocks_count();
+    let report = disk.dedup_scan().unwrap();
+    assert_eq!(report.blocks_shared, 15);
+    assert_eq!(report.blocks_freed, 0);
+    assert!(disk.free_blocks_count() <= free);
+    assert_contents(&mut disk);
+
+    // Until it goes, leaving the space a scan without it leaves
+    disk.snapshot_delete("before").unwrap();
+    let twin_image = TempImage::new("dedup-scan-twin");
+    let mut twin = setup(&twin_image, false);
+    twin.dedup_scan().unwrap();
+    assert_eq!(disk.free_blocks_count(), twin.free_blocks_count());
+    assert_contents(&mut disk);
+}
@@ -0,0 +1,137 @@
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    defrag::DefragOptions,
+    extent::Extent,
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+
+const BLOCK: usize = 4096;
+
+/// Blocks of each file filling the disk
+const SMALL: usize = 6;
+
+/// Blocks of the large file written into the holes
+const BIG: usize = 24;
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+fn blocks_of(disk: &VirtualDisk, path: &str) -> Vec<u64> {
+    disk.file_blocks(&disk.stat_path(path).unwrap()).unwrap()
+}
+
+fn runs(disk: &VirtualDisk, path: &str) -> usize {
+    Extent::from_blocks(&blocks_of(disk, path)).len()
+}
+
+fn assert_clean(disk: &mut VirtualDisk) {
+    let report = disk.fsck().unwrap();
+    assert!(report.is_clean(), "fsck: {:?}", report.issues);
+}
+
+/// A small image with `/big` written into the holes left between small
+/// files, and room made afterwards for it to move into
+fn fragmented(image: &TempImage, extents: bool) -> VirtualDisk {
+    let options = FormatOptions { size: 2 * 1024 * 1024, extents, ..FormatOptions::default() };
+    let disk = VirtualDisk::format(image.path(), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+
+    // Fill the disk, then free every other file
+    let mut count = 0;
+    loop {
+        match disk.write_file_at(&format!("/s{}", count), &pattern(SMALL * BLOCK, count as u8)) {
+            Ok(_) => count += 1,
+            Err(e) if e.is_out_of_space() => break,
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+    for i in (0..count).step_by(2) {
+        disk.remove_path(&format!("/s{}", i)).unwrap();
+    }
+
+    // Blocks ran out before inodes did, so only the holes are free
+    assert!(disk.free_blocks_count() < (count as u64 / 2 + 1) * SMALL as u64);
+    disk.write_file_at("/big", &pattern(BIG * BLOCK, 0xB1)).unwrap();
+    assert!(runs(&disk, "/big") > 1);
+
+    // Free a stretch at the end of the disk large enough for it
+    for i in count - 2 * BIG / SMALL..count {
+        let _ = disk.remove_path(&format!("/s{}", i));
+    }
+    disk
+}
+
+#[test]
+fn defragment_file_makes_one_run() {
+    for extents in [false, true] {
+        let image = TempImage::new(&format!("defrag-file-{}", extents));
+        let mut disk = fragmented(&image, extents);
+        let big = disk.lookup_path("/big").unwrap();
+        let old = blocks_of(&disk, "/big");
+        let fragments = runs(&disk, "/big") as u64;
+
+        let report = disk.defragment_file(big).unwrap();
+        assert_eq!((report.files_examined, report.files_moved, report.files_skipped), (1, 1, 0));
+        assert_eq!(report.blocks_moved, BIG as u64);
+        assert_eq!(report.before.fragments - report.after.fragments, fragments - 1);
+        assert_eq!(report.before.fragmented_inodes, report.after.fragmented_inodes + 1);
+
+        let new = blocks_of(&disk, "/big");
+        assert_eq!(runs(&disk, "/big"), 1, "extents: {}", extents);
+        assert!(new.iter().all(|b| !old.contains(b)));
+        assert_eq!(disk.read_file_at("/big").unwrap(), pattern(BIG * BLOCK, 0xB1));
+        assert_clean(&mut disk);
+
+        // A second pass has nothing left to do
+        let report = disk.defragment_file(big).unwrap();
+        assert_eq!((report.files_moved, report.blocks_moved), (0, 0));
+    }
+}
+
+#[test]
+fn defragment_moves_every_fragmented_file() {
+    for extents in [false, true] {
+        let image = TempImage::new(&format!("defrag-all-{}", extents));
+        let mut disk = fragmented(&image, extents);
+
+        // Two files grown in turns interleave their blocks
+        let a = disk.write_file_at("/a", b"").unwrap();
+        let b = disk.write_file_at("/b", b"").unwrap();
+        for round in 0..4u64 {
+            disk.write_at(a, round * 2 * BLOCK as u64, &pattern(2 * BLOCK, 0xA)).unwrap();
+            disk.write_at(b, round * 2 * BLOCK as u64, &pattern(2 * BLOCK, 0xB)).unwrap();
+        }
+        assert!(runs(&disk, "/a") > 1 && runs(&disk, "/b") > 1);
+
+        let report = disk.defragment(&DefragOptions::default()).unwrap();
+        assert!(report.before.fragmented_inodes >= 3, "{:?}", report.before);
+        assert_eq!(report.after.fragmented_inodes, 0, "extents: {}", extents);
+        assert!(report.files_moved >= 3);
+        assert!(report.blocks_moved >= BIG as u64 + 16);
+        assert_eq!(report.after.data_blocks, report.before.data_blocks);
+        assert!(report.after.fragments_per_inode() < report.before.fragments_per_inode());
+
+        for path in ["/big", "/a", "/b"] {
+            assert_eq!(runs(&disk, path), 1);
+        }
+        assert_eq!(disk.read_file_at("/big").unwrap(), pattern(BIG * BLOCK, 0xB1));
+        assert_eq!(disk.read_file_at("/a").unwrap(), pattern(2 * BLOCK, 0xA).repeat(4));
+        assert_eq!(disk.read_file_at("/b").unwrap(), pattern(2 * BLOCK, 0xB).repeat(4));
+        assert_clean(&mut disk);
+    }
+}
+
+#[test]
+fn compacting_gathers_free_space() {
+    let image = TempImage::new("defrag-compact");
+    let mut disk = fragmented(&image, true);
+    let report = disk.defragment(&DefragOptions { compact: true }).unwrap();
+    assert!(report.free_space_after.free_runs < report.free_space_before.free_runs);
+    assert!(report.free_space_after.external_fragmentation() < report.free_space_before.external_fragmentation());
+    assert_eq!(report.free_space_after.free_blocks, report.free_space_before.free_blocks);
+    assert_eq!(disk.read_file_at("/big").unwrap(), pattern(BIG * BLOCK, 0xB1));
+    assert_clean(&mut disk);
+}
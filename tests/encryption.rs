@@ -0,0 +1,155 @@
+#![cfg(feature = "encryption")]
+
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    encryption::EncryptionOptions,
+    error::FsError,
+    serialization::Permissions,
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+use std::process::Command;
+
+const BLOCK: usize = 4096;
+const PASSPHRASE: &str = "correct horse battery staple";
+
+/// Plain text that is easy to find in the raw image
+fn secret(len: usize, seed: u8) -> Vec<u8> {
+    let line = format!("top secret line of file {}\n", seed);
+    line.as_bytes().iter().copied().cycle().take(len).collect()
+}
+
+fn setup(image: &TempImage, encrypt_names: bool) -> VirtualDisk {
+    let encryption = EncryptionOptions { encrypt_names, kdf_iterations: 1000, ..EncryptionOptions::new(PASSPHRASE) };
+    let options = FormatOptions { size: 8 * 1024 * 1024, encryption: Some(encryption), ..FormatOptions::default() };
+    let disk = VirtualDisk::format(image.path(), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+    disk
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+fn assert_clean(disk: &mut VirtualDisk) {
+    let report = disk.fsck().unwrap();
+    assert!(report.is_clean(), "fsck: {:?}", report.issues);
+}
+
+#[test]
+fn data_round_trips_through_a_reopen() {
+    let image = TempImage::new("encryption-round-trip");
+    let disk = setup(&image, false);
+    let sizes = [0, 1, BLOCK - 1, 3 * BLOCK + 5, 40 * BLOCK];
+    for (i, &len) in sizes.iter().enumerate() {
+        disk.write_file_at(&format!("/f{}", i), &secret(len, i as u8)).unwrap();
+    }
+    // Partial writes rewrite the clusters they touch, sealed anew
+    let big = disk.lookup_path("/f4").unwrap();
+    disk.write_at(big, 17 * BLOCK as u64 - 3, b"patched across blocks").unwrap();
+    disk.create_symlink("/f2", "/link").unwrap();
+    drop(disk);
+
+    // Nothing readable is left on disk
+    let raw = std::fs::read(image.path()).unwrap();
+    assert!(!contains(&raw, b"top secret"));
+    assert!(!contains(&raw, b"patched across"));
+
+    let mut disk = VirtualDisk::open_encrypted(image.path(), PASSPHRASE).unwrap();
+    assert!(disk.is_encrypted() && disk.is_unlocked());
+    for (i, &len) in sizes.iter().enumerate().take(4) {
+        assert_eq!(disk.read_file_at(&format!("/f{}", i)).unwrap(), secret(len, i as u8), "/f{}", i);
+    }
+    let mut expected = secret(40 * BLOCK, 4);
+    expected[17 * BLOCK - 3..17 * BLOCK + 18].copy_from_slice(b"patched across blocks");
+    assert_eq!(disk.read_file_at("/f4").unwrap(), expected);
+    let mut buf = vec![0u8; 100];
+    disk.read_at(big, 17 * BLOCK as u64 - 50, &mut buf).unwrap();
+    assert_eq!(buf, expected[17 * BLOCK - 50..17 * BLOCK + 50]);
+    assert_eq!(disk.read_link("/link").unwrap(), "/f2");
+    assert_clean(&mut disk);
+}
+
+#[test]
+fn equal_files_are_stored_differently() {
+    let image = TempImage::new("encryption-keys");
+    let disk = setup(&image, false);
+    let a = disk.write_file_at("/a", &secret(2 * BLOCK, 1)).unwrap();
+    let b = disk.write_file_at("/b", &secret(2 * BLOCK, 1)).unwrap();
+    let stored = |inode_block: u64| {
+        let block = disk.file_blocks(&disk.read_inode(inode_block).unwrap()).unwrap()[0];
+        let raw = std::fs::read(image.path()).unwrap();
+        raw[block as usize * BLOCK..(block as usize + 1) * BLOCK].to_vec()
+    };
+    // Each file has its own key
+    assert_ne!(stored(a), stored(b));
+    assert_ne!(stored(a), secret(BLOCK, 1));
+
+    // And each write a fresh nonce
+    let before = stored(a);
+    disk.write_file_at("/a", &secret(2 * BLOCK, 1)).unwrap();
+    assert_ne!(stored(a), before);
+    assert_eq!(disk.read_file_at("/a").unwrap(), disk.read_file_at("/b").unwrap());
+}
+
+#[test]
+fn wrong_passphrase_is_wrong_key() {
+    let image = TempImage::new("encryption-wrong-key");
+    let disk = setup(&image, false);
+    disk.write_file_at("/file", &secret(BLOCK, 2)).unwrap();
+    drop(disk);
+
+    assert!(matches!(VirtualDisk::open_encrypted(image.path(), "not it"), Err(FsError::WrongKey)));
+    assert!(matches!(VirtualDisk::open_encrypted(image.path(), ""), Err(FsError::WrongKey)));
+
+    // A locked image still lists its files, but their data needs the key
+    let mut disk = VirtualDisk::new(image.path()).unwrap();
+    assert!(disk.is_encrypted() && !disk.is_unlocked());
+    assert!(matches!(disk.unlock("not it"), Err(FsError::WrongKey)));
+    assert!(!disk.is_unlocked());
+    assert!(disk.lookup_path("/file").is_ok());
+    assert!(matches!(disk.read_file_at("/file"), Err(FsError::WrongKey)));
+    assert!(matches!(disk.write_file_at("/new", b"data"), Err(FsError::WrongKey)));
+    disk.unlock(PASSPHRASE).unwrap();
+    assert_eq!(disk.read_file_at("/file").unwrap(), secret(BLOCK, 2));
+
+    // The command line reports it as a permission problem, not corruption
+    for passphrase in ["not it", PASSPHRASE] {
+        let output = Command::new(env!("CARGO_BIN_EXE_fssim"))
+            .args(["--json", "info", image.path()])
+            .env("FSSIM_PASSPHRASE", passphrase)
+            .output()
+            .unwrap();
+        let expected = if passphrase == PASSPHRASE { 0 } else { FsError::WrongKey.exit_code() };
+        assert_eq!(output.status.code(), Some(expected), "{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert_eq!(FsError::WrongKey.exit_code(), 8);
+}
+
+#[test]
+fn encrypted_names_need_the_key() {
+    let image = TempImage::new("encryption-names");
+    let disk = setup(&image, true);
+    disk.create_directory_at("/confidential-plans", Permissions::new(true, true, true)).unwrap();
+    disk.write_file_at("/confidential-plans/merger-memo.txt", &secret(100, 3)).unwrap();
+    drop(disk);
+
+    let raw = std::fs::read(image.path()).unwrap();
+    assert!(!contains(&raw, b"confidential-plans"));
+    assert!(!contains(&raw, b"merger-memo"));
+
+    let disk = VirtualDisk::new(image.path()).unwrap();
+    assert!(matches!(disk.lookup_path("/confidential-plans"), Err(FsError::WrongKey)));
+    // Listing shows the names as stored
+    let stored: Vec<String> = disk.list_directory_at("/").unwrap().into_iter().map(|e| e.name).collect();
+    assert_eq!(stored.len(), 1);
+    assert_ne!(stored[0], "confidential-plans");
+    drop(disk);
+
+    let mut disk = VirtualDisk::open_encrypted(image.path(), PASSPHRASE).unwrap();
+    let names: Vec<String> = disk.list_directory_at("/confidential-plans").unwrap().into_iter().map(|e| e.name).collect();
+    assert_eq!(names, ["merger-memo.txt"]);
+    assert_eq!(disk.read_file_at("/confidential-plans/merger-memo.txt").unwrap(), secret(100, 3));
+    assert_clean(&mut disk);
+}
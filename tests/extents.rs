@@ -0,0 +1,88 @@
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    error::FsError,
+    extent::{Extent, ExtentEntry, ExtentNode},
+    serialization::{Inode, Permissions},
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+
+/// An extent-mapped disk holding one three-block file
+fn setup(image: &TempImage) -> (VirtualDisk, u64) {
+    let options = FormatOptions {
+        size: 8 * 1024 * 1024,
+        extents: true,
+        inline_data: false,
+        ..FormatOptions::default()
+    };
+    let disk = VirtualDisk::format(image.path(), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+    let file = disk.create_file(1, Permissions::new(true, true, false)).unwrap();
+    disk.write_file(file, &vec![7u8; 3 * 4096]).unwrap();
+    (disk, file)
+}
+
+/// Replace the extent tree root of the inode at `inode_block`
+fn set_root(disk: &VirtualDisk, inode_block: u64, root: &ExtentNode) {
+    let mut inode = disk.read_inode(inode_block).unwrap();
+    inode.set_pointer_area(&root.to_bytes(Inode::POINTER_AREA_SIZE).unwrap());
+    disk.write_inode(inode_block, &inode).unwrap();
+}
+
+fn expect_corrupted(disk: &VirtualDisk, inode_block: u64) {
+    match disk.read_file(inode_block) {
+        Err(FsError::CorruptedFileSystem(_)) => {}
+        other => panic!("expected CorruptedFileSystem, got {:?}", other.map(|data| data.len())),
+    }
+}
+
+#[test]
+fn extent_past_end_of_file_is_corrupted() {
+    let image = TempImage::new("extent-past-end");
+    let (disk, file) = setup(&image);
+    let physical = disk.file_blocks(&disk.read_inode(file).unwrap()).unwrap()[0];
+
+    // Would expand to four billion holes if taken at its word
+    let extent = Extent { logical: u64::from(u32::MAX) - 4, physical, length: 3 };
+    set_root(&disk, file, &ExtentNode { depth: 0, entries: vec![ExtentEntry::Leaf(extent)] });
+    expect_corrupted(&disk, file);
+}
+
+#[test]
+fn block_count_past_end_of_file_is_corrupted() {
+    let image = TempImage::new("extent-block-count");
+    let (disk, file) = setup(&image);
+
+    let mut inode = disk.read_inode(file).unwrap();
+    inode.block_count = u64::from(u32::MAX);
+    disk.write_inode(file, &inode).unwrap();
+    expect_corrupted(&disk, file);
+}
+
+#[test]
+fn extent_tree_cycle_is_corrupted() {
+    let image = TempImage::new("extent-cycle");
+    let (disk, file) = setup(&image);
+
+    // A node that names itself as its only child
+    let node = disk.allocate_block().unwrap();
+    let looped = ExtentNode { depth: 1, entries: vec![ExtentEntry::Index { logical: 0, child: node }] };
+    disk.write_raw(node * disk.block_size(), &looped.to_bytes(disk.block_size() as usize).unwrap())
+        .unwrap();
+    set_root(&disk, file, &ExtentNode { depth: 2, entries: vec![ExtentEntry::Index { logical: 0, child: node }] });
+    expect_corrupted(&disk, file);
+}
+
+#[test]
+fn extent_tree_too_deep_is_corrupted() {
+    let image = TempImage::new("extent-depth");
+    let (disk, file) = setup(&image);
+
+    let root = ExtentNode {
+        depth: ExtentNode::MAX_DEPTH + 1,
+        entries: vec![ExtentEntry::Index { logical: 0, child: 1 }],
+    };
+    set_root(&disk, file, &root);
+    expect_corrupted(&disk, file);
+}
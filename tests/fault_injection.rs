@@ -0,0 +1,150 @@
+//! Runs the file system on a `FaultyDevice` and checks that every kind of
+//! injected fault surfaces as the error it should: flipped bits and lost
+//! or torn writes as checksum mismatches, flipped bits without checksums
+//! as a corrupted file system, and failing blocks or a failing device as
+//! I/O errors. Scrub must report the damaged file by path.
+
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    error::{FsError, FsResult},
+    faulty::FaultyDevice,
+    scrub::ScrubReport,
+    serialization::Permissions,
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+use std::fs::OpenOptions;
+
+const FILE: &str = "/docs/report.txt";
+
+/// A fresh disk on a faulty device, holding `FILE` over several blocks
+fn setup(image: &TempImage, options: FormatOptions) -> (VirtualDisk, FaultyDevice) {
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(image.path()).unwrap();
+    let device = FaultyDevice::new(file, options.block_size);
+    let disk = VirtualDisk::format_device(Box::new(device.clone()), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+    disk.create_directory_at("/docs", Permissions::new(true, true, true)).unwrap();
+    disk.write_file_at(FILE, &contents()).unwrap();
+    (disk, device)
+}
+
+fn contents() -> Vec<u8> {
+    (0..10_000u32).map(|i| (i % 251) as u8).collect()
+}
+
+/// Inode block and first data block of `FILE`
+fn file_blocks(disk: &mut VirtualDisk) -> (u64, u64) {
+    let inode_block = disk.lookup_path(FILE).unwrap();
+    let blocks = disk.file_blocks(&disk.read_inode(inode_block).unwrap()).unwrap();
+    (inode_block, blocks[0])
+}
+
+/// Scrub, checking that it reports `FILE` and only `FILE`, with only
+/// `block` bad
+fn scrub_bad_block(disk: &VirtualDisk, block: u64) -> ScrubReport {
+    let report = disk.scrub().unwrap();
+    assert_eq!(report.bad_files(), [FILE]);
+    assert_eq!(report.bad_blocks.len(), 1, "{:?}", report.bad_blocks);
+    assert_eq!(report.bad_blocks[0].block, block);
+    assert_eq!(report.bad_blocks[0].path.as_deref(), Some(FILE));
+    report
+}
+
+fn expect_mismatch<T: std::fmt::Debug>(result: FsResult<T>, block: u64) {
+    match result {
+        Err(FsError::ChecksumMismatch { block: b, expected, actual }) => {
+            assert_eq!(b, block);
+            assert_ne!(expected, actual);
+        }
+        other => panic!("expected a checksum mismatch in block {}, got {:?}", block, other),
+    }
+}
+
+fn expect_io<T: std::fmt::Debug>(result: FsResult<T>) {
+    assert!(matches!(result, Err(FsError::Io(_))), "expected an I/O error, got {:?}", result);
+}
+
+fn checksummed() -> FormatOptions {
+    FormatOptions {
+        size: 8 * 1024 * 1024,
+        data_checksums: true,
+        ..FormatOptions::default()
+    }
+}
+
+fn plain() -> FormatOptions {
+    FormatOptions {
+        size: 8 * 1024 * 1024,
+        ..FormatOptions::default()
+    }
+}
+
+#[test]
+fn flipped_data_bit() {
+    let image = TempImage::new("fault-flipped-data");
+    let (mut disk, device) = setup(&image, checksummed());
+    let (_, data_block) = file_blocks(&mut disk);
+    device.flip_bit(data_block, 1234);
+    expect_mismatch(disk.read_file_at(FILE), data_block);
+    let report = scrub_bad_block(&disk, data_block);
+    assert!(matches!(report.bad_blocks[0].error, FsError::ChecksumMismatch { block, .. } if block == data_block));
+
+    // The fault was on the read path only, so the data itself is intact
+    device.clear();
+    assert_eq!(disk.read_file_at(FILE).unwrap(), contents());
+}
+
+#[test]
+fn flipped_inode_bit_without_checksums() {
+    let image = TempImage::new("fault-flipped-inode");
+    let (mut disk, device) = setup(&image, plain());
+    let (inode_block, _) = file_blocks(&mut disk);
+    device.flip_bit(inode_block, 0);
+    assert!(matches!(disk.read_file_at(FILE), Err(FsError::CorruptedFileSystem(_))));
+    let report = scrub_bad_block(&disk, inode_block);
+    assert!(matches!(report.bad_blocks[0].error, FsError::CorruptedFileSystem(_)));
+}
+
+#[test]
+fn failing_block() {
+    let image = TempImage::new("fault-failing-block");
+    let (mut disk, device) = setup(&image, plain());
+    let (_, data_block) = file_blocks(&mut disk);
+    device.fail_block(data_block);
+    expect_io(disk.read_file_at(FILE));
+    let report = scrub_bad_block(&disk, data_block);
+    assert!(matches!(report.bad_blocks[0].error, FsError::Io(_)));
+}
+
+#[test]
+fn dropped_write() {
+    let image = TempImage::new("fault-dropped-write");
+    let (mut disk, device) = setup(&image, checksummed());
+    let (inode_block, data_block) = file_blocks(&mut disk);
+    device.drop_writes(data_block);
+    disk.write_at(inode_block, 0, b"lost update").unwrap();
+    expect_mismatch(disk.read_file_at(FILE), data_block);
+    scrub_bad_block(&disk, data_block);
+}
+
+#[test]
+fn torn_write() {
+    let image = TempImage::new("fault-torn-write");
+    let (mut disk, device) = setup(&image, checksummed());
+    let (inode_block, data_block) = file_blocks(&mut disk);
+    device.tear_writes(data_block, 512);
+    disk.write_at(inode_block, 0, &[0xAB; 2048]).unwrap();
+    expect_mismatch(disk.read_file_at(FILE), data_block);
+    scrub_bad_block(&disk, data_block);
+}
+
+#[test]
+fn failing_device() {
+    let image = TempImage::new("fault-failing-device");
+    let (disk, device) = setup(&image, plain());
+    device.fail_after(3);
+    expect_io(disk.write_file_at("/docs/new.txt", &contents()));
+    expect_io(disk.sync());
+    expect_io(disk.read_file_at(FILE));
+}
@@ -0,0 +1,152 @@
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    serialization::{FileType, Permissions},
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+use serde_json::Value;
+use std::io::Read;
+use std::process::{Command, Output, Stdio};
+
+fn fssim(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_fssim")).args(args).output().unwrap()
+}
+
+fn setup(image: &TempImage, size: u64) -> VirtualDisk {
+    let options = FormatOptions { size, ..FormatOptions::default() };
+    let disk = VirtualDisk::format(image.path(), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+    disk
+}
+
+/// The JSON error printed to stderr, checked against the exit code
+fn json_error(output: &Output) -> Value {
+    assert!(output.stdout.is_empty());
+    let error: Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert_eq!(error["exit_code"], output.status.code().unwrap());
+    assert!(error["error"].is_string());
+    error
+}
+
+#[test]
+fn exit_codes_follow_the_error() {
+    let image = TempImage::new("fssim-exit-codes");
+    let disk = setup(&image, 8 * 1024 * 1024);
+    disk.write_file_at("/file", b"contents").unwrap();
+    drop(disk);
+    let spec = |path: &str| format!("{}:{}", image.path(), path);
+
+    assert_eq!(fssim(&[]).status.code(), Some(2));
+    assert_eq!(fssim(&["frobnicate"]).status.code(), Some(2));
+    assert_eq!(fssim(&["ls", "-l"]).status.code(), Some(2));
+    assert_eq!(fssim(&["ls", &spec("/missing")]).status.code(), Some(3));
+    assert_eq!(fssim(&["ls", &spec("/file/below")]).status.code(), Some(5));
+    assert_eq!(fssim(&["snapshot", "create", image.path(), "first"]).status.code(), Some(0));
+    assert_eq!(fssim(&["snapshot", "create", image.path(), "first"]).status.code(), Some(4));
+
+    // More data than the image holds
+    let host = TempImage::new("fssim-exit-codes-host");
+    std::fs::write(host.path(), vec![7u8; 9 * 1024 * 1024]).unwrap();
+    assert_eq!(fssim(&["cp", host.path(), &spec("/big")]).status.code(), Some(6));
+
+    // Not an image at all
+    std::fs::write(host.path(), vec![0xA5u8; 64 * 1024]).unwrap();
+    assert_eq!(fssim(&["info", host.path()]).status.code(), Some(7));
+
+    assert_eq!(fssim(&["ls", "/nonexistent/image.img"]).status.code(), Some(3));
+    let output = fssim(&["cp", &spec("/file"), "/nonexistent/dir/file"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("fssim: "));
+}
+
+#[test]
+fn fsck_exit_codes() {
+    let image = TempImage::new("fssim-fsck");
+    let disk = setup(&image, 8 * 1024 * 1024);
+    disk.write_file_at("/file", b"contents").unwrap();
+    let block = disk.lookup_path("/file").unwrap();
+    assert_eq!(fssim(&["fsck", image.path()]).status.code(), Some(0));
+
+    // A wrong link count only skews statistics
+    let mut inode = disk.read_inode(block).unwrap();
+    inode.link_count = 2;
+    disk.write_inode(block, &inode).unwrap();
+    assert_eq!(fssim(&["fsck", image.path()]).status.code(), Some(11));
+
+    // An entry of the wrong type is an error
+    inode.link_count = 1;
+    inode.file_type = FileType::Directory;
+    disk.write_inode(block, &inode).unwrap();
+    let output = fssim(&["--json", "fsck", image.path()]);
+    assert_eq!(output.status.code(), Some(12));
+    let report: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["clean"], false);
+    assert!(report["errors"].as_u64().unwrap() >= 1);
+    let issues = report["issues"].as_array().unwrap();
+    assert!(issues.iter().any(|i| i["kind"] == "BadEntry" && i["severity"] == "error"), "{:?}", issues);
+}
+
+#[test]
+fn json_output_and_errors() {
+    let image = TempImage::new("fssim-json");
+    let output = fssim(&["--json", "mkfs", "--size", "8M", "--extents", image.path()]);
+    assert_eq!(output.status.code(), Some(0));
+    let info: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(info["total_blocks"], 2048);
+    assert_eq!(info["features"], serde_json::json!(["extents"]));
+    assert_eq!(info["used_inodes"], 1);
+
+    let disk = VirtualDisk::new(image.path()).unwrap();
+    disk.write_file_at("/data", &[1u8; 5000]).unwrap();
+    disk.create_symlink("data", "/link").unwrap();
+    drop(disk);
+
+    let output = fssim(&["ls", "--json", image.path()]);
+    assert_eq!(output.status.code(), Some(0));
+    let listing: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let entry = |name: &str| listing.as_array().unwrap().iter().find(|e| e["name"] == name).unwrap().clone();
+    let data = entry("data");
+    assert_eq!((data["path"].as_str(), data["type"].as_str()), (Some("/data"), Some("regular file")));
+    assert_eq!((data["size"].as_u64(), data["blocks"].as_u64()), (Some(5000), Some(2)));
+    assert!(data["target"].is_null());
+    assert_eq!(entry("link")["target"], "data");
+
+    // Errors go to stderr, with the exit code they end the command with
+    let output = fssim(&["--json", "ls", &format!("{}:/missing", image.path())]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(json_error(&output)["error"].as_str().unwrap().contains("missing"));
+    let output = fssim(&["--json", "ls"]);
+    assert_eq!(output.status.code(), Some(2));
+    json_error(&output);
+}
+
+#[test]
+fn listing_into_a_closed_pipe_exits_cleanly() {
+    let image = TempImage::new("fssim-pipe");
+    let disk = setup(&image, 32 * 1024 * 1024);
+    disk.create_directory_at("/d", Permissions::new(true, true, true)).unwrap();
+    // More than a pipe holds, so writing has to fail once the reader is gone
+    for i in 0..300 {
+        disk.write_file_at(&format!("/d/{:03}-{}", i, "x".repeat(200)), b"").unwrap();
+    }
+    drop(disk);
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_fssim"))
+        .args(["ls", "-l", &format!("{}:/d", image.path())])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Read the first line, as `head -1` would, and close the pipe
+    let mut stdout = child.stdout.take().unwrap();
+    let mut first = [0u8; 16];
+    stdout.read_exact(&mut first).unwrap();
+    drop(stdout);
+
+    let output = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr);
+    assert!(first.starts_with(b"-rw-"));
+}
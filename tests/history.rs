@@ -0,0 +1,257 @@
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    error::FsError,
+    history::HistoryPolicy,
+    serialization::{FileType, Permissions},
+    shared::SharedDisk,
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+use std::thread;
+use std::time::Duration;
+
+const BLOCK: usize = 4096;
+
+fn blocks(count: usize, seed: u8) -> Vec<u8> {
+    (0..count * BLOCK).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+/// A fresh image of `size` bytes keeping history under `policy`
+fn setup(image: &TempImage, size: u64, policy: HistoryPolicy) -> VirtualDisk {
+    let options = FormatOptions { size, ..FormatOptions::default() };
+    let mut disk = VirtualDisk::format(image.path(), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+    disk.set_history_policy(policy).unwrap();
+    disk
+}
+
+fn versions(count: u64) -> HistoryPolicy {
+    HistoryPolicy { versions: count, ..HistoryPolicy::default() }
+}
+
+fn numbers(disk: &VirtualDisk, path: &str) -> Vec<u64> {
+    disk.list_versions(path).unwrap().into_iter().map(|v| v.number).collect()
+}
+
+fn assert_clean(disk: &mut VirtualDisk) {
+    let report = disk.fsck().unwrap();
+    assert!(report.is_clean(), "fsck: {:?}", report.issues);
+}
+
+#[test]
+fn versions_are_listed_and_restored() {
+    let image = TempImage::new("history-versions");
+    let mut disk = setup(&image, 8 * 1024 * 1024, versions(3));
+    for seed in 1..=5 {
+        disk.write_file_at("/f", &blocks(seed as usize, seed)).unwrap();
+    }
+
+    // The oldest beyond the count are dropped
+    let listed = disk.list_versions("/f").unwrap();
+    assert_eq!(listed.iter().map(|v| v.number).collect::<Vec<_>>(), [2, 3, 4]);
+    assert_eq!(listed.iter().map(|v| v.size).collect::<Vec<_>>(), [2, 3, 4].map(|n| (n * BLOCK) as u64));
+    assert!(listed.iter().all(|v| v.replaced >= v.modified));
+    assert_eq!(disk.read_version("/f", 3).unwrap(), blocks(3, 3));
+    assert!(matches!(disk.read_version("/f", 1), Err(FsError::VersionNotFound(_))));
+
+    // Restoring keeps what the file held as the newest version
+    disk.restore_version("/f", 2).unwrap();
+    assert_eq!(disk.read_file_at("/f").unwrap(), blocks(2, 2));
+    assert_eq!(numbers(&disk, "/f"), [3, 4, 5]);
+    assert_eq!(disk.read_version("/f", 5).unwrap(), blocks(5, 5));
+    assert_clean(&mut disk);
+
+    // Versions are files of their own, and never get versions themselves
+    let version = disk.stat_path("/.versions").unwrap();
+    assert_eq!(version.file_type, FileType::Directory);
+    let dir = disk.list_directory_at("/.versions").unwrap().remove(0).name;
+    disk.write_file_at(&format!("/.versions/{}/3", dir), b"edited").unwrap();
+    assert_eq!(disk.list_directory_at("/.versions").unwrap().len(), 1);
+}
+
+#[test]
+fn every_way_of_replacing_a_file_keeps_a_version() {
+    let image = TempImage::new("history-writers");
+    let disk = setup(&image, 8 * 1024 * 1024, versions(10));
+    let f = disk.write_file_at("/f", b"one").unwrap();
+
+    // Writes by inode, as the HTTP and NBD servers make them
+    disk.write_file(f, b"two").unwrap();
+    disk.copy_path("/f", "/g").unwrap();
+    disk.write_file_at("/g", b"other").unwrap();
+    disk.copy_path("/g", "/f").unwrap();
+    assert_eq!(numbers(&disk, "/f"), [1, 2]);
+    assert_eq!(disk.read_version("/f", 1).unwrap(), b"one");
+    assert_eq!(disk.read_version("/f", 2).unwrap(), b"two");
+
+    // Writes in place change the file without keeping anything
+    disk.write_at(f, 0, b"O").unwrap();
+    assert_eq!(numbers(&disk, "/f"), [1, 2]);
+
+    // Through a shared disk as well
+    let shared = SharedDisk::new(disk);
+    shared.write_file("/f", b"three").unwrap();
+    let mut disk = shared.into_disk();
+    assert_eq!(disk.read_version("/f", 3).unwrap(), b"Other");
+    assert_clean(&mut disk);
+}
+
+#[test]
+fn removed_files_go_to_the_trash() {
+    let image = TempImage::new("history-trash");
+    let mut disk = setup(&image, 8 * 1024 * 1024, versions(2));
+    let perms = Permissions::new(true, true, true);
+    disk.create_directory_at("/docs", perms).unwrap();
+    disk.write_file_at("/docs/a", &blocks(2, 1)).unwrap();
+    disk.write_file_at("/docs/a", &blocks(2, 2)).unwrap();
+    disk.create_symlink("/docs/a", "/link").unwrap();
+    disk.write_file_at("/b", b"b").unwrap();
+    disk.create_hard_link("/b", "/b2").unwrap();
+
+    disk.remove_path("/docs/a").unwrap();
+    disk.remove_path("/link").unwrap();
+    // A name that is not the last one is just removed
+    disk.remove_path("/b2").unwrap();
+    disk.remove_tree("/docs").unwrap();
+    let trash = disk.list_trash().unwrap();
+    let listed: Vec<(&str, FileType)> = trash.iter().map(|e| (e.path.as_str(), e.file_type)).collect();
+    assert_eq!(listed, [("/docs/a", FileType::File), ("/link", FileType::Symlink)]);
+    assert_eq!(trash[0].size, 2 * BLOCK as u64);
+    assert!(matches!(disk.lookup_path("/docs"), Err(FsError::FileNotFound(_))));
+
+    // Undeleting makes the directories again, and the versions come back
+    // with the file
+    disk.undelete("/docs/a").unwrap();
+    assert_eq!(disk.read_file_at("/docs/a").unwrap(), blocks(2, 2));
+    assert_eq!(disk.read_version("/docs/a", 1).unwrap(), blocks(2, 1));
+    disk.undelete("/link").unwrap();
+    assert_eq!(disk.read_link("/link").unwrap(), "/docs/a");
+    assert!(disk.list_trash().unwrap().is_empty());
+    assert!(matches!(disk.undelete("/link"), Err(FsError::FileNotFound(_))));
+
+    // Only the most recent file deleted from a path comes back, and not
+    // over one there now
+    disk.write_file_at("/c", b"first").unwrap();
+    disk.remove_path("/c").unwrap();
+    disk.write_file_at("/c", b"second").unwrap();
+    disk.remove_path("/c").unwrap();
+    disk.write_file_at("/c", b"third").unwrap();
+    assert!(matches!(disk.undelete("/c"), Err(FsError::AlreadyExists(_))));
+    disk.remove_path("/c").unwrap();
+    disk.undelete("/c").unwrap();
+    assert_eq!(disk.read_file_at("/c").unwrap(), b"third");
+    assert_clean(&mut disk);
+
+    // Removing from the trash deletes for good, dropping the versions too
+    let shared = SharedDisk::new(disk);
+    shared.remove("/docs/a").unwrap();
+    let mut disk = shared.into_disk();
+    let free = disk.free_blocks_count();
+    disk.empty_trash().unwrap();
+    assert!(disk.list_trash().unwrap().is_empty());
+    assert!(disk.free_blocks_count() >= free + 4);
+    assert_eq!(disk.history_blocks().unwrap(), 0);
+    assert_clean(&mut disk);
+}
+
+#[test]
+fn history_is_pruned_by_age() {
+    let image = TempImage::new("history-age");
+    let mut disk = setup(&image, 8 * 1024 * 1024, HistoryPolicy { versions: 10, max_age: Some(1), max_blocks: None });
+    disk.write_file_at("/f", b"old").unwrap();
+    disk.write_file_at("/f", b"new").unwrap();
+    disk.write_file_at("/gone", b"gone").unwrap();
+    disk.remove_path("/gone").unwrap();
+    assert_eq!(numbers(&disk, "/f"), [1]);
+
+    // Kept while younger than the limit, dropped with the next change
+    // once older
+    thread::sleep(Duration::from_millis(2100));
+    disk.write_file_at("/f", b"newer").unwrap();
+    assert_eq!(numbers(&disk, "/f"), [2]);
+    assert!(disk.list_trash().unwrap().is_empty());
+    assert_clean(&mut disk);
+}
+
+#[test]
+fn history_is_pruned_by_space() {
+    let image = TempImage::new("history-space");
+    let policy = HistoryPolicy { versions: 10, max_age: None, max_blocks: Some(7) };
+    let mut disk = setup(&image, 8 * 1024 * 1024, policy);
+    for seed in 1..=5 {
+        disk.write_file_at("/f", &blocks(3, seed)).unwrap();
+    }
+    // Two versions of three blocks fit, the oldest go
+    assert_eq!(numbers(&disk, "/f"), [3, 4]);
+    assert!(disk.history_blocks().unwrap() <= 7);
+
+    // A deleted file counts as well
+    disk.remove_path("/f").unwrap();
+    assert!(disk.history_blocks().unwrap() <= 7);
+
+    // Lowering the limits prunes at once
+    disk.set_history_policy(HistoryPolicy { max_blocks: Some(1), ..policy }).unwrap();
+    assert!(disk.list_trash().unwrap().is_empty());
+    disk.set_history_policy(HistoryPolicy::default()).unwrap();
+    assert!(disk.lookup_path("/.versions").is_ok());
+    assert!(disk.list_directory_at("/.versions").unwrap().is_empty());
+    assert_clean(&mut disk);
+}
+
+#[test]
+fn history_makes_way_when_the_disk_is_full() {
+    let image = TempImage::new("history-full");
+    let mut disk = setup(&image, 2 * 1024 * 1024, versions(5));
+    let room = disk.free_blocks_count() as usize;
+
+    // Data filling most of the disk, replaced and then deleted, so that
+    // only history holds it
+    let big = room * 2 / 3;
+    disk.write_file_at("/big", &blocks(big, 1)).unwrap();
+    disk.write_file_at("/big", b"small").unwrap();
+    disk.remove_path("/big").unwrap();
+    assert!(disk.history_blocks().unwrap() >= big as u64);
+
+    // A write that needs the space drops history to get it
+    disk.write_file_at("/next", &blocks(big, 2)).unwrap();
+    assert_eq!(disk.read_file_at("/next").unwrap(), blocks(big, 2));
+    assert!(disk.list_trash().unwrap().is_empty());
+    assert_clean(&mut disk);
+
+    // With nothing left to drop the disk is full as usual
+    let result = disk.write_file_at("/more", &blocks(big, 3));
+    assert!(matches!(result, Err(FsError::DiskFull)), "{:?}", result);
+    assert_eq!(disk.read_file_at("/next").unwrap(), blocks(big, 2));
+}
+
+#[test]
+fn threads_keep_history_side_by_side() {
+    let image = TempImage::new("history-threads");
+    let disk = SharedDisk::new(setup(&image, 8 * 1024 * 1024, versions(3)));
+    thread::scope(|scope| {
+        for t in 0..8u8 {
+            let disk = &disk;
+            scope.spawn(move || {
+                let path = format!("/f{}", t);
+                for round in 0..30u8 {
+                    disk.write_file(&path, &blocks(1, t * 16 + round)).unwrap();
+                }
+                disk.remove(&path).unwrap();
+            });
+        }
+    });
+
+    let mut disk = disk.into_disk();
+    let mut trashed: Vec<String> = disk.list_trash().unwrap().into_iter().map(|e| e.path).collect();
+    trashed.sort();
+    let expected: Vec<String> = (0..8).map(|t| format!("/f{}", t)).collect();
+    assert_eq!(trashed, expected);
+    for t in 0..8u8 {
+        let path = format!("/f{}", t);
+        disk.undelete(&path).unwrap();
+        assert_eq!(numbers(&disk, &path), [27, 28, 29]);
+        assert_eq!(disk.read_version(&path, 29).unwrap(), blocks(1, t * 16 + 28));
+    }
+    assert_clean(&mut disk);
+}
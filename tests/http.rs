@@ -0,0 +1,349 @@
+#![cfg(feature = "http")]
+
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    error::FsError,
+    faulty::FaultyDevice,
+    http::HttpServer,
+    serialization::Permissions,
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+use std::fs::OpenOptions;
+use std::io::{self, Cursor, Read, Write};
+
+/// One side of a connection: reads the request given, keeps what is
+/// written back
+struct Connection {
+    request: Cursor<Vec<u8>>,
+    response: Vec<u8>,
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.request.read(buf)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.response.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A parsed response
+struct Reply {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+fn parse(raw: &[u8]) -> Reply {
+    let end = raw.windows(4).position(|w| w == b"\r\n\r\n").expect("no end of headers");
+    let head = std::str::from_utf8(&raw[..end]).unwrap();
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+    let headers = lines
+        .map(|line| {
+            let (name, value) = line.split_once(':').unwrap();
+            (name.to_string(), value.trim().to_string())
+        })
+        .collect();
+    Reply { status, headers, body: raw[end + 4..].to_vec() }
+}
+
+/// Send one raw request, returning the result of serving it and the raw
+/// response
+fn exchange_raw(server: &mut HttpServer, request: Vec<u8>) -> (Result<(), FsError>, Vec<u8>) {
+    let mut connection = Connection { request: Cursor::new(request), response: Vec::new() };
+    let result = server.serve_connection(&mut connection);
+    (result, connection.response)
+}
+
+fn request(server: &mut HttpServer, method: &str, path: &str, headers: &[(&str, &str)], body: &[u8]) -> Reply {
+    let mut raw = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", method, path);
+    for (name, value) in headers {
+        raw.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if !body.is_empty() {
+        raw.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    raw.push_str("\r\n");
+    let mut raw = raw.into_bytes();
+    raw.extend_from_slice(body);
+    let (result, response) = exchange_raw(server, raw);
+    result.unwrap();
+    parse(&response)
+}
+
+fn contents(size: usize, seed: u8) -> Vec<u8> {
+    (0..size).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+/// A fresh image holding `/a.txt`, `/docs/b.txt` and `/docs/sub`
+fn setup(image: &TempImage) -> VirtualDisk {
+    let options = FormatOptions {
+        size: 8 * 1024 * 1024,
+        ..FormatOptions::default()
+    };
+    let disk = VirtualDisk::format(image.path(), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+    let perms = Permissions::new(true, true, true);
+    disk.create_directory_at("/docs", perms).unwrap();
+    disk.create_directory_at("/docs/sub", perms).unwrap();
+    disk.write_file_at("/a.txt", &contents(10_000, 1)).unwrap();
+    disk.write_file_at("/docs/b.txt", b"bee").unwrap();
+    disk
+}
+
+fn assert_clean(disk: &mut VirtualDisk) {
+    let report = disk.fsck().unwrap();
+    assert!(report.is_clean(), "fsck: {:?}", report.issues);
+}
+
+#[test]
+fn get_whole_files_and_ranges() {
+    let image = TempImage::new("http-get");
+    let mut server = HttpServer::new(setup(&image), false);
+    let a = contents(10_000, 1);
+
+    let reply = request(&mut server, "GET", "/a.txt", &[], b"");
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.header("Content-Length"), Some("10000"));
+    assert_eq!(reply.header("Accept-Ranges"), Some("bytes"));
+    assert_eq!(reply.body, a);
+
+    let reply = request(&mut server, "GET", "/a.txt", &[("Range", "bytes=100-4999")], b"");
+    assert_eq!(reply.status, 206);
+    assert_eq!(reply.header("Content-Range"), Some("bytes 100-4999/10000"));
+    assert_eq!(reply.body, a[100..5000]);
+
+    // Open-ended and suffix ranges, the latter longer than the file
+    let reply = request(&mut server, "GET", "/a.txt", &[("Range", "bytes=9990-")], b"");
+    assert_eq!((reply.status, &reply.body[..]), (206, &a[9990..]));
+    let reply = request(&mut server, "GET", "/a.txt", &[("Range", "bytes=-20000")], b"");
+    assert_eq!(reply.header("Content-Range"), Some("bytes 0-9999/10000"));
+    assert_eq!(reply.body, a);
+
+    let reply = request(&mut server, "GET", "/a.txt", &[("Range", "bytes=10000-")], b"");
+    assert_eq!(reply.status, 416);
+    assert_eq!(reply.header("Content-Range"), Some("bytes */10000"));
+
+    // HEAD sends the headers alone
+    let reply = request(&mut server, "HEAD", "/a.txt", &[("Range", "bytes=0-9")], b"");
+    assert_eq!((reply.status, reply.header("Content-Length")), (206, Some("10")));
+    assert!(reply.body.is_empty());
+
+    // Directories are listed, under a path ending in a slash
+    let reply = request(&mut server, "GET", "/docs", &[], b"");
+    assert_eq!((reply.status, reply.header("Location")), (301, Some("/docs/")));
+    let reply = request(&mut server, "GET", "/docs/", &[], b"");
+    assert_eq!(reply.status, 200);
+    assert!(reply.text().contains("<a href=\"b.txt\">b.txt</a>"));
+    assert!(reply.text().contains("<a href=\"sub/\">sub/</a>"));
+}
+
+#[test]
+fn propfind_depths() {
+    let image = TempImage::new("http-propfind");
+    let mut server = HttpServer::new(setup(&image), false);
+
+    let reply = request(&mut server, "PROPFIND", "/docs", &[("Depth", "0")], b"");
+    assert_eq!(reply.status, 207);
+    assert!(reply.text().contains("<D:href>/docs/</D:href>"));
+    assert_eq!(reply.text().matches("<D:response>").count(), 1);
+
+    let reply = request(&mut server, "PROPFIND", "/docs/", &[("Depth", "1")], b"");
+    let text = reply.text();
+    assert_eq!(text.matches("<D:response>").count(), 3);
+    assert!(text.contains("<D:href>/docs/b.txt</D:href>"));
+    assert!(text.contains("<D:getcontentlength>3</D:getcontentlength>"));
+    assert!(text.contains("<D:href>/docs/sub/</D:href>"));
+
+    // The depth defaults to infinity, which is refused
+    assert_eq!(request(&mut server, "PROPFIND", "/docs/", &[], b"").status, 403);
+    assert_eq!(request(&mut server, "PROPFIND", "/docs/", &[("Depth", "infinity")], b"").status, 403);
+    assert_eq!(request(&mut server, "PROPFIND", "/missing", &[("Depth", "0")], b"").status, 404);
+}
+
+#[test]
+fn errors_map_to_statuses() {
+    let image = TempImage::new("http-statuses");
+    let mut server = HttpServer::new(setup(&image), true);
+
+    assert_eq!(request(&mut server, "GET", "/missing", &[], b"").status, 404);
+    assert_eq!(request(&mut server, "GET", "/a.txt/x", &[], b"").status, 409);
+    assert_eq!(request(&mut server, "PUT", "/docs", &[], b"data").status, 409);
+    assert_eq!(request(&mut server, "PUT", "/nowhere/file", &[], b"data").status, 404);
+    assert_eq!(request(&mut server, "MKCOL", "/docs", &[], b"").status, 405);
+    assert_eq!(request(&mut server, "MKCOL", "/a.txt/x", &[], b"").status, 409);
+    assert_eq!(request(&mut server, "GET", "/bad%zz", &[], b"").status, 400);
+    assert_eq!(request(&mut server, "PATCH", "/a.txt", &[], b"").status, 405);
+
+    for (error, status) in [
+        (FsError::FileNotFound("x".into()), 404),
+        (FsError::InvalidPath("x".into()), 400),
+        (FsError::PermissionDenied("x".into()), 403),
+        (FsError::AlreadyExists("x".into()), 409),
+        (FsError::DirectoryNotEmpty("x".into()), 409),
+        (FsError::InvalidOffsetOrSize { offset: 1, size: 0 }, 416),
+        (FsError::NotSupported("x".into()), 501),
+        (FsError::DiskFull, 507),
+        (FsError::QuotaExceeded("x".into()), 507),
+        (FsError::CorruptedFileSystem("x".into()), 500),
+    ] {
+        assert_eq!(error.http_status(), status, "{:?}", error);
+    }
+}
+
+#[test]
+fn read_only_refuses_changes() {
+    let image = TempImage::new("http-read-only");
+    let mut server = HttpServer::new(setup(&image), false);
+
+    let reply = request(&mut server, "OPTIONS", "/", &[], b"");
+    assert_eq!(reply.header("Allow"), Some("OPTIONS, GET, HEAD, PROPFIND"));
+    for (method, path, headers) in [
+        ("PUT", "/a.txt", vec![]),
+        ("MKCOL", "/new", vec![]),
+        ("DELETE", "/a.txt", vec![]),
+        ("MOVE", "/a.txt", vec![("Destination", "/moved.txt")]),
+    ] {
+        let reply = request(&mut server, method, path, &headers, if method == "PUT" { b"new" } else { b"" });
+        assert_eq!(reply.status, 405, "{}", method);
+        assert_eq!(reply.header("Allow"), Some("OPTIONS, GET, HEAD, PROPFIND"));
+    }
+
+    let disk = server.into_disk();
+    assert_eq!(disk.read_file_at("/a.txt").unwrap(), contents(10_000, 1));
+    assert!(matches!(disk.lookup_path("/new"), Err(FsError::FileNotFound(_))));
+    assert!(matches!(disk.lookup_path("/moved.txt"), Err(FsError::FileNotFound(_))));
+}
+
+#[test]
+fn put_and_mkcol() {
+    let image = TempImage::new("http-put");
+    let mut server = HttpServer::new(setup(&image), true);
+
+    assert_eq!(request(&mut server, "PUT", "/new.txt", &[], b"fresh").status, 201);
+    assert_eq!(request(&mut server, "PUT", "/a.txt", &[], b"replaced").status, 204);
+    assert_eq!(request(&mut server, "MKCOL", "/made", &[], b"").status, 201);
+
+    // A body that breaks off leaves the file as it was
+    let raw = b"PUT /docs/b.txt HTTP/1.1\r\nContent-Length: 100\r\n\r\nshort".to_vec();
+    let (result, response) = exchange_raw(&mut server, raw);
+    assert!(matches!(result, Err(FsError::Io(_))), "{:?}", result);
+    assert!(response.is_empty());
+
+    let mut disk = server.into_disk();
+    assert_eq!(disk.read_file_at("/new.txt").unwrap(), b"fresh");
+    assert_eq!(disk.read_file_at("/a.txt").unwrap(), b"replaced");
+    assert_eq!(disk.read_file_at("/docs/b.txt").unwrap(), b"bee");
+    assert!(disk.list_directory_at("/made").unwrap().is_empty());
+    assert_clean(&mut disk);
+}
+
+#[test]
+fn move_and_delete() {
+    let image = TempImage::new("http-move");
+    let mut disk = setup(&image);
+    disk.create_directory_at("/proj", Permissions::new(true, true, true)).unwrap();
+    disk.set_project_tree("/proj", 1).unwrap();
+    disk.write_file_at("/proj/kept", b"kept").unwrap();
+    disk.create_hard_link("/a.txt", "/linked").unwrap();
+    let mut server = HttpServer::new(disk, true);
+
+    // A move that fails leaves the destination in place: here a file with
+    // a second name cannot enter a project tree
+    let reply = request(&mut server, "MOVE", "/linked", &[("Destination", "/proj/kept")], b"");
+    assert_eq!(reply.status, 409);
+
+    // Onto a new name, then over an existing file
+    let reply = request(&mut server, "MOVE", "/a.txt", &[("Destination", "http://localhost/c.txt")], b"");
+    assert_eq!(reply.status, 201);
+    let reply = request(&mut server, "MOVE", "/docs/b.txt", &[("Destination", "/c.txt"), ("Overwrite", "F")], b"");
+    assert_eq!(reply.status, 412);
+    let reply = request(&mut server, "MOVE", "/docs/b.txt", &[("Destination", "/c.txt")], b"");
+    assert_eq!(reply.status, 204);
+
+    // Onto itself, under any spelling, changes nothing
+    for destination in ["/c.txt", "/docs/../c.txt", "http://localhost/./c.txt"] {
+        let reply = request(&mut server, "MOVE", "/c.txt", &[("Destination", destination)], b"");
+        assert_eq!(reply.status, 403, "{}", destination);
+    }
+    // Nor into itself or over its parent
+    let reply = request(&mut server, "MOVE", "/docs", &[("Destination", "/docs/sub/docs")], b"");
+    assert_eq!(reply.status, 409);
+    let reply = request(&mut server, "MOVE", "/docs/sub", &[("Destination", "/docs")], b"");
+    assert_eq!(reply.status, 409);
+
+    let reply = request(&mut server, "MOVE", "/missing", &[("Destination", "/c.txt")], b"");
+    assert_eq!(reply.status, 404);
+
+    // A directory may replace a file
+    let reply = request(&mut server, "MOVE", "/docs/sub", &[("Destination", "/c.txt")], b"");
+    assert_eq!(reply.status, 204);
+
+    let reply = request(&mut server, "GET", "/c.txt/", &[], b"");
+    assert_eq!(reply.status, 200);
+
+    assert_eq!(request(&mut server, "DELETE", "/c.txt", &[], b"").status, 204);
+    assert_eq!(request(&mut server, "DELETE", "/c.txt", &[], b"").status, 404);
+
+    let mut disk = server.into_disk();
+    assert_eq!(disk.read_file_at("/proj/kept").unwrap(), b"kept");
+    assert_eq!(disk.read_file_at("/linked").unwrap(), contents(10_000, 1));
+    assert!(disk.list_directory_at("/docs").unwrap().is_empty());
+    // Nothing moved aside was left behind
+    let mut names: Vec<String> = disk.list_directory_at("/").unwrap().into_iter().map(|e| e.name).collect();
+    names.sort();
+    assert_eq!(names, ["docs", "linked", "proj"]);
+    assert_clean(&mut disk);
+}
+
+#[test]
+fn failure_mid_body_aborts_the_connection() {
+    let image = TempImage::new("http-abort");
+    let options = FormatOptions {
+        size: 8 * 1024 * 1024,
+        ..FormatOptions::default()
+    };
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(image.path()).unwrap();
+    let device = FaultyDevice::new(file, options.block_size);
+    let disk = VirtualDisk::format_device(Box::new(device.clone()), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+    let data = contents(200 * 1024, 2);
+    disk.write_file_at("/big.bin", &data).unwrap();
+    let inode = disk.stat_path("/big.bin").unwrap();
+    // A block in the second chunk of the body
+    device.fail_block(disk.file_blocks(&inode).unwrap()[20]);
+
+    let mut server = HttpServer::new(disk, false);
+    let (result, response) = exchange_raw(&mut server, b"GET /big.bin HTTP/1.1\r\n\r\n".to_vec());
+    assert!(matches!(result, Err(FsError::Io(_))), "{:?}", result);
+
+    // Only the first response went out, cut short
+    let reply = parse(&response);
+    assert_eq!(reply.status, 200);
+    assert_eq!(response.windows(8).filter(|w| w == b"HTTP/1.1").count(), 1);
+    assert!(reply.body.len() < data.len());
+    assert_eq!(reply.body, data[..reply.body.len()]);
+}
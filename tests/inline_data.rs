@@ -0,0 +1,128 @@
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    serialization::{Permissions, INLINE_DATA_SIZE},
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+
+const BLOCK: usize = 4096;
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+fn setup(image: &TempImage) -> VirtualDisk {
+    let options = FormatOptions {
+        size: 8 * 1024 * 1024,
+        inline_data: true,
+        ..FormatOptions::default()
+    };
+    let disk = VirtualDisk::format(image.path(), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+    disk
+}
+
+fn is_inline(disk: &VirtualDisk, inode_block: u64) -> bool {
+    disk.read_inode(inode_block).unwrap().is_inline()
+}
+
+fn assert_clean(disk: &mut VirtualDisk) {
+    let report = disk.fsck().unwrap();
+    assert!(report.is_clean(), "fsck: {:?}", report.issues);
+}
+
+#[test]
+fn inline_is_off_by_default() {
+    let image = TempImage::new("inline-default");
+    let options = FormatOptions { size: 8 * 1024 * 1024, ..FormatOptions::default() };
+    let disk = VirtualDisk::format(image.path(), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+    let small = disk.write_file_at("/small", b"tiny").unwrap();
+    assert!(!is_inline(&disk, small));
+    assert_eq!(disk.read_file(small).unwrap(), b"tiny");
+}
+
+#[test]
+fn growing_files_move_to_blocks() {
+    let image = TempImage::new("inline-grow");
+    let mut disk = setup(&image);
+    let free = disk.free_blocks_count();
+    let f = disk.write_file_at("/f", &pattern(INLINE_DATA_SIZE, 1)).unwrap();
+    assert!(is_inline(&disk, f));
+    assert_eq!(disk.free_blocks_count(), free);
+
+    // A write in place past what the inode holds
+    disk.write_at(f, INLINE_DATA_SIZE as u64, b"more").unwrap();
+    assert!(!is_inline(&disk, f));
+    assert_eq!(disk.free_blocks_count(), free - 1);
+    let mut expected = pattern(INLINE_DATA_SIZE, 1);
+    expected.extend_from_slice(b"more");
+    assert_eq!(disk.read_file(f).unwrap(), expected);
+    assert_clean(&mut disk);
+
+    // And a whole new contents too large for it
+    let g = disk.write_file_at("/g", b"small").unwrap();
+    assert!(is_inline(&disk, g));
+    disk.write_file(g, &pattern(2 * BLOCK, 2)).unwrap();
+    assert!(!is_inline(&disk, g));
+    assert_eq!(disk.read_file(g).unwrap(), pattern(2 * BLOCK, 2));
+    assert_clean(&mut disk);
+}
+
+#[test]
+fn shrinking_files_move_back_inline() {
+    let image = TempImage::new("inline-shrink");
+    let mut disk = setup(&image);
+    let free = disk.free_blocks_count();
+    let f = disk.write_file_at("/f", &pattern(3 * BLOCK, 3)).unwrap();
+    assert!(!is_inline(&disk, f));
+
+    // Cutting the file down to what fits gives its blocks back
+    disk.write_file(f, &pattern(100, 4)).unwrap();
+    assert!(is_inline(&disk, f));
+    assert_eq!(disk.free_blocks_count(), free);
+    assert_eq!(disk.read_file(f).unwrap(), pattern(100, 4));
+
+    // In place changes and reads inside the inode
+    disk.write_at(f, 10, b"inside").unwrap();
+    assert!(is_inline(&disk, f));
+    let mut buf = [0u8; 6];
+    assert_eq!(disk.read_at(f, 10, &mut buf).unwrap(), 6);
+    assert_eq!(&buf, b"inside");
+    disk.write_file(f, b"").unwrap();
+    assert!(disk.read_file(f).unwrap().is_empty());
+    assert_clean(&mut disk);
+}
+
+#[test]
+fn directories_move_between_inode_and_blocks() {
+    let image = TempImage::new("inline-dirs");
+    let mut disk = setup(&image);
+    let perms = Permissions::new(true, true, true);
+    let dir = disk.create_directory_at("/d", perms).unwrap();
+    assert!(is_inline(&disk, dir));
+
+    // Entries are added inline until the next no longer fits
+    let mut names = Vec::new();
+    while is_inline(&disk, dir) {
+        let name = format!("entry-{:02}", names.len());
+        disk.write_file_at(&format!("/d/{}", name), name.as_bytes()).unwrap();
+        names.push(name);
+    }
+    assert!(names.len() > 1);
+    let mut listed: Vec<String> = disk.list_directory_at("/d").unwrap().into_iter().map(|e| e.name).collect();
+    listed.retain(|name| name != "." && name != "..");
+    listed.sort();
+    assert_eq!(listed, names);
+    assert_clean(&mut disk);
+
+    // Removing one brings the rest back into the inode
+    let last = names.pop().unwrap();
+    disk.remove_path(&format!("/d/{}", last)).unwrap();
+    assert!(is_inline(&disk, dir));
+    for name in &names {
+        assert_eq!(disk.read_file_at(&format!("/d/{}", name)).unwrap(), name.as_bytes());
+    }
+    assert_clean(&mut disk);
+}
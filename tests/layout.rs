@@ -0,0 +1,161 @@
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    layout::{BlockKind, DiskLayout, FileLayout},
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+
+const BLOCK: usize = 4096;
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+fn blocks_of(disk: &VirtualDisk, path: &str) -> Vec<u64> {
+    disk.file_blocks(&disk.stat_path(path).unwrap()).unwrap()
+}
+
+fn file<'a>(layout: &'a DiskLayout, name: &str) -> &'a FileLayout {
+    layout.files.iter().find(|f| f.name.as_deref() == Some(name)).unwrap()
+}
+
+/// An image with ten files of three blocks and `/a` and `/b` grown in turns
+/// after them, then holes of three and six blocks punched between the ten;
+/// returns the first block of each hole
+fn setup(image: &TempImage) -> (VirtualDisk, u64, u64) {
+    let options = FormatOptions { size: 8 * 1024 * 1024, ..FormatOptions::default() };
+    let disk = VirtualDisk::format(image.path(), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+    for i in 0..10 {
+        disk.write_file_at(&format!("/s{}", i), &pattern(3 * BLOCK, i)).unwrap();
+    }
+    let a = disk.write_file_at("/a", b"").unwrap();
+    let b = disk.write_file_at("/b", b"").unwrap();
+    for round in 0..4u64 {
+        let offset = round * 2 * BLOCK as u64;
+        disk.write_at(a, offset, &pattern(2 * BLOCK, 0xA)).unwrap();
+        disk.write_at(b, offset, &pattern(2 * BLOCK, 0xB)).unwrap();
+    }
+
+    let (small, large) = (blocks_of(&disk, "/s3")[0], blocks_of(&disk, "/s5")[0]);
+    assert_eq!(blocks_of(&disk, "/s6")[0], large + 3);
+    for name in ["/s3", "/s5", "/s6"] {
+        disk.remove_path(name).unwrap();
+    }
+    (disk, small, large)
+}
+
+#[test]
+fn free_extents_and_histogram() {
+    let image = TempImage::new("layout-free");
+    let (mut disk, small, large) = setup(&image);
+    let layout = disk.disk_layout().unwrap();
+
+    let free = layout.free_extents();
+    assert!(free.contains(&(small, 3)), "{:?}", free);
+    assert!(free.contains(&(large, 6)), "{:?}", free);
+    assert_eq!(free.iter().map(|&(_, len)| len).sum::<u64>(), disk.free_blocks_count());
+
+    let largest = free.iter().map(|&(_, len)| len).max().unwrap();
+    assert_eq!(layout.largest_free_run(), largest);
+    assert_eq!(largest, disk.allocation_stats().largest_free_run);
+
+    // Power-of-two buckets, each counting the runs of its sizes
+    let histogram = layout.free_extent_histogram();
+    for (i, bucket) in histogram.iter().enumerate() {
+        assert_eq!((bucket.min, bucket.max), (1 << i, (2 << i) - 1));
+        let runs: Vec<u64> = free.iter().map(|&(_, len)| len).filter(|len| (bucket.min..=bucket.max).contains(len)).collect();
+        assert_eq!((bucket.runs, bucket.blocks), (runs.len() as u64, runs.iter().sum()));
+    }
+    assert!(histogram[1].runs >= 1 && histogram[2].runs >= 1);
+    assert_eq!(histogram.last().unwrap().max, (largest + 1).next_power_of_two() - 1);
+}
+
+#[test]
+fn files_are_counted_in_fragments() {
+    let image = TempImage::new("layout-files");
+    let (mut disk, _, _) = setup(&image);
+    let layout = disk.disk_layout().unwrap();
+
+    let s0 = file(&layout, "s0");
+    assert_eq!((s0.data_blocks, s0.metadata_blocks, s0.fragments), (3, 0, 1));
+    for name in ["a", "b"] {
+        let grown = file(&layout, name);
+        assert_eq!(grown.data_blocks, 8);
+        assert_eq!(grown.fragments, 4, "{}", name);
+        for &block in &blocks_of(&disk, &format!("/{}", name)) {
+            let info = layout.blocks[block as usize];
+            assert_eq!((info.kind, info.owner), (BlockKind::Data, Some(grown.inode_block)));
+        }
+    }
+    assert!(layout.files.iter().all(|f| f.name.as_deref() != Some("s3")));
+    assert_eq!(file(&layout, "/").label(), "/");
+
+    let report = layout.render_report();
+    assert!(report.contains("fragments=4"));
+    assert!(report.contains(&format!("Largest free run: {} blocks", layout.largest_free_run())));
+    let map = layout.render_ascii(64, 1, false);
+    assert!(map.contains("b (8 blocks, 4 fragments)"));
+}
+
+#[test]
+fn json_export() {
+    let image = TempImage::new("layout-json");
+    let (mut disk, small, _) = setup(&image);
+    let layout = disk.disk_layout().unwrap();
+    let json: serde_json::Value = serde_json::from_str(&layout.to_json()).unwrap();
+
+    assert_eq!(json["block_size"], BLOCK as u64);
+    assert_eq!(json["total_blocks"], layout.total_blocks());
+    assert_eq!(json["largest_free_run"], layout.largest_free_run());
+
+    // Regions cover the disk in order, without gaps
+    let regions = json["regions"].as_array().unwrap();
+    let mut next = 0;
+    for region in regions {
+        assert_eq!(region["start"], next);
+        next += region["length"].as_u64().unwrap();
+    }
+    assert_eq!(next, layout.total_blocks());
+    let hole = regions.iter().find(|r| r["start"] == small).unwrap();
+    assert_eq!((hole["kind"].as_str(), hole["length"].as_u64()), (Some("free"), Some(3)));
+    assert!(hole["owner"].is_null());
+
+    let files = json["files"].as_array().unwrap();
+    assert_eq!(files.len(), layout.files.len());
+    let a = files.iter().find(|f| f["name"] == "a").unwrap();
+    assert_eq!((a["type"].as_str(), a["data_blocks"].as_u64(), a["fragments"].as_u64()), (Some("File"), Some(8), Some(4)));
+
+    let histogram = json["free_extent_histogram"].as_array().unwrap();
+    assert_eq!(histogram.len(), layout.free_extent_histogram().len());
+    assert_eq!(histogram[1]["runs"], layout.free_extent_histogram()[1].runs);
+}
+
+#[test]
+fn svg_export() {
+    let image = TempImage::new("layout-svg");
+    let (mut disk, _, _) = setup(&image);
+    let layout = disk.disk_layout().unwrap();
+    let columns = 64;
+    let svg = layout.to_svg(columns);
+
+    let rows = layout.total_blocks().div_ceil(columns);
+    assert!(svg.starts_with(&format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}""#,
+        columns * 4,
+        rows * 4
+    )));
+    assert!(svg.ends_with("</svg>\n"));
+
+    // One rectangle per region, and one more for each row a region crosses
+    let pieces: u64 = layout
+        .regions()
+        .iter()
+        .map(|r| (r.start + r.length - 1) / columns - r.start / columns + 1)
+        .sum();
+    assert_eq!(svg.matches("<rect ").count() as u64, pieces);
+    let a = file(&layout, "a").inode_block;
+    assert!(svg.contains(&format!("<title>data (inode@{})", a)));
+    assert!(svg.contains("<title>superblock 0-0</title>"));
+}
@@ -0,0 +1,172 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    error::FsError,
+    extent::HOLE,
+    nbd::{NbdClient, NbdExport, NbdServer},
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::thread;
+
+const FILE: &str = "/disk.img";
+const FILE_SIZE: usize = 64 * 1024;
+
+/// A fresh image holding `FILE`, filled with a pattern
+fn setup(image: &TempImage) -> VirtualDisk {
+    let options = FormatOptions {
+        size: 8 * 1024 * 1024,
+        ..FormatOptions::default()
+    };
+    let disk = VirtualDisk::format(image.path(), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+    disk.write_file_at(FILE, &pattern()).unwrap();
+    disk
+}
+
+fn pattern() -> Vec<u8> {
+    (0..FILE_SIZE).map(|i| (i % 251) as u8).collect()
+}
+
+/// Serve one connection on a socket pair, running `client` against it
+fn with_client(server: NbdServer, client: impl FnOnce(NbdClient<UnixStream>)) -> VirtualDisk {
+    let (mut server_end, client_end) = UnixStream::pair().unwrap();
+    let handle = thread::spawn(move || {
+        let mut server = server;
+        server.serve_connection(&mut server_end).unwrap();
+        server.into_disk()
+    });
+    client(NbdClient::new(client_end).unwrap());
+    handle.join().unwrap()
+}
+
+/// Check that `result` failed with `errno` sent by the server
+fn expect_errno<T: std::fmt::Debug>(result: Result<T, FsError>, errno: i32) {
+    match result {
+        Err(FsError::Io(e)) => assert_eq!(e.raw_os_error(), Some(errno), "{}", e),
+        other => panic!("expected errno {}, got {:?}", errno, other),
+    }
+}
+
+#[test]
+fn raw_image_export() {
+    let image = TempImage::new("nbd-image");
+    let disk = setup(&image);
+    let size = disk.image_size();
+    let offset = size - 8192;
+
+    let disk = with_client(NbdServer::new(disk, NbdExport::Image, false), |mut client| {
+        assert_eq!(client.size(), size);
+        assert!(!client.is_read_only());
+        assert_eq!(client.read(0, 4).unwrap(), b"BPUS");
+
+        client.write(offset, &[0xAB; 4096]).unwrap();
+        assert_eq!(client.read(offset, 4096).unwrap(), vec![0xAB; 4096]);
+        client.flush().unwrap();
+        client.write_zeroes(offset, 1024).unwrap();
+        assert_eq!(client.read(offset, 2048).unwrap()[1020..1028], [0, 0, 0, 0, 0xAB, 0xAB, 0xAB, 0xAB]);
+        // Nothing to deallocate in the raw image
+        client.trim(offset, 4096).unwrap();
+        assert_eq!(client.read(offset + 1024, 4).unwrap(), [0xAB; 4]);
+
+        expect_errno(client.read(size - 10, 20), 22);
+        expect_errno(client.write(size - 10, &[0; 20]), 28);
+        client.disconnect().unwrap();
+    });
+
+    let mut data = vec![0u8; 4096];
+    disk.read_raw(offset, &mut data).unwrap();
+    assert_eq!(data[..1024], [0; 1024]);
+    assert_eq!(data[1024..], [0xAB; 3072]);
+}
+
+#[test]
+fn file_export() {
+    let image = TempImage::new("nbd-file");
+    let disk = setup(&image);
+    let mut expected = pattern();
+
+    let mut disk = with_client(NbdServer::new(disk, NbdExport::File(FILE.to_string()), false), |mut client| {
+        assert_eq!(client.size(), FILE_SIZE as u64);
+        assert_eq!(client.read(5000, 3000).unwrap(), expected[5000..8000]);
+
+        client.write_fua(100, b"hello").unwrap();
+        expected[100..105].copy_from_slice(b"hello");
+        client.flush().unwrap();
+
+        // Whole blocks become holes; partial blocks are zeroed
+        client.trim(8192, 8192).unwrap();
+        expected[8192..16384].fill(0);
+        client.write_zeroes(30000, 10000).unwrap();
+        expected[30000..40000].fill(0);
+        assert_eq!(client.read(0, FILE_SIZE as u32).unwrap(), expected);
+
+        // The device does not grow past the file
+        expect_errno(client.write(FILE_SIZE as u64 - 2, b"tail"), 28);
+        client.disconnect().unwrap();
+    });
+
+    assert_eq!(disk.read_file_at(FILE).unwrap(), expected);
+    let inode = disk.stat_path(FILE).unwrap();
+    let blocks = disk.file_blocks(&inode).unwrap();
+    assert_eq!(blocks[2..4], [HOLE, HOLE]);
+    assert!(blocks[8] == HOLE && blocks[7] != HOLE && blocks[9] != HOLE);
+    assert!(disk.fsck().unwrap().is_clean());
+}
+
+#[test]
+fn read_only_export_refuses_writes() {
+    let image = TempImage::new("nbd-read-only");
+    let disk = setup(&image);
+    let inode = disk.stat_path(FILE).unwrap();
+    let data_offset = disk.file_blocks(&inode).unwrap()[0] * disk.block_size();
+    drop(disk);
+
+    for (export, offset) in [(NbdExport::Image, data_offset), (NbdExport::File(FILE.to_string()), 0)] {
+        let disk = VirtualDisk::new(image.path()).unwrap();
+        with_client(NbdServer::new(disk, export, true), |mut client| {
+            assert!(client.is_read_only());
+            let before = client.read(offset, 4096).unwrap();
+            expect_errno(client.write(offset, &[0xFF; 4096]), 1);
+            expect_errno(client.trim(offset, 4096), 1);
+            expect_errno(client.write_zeroes(offset, 4096), 1);
+            assert_eq!(client.read(offset, 4096).unwrap(), before);
+            client.flush().unwrap();
+            client.disconnect().unwrap();
+        });
+    }
+
+    let disk = VirtualDisk::new(image.path()).unwrap();
+    assert_eq!(disk.read_file_at(FILE).unwrap(), pattern());
+}
+
+#[test]
+fn server_keeps_accepting_after_a_failed_connection() {
+    let image = TempImage::new("nbd-listener");
+    let disk = setup(&image);
+    let socket = std::env::temp_dir().join(format!("fssim-test-nbd-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&socket);
+    let listener = UnixListener::bind(&socket).unwrap();
+
+    // The server runs until the test process exits
+    thread::spawn(move || {
+        let mut server = NbdServer::new(disk, NbdExport::File(FILE.to_string()), false);
+        server.serve_unix(&listener)
+    });
+
+    // A write larger than the server accepts ends the connection
+    let mut client = NbdClient::connect_unix(&socket).unwrap();
+    assert!(client.write(0, &vec![0u8; 33 * 1024 * 1024]).is_err());
+    drop(client);
+
+    // So does a client that goes away mid-handshake
+    drop(UnixStream::connect(&socket).unwrap());
+
+    let mut client = NbdClient::connect_unix(&socket).unwrap();
+    assert_eq!(client.read(0, 4096).unwrap(), pattern()[..4096]);
+    client.disconnect().unwrap();
+    let _ = std::fs::remove_file(&socket);
+}
@@ -0,0 +1,245 @@
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    error::FsError,
+    fsck::FsckIssueKind,
+    quota::{GracePeriods, QuotaKind, QuotaLimits},
+    serialization::Permissions,
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const BLOCK: usize = 4096;
+const USER: u32 = 1000;
+const GROUP: u32 = 100;
+
+fn blocks(count: usize, seed: u8) -> Vec<u8> {
+    (0..count * BLOCK).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+/// A fresh image with quotas on, creating files as `USER` in `GROUP`
+fn setup(image: &TempImage) -> VirtualDisk {
+    let options = FormatOptions {
+        size: 8 * 1024 * 1024,
+        inline_data: false,
+        ..FormatOptions::default()
+    };
+    let mut disk = VirtualDisk::format(image.path(), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+    disk.set_quota(QuotaKind::User, USER, QuotaLimits::default()).unwrap();
+    assert!(disk.is_quota_enabled());
+    disk.set_credentials(USER, GROUP);
+    disk
+}
+
+fn limits(block_soft: u64, block_hard: u64) -> QuotaLimits {
+    QuotaLimits { block_soft, block_hard, ..QuotaLimits::default() }
+}
+
+fn expect_exceeded<T: std::fmt::Debug>(result: Result<T, FsError>) {
+    assert!(matches!(result, Err(FsError::QuotaExceeded(_))), "expected QuotaExceeded, got {:?}", result);
+}
+
+fn used(disk: &VirtualDisk, kind: QuotaKind, id: u32) -> (u64, u64) {
+    let usage = disk.quota(kind, id);
+    (usage.blocks, usage.inodes)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Check that fsck finds the usage followed equal to a count from the
+/// inodes, and that recounting changes nothing
+fn assert_accounted(disk: &mut VirtualDisk) {
+    let report = disk.fsck().unwrap();
+    assert!(report.is_clean(), "fsck: {:?}", report.issues);
+    assert!(!report.issues.iter().any(|issue| issue.kind == FsckIssueKind::CountMismatch));
+    let followed = disk.quota_report();
+    disk.quota_recount().unwrap();
+    assert_eq!(disk.quota_report(), followed);
+}
+
+#[test]
+fn hard_block_limit() {
+    let image = TempImage::new("quota-hard-blocks");
+    let mut disk = setup(&image);
+    disk.set_quota(QuotaKind::User, USER, limits(0, 5)).unwrap();
+
+    disk.write_file_at("/a", &blocks(3, 1)).unwrap();
+    assert_eq!(used(&disk, QuotaKind::User, USER), (3, 1));
+
+    // A file that does not fit is not written, and nothing is charged
+    let b = disk.create_file_at("/b", Permissions::new(true, true, false)).unwrap();
+    expect_exceeded(disk.write_file(b, &blocks(3, 2)));
+    assert_eq!(disk.read_file(b).unwrap(), b"");
+    expect_exceeded(disk.append_file_at("/a", &blocks(3, 2)));
+    assert_eq!(disk.read_file_at("/a").unwrap(), blocks(3, 1));
+    assert_eq!(used(&disk, QuotaKind::User, USER), (3, 2));
+
+    // Up to the limit is fine, and rewriting a file in place is too
+    disk.write_file(b, &blocks(2, 2)).unwrap();
+    disk.write_file_at("/a", &blocks(3, 3)).unwrap();
+    assert_eq!(used(&disk, QuotaKind::User, USER), (5, 2));
+    expect_exceeded(disk.write_at(b, 2 * BLOCK as u64, b"x"));
+
+    // Freeing blocks makes room again
+    disk.remove_path("/a").unwrap();
+    disk.write_file(b, &blocks(5, 4)).unwrap();
+    assert_eq!(used(&disk, QuotaKind::User, USER), (5, 1));
+
+    // Other users are not limited
+    disk.set_credentials(USER + 1, GROUP);
+    disk.write_file_at("/c", &blocks(8, 5)).unwrap();
+    assert_eq!(used(&disk, QuotaKind::User, USER + 1), (8, 1));
+    assert_eq!(used(&disk, QuotaKind::Group, GROUP), (13, 2));
+    assert_accounted(&mut disk);
+
+    // The limits are kept in the image
+    drop(disk);
+    let mut disk = VirtualDisk::new(image.path()).unwrap();
+    disk.set_credentials(USER, GROUP);
+    assert_eq!(disk.quota(QuotaKind::User, USER).limits, limits(0, 5));
+    expect_exceeded(disk.write_file_at("/d", &blocks(1, 6)));
+}
+
+#[test]
+fn hard_inode_limit() {
+    let image = TempImage::new("quota-hard-inodes");
+    let mut disk = setup(&image);
+    let limits = QuotaLimits { inode_hard: 2, ..QuotaLimits::default() };
+    disk.set_quota(QuotaKind::User, USER, limits).unwrap();
+
+    let perms = Permissions::new(true, true, true);
+    disk.create_directory_at("/dir", perms).unwrap();
+    disk.create_file_at("/dir/a", perms).unwrap();
+    expect_exceeded(disk.create_file_at("/dir/b", perms));
+    expect_exceeded(disk.create_symlink("/dir/a", "/link"));
+    assert_eq!(disk.list_directory_at("/dir").unwrap().len(), 1);
+    assert_eq!(used(&disk, QuotaKind::User, USER).1, 2);
+
+    // A hard link takes no inode
+    disk.create_hard_link("/dir/a", "/dir/b").unwrap();
+    disk.remove_path("/dir/a").unwrap();
+    disk.remove_path("/dir/b").unwrap();
+    disk.create_file_at("/dir/c", perms).unwrap();
+    assert_accounted(&mut disk);
+}
+
+#[test]
+fn group_and_project_limits() {
+    let image = TempImage::new("quota-group-project");
+    let mut disk = setup(&image);
+    disk.create_directory_at("/proj", Permissions::new(true, true, true)).unwrap();
+    disk.set_project_tree("/proj", 7).unwrap();
+    disk.set_quota(QuotaKind::Group, GROUP, limits(0, 9)).unwrap();
+    disk.set_quota(QuotaKind::Project, 7, limits(0, 5)).unwrap();
+
+    // Files in the project tree count against it, as does the directory
+    // at its root; others do not
+    disk.write_file_at("/proj/a", &blocks(3, 1)).unwrap();
+    expect_exceeded(disk.write_file_at("/proj/b", &blocks(2, 2)));
+    disk.write_file_at("/outside", &blocks(2, 3)).unwrap();
+    // Like a write after open, the file is created before its data is
+    // refused
+    assert_eq!(disk.read_file_at("/proj/b").unwrap(), b"");
+    assert_eq!(used(&disk, QuotaKind::Project, 7), (1 + 3, 3));
+
+    // The group is shared by its users
+    disk.set_credentials(USER + 1, GROUP);
+    disk.write_file_at("/other", &blocks(3, 4)).unwrap();
+    expect_exceeded(disk.write_file_at("/more", &blocks(1, 5)));
+    assert_eq!(used(&disk, QuotaKind::Group, GROUP).0, 1 + 3 + 2 + 3);
+    assert_accounted(&mut disk);
+}
+
+#[test]
+fn soft_limit_is_enforced_once_grace_runs_out() {
+    let image = TempImage::new("quota-soft");
+    let mut disk = setup(&image);
+    disk.set_grace_periods(QuotaKind::User, GracePeriods { blocks: 1, inodes: 1 }).unwrap();
+    disk.set_quota(QuotaKind::User, USER, limits(2, 10)).unwrap();
+
+    // Going over the soft limit starts the grace period
+    disk.write_file_at("/a", &blocks(2, 1)).unwrap();
+    assert_eq!(disk.quota(QuotaKind::User, USER).block_grace_ends, None);
+    let start = now();
+    disk.write_file_at("/b", &blocks(1, 2)).unwrap();
+    let usage = disk.quota(QuotaKind::User, USER);
+    assert!(usage.is_over());
+    let ends = usage.block_grace_ends.unwrap();
+    assert!((start + 1..=now() + 1).contains(&ends), "{} not a second after {}", ends, start);
+
+    // Once it is over, the soft limit holds like a hard one
+    while now() < ends {
+        thread::sleep(Duration::from_millis(100));
+    }
+    expect_exceeded(disk.write_file_at("/c", &blocks(1, 3)));
+    assert_eq!(used(&disk, QuotaKind::User, USER).0, 3);
+
+    // Until usage drops back to it
+    disk.remove_path("/b").unwrap();
+    assert_eq!(disk.quota(QuotaKind::User, USER).block_grace_ends, None);
+    assert!(!disk.quota(QuotaKind::User, USER).is_over());
+    disk.write_file_at("/c", &blocks(1, 3)).unwrap();
+    assert!(disk.quota(QuotaKind::User, USER).block_grace_ends.is_some());
+    assert_accounted(&mut disk);
+}
+
+#[test]
+fn soft_limit_without_grace_is_enforced_at_once() {
+    let image = TempImage::new("quota-soft-no-grace");
+    let mut disk = setup(&image);
+    disk.set_grace_periods(QuotaKind::Group, GracePeriods { blocks: 0, inodes: 0 }).unwrap();
+    let limits = QuotaLimits { block_soft: 2, inode_soft: 1, ..QuotaLimits::default() };
+    disk.set_quota(QuotaKind::Group, GROUP, limits).unwrap();
+
+    disk.write_file_at("/a", &blocks(2, 1)).unwrap();
+    expect_exceeded(disk.append_file_at("/a", &blocks(1, 2)));
+    expect_exceeded(disk.create_file_at("/b", Permissions::new(true, true, false)));
+    assert_eq!(used(&disk, QuotaKind::Group, GROUP), (2, 1));
+    assert!(!disk.quota(QuotaKind::Group, GROUP).is_over());
+    assert_accounted(&mut disk);
+}
+
+#[test]
+fn recount_agrees_with_accounting() {
+    let image = TempImage::new("quota-recount");
+    let mut disk = setup(&image);
+    let perms = Permissions::new(true, true, true);
+    disk.create_directory_at("/proj", perms).unwrap();
+    disk.set_project_tree("/proj", 3).unwrap();
+
+    // Writes, appends, holes, clones and moves between owners and
+    // projects, each charged as it goes
+    disk.write_file_at("/a", &blocks(6, 1)).unwrap();
+    disk.append_file_at("/a", &blocks(2, 2)).unwrap();
+    let a = disk.lookup_path("/a").unwrap();
+    disk.punch_hole(a, BLOCK as u64, 2 * BLOCK as u64).unwrap();
+    disk.write_at(a, 20 * BLOCK as u64, b"past the end").unwrap();
+    let clone = disk.create_file_at("/proj/clone", perms).unwrap();
+    disk.clone_file(a, clone).unwrap();
+    disk.write_at(clone, 0, b"unshared").unwrap();
+    disk.set_credentials(USER + 1, GROUP + 1);
+    disk.write_file_at("/proj/b", &blocks(3, 3)).unwrap();
+    disk.rename_path("/proj/b", "/b").unwrap();
+    disk.set_owner(a, USER + 2, GROUP).unwrap();
+    disk.create_symlink("/proj/clone", "/proj/link").unwrap();
+    disk.remove_path("/proj/clone").unwrap();
+    disk.snapshot_create("kept").unwrap();
+    disk.write_file_at("/b", &blocks(1, 4)).unwrap();
+
+    assert!(used(&disk, QuotaKind::User, USER + 2).0 > 0);
+    // Left in the project: its directory and the symlink
+    assert_eq!(used(&disk, QuotaKind::Project, 3), (2, 2));
+    assert_accounted(&mut disk);
+
+    // Usage counted again when the image is opened is the same too
+    let report = disk.quota_report();
+    drop(disk);
+    let mut disk = VirtualDisk::new(image.path()).unwrap();
+    assert_eq!(disk.quota_report(), report);
+    assert_accounted(&mut disk);
+}
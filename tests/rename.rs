@@ -0,0 +1,135 @@
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    error::{FsError, FsResult},
+    quota::{QuotaKind, QuotaLimits},
+    serialization::Permissions,
+    shared::SharedDisk,
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+
+const BLOCK: usize = 4096;
+
+fn blocks(count: usize, seed: u8) -> Vec<u8> {
+    (0..count * BLOCK).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+/// A fresh image with two project trees, `/p1` and `/p2`, and a
+/// directory outside any project
+///
+/// `/p1` holds a three-block file `f`, a file `g` with a second name
+/// `g2`, and a directory `sub` holding a file.
+fn setup(image: &TempImage) -> VirtualDisk {
+    let options = FormatOptions {
+        size: 8 * 1024 * 1024,
+        inline_data: false,
+        ..FormatOptions::default()
+    };
+    let mut disk = VirtualDisk::format(image.path(), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+    let perms = Permissions::new(true, true, true);
+    for (dir, project) in [("/p1", 1), ("/p2", 2)] {
+        disk.create_directory_at(dir, perms).unwrap();
+        disk.set_project_tree(dir, project).unwrap();
+    }
+    disk.create_directory_at("/plain", perms).unwrap();
+    disk.write_file_at("/p1/f", &blocks(3, 1)).unwrap();
+    disk.write_file_at("/p1/g", &blocks(1, 2)).unwrap();
+    disk.create_hard_link("/p1/g", "/p1/g2").unwrap();
+    disk.create_directory_at("/p1/sub", perms).unwrap();
+    disk.write_file_at("/p1/sub/h", &blocks(1, 3)).unwrap();
+    disk.set_quota(QuotaKind::Project, 2, QuotaLimits { block_hard: 6, ..QuotaLimits::default() }).unwrap();
+    disk
+}
+
+fn used(disk: &VirtualDisk, project: u32) -> (u64, u64) {
+    let usage = disk.quota(QuotaKind::Project, project);
+    (usage.blocks, usage.inodes)
+}
+
+fn expect_crosses<T: std::fmt::Debug>(result: FsResult<T>) {
+    assert!(matches!(result, Err(FsError::CrossesProjects(_))), "expected CrossesProjects, got {:?}", result);
+}
+
+fn assert_accounted(disk: &mut VirtualDisk) {
+    let report = disk.fsck().unwrap();
+    assert!(report.is_clean(), "fsck: {:?}", report.issues);
+    let followed = disk.quota_report();
+    disk.quota_recount().unwrap();
+    assert_eq!(disk.quota_report(), followed);
+}
+
+#[test]
+fn rename_path_between_projects() {
+    let image = TempImage::new("rename-projects");
+    let mut disk = setup(&image);
+    let (p1, p2) = (used(&disk, 1), used(&disk, 2));
+
+    // A directory inside a project cannot leave it, into another project
+    // or out of any
+    expect_crosses(disk.rename_path("/p1/sub", "/p2/sub"));
+    expect_crosses(disk.rename_path("/p1/sub", "/plain"));
+    assert_eq!(disk.read_file_at("/p1/sub/h").unwrap(), blocks(1, 3));
+    // Nor can a file with other names
+    expect_crosses(disk.rename_path("/p1/g", "/p2/g"));
+    assert_eq!((used(&disk, 1), used(&disk, 2)), (p1, p2));
+
+    // A file moves, taking its charge along
+    disk.rename_path("/p1/f", "/p2/f").unwrap();
+    assert_eq!(disk.stat_path("/p2/f").unwrap().project, 2);
+    assert_eq!(used(&disk, 1), (p1.0 - 3, p1.1 - 1));
+    assert_eq!(used(&disk, 2), (p2.0 + 3, p2.1 + 1));
+
+    // Within the limits of its new project
+    disk.write_file_at("/plain/big", &blocks(3, 4)).unwrap();
+    let plain = used(&disk, 0);
+    assert!(matches!(disk.rename_path("/plain/big", "/p2/big"), Err(FsError::QuotaExceeded(_))));
+    assert_eq!(disk.read_file_at("/plain/big").unwrap(), blocks(3, 4));
+    assert_eq!(used(&disk, 0), plain);
+
+    // Replacing a file there frees its charge first
+    disk.write_file_at("/p1/small", &blocks(1, 5)).unwrap();
+    disk.rename_path("/p1/small", "/p2/f").unwrap();
+    assert_eq!(used(&disk, 2), (p2.0 + 1, p2.1 + 1));
+
+    // The root of a project tree keeps its project wherever it goes
+    disk.rename_path("/p1", "/p2/p1").unwrap();
+    assert_eq!(disk.stat_path("/p2/p1").unwrap().project, 1);
+    assert_eq!(disk.stat_path("/p2/p1/sub/h").unwrap().project, 1);
+    assert_eq!(used(&disk, 2), (p2.0 + 1, p2.1 + 1));
+
+    // Moves within a project need no checks
+    disk.rename_path("/p2/p1/sub/h", "/p2/p1/h").unwrap();
+    disk.rename_path("/p2/p1/g2", "/p2/p1/sub/g2").unwrap();
+    assert_accounted(&mut disk);
+}
+
+#[test]
+fn shared_rename_between_projects() {
+    let image = TempImage::new("rename-projects-shared");
+    let disk = setup(&image);
+    let (p1, p2) = (used(&disk, 1), used(&disk, 2));
+    let disk = SharedDisk::new(disk);
+
+    expect_crosses(disk.rename("/p1/sub", "/p2/sub"));
+    expect_crosses(disk.rename("/p1/sub", "/plain/sub"));
+    expect_crosses(disk.rename("/p1/g2", "/p2/g2"));
+    assert_eq!(disk.read_file("/p1/sub/h").unwrap(), blocks(1, 3));
+
+    disk.rename("/p1/f", "/p2/f").unwrap();
+    assert_eq!(disk.stat("/p2/f").unwrap().project, 2);
+
+    disk.write_file("/plain/big", &blocks(3, 4)).unwrap();
+    assert!(matches!(disk.rename("/plain/big", "/p2/big"), Err(FsError::QuotaExceeded(_))));
+    assert_eq!(disk.read_file("/plain/big").unwrap(), blocks(3, 4));
+
+    disk.rename("/p1", "/p2/p1").unwrap();
+    assert_eq!(disk.stat("/p2/p1/sub").unwrap().project, 1);
+
+    // Only the file that moved was charged again
+    let mut disk = disk.into_disk();
+    assert_eq!(used(&disk, 1), (p1.0 - 3, p1.1 - 1));
+    assert_eq!(used(&disk, 2), (p2.0 + 3, p2.1 + 1));
+    assert_accounted(&mut disk);
+}
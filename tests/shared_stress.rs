@@ -0,0 +1,250 @@
+//! Stress test for `SharedDisk`: writer threads create, rewrite, rename
+//! and remove files and directories while reader threads keep reading
+//! them back, and a background thread keeps scrubbing the disk. Every
+//! file holds a self-describing pattern, so a reader can tell a torn or
+//! mixed-up file from a good one. At the end each writer's private
+//! directory must hold exactly what it last wrote there, and the image
+//! must pass fsck.
+
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    error::{FsError, FsResult},
+    serialization::Permissions,
+    shared::SharedDisk,
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+const THREADS: u64 = 4;
+const OPS: u64 = 150;
+
+/// Largest file written, in bytes
+const MAX_FILE_SIZE: u64 = 48 * 1024;
+
+/// Files per private writer directory, and shared names of each kind
+const PRIVATE_FILES: u64 = 8;
+const SHARED_NAMES: u64 = 12;
+
+/// Small xorshift generator, seeded per thread
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// File contents: seed and length, then bytes derived from the seed
+fn pattern(seed: u64, len: u64) -> Vec<u8> {
+    let mut data = Vec::with_capacity(16 + len as usize);
+    data.extend_from_slice(&seed.to_le_bytes());
+    data.extend_from_slice(&len.to_le_bytes());
+    data.extend((0..len).map(|i| (seed.wrapping_mul(31).wrapping_add(i) % 251) as u8));
+    data
+}
+
+/// Check that `data` is a complete pattern
+fn check_pattern(data: &[u8]) -> Result<(), String> {
+    if data.len() < 16 {
+        return Err(format!("file of {} bytes has no header", data.len()));
+    }
+    let seed = u64::from_le_bytes(data[..8].try_into().unwrap());
+    let len = u64::from_le_bytes(data[8..16].try_into().unwrap());
+    if data != pattern(seed, len) {
+        return Err(format!("file of {} bytes does not match seed {} length {}", data.len(), seed, len));
+    }
+    Ok(())
+}
+
+/// Errors that concurrent changes by other threads can cause
+fn expected<T>(result: FsResult<T>) -> FsResult<Option<T>> {
+    match result {
+        Err(
+            FsError::FileNotFound(_)
+            | FsError::DirectoryNotFound(_)
+            | FsError::AlreadyExists(_)
+            | FsError::DirectoryNotEmpty(_)
+            | FsError::InvalidPath(_),
+        ) => Ok(None),
+        result => result.map(Some),
+    }
+}
+
+/// Run `ops` random operations; returns what the writer's private
+/// directory should hold afterwards, by file name
+fn writer(disk: &SharedDisk, id: u64, ops: u64) -> FsResult<BTreeMap<String, Vec<u8>>> {
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15 ^ (id + 1).wrapping_mul(0x2545_f491_4f6c_dd1d));
+    let perms = Permissions::new(true, true, true);
+    let mut private = BTreeMap::new();
+    for _ in 0..ops {
+        let seed = rng.next();
+        let data = pattern(seed, rng.below(MAX_FILE_SIZE));
+        let (a, b) = (rng.below(SHARED_NAMES), rng.below(SHARED_NAMES));
+        let name = format!("f{}", rng.below(PRIVATE_FILES));
+        let result = match rng.below(12) {
+            0..=3 => {
+                disk.write_file(&format!("/w{}/{}", id, name), &data)?;
+                private.insert(name, data);
+                Ok(())
+            }
+            4..=5 => disk.write_file(&format!("/shared/f{}", a), &data).map(|_| ()),
+            6 => disk.remove(&format!("/shared/f{}", a)),
+            7 => disk.rename(&format!("/shared/f{}", a), &format!("/shared/f{}", b)),
+            8 => {
+                let moved = disk.rename(&format!("/w{}/{}", id, name), &format!("/shared/f{}", a));
+                if moved.is_ok() {
+                    private.remove(&name);
+                }
+                moved
+            }
+            9 => disk.create_directory(&format!("/shared/d{}", a), perms).map(|_| ()),
+            10 => disk.remove(&format!("/shared/d{}", a)),
+            // Move directories in and out of each other, racing the check
+            // that keeps a directory from moving below itself
+            _ => match disk.lookup(&format!("/shared/d{}", a)) {
+                Ok(_) => disk.rename(&format!("/shared/d{}", a), &format!("/shared/d{}/d{}", b, a)),
+                Err(_) => disk.rename(&format!("/shared/d{}/d{}", b, a), &format!("/shared/d{}", a)),
+            },
+        };
+        expected(result)?;
+    }
+    Ok(private)
+}
+
+/// Read every file in `dir`, returning how many were checked
+fn read_directory(disk: &SharedDisk, dir: &str) -> Result<u64, String> {
+    let entries = match disk.list_directory(dir) {
+        Ok(entries) => entries,
+        Err(FsError::FileNotFound(_)) => return Ok(0),
+        Err(e) => return Err(format!("{}: {}", dir, e)),
+    };
+    let mut checked = 0;
+    for entry in entries.iter().filter(|e| e.name.starts_with('f')) {
+        let path = format!("{}/{}", dir, entry.name);
+        match disk.read_file(&path) {
+            Ok(data) => {
+                check_pattern(&data).map_err(|e| format!("{}: {}", path, e))?;
+                checked += 1;
+            }
+            Err(FsError::FileNotFound(_)) => {}
+            Err(e) => return Err(format!("{}: {}", path, e)),
+        }
+    }
+    Ok(checked)
+}
+
+fn run(name: &str, options: FormatOptions) {
+    let image = TempImage::new(name);
+    let disk = VirtualDisk::format(image.path(), FormatOptions { size: 64 * 1024 * 1024, ..options }).unwrap();
+    disk.initialize_root_dir().unwrap();
+    let disk = SharedDisk::new(disk);
+    let perms = Permissions::new(true, true, true);
+    disk.create_directory("/shared", perms).unwrap();
+    for id in 0..THREADS {
+        disk.create_directory(&format!("/w{}", id), perms).unwrap();
+    }
+
+    let done = AtomicBool::new(false);
+    let reads = AtomicU64::new(0);
+    let errors = Mutex::new(Vec::new());
+
+    let private: Vec<_> = thread::scope(|scope| {
+        let readers: Vec<_> = (0..THREADS)
+            .map(|id| {
+                let (disk, done, reads, errors) = (&disk, &done, &reads, &errors);
+                scope.spawn(move || {
+                    let dirs: Vec<String> = std::iter::once("/shared".to_string())
+                        .chain((0..THREADS).map(|w| format!("/w{}", (w + id) % THREADS)))
+                        .collect();
+                    while !done.load(Ordering::Relaxed) {
+                        for dir in &dirs {
+                            match read_directory(disk, dir) {
+                                Ok(n) => reads.fetch_add(n, Ordering::Relaxed),
+                                Err(e) => return errors.lock().unwrap().push(e),
+                            };
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let scrubber = {
+            let (disk, done, errors) = (&disk, &done, &errors);
+            scope.spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    match disk.scrub() {
+                        Ok(report) if report.is_clean() => {}
+                        Ok(report) => {
+                            let bad = &report.bad_blocks[0];
+                            return errors.lock().unwrap().push(format!("scrub: block {}: {}", bad.block, bad.error));
+                        }
+                        Err(e) => return errors.lock().unwrap().push(format!("scrub: {}", e)),
+                    };
+                }
+            })
+        };
+
+        let writers: Vec<_> = (0..THREADS)
+            .map(|id| {
+                let disk = &disk;
+                scope.spawn(move || writer(disk, id, OPS))
+            })
+            .collect();
+
+        let private = writers.into_iter().map(|handle| handle.join().unwrap()).collect();
+        done.store(true, Ordering::Relaxed);
+        for handle in readers {
+            handle.join().unwrap();
+        }
+        scrubber.join().unwrap();
+        private
+    });
+
+    let errors = errors.into_inner().unwrap();
+    assert!(errors.is_empty(), "{} errors, first: {}", errors.len(), errors[0]);
+    assert!(reads.load(Ordering::Relaxed) > 0);
+
+    // Every writer's directory holds exactly its last writes
+    for (id, private) in private.into_iter().enumerate() {
+        let private = private.unwrap_or_else(|e| panic!("writer {}: {}", id, e));
+        let dir = format!("/w{}", id);
+        let mut names: Vec<String> = disk.list_directory(&dir).unwrap().into_iter().map(|e| e.name).collect();
+        names.sort();
+        assert_eq!(names, private.keys().cloned().collect::<Vec<_>>(), "{}", dir);
+        for (name, data) in &private {
+            assert!(disk.read_file(&format!("{}/{}", dir, name)).unwrap() == *data, "{}/{}", dir, name);
+        }
+    }
+    read_directory(&disk, "/shared").unwrap();
+
+    let mut disk = disk.into_disk();
+    let report = disk.fsck().unwrap();
+    assert!(report.is_clean(), "fsck: {:?}", report.issues);
+}
+
+#[test]
+fn concurrent_readers_and_writers() {
+    run("stress", FormatOptions::default());
+}
+
+#[test]
+fn concurrent_readers_and_writers_with_extents() {
+    run("stress-extents", FormatOptions { extents: true, ..FormatOptions::default() });
+}
+
+#[test]
+fn concurrent_readers_and_writers_with_checksums() {
+    run("stress-checksums", FormatOptions { data_checksums: true, ..FormatOptions::default() });
+}
@@ -0,0 +1,174 @@
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    error::FsError,
+    shell::{tokenize, Shell, Word, COMMANDS},
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+
+fn setup(image: &TempImage) -> Shell {
+    let options = FormatOptions { size: 8 * 1024 * 1024, ..FormatOptions::default() };
+    let disk = VirtualDisk::format(image.path(), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+    Shell::new(disk)
+}
+
+/// Run a command line and return what it printed
+fn run(shell: &mut Shell, line: &str) -> String {
+    let mut out = Vec::new();
+    shell.execute(line, &mut out).unwrap_or_else(|e| panic!("{}: {:?}", line, e));
+    String::from_utf8(out).unwrap()
+}
+
+fn lines(output: &str) -> Vec<&str> {
+    output.lines().collect()
+}
+
+#[test]
+fn tokenize_quotes_and_redirections() {
+    let text = |s: &str| Word::Text(s.to_string());
+    assert_eq!(
+        tokenize(r#"echo 'a  b' "c \"d\"" e\ f>out"#).unwrap(),
+        [text("echo"), text("a  b"), text("c \"d\""), text("e f"), Word::Redirect, text("out")]
+    );
+    assert_eq!(tokenize("cat x >> y").unwrap(), [text("cat"), text("x"), Word::Append, text("y")]);
+    assert_eq!(tokenize("''").unwrap(), [text("")]);
+    assert!(tokenize("   ").unwrap().is_empty());
+    assert!(matches!(tokenize("echo 'open"), Err(FsError::InvalidPath(_))));
+}
+
+#[test]
+fn directories_and_the_current_directory() {
+    let image = TempImage::new("shell-dirs");
+    let mut shell = setup(&image);
+    assert_eq!(run(&mut shell, "pwd"), "/\n");
+
+    run(&mut shell, "mkdir -p a/b/c");
+    run(&mut shell, "cd a/b");
+    assert_eq!(shell.cwd(), "/a/b");
+    run(&mut shell, "cd ../../a/./b/c");
+    assert_eq!(run(&mut shell, "pwd"), "/a/b/c\n");
+    run(&mut shell, "cd");
+    assert_eq!(shell.cwd(), "/");
+
+    // Without -p the parent has to exist
+    let mut out = Vec::new();
+    assert!(matches!(shell.execute("mkdir x/y", &mut out), Err(FsError::DirectoryNotFound(_) | FsError::FileNotFound(_))));
+    run(&mut shell, "mkdir x x/y");
+    assert_eq!(lines(&run(&mut shell, "ls")), ["a", "x"]);
+
+    run(&mut shell, "touch a/file");
+    assert!(matches!(shell.execute("cd a/file", &mut out), Err(FsError::NotADirectory(_))));
+    assert_eq!(shell.cwd(), "/");
+}
+
+#[test]
+fn echo_redirects_into_files() {
+    let image = TempImage::new("shell-echo");
+    let mut shell = setup(&image);
+    assert_eq!(run(&mut shell, "echo hello   world"), "hello world\n");
+
+    assert_eq!(run(&mut shell, "echo first > notes"), "");
+    run(&mut shell, "echo 'second line' >> notes");
+    assert_eq!(run(&mut shell, "cat notes"), "first\nsecond line\n");
+    run(&mut shell, "echo replaced > notes");
+    assert_eq!(run(&mut shell, "cat /notes notes"), "replaced\nreplaced\n");
+
+    // Any command's output can be redirected
+    run(&mut shell, "pwd > where");
+    assert_eq!(shell.disk().read_file_at("/where").unwrap(), b"/\n");
+
+    let mut out = Vec::new();
+    assert!(matches!(shell.execute("echo x >", &mut out), Err(FsError::InvalidPath(_))));
+    assert!(matches!(shell.execute("echo x > a b", &mut out), Err(FsError::InvalidPath(_))));
+    assert!(matches!(shell.execute("cat missing", &mut out), Err(FsError::FileNotFound(_))));
+}
+
+#[test]
+fn long_listing_and_stat() {
+    let image = TempImage::new("shell-ls");
+    let mut shell = setup(&image);
+    run(&mut shell, "mkdir d");
+    run(&mut shell, "echo 12345 > d/five");
+    run(&mut shell, "ln -s five d/link");
+    run(&mut shell, "ln d/five d/hard");
+
+    let listing = run(&mut shell, "ls -l d");
+    let five = lines(&listing).into_iter().find(|l| l.ends_with(" five")).unwrap();
+    assert!(five.starts_with("-rw-  2          6 "), "{}", five);
+    assert!(listing.lines().any(|l| l.starts_with('l') && l.ends_with(" link -> five")), "{}", listing);
+
+    // Files are listed by themselves, several directories under headings
+    assert_eq!(run(&mut shell, "ls d/five"), "d/five\n");
+    run(&mut shell, "mkdir e");
+    assert_eq!(run(&mut shell, "ls d e"), "d:\nfive\nhard\nlink\n\ne:\n");
+
+    let stat = run(&mut shell, "stat d/link");
+    assert!(stat.starts_with("  File: d/link -> five\n  Type: symbolic link\n"), "{}", stat);
+    let stat = run(&mut shell, "stat d/hard");
+    assert!(stat.contains("  Type: regular file\n"), "{}", stat);
+    assert!(stat.contains("Links: 2  Mode: -rw-"), "{}", stat);
+    assert!(stat.contains("  Size: 6  Blocks: 1  Mapping blocks: 0  Fragments: 1\n"), "{}", stat);
+}
+
+#[test]
+fn copy_move_and_remove() {
+    let image = TempImage::new("shell-files");
+    let mut shell = setup(&image);
+    run(&mut shell, "mkdir -p src/sub");
+    run(&mut shell, "echo one > src/one");
+    run(&mut shell, "echo two > src/sub/two");
+
+    let mut out = Vec::new();
+    assert!(matches!(shell.execute("cp src copy", &mut out), Err(FsError::NotAFile(_))));
+    run(&mut shell, "cp -r src copy");
+    assert_eq!(run(&mut shell, "cat copy/one copy/sub/two"), "one\ntwo\n");
+
+    run(&mut shell, "mv copy/one copy/sub/renamed");
+    assert_eq!(run(&mut shell, "cat copy/sub/renamed"), "one\n");
+    assert!(matches!(shell.execute("cat copy/one", &mut out), Err(FsError::FileNotFound(_))));
+
+    assert!(matches!(shell.execute("rm copy", &mut out), Err(FsError::NotAFile(_))));
+    run(&mut shell, "rm copy/sub/renamed");
+    run(&mut shell, "rm -r copy");
+    assert_eq!(lines(&run(&mut shell, "ls")), ["src"]);
+
+    // The root stays, however it is spelled
+    for line in ["rm -r /", "rm -r src/..", "rm /"] {
+        assert!(matches!(shell.execute(line, &mut out), Err(FsError::PermissionDenied(_))), "{}", line);
+    }
+    assert_eq!(run(&mut shell, "cat src/one"), "one\n");
+
+    let report = shell.disk().fsck().unwrap();
+    assert!(report.is_clean(), "fsck: {:?}", report.issues);
+}
+
+#[test]
+fn tree_df_and_help() {
+    let image = TempImage::new("shell-tree");
+    let mut shell = setup(&image);
+    run(&mut shell, "mkdir -p top/inner");
+    run(&mut shell, "touch top/a top/inner/b");
+    run(&mut shell, "ln -s a top/to-a");
+
+    assert_eq!(
+        run(&mut shell, "tree top"),
+        "top\n├── a\n├── inner\n│   └── b\n└── to-a -> a\n\n1 directories, 3 files\n"
+    );
+
+    let df = run(&mut shell, "df");
+    assert!(df.starts_with("   1K-blocks         Used    Available   Use%\n"), "{}", df);
+    assert!(df.contains("Inodes: "), "{}", df);
+
+    let help = run(&mut shell, "help");
+    assert_eq!(help.lines().count(), COMMANDS.len());
+    for (name, _) in COMMANDS {
+        assert!(help.lines().any(|l| l.trim_start().starts_with(name)), "{}", name);
+    }
+
+    let mut out = Vec::new();
+    assert!(matches!(shell.execute("frobnicate", &mut out), Err(FsError::NotSupported(_))));
+    assert!(out.is_empty());
+    assert_eq!(run(&mut shell, ""), "");
+}
@@ -0,0 +1,264 @@
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    error::FsError,
+    serialization::Permissions,
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+use std::io::ErrorKind;
+
+const FILE_SIZE: usize = 5 * 4096;
+
+fn pattern(seed: u8) -> Vec<u8> {
+    (0..FILE_SIZE).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+/// A fresh image holding `/a` and `/docs/b`
+fn setup(image: &TempImage, options: FormatOptions) -> VirtualDisk {
+    let disk = VirtualDisk::format(image.path(), FormatOptions { size: 8 * 1024 * 1024, ..options }).unwrap();
+    disk.initialize_root_dir().unwrap();
+    disk.create_directory_at("/docs", Permissions::new(true, true, true)).unwrap();
+    disk.write_file_at("/a", &pattern(1)).unwrap();
+    disk.write_file_at("/docs/b", &pattern(2)).unwrap();
+    disk
+}
+
+/// Change everything `setup` wrote: overwrite `/a` in place and as a
+/// whole, remove `/docs/b` and add `/c`
+fn change(disk: &mut VirtualDisk) {
+    let a = disk.lookup_path("/a").unwrap();
+    disk.write_at(a, 100, b"changed").unwrap();
+    disk.write_file_at("/a", &pattern(3)).unwrap();
+    disk.remove_path("/docs/b").unwrap();
+    disk.write_file_at("/c", &pattern(4)).unwrap();
+    disk.sync().unwrap();
+}
+
+/// Check that `disk` holds what `setup` wrote
+fn assert_original(disk: &mut VirtualDisk) {
+    assert_eq!(disk.read_file_at("/a").unwrap(), pattern(1));
+    assert_eq!(disk.read_file_at("/docs/b").unwrap(), pattern(2));
+    assert!(matches!(disk.lookup_path("/c"), Err(FsError::FileNotFound(_))));
+}
+
+fn blocks_of(disk: &mut VirtualDisk, path: &str) -> Vec<u64> {
+    let inode = disk.stat_path(path).unwrap();
+    disk.file_blocks(&inode).unwrap()
+}
+
+fn assert_clean(disk: &mut VirtualDisk) {
+    let report = disk.fsck().unwrap();
+    assert!(report.is_clean(), "fsck: {:?}", report.issues);
+}
+
+#[test]
+fn create_and_list() {
+    let image = TempImage::new("snapshot-create");
+    let mut disk = setup(&image, FormatOptions::default());
+    let used = disk.used_blocks_count();
+    let info = disk.snapshot_create("first").unwrap();
+    assert_eq!(info.name, "first");
+    assert_eq!((info.blocks, info.copied), (used, 0));
+    disk.snapshot_create("second").unwrap();
+
+    let names: Vec<String> = disk.snapshot_list().into_iter().map(|s| s.name).collect();
+    assert_eq!(names, ["first", "second"]);
+    assert!(matches!(disk.snapshot_create("first"), Err(FsError::AlreadyExists(_))));
+    for name in ["", "a/b", "a@b", &"x".repeat(65)] {
+        assert!(matches!(disk.snapshot_create(name), Err(FsError::InvalidFileName(_))), "{:?}", name);
+    }
+
+    // The list is kept in the image
+    drop(disk);
+    let disk = VirtualDisk::new(image.path()).unwrap();
+    assert_eq!(disk.snapshot_list().len(), 2);
+}
+
+#[test]
+fn overwrites_are_copied_aside() {
+    let image = TempImage::new("snapshot-overwrite");
+    let mut disk = setup(&image, FormatOptions { data_checksums: true, ..FormatOptions::default() });
+    disk.snapshot_create("before").unwrap();
+    change(&mut disk);
+
+    assert!(disk.snapshot_list()[0].copied > 0);
+    assert_eq!(disk.read_file_at("/a").unwrap(), pattern(3));
+    assert_eq!(disk.read_file_at("/c").unwrap(), pattern(4));
+    assert_clean(&mut disk);
+
+    let mut snapshot = VirtualDisk::open_snapshot(image.path(), "before").unwrap();
+    assert_original(&mut snapshot);
+    assert_clean(&mut snapshot);
+    assert!(matches!(VirtualDisk::open_snapshot(image.path(), "after"), Err(FsError::SnapshotNotFound(_))));
+}
+
+#[test]
+fn open_snapshot_is_read_only() {
+    let image = TempImage::new("snapshot-read-only");
+    let mut disk = setup(&image, FormatOptions::default());
+    disk.snapshot_create("frozen").unwrap();
+    drop(disk);
+
+    let mut snapshot = VirtualDisk::open_snapshot(image.path(), "frozen").unwrap();
+    for result in [snapshot.write_file_at("/a", b"new").map(|_| ()), snapshot.remove_path("/docs/b")] {
+        match result {
+            Err(FsError::Io(e)) => assert_eq!(e.kind(), ErrorKind::ReadOnlyFilesystem),
+            other => panic!("expected a read-only error, got {:?}", other),
+        }
+    }
+    assert_original(&mut snapshot);
+}
+
+#[test]
+fn rollback_restores_files_and_space() {
+    let image = TempImage::new("snapshot-rollback");
+    let mut disk = setup(&image, FormatOptions { extents: true, ..FormatOptions::default() });
+    disk.snapshot_create("before").unwrap();
+    let free = disk.free_blocks_count();
+    change(&mut disk);
+
+    disk.snapshot_rollback("before").unwrap();
+    assert_original(&mut disk);
+    assert_eq!(disk.free_blocks_count(), free);
+    assert_eq!(disk.snapshot_list().len(), 1);
+    assert_clean(&mut disk);
+
+    // The snapshot still works after a rollback, and survives a reopen
+    change(&mut disk);
+    drop(disk);
+    let mut disk = VirtualDisk::new(image.path()).unwrap();
+    disk.snapshot_rollback("before").unwrap();
+    assert_original(&mut disk);
+    assert_clean(&mut disk);
+    assert!(matches!(disk.snapshot_rollback("missing"), Err(FsError::SnapshotNotFound(_))));
+}
+
+#[test]
+fn rollback_leaves_other_snapshots_alone() {
+    let image = TempImage::new("snapshot-rollback-other");
+    let mut disk = setup(&image, FormatOptions::default());
+    disk.snapshot_create("old").unwrap();
+    disk.write_file_at("/a", &pattern(5)).unwrap();
+    disk.snapshot_create("new").unwrap();
+    change(&mut disk);
+
+    disk.snapshot_rollback("old").unwrap();
+    assert_original(&mut disk);
+    disk.sync().unwrap();
+    let new = VirtualDisk::open_snapshot(image.path(), "new").unwrap();
+    assert_eq!(new.read_file_at("/a").unwrap(), pattern(5));
+    assert_eq!(new.read_file_at("/docs/b").unwrap(), pattern(2));
+    assert_clean(&mut disk);
+}
+
+#[test]
+fn delete_frees_what_only_the_snapshot_kept() {
+    let image = TempImage::new("snapshot-delete");
+    let mut disk = setup(&image, FormatOptions::default());
+    let free = disk.free_blocks_count();
+    disk.snapshot_create("first").unwrap();
+    disk.snapshot_create("second").unwrap();
+
+    // Same sizes as before, so the live files take the same space
+    disk.write_file_at("/a", &pattern(3)).unwrap();
+    disk.write_file_at("/docs/b", &pattern(4)).unwrap();
+    assert!(disk.free_blocks_count() < free);
+
+    // Copies still used by the other snapshot are kept
+    disk.snapshot_delete("first").unwrap();
+    disk.sync().unwrap();
+    let mut second = VirtualDisk::open_snapshot(image.path(), "second").unwrap();
+    assert_original(&mut second);
+
+    disk.snapshot_delete("second").unwrap();
+    assert!(disk.snapshot_list().is_empty());
+    assert_eq!(disk.free_blocks_count(), free);
+    assert!(matches!(disk.snapshot_delete("second"), Err(FsError::SnapshotNotFound(_))));
+    assert_clean(&mut disk);
+}
+
+#[test]
+fn write_raw_bypasses_preservation() {
+    let image = TempImage::new("snapshot-write-raw");
+    let mut disk = setup(&image, FormatOptions::default());
+    disk.snapshot_create("before").unwrap();
+    let block = blocks_of(&mut disk, "/a")[0];
+    let copied = disk.snapshot_list()[0].copied;
+    disk.write_raw(block * disk.block_size(), b"raw").unwrap();
+
+    // Nothing was copied aside, so the snapshot sees the change too
+    assert_eq!(disk.snapshot_list()[0].copied, copied);
+    let snapshot = VirtualDisk::open_snapshot(image.path(), "before").unwrap();
+    let data = snapshot.read_file_at("/a").unwrap();
+    assert_eq!(data[..3], *b"raw");
+    assert_eq!(data[3..], pattern(1)[3..]);
+}
+
+#[test]
+fn snapshots_of_cloned_files() {
+    let image = TempImage::new("snapshot-clones");
+    let mut disk = setup(&image, FormatOptions::default());
+    let a = disk.lookup_path("/a").unwrap();
+    let clone = disk.create_file_at("/clone", Permissions::new(true, true, false)).unwrap();
+    disk.clone_file(a, clone).unwrap();
+    let shared = disk.file_blocks(&disk.read_inode(a).unwrap()).unwrap();
+    assert!(shared.iter().all(|&b| disk.is_block_shared(b)));
+    let free = disk.free_blocks_count();
+    disk.snapshot_create("cloned").unwrap();
+
+    // Writing to the clone moves it off the shared block, and removing
+    // the source leaves the clone the only reference
+    disk.write_at(clone, 0, b"clone").unwrap();
+    disk.remove_path("/a").unwrap();
+    assert!(!shared.iter().any(|&b| disk.is_block_shared(b)));
+    disk.sync().unwrap();
+
+    let snapshot = VirtualDisk::open_snapshot(image.path(), "cloned").unwrap();
+    assert_eq!(snapshot.read_file_at("/a").unwrap(), pattern(1));
+    assert_eq!(snapshot.read_file_at("/clone").unwrap(), pattern(1));
+
+    // The reference counts come back with everything else
+    disk.snapshot_rollback("cloned").unwrap();
+    assert_eq!(disk.read_file_at("/a").unwrap(), pattern(1));
+    assert_eq!(disk.read_file_at("/clone").unwrap(), pattern(1));
+    assert!(shared.iter().all(|&b| disk.is_block_shared(b)));
+    assert_clean(&mut disk);
+
+    // So removing one clone keeps the data for the other
+    disk.snapshot_delete("cloned").unwrap();
+    assert_eq!(disk.free_blocks_count(), free);
+    disk.remove_path("/a").unwrap();
+    assert_eq!(disk.read_file_at("/clone").unwrap(), pattern(1));
+    assert_clean(&mut disk);
+}
+
+#[test]
+fn snapshots_of_deduplicated_files() {
+    let image = TempImage::new("snapshot-dedup");
+    let mut disk = setup(&image, FormatOptions { dedup: true, ..FormatOptions::default() });
+    disk.write_file_at("/twin", &pattern(1)).unwrap();
+    let blocks = blocks_of(&mut disk, "/a");
+    assert_eq!(blocks_of(&mut disk, "/twin"), blocks);
+    disk.snapshot_create("deduped").unwrap();
+
+    disk.write_file_at("/twin", &pattern(6)).unwrap();
+    let a = disk.lookup_path("/a").unwrap();
+    disk.write_at(a, 0, b"unshared").unwrap();
+    assert!(!blocks.iter().any(|&b| disk.is_block_shared(b)));
+    disk.sync().unwrap();
+
+    let snapshot = VirtualDisk::open_snapshot(image.path(), "deduped").unwrap();
+    assert_eq!(snapshot.read_file_at("/twin").unwrap(), pattern(1));
+    assert_eq!(snapshot.read_file_at("/a").unwrap(), pattern(1));
+
+    disk.snapshot_rollback("deduped").unwrap();
+    assert_eq!(disk.read_file_at("/twin").unwrap(), pattern(1));
+    assert!(blocks.iter().all(|&b| disk.is_block_shared(b)));
+    assert_clean(&mut disk);
+
+    // The restored dedup table still finds the blocks
+    disk.write_file_at("/third", &pattern(1)).unwrap();
+    assert_eq!(blocks_of(&mut disk, "/third"), blocks);
+    assert_clean(&mut disk);
+}
@@ -0,0 +1,147 @@
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    serialization::{FileType, Permissions},
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+
+const BLOCK: usize = 4096;
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+fn setup(image: &TempImage) -> VirtualDisk {
+    let options = FormatOptions { size: 8 * 1024 * 1024, ..FormatOptions::default() };
+    let disk = VirtualDisk::format(image.path(), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+    disk
+}
+
+/// Set the permissions and modification time of an image entry
+fn stamp(disk: &VirtualDisk, path: &str, permissions: Permissions, seconds: u64) {
+    let block = disk.lookup_path_nofollow(path).unwrap();
+    let mut inode = disk.read_inode(block).unwrap();
+    inode.permissions = permissions;
+    inode.modified = seconds;
+    disk.write_inode(block, &inode).unwrap();
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+fn assert_clean(disk: &mut VirtualDisk) {
+    let report = disk.fsck().unwrap();
+    assert!(report.is_clean(), "fsck: {:?}", report.issues);
+}
+
+/// Export `/src` of a fresh image set up by `fill`, import the archive
+/// into `/dst` of another, and return both the archive and that image
+fn round_trip(name: &str, fill: impl FnOnce(&mut VirtualDisk)) -> (Vec<u8>, VirtualDisk, TempImage) {
+    let (source, copy) = (TempImage::new(&format!("{}-source", name)), TempImage::new(&format!("{}-copy", name)));
+    let mut disk = setup(&source);
+    disk.create_directory_at("/src", Permissions::new(true, true, true)).unwrap();
+    fill(&mut disk);
+    let mut archive = Vec::new();
+    let exported = disk.export_tar("/src", &mut archive).unwrap();
+    assert_eq!(archive.len() % 512, 0);
+
+    let mut imported = setup(&copy);
+    assert_eq!(imported.import_tar(&mut archive.as_slice(), "/dst").unwrap(), exported);
+    assert_clean(&mut imported);
+    (archive, imported, copy)
+}
+
+#[test]
+fn files_and_directories_keep_contents_modes_and_times() {
+    let (_, disk, _image) = round_trip("tar-files", |disk| {
+        disk.create_directory_at("/src/sub", Permissions::new(true, true, true)).unwrap();
+        disk.write_file_at("/src/sub/data.bin", &pattern(2 * BLOCK + 11, 1)).unwrap();
+        disk.write_file_at("/src/empty", b"").unwrap();
+        disk.write_file_at("/src/run.sh", b"#!/bin/sh\n").unwrap();
+        stamp(disk, "/src/sub/data.bin", Permissions::new(true, false, false), 1_000_000_000);
+        stamp(disk, "/src/run.sh", Permissions::new(true, true, true), 1_100_000_000);
+        stamp(disk, "/src/sub", Permissions::new(true, true, true), 1_200_000_000);
+    });
+
+    assert_eq!(disk.read_file_at("/dst/sub/data.bin").unwrap(), pattern(2 * BLOCK + 11, 1));
+    assert!(disk.read_file_at("/dst/empty").unwrap().is_empty());
+
+    let data = disk.stat_path("/dst/sub/data.bin").unwrap();
+    assert_eq!(data.permissions.to_mode(), 0o444);
+    assert_eq!(data.modified, 1_000_000_000);
+    let script = disk.stat_path("/dst/run.sh").unwrap();
+    assert_eq!(script.permissions.to_mode(), 0o755);
+    assert_eq!(script.modified, 1_100_000_000);
+    // Set after its contents were added
+    let sub = disk.stat_path("/dst/sub").unwrap();
+    assert_eq!(sub.file_type, FileType::Directory);
+    assert_eq!(sub.modified, 1_200_000_000);
+}
+
+#[test]
+fn hard_links_are_stored_once() {
+    let (archive, disk, _image) = round_trip("tar-hard-links", |disk| {
+        disk.create_directory_at("/src/b", Permissions::new(true, true, true)).unwrap();
+        disk.write_file_at("/src/a", &pattern(3 * BLOCK, 2)).unwrap();
+        disk.create_hard_link("/src/a", "/src/b/second").unwrap();
+        disk.create_hard_link("/src/a", "/src/c").unwrap();
+    });
+
+    // The data appears in the archive once, the other names as links
+    let links = archive
+        .chunks(512)
+        .filter(|header| &header[257..262] == b"ustar" && header[156] == b'1')
+        .count();
+    assert_eq!(links, 2);
+    assert!(archive.len() < 2 * 3 * BLOCK);
+
+    let a = disk.lookup_path("/dst/a").unwrap();
+    assert_eq!(disk.lookup_path("/dst/b/second").unwrap(), a);
+    assert_eq!(disk.lookup_path("/dst/c").unwrap(), a);
+    assert_eq!(disk.read_inode(a).unwrap().link_count, 3);
+    assert_eq!(disk.read_file_at("/dst/c").unwrap(), pattern(3 * BLOCK, 2));
+}
+
+#[test]
+fn long_paths_use_pax_headers() {
+    let deep: Vec<String> = (0..6).map(|i| format!("directory-{:02}-{}", i, "x".repeat(20))).collect();
+    let dir = format!("/src/{}", deep.join("/"));
+    let name = format!("{}.txt", "n".repeat(120));
+    let long = format!("{}/{}", dir, name);
+    let (archive, disk, _image) = round_trip("tar-long-paths", |disk| {
+        let mut path = String::from("/src");
+        for part in &deep {
+            path = format!("{}/{}", path, part);
+            disk.create_directory_at(&path, Permissions::new(true, true, true)).unwrap();
+        }
+        disk.write_file_at(&long, b"deep down").unwrap();
+    });
+
+    let member = format!("{}/{}", deep.join("/"), name);
+    assert!(member.len() > 255);
+    assert!(contains(&archive, format!(" path={}\n", member).as_bytes()));
+    assert_eq!(disk.read_file_at(&long.replacen("/src", "/dst", 1)).unwrap(), b"deep down");
+}
+
+#[test]
+fn symlinks_keep_their_targets() {
+    let far = format!("/{}/target", "t".repeat(150));
+    let (archive, disk, _image) = round_trip("tar-symlinks", |disk| {
+        disk.write_file_at("/src/file", b"pointed at").unwrap();
+        disk.create_symlink("file", "/src/relative").unwrap();
+        disk.create_symlink("/nowhere/at/all", "/src/dangling").unwrap();
+        disk.create_symlink(&far, "/src/far").unwrap();
+    });
+
+    assert_eq!(disk.read_link("/dst/relative").unwrap(), "file");
+    assert_eq!(disk.read_file_at("/dst/relative").unwrap(), b"pointed at");
+    assert_eq!(disk.read_link("/dst/dangling").unwrap(), "/nowhere/at/all");
+    // Too long for the header, so kept in a pax record
+    assert!(contains(&archive, format!(" linkpath={}\n", far).as_bytes()));
+    assert_eq!(disk.read_link("/dst/far").unwrap(), far);
+    let link = disk.lookup_path_nofollow("/dst/far").unwrap();
+    assert_eq!(disk.read_inode(link).unwrap().file_type, FileType::Symlink);
+}
@@ -0,0 +1,184 @@
+mod common;
+
+use common::TempImage;
+use file_system_simulator::{
+    error::FsError,
+    serialization::{FileType, Permissions},
+    virtual_disk::{FormatOptions, VirtualDisk},
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+const BLOCK: usize = 4096;
+
+/// A host directory in the temporary directory, removed when dropped
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("fssim-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+fn setup(image: &TempImage, size: u64) -> VirtualDisk {
+    let options = FormatOptions { size, ..FormatOptions::default() };
+    let disk = VirtualDisk::format(image.path(), options).unwrap();
+    disk.initialize_root_dir().unwrap();
+    disk
+}
+
+fn modified(path: &Path) -> u64 {
+    let metadata = fs::symlink_metadata(path).unwrap();
+    metadata.modified().unwrap().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Set the permissions and times of an image entry
+fn stamp(disk: &VirtualDisk, path: &str, permissions: Permissions, seconds: u64) {
+    let block = disk.lookup_path_nofollow(path).unwrap();
+    let mut inode = disk.read_inode(block).unwrap();
+    inode.permissions = permissions;
+    inode.modified = seconds;
+    inode.accessed = seconds;
+    disk.write_inode(block, &inode).unwrap();
+}
+
+fn assert_clean(disk: &mut VirtualDisk) {
+    let report = disk.fsck().unwrap();
+    assert!(report.is_clean(), "fsck: {:?}", report.issues);
+}
+
+#[test]
+fn import_then_export_round_trip() {
+    let image = TempImage::new("transfer-round-trip");
+    let (source, copy) = (TempDir::new("transfer-source"), TempDir::new("transfer-copy"));
+    fs::create_dir_all(source.path().join("docs/deep")).unwrap();
+    fs::write(source.path().join("top.txt"), b"top").unwrap();
+    fs::write(source.path().join("docs/big.bin"), pattern(3 * BLOCK + 7, 1)).unwrap();
+    fs::write(source.path().join("docs/deep/empty"), b"").unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink("../top.txt", source.path().join("docs/link")).unwrap();
+
+    let mut disk = setup(&image, 8 * 1024 * 1024);
+    let mut seen = Vec::new();
+    let report = disk.import_tree(source.path(), "/imported", &mut |e| seen.push(e.image_path.clone())).unwrap();
+    assert!(report.is_complete());
+    assert_eq!((report.files(), report.directories()), (3, 3));
+    assert_eq!(report.bytes(), 3 + 3 * BLOCK as u64 + 7);
+    assert_eq!(seen, report.entries.iter().map(|e| e.image_path.clone()).collect::<Vec<_>>());
+    assert_eq!(disk.read_file_at("/imported/docs/big.bin").unwrap(), pattern(3 * BLOCK + 7, 1));
+    #[cfg(unix)]
+    assert_eq!(disk.read_link("/imported/docs/link").unwrap(), "../top.txt");
+    assert_clean(&mut disk);
+
+    let report = disk.export_tree("/imported", copy.path(), &mut |_| {}).unwrap();
+    assert!(report.is_complete());
+    assert_eq!(fs::read(copy.path().join("top.txt")).unwrap(), b"top");
+    assert_eq!(fs::read(copy.path().join("docs/big.bin")).unwrap(), pattern(3 * BLOCK + 7, 1));
+    assert!(fs::read(copy.path().join("docs/deep/empty")).unwrap().is_empty());
+    #[cfg(unix)]
+    assert_eq!(fs::read_link(copy.path().join("docs/link")).unwrap(), Path::new("../top.txt"));
+    assert_eq!(modified(&copy.path().join("top.txt")), modified(&source.path().join("top.txt")));
+
+    // Nothing but directories, files and symlinks is copied
+    assert!(matches!(
+        disk.export_tree("/imported/top.txt", copy.path(), &mut |_| {}),
+        Err(FsError::NotADirectory(_))
+    ));
+}
+
+#[test]
+fn export_restores_modes_and_times() {
+    let image = TempImage::new("transfer-modes");
+    let copy = TempDir::new("transfer-modes");
+    let mut disk = setup(&image, 8 * 1024 * 1024);
+    disk.create_directory_at("/out", Permissions::new(true, true, true)).unwrap();
+    disk.write_file_at("/out/read-only", b"read only").unwrap();
+    disk.write_file_at("/out/write-only", b"write only").unwrap();
+    disk.write_file_at("/out/script", b"#!/bin/sh\n").unwrap();
+    let modes = [
+        ("/out/read-only", Permissions::new(true, false, false)),
+        ("/out/write-only", Permissions::new(false, true, false)),
+        ("/out/script", Permissions::new(true, true, true)),
+        ("/out", Permissions::new(true, false, true)),
+    ];
+    for (i, (path, permissions)) in modes.into_iter().enumerate() {
+        stamp(&disk, path, permissions, 1_000_000_000 + i as u64 * 1000);
+    }
+
+    // Twice, the second time over the read-only files of the first
+    for _ in 0..2 {
+        let report = disk.export_tree("/out", copy.path(), &mut |_| {}).unwrap();
+        assert!(report.is_complete());
+        assert_eq!(modified(&copy.path().join("read-only")), 1_000_000_000);
+        assert_eq!(modified(&copy.path().join("write-only")), 1_000_001_000);
+        assert_eq!(modified(&copy.path().join("script")), 1_000_002_000);
+        assert_eq!(modified(copy.path()), 1_000_003_000);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |name: &str| fs::metadata(copy.path().join(name)).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode("read-only"), 0o444);
+            assert_eq!(mode("write-only"), 0o200);
+            assert_eq!(mode("script"), 0o755);
+            // Directories stay open to their owner
+            assert_eq!(mode(""), 0o755);
+        }
+        #[cfg(not(unix))]
+        assert!(fs::metadata(copy.path().join("read-only")).unwrap().permissions().readonly());
+    }
+    assert_eq!(fs::read(copy.path().join("read-only")).unwrap(), b"read only");
+}
+
+#[test]
+fn import_stops_cleanly_when_the_disk_is_full() {
+    let image = TempImage::new("transfer-full");
+    let source = TempDir::new("transfer-full");
+    let mut disk = setup(&image, 2 * 1024 * 1024);
+    let room = disk.free_blocks_count() as usize;
+    for (i, blocks) in [1, 2, room / 2, room / 2, 1].into_iter().enumerate() {
+        fs::write(source.path().join(format!("{}.bin", i)), pattern(blocks * BLOCK, i as u8)).unwrap();
+    }
+
+    let report = disk.import_tree(source.path(), "/in", &mut |_| {}).unwrap();
+    assert!(matches!(report.stopped, Some(FsError::DiskFull)), "{:?}", report.stopped);
+    assert!(!report.is_complete());
+
+    // What is listed was copied whole, and nothing else was left behind
+    let copied: Vec<&str> = report.entries.iter().map(|e| e.image_path.as_str()).collect();
+    assert_eq!(copied, ["/in", "/in/0.bin", "/in/1.bin", "/in/2.bin"]);
+    assert_eq!(report.entries[3].file_type, FileType::File);
+    for entry in &report.entries[1..] {
+        assert_eq!(disk.read_file_at(&entry.image_path).unwrap(), fs::read(&entry.host_path).unwrap());
+    }
+    let mut names: Vec<String> = disk.list_directory_at("/in").unwrap().into_iter().map(|e| e.name).collect();
+    names.retain(|name| name != "." && name != "..");
+    names.sort();
+    assert_eq!(names, ["0.bin", "1.bin", "2.bin"]);
+    assert_clean(&mut disk);
+
+    // Once there is room again the copy finishes
+    disk.remove_path("/in/2.bin").unwrap();
+    fs::remove_file(source.path().join("3.bin")).unwrap();
+    let report = disk.import_tree(source.path(), "/in", &mut |_| {}).unwrap();
+    assert!(report.is_complete());
+    assert_eq!(disk.read_file_at("/in/4.bin").unwrap(), pattern(BLOCK, 4));
+}